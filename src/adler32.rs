@@ -0,0 +1,73 @@
+// Adler-32 as used by zlib streams, RFC 1950 section 8.2 https://tools.ietf.org/html/rfc1950
+// Two running sums mod 65521, the largest prime below 2^16.
+
+const MODULUS: u32 = 65521;
+
+/// Most bytes that can be summed before `b` could overflow a u32 and has to be reduced, zlib's NMAX
+const MAX_RUN: usize = 5552;
+
+/// Running Adler-32, for data that arrives in pieces
+#[derive(Debug, Copy, Clone)]
+pub struct Adler32 {
+    a: u32,
+    b: u32
+}
+
+impl Default for Adler32 {
+    fn default() -> Adler32 {
+        Adler32::new()
+    }
+}
+
+impl Adler32 {
+    pub fn new() -> Adler32 {
+        Adler32 { a: 1, b: 0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for run in data.chunks(MAX_RUN) {
+            for &byte in run {
+                self.a += byte as u32;
+                self.b += self.a;
+            }
+            self.a %= MODULUS;
+            self.b %= MODULUS;
+        }
+    }
+
+    /// The checksum of everything passed to `update` so far
+    pub fn finish(&self) -> u32 {
+        self.b << 16 | self.a
+    }
+}
+
+/// Adler-32 of a complete buffer
+pub fn checksum(data: &[u8]) -> u32 {
+    let mut adler = Adler32::new();
+    adler.update(data);
+    adler.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_values() {
+        assert_eq!(checksum(b""), 1);
+        assert_eq!(checksum(b"abc"), 0x024D_0127);
+        assert_eq!(checksum(b"Wikipedia"), 0x11E6_0398);
+        // Long enough that both sums wrap many times over
+        assert_eq!(checksum(&[0xFF; 1_000_000]), 0x3843_E1BE);
+    }
+
+    #[test]
+    fn pieces_sum_to_the_whole() {
+        let data: Vec<u8> = (0..20_000u32).map(|i| (i * 7 + i / 300) as u8).collect();
+        for &piece in &[1, 7, MAX_RUN - 1, MAX_RUN, MAX_RUN + 1] {
+            let mut adler = Adler32::new();
+            data.chunks(piece).for_each(|chunk| adler.update(chunk));
+            assert_eq!(adler.finish(), checksum(&data), "{} byte pieces", piece);
+        }
+    }
+}
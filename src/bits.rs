@@ -0,0 +1,233 @@
+// Bit-level reading and writing for the huffman and deflate coders.
+// DEFLATE packs bits LSB-first: the first bit of the stream is the lowest bit of the first byte.
+// The tree coder's codes_to_bin packs MSB-first instead, the first bit being the highest of the first byte.
+use std::fmt;
+
+/// Which end of each byte the stream starts from
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BitOrder {
+    LsbFirst,
+    MsbFirst
+}
+
+/// Ran out of input partway through a read
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EndOfInput;
+
+impl fmt::Display for EndOfInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unexpected end of input")
+    }
+}
+
+impl std::error::Error for EndOfInput {}
+
+fn mask(count: u32) -> u64 {
+    if count >= 64 { u64::MAX } else { (1 << count) - 1 }
+}
+
+/// Reads bits out of a byte slice through a 64 bit buffer, refilled a byte at a time as it runs low.
+/// The buffer holds the next `bit_count` bits of the stream in its low bits; LSB-first the next bit
+/// is bit 0, MSB-first it's bit `bit_count - 1`.
+/// Cloning one is a cheap checkpoint to rewind to when a read runs out of input partway.
+#[derive(Clone)]
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit_buf: u64,
+    bit_count: u32,
+    order: BitOrder
+}
+
+impl BitReader<'_> {
+    /// Reads LSB-first, as DEFLATE does
+    pub fn new(data: &[u8]) -> BitReader<'_> {
+        BitReader::with_order(data, BitOrder::LsbFirst)
+    }
+
+    pub fn with_order(data: &[u8], order: BitOrder) -> BitReader<'_> {
+        BitReader { data, pos: 0, bit_buf: 0, bit_count: 0, order }
+    }
+
+    /// Moves as many whole bytes as fit from the input into the buffer
+    pub fn refill(&mut self) {
+        while self.bit_count <= 56 && self.pos < self.data.len() {
+            let byte = self.data[self.pos] as u64;
+            match self.order {
+                BitOrder::LsbFirst => self.bit_buf |= byte << self.bit_count,
+                BitOrder::MsbFirst => self.bit_buf = self.bit_buf << 8 | byte
+            }
+            self.pos += 1;
+            self.bit_count += 8;
+        }
+    }
+
+    /// The next `count` bits (at most 32) without using them up, the first bit read landing in bit
+    /// `count - 1` for MSB-first streams.
+    /// Past the end of the input the missing bits read as zero; `consume` is what notices.
+    pub fn peek(&mut self, count: u32) -> u32 {
+        if self.bit_count < count {
+            self.refill();
+        }
+        let bits = match self.order {
+            BitOrder::LsbFirst => self.bit_buf,
+            BitOrder::MsbFirst if self.bit_count >= count => self.bit_buf >> (self.bit_count - count),
+            BitOrder::MsbFirst => self.bit_buf << (count - self.bit_count)
+        };
+        (bits & mask(count)) as u32
+    }
+
+    /// Whether at least `count` more bits of input are left
+    pub fn has_bits(&mut self, count: u32) -> bool {
+        if self.bit_count < count {
+            self.refill();
+        }
+        self.bit_count >= count
+    }
+
+    /// Bits read so far
+    pub fn bit_position(&self) -> usize {
+        self.pos * 8 - self.bit_count as usize
+    }
+
+    /// Whole bytes left to read; only meaningful on a byte boundary
+    pub fn remaining_bytes(&self) -> usize {
+        self.data.len() - self.pos + (self.bit_count / 8) as usize
+    }
+
+    /// Drops `count` bits, which must have been peeked first
+    pub fn consume(&mut self, count: u32) -> Result<(), EndOfInput> {
+        if count > self.bit_count {
+            return Err(EndOfInput);
+        }
+        self.bit_count -= count;
+        match self.order {
+            BitOrder::LsbFirst => self.bit_buf = if count == 64 { 0 } else { self.bit_buf >> count },
+            BitOrder::MsbFirst => self.bit_buf &= mask(self.bit_count)
+        }
+        Ok(())
+    }
+
+    /// Reads a `count` bit value (at most 32)
+    pub fn bits(&mut self, count: u32) -> Result<u32, EndOfInput> {
+        let value = self.peek(count);
+        self.consume(count)?;
+        Ok(value)
+    }
+
+    /// Skips to the next byte boundary, handing any whole buffered bytes back to the input
+    pub fn align_to_byte(&mut self) {
+        self.pos -= (self.bit_count / 8) as usize;
+        self.bit_buf = 0;
+        self.bit_count = 0;
+    }
+
+    /// Reads `count` whole bytes; only valid on a byte boundary
+    pub fn read_bytes(&mut self, count: usize) -> Result<&[u8], EndOfInput> {
+        debug_assert_eq!(self.bit_count, 0, "read_bytes needs align_to_byte first");
+        let bytes = self.data.get(self.pos..self.pos + count).ok_or(EndOfInput)?;
+        self.pos += count;
+        Ok(bytes)
+    }
+}
+
+/// Packs bits into a byte vector through a 64 bit buffer, the counterpart of `BitReader`.
+/// Whole bytes are flushed after every write, so fewer than 8 bits are ever left waiting.
+pub struct BitWriter {
+    output: Vec<u8>,
+    bit_buf: u64,
+    bit_count: u32,
+    order: BitOrder
+}
+
+impl BitWriter {
+    /// Writes LSB-first, as DEFLATE does
+    pub fn new() -> BitWriter {
+        BitWriter::with_order(BitOrder::LsbFirst)
+    }
+
+    pub fn with_order(order: BitOrder) -> BitWriter {
+        BitWriter { output: Vec::new(), bit_buf: 0, bit_count: 0, order }
+    }
+
+    /// Appends the low `count` bits of `value` (at most 64). MSB-first streams take the value's highest
+    /// bit first, LSB-first streams its lowest.
+    pub fn write_bits(&mut self, value: u64, count: u32) {
+        // Keep each push small enough to fit beside the up to 7 bits still waiting
+        if count > 56 {
+            match self.order {
+                BitOrder::LsbFirst => {
+                    self.write_bits(value, 32);
+                    self.write_bits(value >> 32, count - 32);
+                },
+                BitOrder::MsbFirst => {
+                    self.write_bits(value >> 32, count - 32);
+                    self.write_bits(value, 32);
+                }
+            }
+            return;
+        }
+
+        let value = value & mask(count);
+        match self.order {
+            BitOrder::LsbFirst => self.bit_buf |= value << self.bit_count,
+            BitOrder::MsbFirst => self.bit_buf = self.bit_buf << count | value
+        }
+        self.bit_count += count;
+
+        while self.bit_count >= 8 {
+            self.bit_count -= 8;
+            match self.order {
+                BitOrder::LsbFirst => {
+                    self.output.push(self.bit_buf as u8);
+                    self.bit_buf >>= 8;
+                },
+                BitOrder::MsbFirst => self.output.push((self.bit_buf >> self.bit_count) as u8)
+            }
+        }
+        if self.order == BitOrder::MsbFirst {
+            self.bit_buf &= mask(self.bit_count);
+        }
+    }
+
+    /// Pads with zero bits up to the next byte boundary
+    pub fn align_to_byte(&mut self) {
+        if self.bit_count > 0 {
+            self.write_bits(0, 8 - self.bit_count);
+        }
+    }
+
+    /// Appends whole bytes; only valid on a byte boundary
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        debug_assert_eq!(self.bit_count, 0, "write_bytes needs align_to_byte first");
+        self.output.extend_from_slice(bytes);
+    }
+
+    /// Number of bits written so far, including any waiting for a full byte
+    pub fn bit_len(&self) -> u64 {
+        self.output.len() as u64 * 8 + self.bit_count as u64
+    }
+
+    /// Hands over the whole bytes written so far, keeping any partial byte for later writes
+    pub fn take_bytes(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Pads the last partial byte with zero bits and returns everything written
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.bit_count > 0 {
+            let last = match self.order {
+                BitOrder::LsbFirst => self.bit_buf,
+                BitOrder::MsbFirst => self.bit_buf << (8 - self.bit_count)
+            };
+            self.output.push(last as u8);
+        }
+        self.output
+    }
+}
+
+impl Default for BitWriter {
+    fn default() -> BitWriter {
+        BitWriter::new()
+    }
+}
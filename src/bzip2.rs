@@ -0,0 +1,714 @@
+// bzip2 streams, zip method 12. There's no spec beyond the reference implementation:
+// https://sourceware.org/bzip2/ and the reverse engineered description at
+// https://github.com/dsnet/compress/blob/master/doc/bzip2-format.pdf
+// Each block is run length encoded (RLE1), Burrows-Wheeler transformed, move-to-front coded with runs of
+// zeros written in bijective base 2 (RUNA/RUNB), then huffman coded with up to six tables, switching
+// table every 50 symbols. Bits are packed MSB-first.
+use std::fmt;
+use crate::bits::{BitOrder, BitReader, BitWriter, EndOfInput};
+use crate::huffman::limited_code_lengths;
+
+const STREAM_MAGIC: &[u8; 3] = b"BZh";
+const BLOCK_MAGIC: u64 = 0x314159265359;
+const END_MAGIC: u64 = 0x177245385090;
+
+/// Block size in units of 100k, what the digit after "BZh" gives
+pub const MIN_LEVEL: u8 = 1;
+pub const MAX_LEVEL: u8 = 9;
+const BLOCK_UNIT: usize = 100_000;
+/// bzip2 stops filling a block this far short of the limit, so one more RLE1 run always fits
+const BLOCK_SLACK: usize = 19;
+
+const RUNA: u16 = 0;
+const RUNB: u16 = 1;
+const MIN_TABLES: usize = 2;
+const MAX_TABLES: usize = 6;
+/// Symbols coded with one table before the next selector applies
+const GROUP_SIZE: usize = 50;
+/// The most selectors bzip2 1.0.8 will use; any past it are read and ignored
+const MAX_SELECTORS: usize = 18002;
+/// Longest code a decoder accepts, and the longest the encoder makes
+const MAX_CODE_LENGTH: usize = 20;
+const MAX_ENCODE_LENGTH: u8 = 17;
+/// Rounds of reassigning groups to tables and rebuilding the tables, as bzip2 does
+const TABLE_ITERATIONS: usize = 4;
+/// Cost the first round's tables give symbols outside their share of the alphabet, bzip2's BZ_GREATER_ICOST
+const OUTSIDE_COST: u8 = 15;
+
+/// bzip2's CRC-32 runs MSB-first (the polynomial unreflected), unlike zip's
+const CRC_TABLE: [u32; 256] = make_crc_table();
+
+const fn make_crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = (n as u32) << 24;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 0x8000_0000 != 0 { (c << 1) ^ 0x04C1_1DB7 } else { c << 1 };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+fn block_crc(data: &[u8]) -> u32 {
+    let crc = data.iter().fold(0xFFFF_FFFFu32, |crc, &byte| (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ byte) as usize]);
+    !crc
+}
+
+/// Folds a block's CRC into the stream's
+fn combine_crc(stream: u32, block: u32) -> u32 {
+    stream.rotate_left(1) ^ block
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bzip2Error {
+    /// The stream doesn't start with "BZh" and a block size digit 1-9
+    InvalidHeader,
+    /// Neither a block nor the end of stream marker where one should be
+    InvalidBlockMagic,
+    /// Randomised blocks come from bzip2 versions before 0.9.5 and aren't supported
+    RandomisedBlock,
+    /// The huffman tables or selectors are malformed
+    InvalidTables,
+    /// A code that maps to no symbol
+    InvalidSymbol,
+    /// The block holds more than its declared block size
+    BlockTooLarge,
+    /// The BWT origin pointer is outside the block
+    InvalidOrigin,
+    BlockChecksumMismatch { expected: u32, found: u32 },
+    StreamChecksumMismatch { expected: u32, found: u32 },
+    UnexpectedEof,
+    /// Decompressing would produce more than the allowed number of bytes
+    OutputLimit,
+    /// Bytes after the last stream that aren't another stream
+    TrailingData
+}
+
+impl fmt::Display for Bzip2Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Bzip2Error::InvalidHeader => write!(f, "not a bzip2 stream"),
+            Bzip2Error::InvalidBlockMagic => write!(f, "bad block header magic"),
+            Bzip2Error::RandomisedBlock => write!(f, "randomised blocks are not supported"),
+            Bzip2Error::InvalidTables => write!(f, "invalid huffman tables or selectors"),
+            Bzip2Error::InvalidSymbol => write!(f, "invalid huffman symbol"),
+            Bzip2Error::BlockTooLarge => write!(f, "block is larger than its declared size"),
+            Bzip2Error::InvalidOrigin => write!(f, "BWT origin pointer out of range"),
+            Bzip2Error::BlockChecksumMismatch { expected, found } =>
+                write!(f, "block CRC mismatch (expected {:#010X}, found {:#010X})", expected, found),
+            Bzip2Error::StreamChecksumMismatch { expected, found } =>
+                write!(f, "stream CRC mismatch (expected {:#010X}, found {:#010X})", expected, found),
+            Bzip2Error::UnexpectedEof => write!(f, "unexpected end of compressed data"),
+            Bzip2Error::OutputLimit => write!(f, "output exceeds the size limit"),
+            Bzip2Error::TrailingData => write!(f, "trailing garbage after bzip2 data")
+        }
+    }
+}
+
+impl std::error::Error for Bzip2Error {}
+
+impl From<EndOfInput> for Bzip2Error {
+    fn from(_: EndOfInput) -> Bzip2Error {
+        Bzip2Error::UnexpectedEof
+    }
+}
+
+/// Decompresses one or more concatenated bzip2 streams, refusing to produce more than `max_output` bytes
+pub fn decompress(data: &[u8], max_output: u64) -> Result<Vec<u8>, Bzip2Error> {
+    let mut input = BitReader::with_order(data, BitOrder::MsbFirst);
+    let mut output = Vec::new();
+
+    loop {
+        read_stream(&mut input, &mut output, max_output)?;
+        input.align_to_byte();
+        if input.remaining_bytes() == 0 {
+            return Ok(output);
+        }
+        if input.remaining_bytes() < STREAM_MAGIC.len() + 1 || input.peek(24) != u32::from_be_bytes([0, b'B', b'Z', b'h']) {
+            return Err(Bzip2Error::TrailingData);
+        }
+    }
+}
+
+fn read_stream(input: &mut BitReader, output: &mut Vec<u8>, max_output: u64) -> Result<(), Bzip2Error> {
+    for &expected in STREAM_MAGIC {
+        if input.bits(8).map_err(|_| Bzip2Error::InvalidHeader)? != expected as u32 {
+            return Err(Bzip2Error::InvalidHeader);
+        }
+    }
+    let level = input.bits(8).map_err(|_| Bzip2Error::InvalidHeader)?.wrapping_sub(b'0' as u32);
+    if !(MIN_LEVEL as u32..=MAX_LEVEL as u32).contains(&level) {
+        return Err(Bzip2Error::InvalidHeader);
+    }
+    let max_block = level as usize * BLOCK_UNIT;
+
+    let mut stream_crc = 0;
+    loop {
+        let magic = (input.bits(24)? as u64) << 24 | input.bits(24)? as u64;
+        let expected = input.bits(32)?;
+        match magic {
+            BLOCK_MAGIC => {
+                let start = output.len();
+                read_block(input, max_block, output, max_output)?;
+                let found = block_crc(&output[start..]);
+                if found != expected {
+                    return Err(Bzip2Error::BlockChecksumMismatch { expected, found });
+                }
+                stream_crc = combine_crc(stream_crc, found);
+            },
+            END_MAGIC => {
+                if stream_crc != expected {
+                    return Err(Bzip2Error::StreamChecksumMismatch { expected, found: stream_crc });
+                }
+                return Ok(());
+            },
+            _ => return Err(Bzip2Error::InvalidBlockMagic)
+        }
+    }
+}
+
+/// A canonical huffman code read MSB-first a bit at a time; bzip2 codes go up to 20 bits, past what
+/// `huffman::Decoder` handles
+struct Table {
+    /// How many codes there are of each length
+    counts: [u16; MAX_CODE_LENGTH + 1],
+    /// Symbols in code order: by length, then by symbol
+    symbols: Vec<u16>
+}
+
+impl Table {
+    fn new(lengths: &[u8]) -> Table {
+        let mut counts = [0u16; MAX_CODE_LENGTH + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        let mut symbols = Vec::with_capacity(lengths.len());
+        for len in 1..=MAX_CODE_LENGTH as u8 {
+            symbols.extend((0..lengths.len() as u16).filter(|&symbol| lengths[symbol as usize] == len));
+        }
+        Table { counts, symbols }
+    }
+
+    fn decode(&self, input: &mut BitReader) -> Result<u16, Bzip2Error> {
+        let bits = input.peek(MAX_CODE_LENGTH as u32);
+        // Codes of each length follow on from the last code of the length before, shifted up a bit
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+        for len in 1..=MAX_CODE_LENGTH {
+            code |= (bits >> (MAX_CODE_LENGTH - len) & 1) as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                input.consume(len as u32)?;
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        if !input.has_bits(MAX_CODE_LENGTH as u32) {
+            return Err(Bzip2Error::UnexpectedEof);
+        }
+        Err(Bzip2Error::InvalidSymbol)
+    }
+}
+
+fn read_block(input: &mut BitReader, max_block: usize, output: &mut Vec<u8>, max_output: u64) -> Result<(), Bzip2Error> {
+    if input.bits(1)? == 1 {
+        return Err(Bzip2Error::RandomisedBlock);
+    }
+    let origin = input.bits(24)? as usize;
+
+    // Which bytes occur, as a bitmap of 16 byte ranges and a bitmap of each range used
+    let ranges = input.bits(16)?;
+    let mut used: Vec<u8> = Vec::new();
+    for range in 0..16 {
+        if ranges & (0x8000 >> range) != 0 {
+            let bytes = input.bits(16)?;
+            used.extend((0..16).filter(|bit| bytes & (0x8000 >> bit) != 0).map(|bit| (range * 16 + bit) as u8));
+        }
+    }
+    if used.is_empty() {
+        return Err(Bzip2Error::InvalidTables);
+    }
+    let alphabet_size = used.len() + 2;
+
+    let table_count = input.bits(3)? as usize;
+    let selector_count = input.bits(15)? as usize;
+    if !(MIN_TABLES..=MAX_TABLES).contains(&table_count) || selector_count == 0 {
+        return Err(Bzip2Error::InvalidTables);
+    }
+
+    // Selectors are move-to-front coded, each index in unary
+    let mut order: Vec<u8> = (0..table_count as u8).collect();
+    let mut selectors = Vec::with_capacity(selector_count.min(MAX_SELECTORS));
+    for _ in 0..selector_count {
+        let mut index = 0;
+        while input.bits(1)? == 1 {
+            index += 1;
+            if index >= table_count {
+                return Err(Bzip2Error::InvalidTables);
+            }
+        }
+        let table = order.remove(index);
+        order.insert(0, table);
+        if selectors.len() < MAX_SELECTORS {
+            selectors.push(table);
+        }
+    }
+
+    // Code lengths are deltas from the previous symbol's, starting from a 5 bit value
+    let mut tables = Vec::with_capacity(table_count);
+    for _ in 0..table_count {
+        let mut lengths = vec![0u8; alphabet_size];
+        let mut len = input.bits(5)? as i32;
+        for slot in lengths.iter_mut() {
+            loop {
+                if len < 1 || len > MAX_CODE_LENGTH as i32 {
+                    return Err(Bzip2Error::InvalidTables);
+                }
+                if input.bits(1)? == 0 {
+                    break;
+                }
+                len += if input.bits(1)? == 0 { 1 } else { -1 };
+            }
+            *slot = len as u8;
+        }
+        tables.push(Table::new(&lengths));
+    }
+
+    // Undo the huffman, zero run and move-to-front coding
+    let end_of_block = (alphabet_size - 1) as u16;
+    let mut mtf: Vec<u8> = used.clone();
+    let mut block: Vec<u8> = Vec::new();
+    let mut run = 0usize;
+    let mut run_weight = 1usize;
+    let mut decoded = 0;
+    loop {
+        let selector = *selectors.get(decoded / GROUP_SIZE).ok_or(Bzip2Error::InvalidTables)?;
+        let symbol = tables[selector as usize].decode(input)?;
+        decoded += 1;
+
+        if symbol == RUNA || symbol == RUNB {
+            run += run_weight << symbol;
+            run_weight <<= 1;
+            if run > max_block {
+                return Err(Bzip2Error::BlockTooLarge);
+            }
+            continue;
+        }
+        if run > 0 {
+            if block.len() + run > max_block {
+                return Err(Bzip2Error::BlockTooLarge);
+            }
+            block.resize(block.len() + run, mtf[0]);
+            run = 0;
+            run_weight = 1;
+        }
+        if symbol == end_of_block {
+            break;
+        }
+        if block.len() >= max_block {
+            return Err(Bzip2Error::BlockTooLarge);
+        }
+        let byte = mtf.remove(symbol as usize - 1);
+        mtf.insert(0, byte);
+        block.push(byte);
+    }
+    if origin >= block.len() {
+        return Err(Bzip2Error::InvalidOrigin);
+    }
+
+    // Inverse BWT: next[i] is where the byte after block[i]'s rotation sits in the sorted order
+    let mut starts = [0usize; 256];
+    for &byte in &block {
+        starts[byte as usize] += 1;
+    }
+    let mut total = 0;
+    for start in starts.iter_mut() {
+        let count = *start;
+        *start = total;
+        total += count;
+    }
+    let mut next = vec![0u32; block.len()];
+    for (i, &byte) in block.iter().enumerate() {
+        next[starts[byte as usize]] = i as u32;
+        starts[byte as usize] += 1;
+    }
+
+    // Undo RLE1 as the bytes come out: four of a kind are followed by a count of how many more
+    let mut pos = next[origin] as usize;
+    let mut last = None;
+    let mut repeats = 0;
+    for _ in 0..block.len() {
+        let byte = block[pos];
+        pos = next[pos] as usize;
+        if repeats == 4 {
+            let previous = last.expect("a run has a byte");
+            if output.len() as u64 + byte as u64 > max_output {
+                return Err(Bzip2Error::OutputLimit);
+            }
+            output.resize(output.len() + byte as usize, previous);
+            repeats = 0;
+            continue;
+        }
+        if Some(byte) == last {
+            repeats += 1;
+        } else {
+            last = Some(byte);
+            repeats = 1;
+        }
+        if output.len() as u64 >= max_output {
+            return Err(Bzip2Error::OutputLimit);
+        }
+        output.push(byte);
+    }
+    Ok(())
+}
+
+/// Compresses `data` into a bzip2 stream with blocks of `level` * 100k (1-9, clamped)
+pub fn compress(data: &[u8], level: u8) -> Vec<u8> {
+    let level = level.clamp(MIN_LEVEL, MAX_LEVEL);
+    let max_block = level as usize * BLOCK_UNIT - BLOCK_SLACK;
+
+    let mut output = BitWriter::with_order(BitOrder::MsbFirst);
+    output.write_bytes(STREAM_MAGIC);
+    output.write_bits((b'0' + level) as u64, 8);
+
+    let mut stream_crc = 0;
+    let mut pos = 0;
+    while pos < data.len() {
+        let start = pos;
+        let mut block = Vec::with_capacity(max_block.min(data.len() - start + 1));
+        // RLE1: runs of 4 to 255 become four bytes and a count of the rest
+        while pos < data.len() && block.len() + 5 <= max_block {
+            let byte = data[pos];
+            let run = data[pos..].iter().take(255).take_while(|&&b| b == byte).count();
+            if run >= 4 {
+                block.extend_from_slice(&[byte; 4]);
+                block.push((run - 4) as u8);
+            } else {
+                block.resize(block.len() + run, byte);
+            }
+            pos += run;
+        }
+
+        let crc = block_crc(&data[start..pos]);
+        stream_crc = combine_crc(stream_crc, crc);
+        write_block(&mut output, &block, crc);
+    }
+
+    output.write_bits(END_MAGIC, 48);
+    output.write_bits(stream_crc as u64, 32);
+    output.finish()
+}
+
+/// The last column of the sorted rotations of `block`, and where the unrotated block ended up
+fn bwt(block: &[u8]) -> (Vec<u8>, usize) {
+    let n = block.len();
+    let mut order: Vec<u32> = (0..n as u32).collect();
+    let mut rank: Vec<u32> = block.iter().map(|&byte| byte as u32).collect();
+    let mut next_rank = vec![0u32; n];
+
+    // Prefix doubling: sort by the first k bytes of each rotation, then by 2k using the ranks so far
+    let mut k = 1;
+    let mut classes = 0;
+    loop {
+        let key = |i: u32| (rank[i as usize] as u64) << 32 | rank[(i as usize + k) % n] as u64;
+        order.sort_unstable_by_key(|&i| key(i));
+
+        next_rank[order[0] as usize] = 0;
+        for j in 1..n {
+            let same = key(order[j]) == key(order[j - 1]);
+            next_rank[order[j] as usize] = next_rank[order[j - 1] as usize] + !same as u32;
+        }
+        std::mem::swap(&mut rank, &mut next_rank);
+
+        // Once a round splits no more classes none ever will; what's still tied are identical
+        // rotations, which give the same last byte whichever order they're in
+        let new_classes = rank[order[n - 1] as usize] as usize + 1;
+        if new_classes == n || new_classes == classes || k >= n {
+            break;
+        }
+        classes = new_classes;
+        k *= 2;
+    }
+
+    let last = order.iter().map(|&i| block[(i as usize + n - 1) % n]).collect();
+    let origin = order.iter().position(|&i| i == 0).expect("rotation 0 is sorted somewhere");
+    (last, origin)
+}
+
+/// Canonical codes, assigned by length then symbol, to be written MSB-first
+fn assign_codes(lengths: &[u8]) -> Vec<u32> {
+    let mut codes = vec![0u32; lengths.len()];
+    let mut code = 0u32;
+    for len in 1..=MAX_CODE_LENGTH as u8 {
+        for (symbol, _) in lengths.iter().enumerate().filter(|&(_, &l)| l == len) {
+            codes[symbol] = code;
+            code += 1;
+        }
+        code <<= 1;
+    }
+    codes
+}
+
+fn write_block(output: &mut BitWriter, block: &[u8], crc: u32) {
+    let (last, origin) = bwt(block);
+
+    let mut in_use = [false; 256];
+    for &byte in block {
+        in_use[byte as usize] = true;
+    }
+    let used: Vec<u8> = (0..=255u8).filter(|&byte| in_use[byte as usize]).collect();
+
+    // Move-to-front, with runs of zeros in bijective base 2
+    let end_of_block = (used.len() + 1) as u16;
+    let mut mtf = used.clone();
+    let mut symbols: Vec<u16> = Vec::with_capacity(last.len() + 1);
+    let mut zeros = 0usize;
+    let flush_zeros = |symbols: &mut Vec<u16>, zeros: &mut usize| {
+        if *zeros == 0 {
+            return;
+        }
+        let mut run = *zeros - 1;
+        loop {
+            symbols.push(if run & 1 == 1 { RUNB } else { RUNA });
+            if run < 2 {
+                break;
+            }
+            run = (run - 2) / 2;
+        }
+        *zeros = 0;
+    };
+    for &byte in &last {
+        let index = mtf.iter().position(|&b| b == byte).expect("byte is in use");
+        if index == 0 {
+            zeros += 1;
+            continue;
+        }
+        flush_zeros(&mut symbols, &mut zeros);
+        mtf.remove(index);
+        mtf.insert(0, byte);
+        symbols.push(index as u16 + 1);
+    }
+    flush_zeros(&mut symbols, &mut zeros);
+    symbols.push(end_of_block);
+
+    let alphabet_size = used.len() + 2;
+    let (tables, selectors) = choose_tables(&symbols, alphabet_size);
+
+    output.write_bits(BLOCK_MAGIC, 48);
+    output.write_bits(crc as u64, 32);
+    output.write_bits(0, 1); // not randomised
+    output.write_bits(origin as u64, 24);
+
+    let mut ranges = 0u64;
+    for range in 0..16 {
+        if in_use[range * 16..range * 16 + 16].iter().any(|&u| u) {
+            ranges |= 0x8000 >> range;
+        }
+    }
+    output.write_bits(ranges, 16);
+    for range in (0..16).filter(|range| ranges & (0x8000 >> range) != 0) {
+        let bits = (0..16).filter(|&bit| in_use[range * 16 + bit]).fold(0u64, |bits, bit| bits | 0x8000 >> bit);
+        output.write_bits(bits, 16);
+    }
+
+    output.write_bits(tables.len() as u64, 3);
+    output.write_bits(selectors.len() as u64, 15);
+    let mut order: Vec<u8> = (0..tables.len() as u8).collect();
+    for &selector in &selectors {
+        let index = order.iter().position(|&t| t == selector).expect("selector names a table");
+        order.remove(index);
+        order.insert(0, selector);
+        output.write_bits((1 << (index + 1)) - 2, index as u32 + 1);
+    }
+
+    for lengths in &tables {
+        let mut len = lengths[0];
+        output.write_bits(len as u64, 5);
+        for &target in lengths {
+            while len < target {
+                output.write_bits(0b10, 2);
+                len += 1;
+            }
+            while len > target {
+                output.write_bits(0b11, 2);
+                len -= 1;
+            }
+            output.write_bits(0, 1);
+        }
+    }
+
+    let codes: Vec<Vec<u32>> = tables.iter().map(|lengths| assign_codes(lengths)).collect();
+    for (group, chunk) in symbols.chunks(GROUP_SIZE).enumerate() {
+        let table = selectors[group] as usize;
+        for &symbol in chunk {
+            output.write_bits(codes[table][symbol as usize] as u64, tables[table][symbol as usize] as u32);
+        }
+    }
+}
+
+/// Picks code lengths for each table and which table codes each group of 50 symbols, bzip2's way:
+/// start from tables that each favour a slice of the alphabet, then repeatedly give each group the table
+/// that codes it smallest and rebuild the tables from the groups they got
+fn choose_tables(symbols: &[u16], alphabet_size: usize) -> (Vec<Vec<u8>>, Vec<u8>) {
+    let table_count = match symbols.len() {
+        0..=199 => 2,
+        200..=599 => 3,
+        600..=1199 => 4,
+        1200..=2399 => 5,
+        _ => MAX_TABLES
+    };
+
+    let mut frequencies = vec![0u64; alphabet_size];
+    for &symbol in symbols {
+        frequencies[symbol as usize] += 1;
+    }
+
+    // Split the alphabet into runs of roughly equal total frequency, one per table
+    let mut tables: Vec<Vec<u8>> = vec![vec![OUTSIDE_COST; alphabet_size]; table_count];
+    let mut remaining = symbols.len() as u64;
+    let mut start = 0;
+    for parts_left in (1..=table_count).rev() {
+        let target = remaining / parts_left as u64;
+        let mut end = start;
+        let mut share = 0;
+        while end < alphabet_size && (share < target || end == start) {
+            share += frequencies[end];
+            end += 1;
+        }
+        // Alternate tables give their last symbol back, as bzip2 does, to even the split out
+        if end > start + 1 && parts_left != table_count && parts_left != 1 && (table_count - parts_left) % 2 == 1 {
+            end -= 1;
+            share -= frequencies[end];
+        }
+        for len in &mut tables[parts_left - 1][start..end] {
+            *len = 0;
+        }
+        remaining -= share;
+        start = end;
+    }
+
+    let group_count = symbols.len().div_ceil(GROUP_SIZE);
+    let mut selectors = vec![0u8; group_count];
+    for _ in 0..TABLE_ITERATIONS {
+        let mut table_frequencies = vec![vec![0u64; alphabet_size]; table_count];
+        for (group, chunk) in symbols.chunks(GROUP_SIZE).enumerate() {
+            let best = (0..table_count)
+                .min_by_key(|&t| chunk.iter().map(|&symbol| tables[t][symbol as usize] as u64).sum::<u64>())
+                .expect("there are tables");
+            selectors[group] = best as u8;
+            for &symbol in chunk {
+                table_frequencies[best][symbol as usize] += 1;
+            }
+        }
+        // Every symbol needs a code whether it's used or not
+        for (table, frequencies) in tables.iter_mut().zip(&table_frequencies) {
+            let weights: Vec<u64> = frequencies.iter().map(|&freq| freq.max(1)).collect();
+            *table = limited_code_lengths(&weights, MAX_ENCODE_LENGTH);
+        }
+    }
+
+    (tables, selectors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::CompressionMethod;
+    use crate::crc32;
+    use crate::limits::ReadLimits;
+    use crate::ziparchive::ZipArchive;
+
+    // resources/bzip2.zip is written by bzip2 1.0.8: level9.txt holds 18024 bytes of text, every byte value
+    // and a run of zeros at level 9, and periodic-level1.txt the first 1000 bytes of english3.txt 1000 times
+    // over at level 1, so two blocks.
+    fn check(name: &str, size: usize, crc: u32) {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/bzip2.zip");
+        // The periodic entry compresses better than the default ratio limit allows
+        let mut archive = ZipArchive::with_limits(path, ReadLimits::unlimited()).unwrap();
+        let entry = archive.entries().into_iter().find(|entry| entry.name == name).unwrap();
+        assert_eq!(entry.compression_method, CompressionMethod::Bzip2);
+
+        let data = archive.read_entry(entry.index).unwrap();
+        assert_eq!(data.len(), size, "{}", name);
+        assert_eq!(crc32::checksum(&data), crc, "{}", name);
+    }
+
+    fn round_trip(data: &[u8], level: u8) {
+        let compressed = compress(data, level);
+        assert_eq!(decompress(&compressed, data.len() as u64).unwrap(), data, "{} bytes at level {}", data.len(), level);
+    }
+
+    fn text() -> Vec<u8> {
+        std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/english3.txt")).unwrap()
+    }
+
+    #[test]
+    fn reference_level_9() {
+        check("level9.txt", 18024, 0xE16B_CCBE);
+    }
+
+    #[test]
+    fn reference_periodic_blocks() {
+        check("periodic-level1.txt", 1_000_000, 0xE783_F9B5);
+    }
+
+    #[test]
+    fn round_trips_several_blocks() {
+        let text = text();
+        round_trip(&text[..1_000_000], MIN_LEVEL);
+    }
+
+    #[test]
+    fn round_trips_small_inputs_and_runs() {
+        round_trip(b"", 9);
+        round_trip(b"a", 9);
+        round_trip(&[b'x'; 1000], 9);
+        // Runs right at the RLE1 boundaries
+        let runs: Vec<u8> = (1..=260).flat_map(|len| vec![len as u8; len]).collect();
+        round_trip(&runs, 9);
+    }
+
+    #[test]
+    fn sorts_tied_rotations() {
+        // Every rotation of a periodic block equals another, so the sort can't break the tie
+        let (last, origin) = bwt(b"abab");
+        assert_eq!(last, b"bbaa");
+        assert!(origin < 2);
+        let (last, origin) = bwt(b"banana");
+        assert_eq!((&last[..], origin), (&b"nnbaaa"[..], 3));
+    }
+
+    #[test]
+    fn round_trips_periodic_input() {
+        let text = text();
+        round_trip(&b"ab".repeat(100_000), 9);
+        round_trip(&b"abc".repeat(100_000), 1);
+        round_trip(&text[..1000].repeat(100), 9);
+    }
+
+    #[test]
+    fn concatenated_streams() {
+        let mut compressed = compress(b"hello ", 9);
+        compressed.extend_from_slice(&compress(b"world", 1));
+        assert_eq!(decompress(&compressed, 100).unwrap(), b"hello world");
+
+        compressed.extend_from_slice(b"xyzzy");
+        assert_eq!(decompress(&compressed, 100), Err(Bzip2Error::TrailingData));
+    }
+
+    #[test]
+    fn limits_the_output() {
+        let compressed = compress(&[7; 10_000], 9);
+        assert_eq!(decompress(&compressed, 9_999), Err(Bzip2Error::OutputLimit));
+        assert_eq!(decompress(&compressed, 10_000).unwrap().len(), 10_000);
+    }
+}
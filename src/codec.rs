@@ -0,0 +1,496 @@
+// Compression methods and the codecs behind them. ZipArchive and ZipWriter look methods up in a
+// CodecRegistry, which comes with every method rip implements and takes more, so an application can
+// handle a method rip doesn't (an in-house codec on an unused method ID, say) without changing rip.
+// Method IDs: https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT section 4.4.5
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use crate::bzip2;
+use crate::deflate;
+use crate::inflate;
+use crate::legacy;
+use crate::lzma;
+use crate::zstd;
+use crate::ziparchive::{ZipEntry, ZipError};
+
+/// An entry's compression_method field
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CompressionMethod {
+    Stored,
+    Shrunk,
+    /// Methods 2 to 5, compression factors 1 to 4
+    Reduced(u8),
+    Imploded,
+    Deflated,
+    Deflate64,
+    Bzip2,
+    Lzma,
+    Zstd,
+    Xz,
+    Ppmd,
+    /// WinZip AES encryption, the real method being in the AES extra field
+    Aes,
+    /// Any ID without a name of its own, including ones an application registers a codec for
+    Other(u16)
+}
+
+impl CompressionMethod {
+    pub fn from_id(id: u16) -> CompressionMethod {
+        match id {
+            0 => CompressionMethod::Stored,
+            1 => CompressionMethod::Shrunk,
+            2..=5 => CompressionMethod::Reduced((id - 1) as u8),
+            6 => CompressionMethod::Imploded,
+            8 => CompressionMethod::Deflated,
+            9 => CompressionMethod::Deflate64,
+            12 => CompressionMethod::Bzip2,
+            14 => CompressionMethod::Lzma,
+            93 => CompressionMethod::Zstd,
+            95 => CompressionMethod::Xz,
+            98 => CompressionMethod::Ppmd,
+            99 => CompressionMethod::Aes,
+            _ => CompressionMethod::Other(id)
+        }
+    }
+
+    /// The value stored in the headers
+    pub fn id(self) -> u16 {
+        match self {
+            CompressionMethod::Stored => 0,
+            CompressionMethod::Shrunk => 1,
+            CompressionMethod::Reduced(factor) => factor as u16 + 1,
+            CompressionMethod::Imploded => 6,
+            CompressionMethod::Deflated => 8,
+            CompressionMethod::Deflate64 => 9,
+            CompressionMethod::Bzip2 => 12,
+            CompressionMethod::Lzma => 14,
+            CompressionMethod::Zstd => 93,
+            CompressionMethod::Xz => 95,
+            CompressionMethod::Ppmd => 98,
+            CompressionMethod::Aes => 99,
+            CompressionMethod::Other(id) => id
+        }
+    }
+
+    /// Human readable name
+    pub fn name(self) -> &'static str {
+        match self {
+            CompressionMethod::Stored => "stored",
+            CompressionMethod::Shrunk => "shrunk",
+            CompressionMethod::Reduced(_) => "reduced",
+            CompressionMethod::Imploded => "imploded",
+            CompressionMethod::Deflated => "deflated",
+            CompressionMethod::Deflate64 => "deflate64",
+            CompressionMethod::Bzip2 => "bzip2",
+            CompressionMethod::Lzma => "lzma",
+            CompressionMethod::Zstd => "zstd",
+            CompressionMethod::Xz => "xz",
+            CompressionMethod::Ppmd => "ppmd",
+            CompressionMethod::Aes => "aes",
+            CompressionMethod::Other(_) => "unknown"
+        }
+    }
+}
+
+impl From<u16> for CompressionMethod {
+    fn from(id: u16) -> CompressionMethod {
+        CompressionMethod::from_id(id)
+    }
+}
+
+impl From<CompressionMethod> for u16 {
+    fn from(method: CompressionMethod) -> u16 {
+        method.id()
+    }
+}
+
+/// Decompresses one entry's data
+pub trait Decoder {
+    /// Decompresses all of `data`. Producing more than `max_output` bytes has to fail with
+    /// `ZipError::OutputLimit`, which the archive reports as whichever of its limits set the budget.
+    fn decode(&mut self, data: &[u8], max_output: u64) -> Result<Vec<u8>, ZipError>;
+}
+
+impl<F: FnMut(&[u8], u64) -> Result<Vec<u8>, ZipError>> Decoder for F {
+    fn decode(&mut self, data: &[u8], max_output: u64) -> Result<Vec<u8>, ZipError> {
+        self(data, max_output)
+    }
+}
+
+/// Compresses one entry's data
+pub trait Encoder {
+    fn encode(&mut self, data: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+impl<F: FnMut(&[u8]) -> io::Result<Vec<u8>>> Encoder for F {
+    fn encode(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self(data)
+    }
+}
+
+/// A compression method's implementation, handing out a decoder per entry read and an encoder per entry written
+pub trait Codec: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// A decoder for `entry`, whose flags and sizes are there for methods that depend on them
+    fn decoder(&self, entry: &ZipEntry) -> Box<dyn Decoder>;
+
+    /// An encoder at `level`, 1-9 or `deflate::ULTRA_LEVEL`; None for methods that can only be read
+    fn encoder(&self, _level: u8) -> Option<Box<dyn Encoder>> {
+        None
+    }
+
+    /// The version needed to extract, for the headers of entries this codec encodes
+    fn version_needed(&self) -> u16 {
+        20
+    }
+
+    /// General purpose flag bits to set on entries this codec encodes
+    fn flags(&self) -> u16 {
+        0
+    }
+}
+
+struct StoredCodec;
+
+impl Codec for StoredCodec {
+    fn name(&self) -> &str {
+        "stored"
+    }
+
+    fn decoder(&self, _entry: &ZipEntry) -> Box<dyn Decoder> {
+        Box::new(|data: &[u8], max_output: u64| {
+            if data.len() as u64 > max_output {
+                return Err(ZipError::OutputLimit);
+            }
+            Ok(data.to_vec())
+        })
+    }
+
+    fn encoder(&self, _level: u8) -> Option<Box<dyn Encoder>> {
+        Some(Box::new(|data: &[u8]| Ok(data.to_vec())))
+    }
+
+    fn version_needed(&self) -> u16 {
+        10
+    }
+}
+
+/// Shrink, reduce and implode, which only get read
+struct LegacyCodec;
+
+impl Codec for LegacyCodec {
+    fn name(&self) -> &str {
+        "legacy"
+    }
+
+    fn decoder(&self, entry: &ZipEntry) -> Box<dyn Decoder> {
+        let (method, flags, size) = (entry.compression_method, entry.flags, entry.uncompressed_size);
+        Box::new(move |data: &[u8], max_output: u64| Ok(match method {
+            CompressionMethod::Reduced(factor) => legacy::unreduce(data, factor, size, max_output)?,
+            CompressionMethod::Imploded => legacy::explode(data, flags, size, max_output)?,
+            _ => legacy::unshrink(data, size, max_output)?
+        }))
+    }
+}
+
+struct DeflateCodec;
+
+impl Codec for DeflateCodec {
+    fn name(&self) -> &str {
+        "deflated"
+    }
+
+    fn decoder(&self, _entry: &ZipEntry) -> Box<dyn Decoder> {
+        Box::new(|data: &[u8], max_output: u64| Ok(inflate::inflate(data, max_output)?))
+    }
+
+    fn encoder(&self, level: u8) -> Option<Box<dyn Encoder>> {
+        Some(Box::new(move |data: &[u8]| Ok(deflate::deflate(data, level))))
+    }
+}
+
+struct Deflate64Codec;
+
+impl Codec for Deflate64Codec {
+    fn name(&self) -> &str {
+        "deflate64"
+    }
+
+    fn decoder(&self, _entry: &ZipEntry) -> Box<dyn Decoder> {
+        Box::new(|data: &[u8], max_output: u64| Ok(inflate::inflate64(data, max_output)?))
+    }
+}
+
+struct Bzip2Codec;
+
+impl Codec for Bzip2Codec {
+    fn name(&self) -> &str {
+        "bzip2"
+    }
+
+    fn decoder(&self, _entry: &ZipEntry) -> Box<dyn Decoder> {
+        Box::new(|data: &[u8], max_output: u64| Ok(bzip2::decompress(data, max_output)?))
+    }
+
+    /// The level picks the block size, 1-9 times 100k
+    fn encoder(&self, level: u8) -> Option<Box<dyn Encoder>> {
+        Some(Box::new(move |data: &[u8]| Ok(bzip2::compress(data, level))))
+    }
+
+    fn version_needed(&self) -> u16 {
+        46
+    }
+}
+
+struct LzmaCodec;
+
+impl Codec for LzmaCodec {
+    fn name(&self) -> &str {
+        "lzma"
+    }
+
+    fn decoder(&self, entry: &ZipEntry) -> Box<dyn Decoder> {
+        let size = entry.uncompressed_size;
+        let end_marker = entry.flags & lzma::END_MARKER_FLAG != 0;
+        Box::new(move |data: &[u8], max_output: u64| Ok(lzma::decompress_zip(data, size, end_marker, max_output)?))
+    }
+
+    /// The level picks the dictionary size and search effort
+    fn encoder(&self, level: u8) -> Option<Box<dyn Encoder>> {
+        Some(Box::new(move |data: &[u8]| Ok(lzma::compress_zip(data, level))))
+    }
+
+    fn version_needed(&self) -> u16 {
+        63
+    }
+
+    /// The encoder always ends its stream with an end marker
+    fn flags(&self) -> u16 {
+        lzma::END_MARKER_FLAG
+    }
+}
+
+struct ZstdCodec;
+
+impl Codec for ZstdCodec {
+    fn name(&self) -> &str {
+        "zstd"
+    }
+
+    fn decoder(&self, _entry: &ZipEntry) -> Box<dyn Decoder> {
+        Box::new(|data: &[u8], max_output: u64| Ok(zstd::decompress(data, max_output)?))
+    }
+
+    /// The level picks the same match search as deflate's
+    fn encoder(&self, level: u8) -> Option<Box<dyn Encoder>> {
+        let level = level.min(deflate::MAX_LEVEL);
+        Some(Box::new(move |data: &[u8]| Ok(zstd::compress(data, level))))
+    }
+
+    fn version_needed(&self) -> u16 {
+        63
+    }
+}
+
+/// Which codec handles each method ID. The default registry has every method rip implements.
+#[derive(Clone)]
+pub struct CodecRegistry {
+    codecs: BTreeMap<u16, Arc<dyn Codec>>
+}
+
+impl fmt::Debug for CodecRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.codecs.iter().map(|(id, codec)| (id, codec.name()))).finish()
+    }
+}
+
+impl Default for CodecRegistry {
+    fn default() -> CodecRegistry {
+        let mut registry = CodecRegistry::empty();
+        registry.register(CompressionMethod::Stored, StoredCodec);
+        registry.register(CompressionMethod::Shrunk, LegacyCodec);
+        for factor in 1..=4 {
+            registry.register(CompressionMethod::Reduced(factor), LegacyCodec);
+        }
+        registry.register(CompressionMethod::Imploded, LegacyCodec);
+        registry.register(CompressionMethod::Deflated, DeflateCodec);
+        registry.register(CompressionMethod::Deflate64, Deflate64Codec);
+        registry.register(CompressionMethod::Bzip2, Bzip2Codec);
+        registry.register(CompressionMethod::Lzma, LzmaCodec);
+        registry.register(CompressionMethod::Zstd, ZstdCodec);
+        registry
+    }
+}
+
+impl CodecRegistry {
+    /// A registry with no methods at all, not even stored
+    pub fn empty() -> CodecRegistry {
+        CodecRegistry { codecs: BTreeMap::new() }
+    }
+
+    /// Handles `method` with `codec` from now on, replacing whatever handled it before
+    pub fn register<C: Codec + 'static>(&mut self, method: CompressionMethod, codec: C) {
+        self.codecs.insert(method.id(), Arc::new(codec));
+    }
+
+    /// Stops handling `method`, so entries using it can be neither read nor written
+    pub fn unregister(&mut self, method: CompressionMethod) {
+        self.codecs.remove(&method.id());
+    }
+
+    pub fn get(&self, method: CompressionMethod) -> Option<&dyn Codec> {
+        self.codecs.get(&method.id()).map(|codec| codec.as_ref())
+    }
+
+    /// `method`'s name, or for an ID without one the name of the codec registered for it
+    pub fn method_name(&self, method: CompressionMethod) -> &str {
+        match (method, self.get(method)) {
+            (CompressionMethod::Other(_), Some(codec)) => codec.name(),
+            _ => method.name()
+        }
+    }
+
+    /// Every method there's a codec for, in ID order
+    pub fn methods(&self) -> Vec<CompressionMethod> {
+        self.codecs.keys().map(|&id| CompressionMethod::from_id(id)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::limits::{Limit, ReadLimits};
+    use crate::ziparchive::ZipArchive;
+    use crate::dostime::DosDateTime;
+    use crate::zipwriter::ZipWriter;
+
+    const RUNS: CompressionMethod = CompressionMethod::Other(0x7A01);
+
+    /// Run length coding, (count, byte) pairs: the sort of in-house method an application might add
+    struct RunsCodec;
+
+    impl Codec for RunsCodec {
+        fn name(&self) -> &str {
+            "runs"
+        }
+
+        fn decoder(&self, _entry: &ZipEntry) -> Box<dyn Decoder> {
+            Box::new(|data: &[u8], max_output: u64| {
+                if data.len() % 2 != 0 {
+                    return Err(ZipError::Codec("odd number of bytes".into()));
+                }
+                let mut output = Vec::new();
+                for pair in data.chunks(2) {
+                    if (output.len() + pair[0] as usize) as u64 > max_output {
+                        return Err(ZipError::OutputLimit);
+                    }
+                    output.resize(output.len() + pair[0] as usize, pair[1]);
+                }
+                Ok(output)
+            })
+        }
+
+        fn encoder(&self, _level: u8) -> Option<Box<dyn Encoder>> {
+            Some(Box::new(|data: &[u8]| {
+                let mut output = Vec::new();
+                let mut rest = data;
+                while let Some(&byte) = rest.first() {
+                    let run = rest.iter().take(255).take_while(|&&b| b == byte).count();
+                    output.extend_from_slice(&[run as u8, byte]);
+                    rest = &rest[run..];
+                }
+                Ok(output)
+            }))
+        }
+
+        fn version_needed(&self) -> u16 {
+            63
+        }
+    }
+
+    fn with_runs() -> CodecRegistry {
+        let mut codecs = CodecRegistry::default();
+        codecs.register(RUNS, RunsCodec);
+        codecs
+    }
+
+    /// An archive of a file that run length codes well, written with the runs codec
+    fn runs_archive() -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        writer.set_codecs(with_runs());
+        writer.set_method(RUNS).unwrap();
+        writer.add_file("runs.bin", &data(), DosDateTime::from_unix(0)).unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn data() -> Vec<u8> {
+        (0..100u8).flat_map(|i| vec![i; 1000]).collect()
+    }
+
+    fn open(zip: Vec<u8>, limits: ReadLimits) -> ZipArchive {
+        ZipArchive::from_reader(Cursor::new(zip), "runs.zip", limits).unwrap()
+    }
+
+    #[test]
+    fn method_ids_round_trip() {
+        for id in 0..=u16::MAX {
+            assert_eq!(CompressionMethod::from_id(id).id(), id);
+        }
+        assert_eq!(CompressionMethod::from_id(0x7A01), RUNS);
+    }
+
+    #[test]
+    fn registers_a_method() {
+        let codecs = with_runs();
+        assert_eq!(codecs.method_name(RUNS), "runs");
+        assert!(codecs.methods().contains(&RUNS));
+        assert!(CodecRegistry::default().get(RUNS).is_none());
+        assert!(CodecRegistry::empty().methods().is_empty());
+    }
+
+    #[test]
+    fn writes_and_reads_a_registered_method() {
+        // 100 runs of 1000 bytes in 800 bytes is past the default ratio limit
+        let mut archive = open(runs_archive(), ReadLimits::unlimited());
+        let entry = archive.entries().remove(0);
+        assert_eq!(entry.compression_method, RUNS);
+        assert_eq!(entry.version_needed, 63);
+        assert_eq!(entry.compressed_size, 2 * 4 * 100);
+
+        // Rip can't read it by itself
+        assert!(matches!(archive.read_entry(0), Err(ZipError::UnsupportedMethod(RUNS))));
+        archive.set_codecs(with_runs());
+        assert_eq!(archive.read_entry(0).unwrap(), data());
+
+        let mut codecs = with_runs();
+        codecs.unregister(RUNS);
+        archive.set_codecs(codecs);
+        assert!(matches!(archive.read_entry(0), Err(ZipError::UnsupportedMethod(RUNS))));
+    }
+
+    #[test]
+    fn registered_decoders_keep_to_the_limits() {
+        let limits = ReadLimits { max_entry_size: 99_999, ..ReadLimits::unlimited() };
+        let mut archive = open(runs_archive(), limits);
+        archive.set_codecs(with_runs());
+        assert!(matches!(archive.read_entry(0), Err(ZipError::LimitExceeded { limit: Limit::EntrySize, .. })));
+    }
+
+    #[test]
+    fn writer_needs_an_encoder() {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        assert!(writer.set_method(RUNS).is_err());
+        // Deflate64 can be read but not written
+        assert!(writer.set_method(CompressionMethod::Deflate64).is_err());
+
+        writer.set_codecs(with_runs());
+        writer.set_method(RUNS).unwrap();
+        let mut codecs = with_runs();
+        codecs.unregister(RUNS);
+        writer.set_codecs(codecs);
+        assert!(writer.add_file("runs.bin", &data(), DosDateTime::from_unix(0)).is_err());
+    }
+}
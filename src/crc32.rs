@@ -0,0 +1,60 @@
+// CRC-32 as used by zip and gzip (ISO-HDLC, reflected polynomial 0xEDB88320)
+// https://www.w3.org/TR/PNG/#D-CRCAppendix
+
+const POLYNOMIAL: u32 = 0xEDB88320;
+
+const TABLE: [u32; 256] = make_table();
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 == 1 { POLYNOMIAL ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+/// Running CRC-32, for data that arrives in pieces
+#[derive(Debug, Copy, Clone)]
+pub struct Crc32 {
+    value: u32
+}
+
+impl Default for Crc32 {
+    fn default() -> Crc32 {
+        Crc32::new()
+    }
+}
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        Crc32 { value: 0xFFFFFFFF }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let mut c = self.value;
+        for &byte in data {
+            c = TABLE[((c ^ byte as u32) & 0xFF) as usize] ^ (c >> 8);
+        }
+        self.value = c;
+    }
+
+    /// The checksum of everything passed to `update` so far
+    pub fn finish(&self) -> u32 {
+        self.value ^ 0xFFFFFFFF
+    }
+}
+
+/// CRC-32 of a complete buffer
+pub fn checksum(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
@@ -0,0 +1,665 @@
+// DEFLATE encoder, RFC 1951 https://tools.ietf.org/html/rfc1951
+// lz77 turns the input into literals and matches. Those are split into blocks where their statistics
+// change, and each block goes out stored, with the fixed code, or with a dynamic code from
+// limited_code_lengths, whichever comes to the fewest bits.
+use crate::bits::BitWriter;
+use crate::huffman::{canonical_codes, limited_code_lengths, HuffCode};
+use crate::inflate::{CODE_LENGTH_ORDER, DIST_BASE, DIST_EXTRA, LENGTH_BASE, LENGTH_EXTRA, MAX_DIST_CODES, MAX_LIT_CODES};
+use crate::lz77;
+use crate::lz77::Token;
+use crate::optimal;
+use crate::stream::{Flush, Status};
+
+/// zlib's default, a good balance of speed and size
+pub const DEFAULT_LEVEL: u8 = 6;
+pub const MAX_LEVEL: u8 = 9;
+/// Past the zlib levels: optimal parsing, for the smallest output however long it takes
+pub const ULTRA_LEVEL: u8 = 10;
+
+/// Most bytes a stored block can hold
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+/// Blocks are only split on multiples of this many tokens, or more for inputs big enough to have more
+/// than `MAX_GRANULES` of them, which keeps the search cheap whatever the input size
+const MIN_GRANULE_TOKENS: usize = 4096;
+const MAX_GRANULES: usize = 1024;
+
+/// Split points tried per round when narrowing in on the best one
+const SPLIT_SAMPLES: usize = 9;
+
+const END_OF_BLOCK: usize = 256;
+const MAX_CODE_LENGTH_BITS: u8 = 7;
+const MAX_LITERAL_BITS: u8 = 15;
+
+/// Compresses `data` into a raw DEFLATE stream at `level`, 0 (stored) to 9 (smallest of the zlib levels),
+/// or `ULTRA_LEVEL` for optimal parsing
+pub fn deflate(data: &[u8], level: u8) -> Vec<u8> {
+    let mut output = BitWriter::new();
+    write_blocks(&mut output, data, 0, level, true);
+    output.finish()
+}
+
+/// Compresses `data` as `deflate` does, but with matches allowed to reach back into a preset dictionary.
+/// Only the last window's worth of `dictionary` can be reached; the decoder needs the same dictionary.
+pub fn deflate_with_dictionary(data: &[u8], dictionary: &[u8], level: u8) -> Vec<u8> {
+    let history = dictionary_window(dictionary);
+    let mut window = Vec::with_capacity(history.len() + data.len());
+    window.extend_from_slice(history);
+    window.extend_from_slice(data);
+
+    let mut output = BitWriter::new();
+    write_blocks(&mut output, &window, history.len(), level, true);
+    output.finish()
+}
+
+/// The part of a preset dictionary a DEFLATE stream can see: its last 32 KiB
+pub(crate) fn dictionary_window(dictionary: &[u8]) -> &[u8] {
+    &dictionary[dictionary.len().saturating_sub(lz77::WINDOW_SIZE)..]
+}
+
+/// Writes `data[start..]` as blocks, with matches reaching back into `data[..start]`, which the decoder
+/// must already have. Only a `last` call writes anything for an empty range.
+fn write_blocks(output: &mut BitWriter, data: &[u8], start: usize, level: u8, last: bool) {
+    if start == data.len() && !last {
+        return;
+    }
+    if level == 0 {
+        write_stored(output, &data[start..], last);
+        return;
+    }
+
+    let tokens = if level >= ULTRA_LEVEL { optimal::tokenize_from(data, start) } else { lz77::tokenize_from(data, start, level) };
+    let bounds = block_bounds(&tokens);
+    let mut byte_pos = start;
+    for (i, range) in bounds.windows(2).enumerate() {
+        let block = &tokens[range[0]..range[1]];
+        let byte_len = block.iter().map(token_bytes).sum::<usize>();
+        write_block(output, block, &data[byte_pos..byte_pos + byte_len], last && i + 2 == bounds.len());
+        byte_pos += byte_len;
+    }
+}
+
+/// Incremental DEFLATE encoder. Input is gathered into chunks of `STREAM_CHUNK` bytes so the block
+/// splitter has something to work with, each compressed against the window of input before it;
+/// a flush compresses whatever has been gathered straight away.
+pub struct Deflater {
+    level: u8,
+    /// Up to a window of input already compressed, then the input waiting to be
+    window: Vec<u8>,
+    history: usize,
+    /// Holds the partial byte left between blocks
+    writer: BitWriter,
+    /// Compressed bytes not handed over yet, from `delivered` on
+    pending: Vec<u8>,
+    delivered: usize,
+    /// Nothing has been taken in since the last sync or full flush
+    flushed: bool,
+    finished: bool
+}
+
+/// Input gathered before a block is compressed, when nothing forces it sooner
+const STREAM_CHUNK: usize = 1 << 17;
+
+impl Deflater {
+    /// A stream compressing at `level`, as for `deflate`
+    pub fn new(level: u8) -> Deflater {
+        Deflater {
+            level: level.min(ULTRA_LEVEL),
+            window: Vec::new(),
+            history: 0,
+            writer: BitWriter::new(),
+            pending: Vec::new(),
+            delivered: 0,
+            flushed: false,
+            finished: false
+        }
+    }
+
+    /// A stream whose matches can reach back into a preset dictionary, as for `deflate_with_dictionary`
+    pub fn with_dictionary(level: u8, dictionary: &[u8]) -> Deflater {
+        let mut deflater = Deflater::new(level);
+        deflater.window = dictionary_window(dictionary).to_vec();
+        deflater.history = deflater.window.len();
+        deflater
+    }
+
+    /// Takes in as much of `input` as it can and hands over what compressed output it has into `output`,
+    /// returning how many bytes of each were used.
+    /// `flush` is applied once all of `input` has been taken in. With a full `output`, call again with the
+    /// same flush and more room; `Status::StreamEnd` means a `Flush::Finish` has been fully delivered.
+    pub fn process(&mut self, input: &[u8], output: &mut [u8], flush: Flush) -> (usize, usize, Status) {
+        let mut produced = self.deliver(output);
+        let mut consumed = 0;
+
+        while !self.finished && consumed < input.len() && self.delivered == self.pending.len() {
+            let take = (STREAM_CHUNK - (self.window.len() - self.history)).min(input.len() - consumed);
+            self.window.extend_from_slice(&input[consumed..consumed + take]);
+            consumed += take;
+            self.flushed = false;
+            if self.window.len() - self.history == STREAM_CHUNK {
+                self.compress(false);
+                produced += self.deliver(&mut output[produced..]);
+            }
+        }
+
+        if !self.finished && consumed == input.len() {
+            match flush {
+                Flush::None => {},
+                Flush::Sync | Flush::Full if !self.flushed => {
+                    self.compress(false);
+                    // An empty stored block byte-aligns everything before it
+                    write_stored_block(&mut self.writer, &[], false);
+                    if flush == Flush::Full {
+                        self.window.clear();
+                        self.history = 0;
+                    }
+                    self.flushed = true;
+                    self.take_output();
+                },
+                Flush::Sync | Flush::Full => {},
+                Flush::Finish => {
+                    self.compress(true);
+                    self.writer.align_to_byte();
+                    self.take_output();
+                    self.finished = true;
+                }
+            }
+            produced += self.deliver(&mut output[produced..]);
+        }
+
+        let status = if self.finished && self.delivered == self.pending.len() { Status::StreamEnd } else { Status::Ok };
+        (consumed, produced, status)
+    }
+
+    /// Compresses the input gathered so far, then drops all but a window of it
+    fn compress(&mut self, last: bool) {
+        write_blocks(&mut self.writer, &self.window, self.history, self.level, last);
+        self.take_output();
+
+        let excess = self.window.len().saturating_sub(lz77::WINDOW_SIZE);
+        self.window.drain(..excess);
+        self.history = self.window.len();
+    }
+
+    fn take_output(&mut self) {
+        let bytes = self.writer.take_bytes();
+        if self.delivered == self.pending.len() {
+            self.pending = bytes;
+            self.delivered = 0;
+        } else {
+            self.pending.extend_from_slice(&bytes);
+        }
+    }
+
+    fn deliver(&mut self, output: &mut [u8]) -> usize {
+        let count = output.len().min(self.pending.len() - self.delivered);
+        output[..count].copy_from_slice(&self.pending[self.delivered..self.delivered + count]);
+        self.delivered += count;
+        count
+    }
+}
+
+/// How many bytes of input a token stands for
+fn token_bytes(token: &Token) -> usize {
+    match *token {
+        Token::Literal(_) => 1,
+        Token::Match { length, .. } => length as usize
+    }
+}
+
+/// Where a match length falls in LENGTH_BASE: its length symbol (257-285) less 257
+pub(crate) fn length_symbol(length: u16) -> usize {
+    LENGTH_BASE.partition_point(|&base| base <= length) - 1
+}
+
+/// The distance symbol (0-29) for a match distance
+pub(crate) fn distance_symbol(distance: u16) -> usize {
+    DIST_BASE.partition_point(|&base| base <= distance) - 1
+}
+
+/// Symbol counts for a run of tokens, along with what they cost regardless of the code
+#[derive(Debug, Clone)]
+struct Histogram {
+    literals: Vec<u64>,
+    distances: Vec<u64>,
+    /// Length and distance extra bits
+    extra_bits: u64,
+    /// Input bytes covered, for pricing the run as a stored block
+    bytes: u64
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram { literals: vec![0; MAX_LIT_CODES], distances: vec![0; MAX_DIST_CODES], extra_bits: 0, bytes: 0 }
+    }
+
+    fn of(tokens: &[Token]) -> Histogram {
+        let mut histogram = Histogram::new();
+        for token in tokens {
+            histogram.add(token);
+        }
+        histogram
+    }
+
+    fn add(&mut self, token: &Token) {
+        match *token {
+            Token::Literal(byte) => self.literals[byte as usize] += 1,
+            Token::Match { length, distance } => {
+                let length_symbol = length_symbol(length);
+                let distance_symbol = distance_symbol(distance);
+                self.literals[257 + length_symbol] += 1;
+                self.distances[distance_symbol] += 1;
+                self.extra_bits += LENGTH_EXTRA[length_symbol] as u64 + DIST_EXTRA[distance_symbol] as u64;
+            }
+        }
+        self.bytes += token_bytes(token) as u64;
+    }
+
+    /// The counts between two running totals
+    fn between(start: &Histogram, end: &Histogram) -> Histogram {
+        let minus = |a: &[u64], b: &[u64]| a.iter().zip(b).map(|(x, y)| x - y).collect();
+        Histogram {
+            literals: minus(&end.literals, &start.literals),
+            distances: minus(&end.distances, &start.distances),
+            extra_bits: end.extra_bits - start.extra_bits,
+            bytes: end.bytes - start.bytes
+        }
+    }
+
+    /// Bits for the tokens and end of block under the given code lengths
+    fn coded_bits(&self, lit_lengths: &[u8], dist_lengths: &[u8]) -> u64 {
+        let literals: u64 = self.literals.iter().zip(lit_lengths).map(|(&freq, &len)| freq * len as u64).sum();
+        let distances: u64 = self.distances.iter().zip(dist_lengths).map(|(&freq, &len)| freq * len as u64).sum();
+        literals + distances + self.extra_bits + lit_lengths[END_OF_BLOCK] as u64
+    }
+}
+
+/// The code lengths of the fixed code (RFC 1951 3.2.6)
+fn fixed_lengths() -> (Vec<u8>, Vec<u8>) {
+    let literals = (0..288).map(|symbol| match symbol {
+        0..=143 => 8,
+        144..=255 => 9,
+        256..=279 => 7,
+        _ => 8
+    }).collect();
+    (literals, vec![5; MAX_DIST_CODES])
+}
+
+/// Everything a dynamic block header says, worked out from the block's statistics
+struct DynamicCodes {
+    lit_lengths: Vec<u8>,
+    dist_lengths: Vec<u8>,
+    hlit: usize,
+    hdist: usize,
+    /// The code lengths run-length coded, as (symbol, extra bits value)
+    rle: Vec<(u8, u8)>,
+    cl_lengths: Vec<u8>,
+    hclen: usize
+}
+
+impl DynamicCodes {
+    fn new(histogram: &Histogram) -> DynamicCodes {
+        let mut lit_freqs = histogram.literals.clone();
+        lit_freqs[END_OF_BLOCK] = 1;
+        let mut dist_freqs = histogram.distances.clone();
+        // A block of only literals still has to describe a distance code
+        if dist_freqs.iter().all(|&freq| freq == 0) {
+            dist_freqs[0] = 1;
+        }
+
+        let lit_lengths = limited_code_lengths(&lit_freqs, MAX_LITERAL_BITS);
+        let dist_lengths = limited_code_lengths(&dist_freqs, MAX_LITERAL_BITS);
+        let hlit = 257.max(lit_lengths.iter().rposition(|&len| len > 0).unwrap_or(0) + 1);
+        let hdist = 1.max(dist_lengths.iter().rposition(|&len| len > 0).unwrap_or(0) + 1);
+
+        let mut all_lengths = lit_lengths[..hlit].to_vec();
+        all_lengths.extend_from_slice(&dist_lengths[..hdist]);
+        let rle = run_length_code(&all_lengths);
+
+        let mut cl_freqs = vec![0u64; CODE_LENGTH_ORDER.len()];
+        for &(symbol, _) in &rle {
+            cl_freqs[symbol as usize] += 1;
+        }
+        // Inflaters insist the code length code is complete, which a single code of one bit isn't
+        if cl_freqs.iter().filter(|&&freq| freq > 0).count() == 1 {
+            let unused = cl_freqs.iter().position(|&freq| freq == 0).unwrap();
+            cl_freqs[unused] = 1;
+        }
+        let cl_lengths = limited_code_lengths(&cl_freqs, MAX_CODE_LENGTH_BITS);
+        let hclen = 4.max(CODE_LENGTH_ORDER.iter().rposition(|&symbol| cl_lengths[symbol] > 0).unwrap_or(0) + 1);
+
+        DynamicCodes { lit_lengths, dist_lengths, hlit, hdist, rle, cl_lengths, hclen }
+    }
+
+    /// Bits in the header after the block type
+    fn header_bits(&self) -> u64 {
+        let codes: u64 = self.rle.iter()
+            .map(|&(symbol, _)| self.cl_lengths[symbol as usize] as u64 + rle_extra_bits(symbol) as u64)
+            .sum();
+        5 + 5 + 4 + 3 * self.hclen as u64 + codes
+    }
+
+    fn write_header(&self, output: &mut BitWriter) {
+        output.write_bits((self.hlit - 257) as u64, 5);
+        output.write_bits((self.hdist - 1) as u64, 5);
+        output.write_bits((self.hclen - 4) as u64, 4);
+        for &symbol in CODE_LENGTH_ORDER.iter().take(self.hclen) {
+            output.write_bits(self.cl_lengths[symbol] as u64, 3);
+        }
+        let cl_codes = canonical_codes(&self.cl_lengths);
+        for &(symbol, extra) in &self.rle {
+            write_code(output, &cl_codes[symbol as usize]);
+            output.write_bits(extra as u64, rle_extra_bits(symbol));
+        }
+    }
+}
+
+fn rle_extra_bits(symbol: u8) -> u32 {
+    match symbol {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _ => 0
+    }
+}
+
+/// Run-length codes the literal/length and distance code lengths with symbols 0-18 (RFC 1951 3.2.7).
+/// Each entry is a symbol and the value of its extra bits.
+fn run_length_code(lengths: &[u8]) -> Vec<(u8, u8)> {
+    let mut symbols = Vec::new();
+    let mut pos = 0;
+    while pos < lengths.len() {
+        let len = lengths[pos];
+        let run = lengths[pos..].iter().take_while(|&&l| l == len).count();
+
+        if len == 0 && run >= 11 {
+            let count = run.min(138);
+            symbols.push((18, (count - 11) as u8));
+            pos += count;
+        } else if len == 0 && run >= 3 {
+            symbols.push((17, (run - 3) as u8));
+            pos += run;
+        } else if len != 0 && run >= 4 {
+            // The first copy goes out as itself, then 16 repeats it 3-6 times at a go
+            symbols.push((len, 0));
+            let mut left = run - 1;
+            while left >= 3 {
+                let count = left.min(6);
+                symbols.push((16, (count - 3) as u8));
+                left -= count;
+            }
+            for _ in 0..left {
+                symbols.push((len, 0));
+            }
+            pos += run;
+        } else {
+            symbols.push((len, 0));
+            pos += 1;
+        }
+    }
+    symbols
+}
+
+/// Bits to store `bytes` starting `bit_pos` bits into the stream: each 64 KiB gets a 3 bit header
+/// padded out to a byte, then LEN and NLEN
+fn stored_bits(bytes: u64, bit_pos: u64) -> u64 {
+    let blocks = bytes.div_ceil(MAX_STORED_BLOCK as u64).max(1);
+    let first_header = 3 + (8 - (bit_pos + 3) % 8) % 8;
+    first_header + (blocks - 1) * 8 + blocks * 32 + bytes * 8
+}
+
+enum BlockKind {
+    Stored,
+    Fixed,
+    Dynamic(DynamicCodes)
+}
+
+/// The cheapest way to code a block starting `bit_pos` bits into the stream, and its size in bits
+fn cheapest_block(histogram: &Histogram, bit_pos: u64) -> (BlockKind, u64) {
+    let dynamic = DynamicCodes::new(histogram);
+    let dynamic_bits = 3 + dynamic.header_bits() + histogram.coded_bits(&dynamic.lit_lengths, &dynamic.dist_lengths);
+    let (fixed_lit, fixed_dist) = fixed_lengths();
+    let fixed_bits = 3 + histogram.coded_bits(&fixed_lit, &fixed_dist);
+    let stored_bits = stored_bits(histogram.bytes, bit_pos);
+
+    if stored_bits < fixed_bits && stored_bits < dynamic_bits {
+        (BlockKind::Stored, stored_bits)
+    } else if fixed_bits <= dynamic_bits {
+        (BlockKind::Fixed, fixed_bits)
+    } else {
+        (BlockKind::Dynamic(dynamic), dynamic_bits)
+    }
+}
+
+/// Token indexes where blocks start, plus the end. The stream is cut wherever coding the two sides
+/// separately beats coding them together, headers included, and then each side is looked at again.
+/// Cuts fall on granule boundaries, where running histograms make pricing any span cheap.
+fn block_bounds(tokens: &[Token]) -> Vec<usize> {
+    let granule = MIN_GRANULE_TOKENS.max(tokens.len().div_ceil(MAX_GRANULES));
+    let mut running = vec![Histogram::new()];
+    for chunk in tokens.chunks(granule) {
+        let mut next = running[running.len() - 1].clone();
+        for token in chunk {
+            next.add(token);
+        }
+        running.push(next);
+    }
+
+    let granules = running.len() - 1;
+    let cost = |start: usize, end: usize| cheapest_block(&Histogram::between(&running[start], &running[end]), 0).1;
+
+    let mut cuts = Vec::new();
+    let mut pending = vec![(0, granules)];
+    while let Some((start, end)) = pending.pop() {
+        if end - start < 2 {
+            continue;
+        }
+        let (cut, split_cost) = find_minimum(|cut| cost(start, cut) + cost(cut, end), start + 1, end);
+        if split_cost < cost(start, end) {
+            cuts.push(cut);
+            pending.push((start, cut));
+            pending.push((cut, end));
+        }
+    }
+    cuts.sort_unstable();
+
+    let mut bounds = vec![0];
+    bounds.extend(cuts.iter().map(|&cut| cut * granule));
+    bounds.push(tokens.len());
+    bounds
+}
+
+/// Where `f` is lowest over `start..end`, narrowing in around the best of a few evenly spaced samples the
+/// way zopfli's blocksplitter does. Not exhaustive, but block costs change smoothly enough for it.
+fn find_minimum<F: Fn(usize) -> u64>(f: F, mut start: usize, mut end: usize) -> (usize, u64) {
+    loop {
+        if end - start <= SPLIT_SAMPLES {
+            return (start..end).map(|i| (i, f(i))).min_by_key(|&(_, cost)| cost).unwrap();
+        }
+
+        let points: Vec<usize> = (0..SPLIT_SAMPLES)
+            .map(|i| start + (end - start) * (i + 1) / (SPLIT_SAMPLES + 1))
+            .collect();
+        let best = (0..SPLIT_SAMPLES).min_by_key(|&i| f(points[i])).unwrap();
+        start = if best == 0 { start } else { points[best - 1] + 1 };
+        end = if best + 1 == SPLIT_SAMPLES { end } else { points[best + 1] };
+    }
+}
+
+fn write_code(output: &mut BitWriter, code: &HuffCode) {
+    output.write_bits(code.code, code.bitlength as u32);
+}
+
+/// Writes one block's tokens, which cover `bytes` of the input, whichever way comes out smallest
+fn write_block(output: &mut BitWriter, tokens: &[Token], bytes: &[u8], last: bool) {
+    let histogram = Histogram::of(tokens);
+    let start = output.bit_len();
+    let (kind, bits) = cheapest_block(&histogram, start);
+    match kind {
+        BlockKind::Stored => write_stored(output, bytes, last),
+        BlockKind::Fixed => {
+            let (lit_lengths, dist_lengths) = fixed_lengths();
+            output.write_bits(last as u64, 1);
+            output.write_bits(1, 2);
+            write_tokens(output, tokens, &canonical_codes(&lit_lengths), &canonical_codes(&dist_lengths));
+        },
+        BlockKind::Dynamic(codes) => {
+            output.write_bits(last as u64, 1);
+            output.write_bits(2, 2);
+            codes.write_header(output);
+            write_tokens(output, tokens, &canonical_codes(&codes.lit_lengths), &canonical_codes(&codes.dist_lengths));
+        }
+    }
+    debug_assert_eq!(output.bit_len() - start, bits, "block cost estimate is off");
+}
+
+/// Writes `data` as stored blocks, at least one even when there's nothing to store
+fn write_stored(output: &mut BitWriter, data: &[u8], last: bool) {
+    let mut chunks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if chunks.peek().is_none() {
+        write_stored_block(output, &[], last);
+    }
+    while let Some(chunk) = chunks.next() {
+        write_stored_block(output, chunk, last && chunks.peek().is_none());
+    }
+}
+
+fn write_stored_block(output: &mut BitWriter, chunk: &[u8], last: bool) {
+    output.write_bits(last as u64, 1);
+    output.write_bits(0, 2);
+    output.align_to_byte();
+    let len = chunk.len() as u16;
+    output.write_bytes(&len.to_le_bytes());
+    output.write_bytes(&(!len).to_le_bytes());
+    output.write_bytes(chunk);
+}
+
+/// The tokens followed by the end of block code
+fn write_tokens(output: &mut BitWriter, tokens: &[Token], lit_codes: &[HuffCode], dist_codes: &[HuffCode]) {
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => write_code(output, &lit_codes[byte as usize]),
+            Token::Match { length, distance } => {
+                let symbol = length_symbol(length);
+                write_code(output, &lit_codes[257 + symbol]);
+                output.write_bits((length - LENGTH_BASE[symbol]) as u64, LENGTH_EXTRA[symbol] as u32);
+
+                let symbol = distance_symbol(distance);
+                write_code(output, &dist_codes[symbol]);
+                output.write_bits((distance - DIST_BASE[symbol]) as u64, DIST_EXTRA[symbol] as u32);
+            }
+        }
+    }
+    write_code(output, &lit_codes[END_OF_BLOCK]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inflate::{inflate, Inflater};
+
+    fn text() -> Vec<u8> {
+        std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/english3.txt")).unwrap()
+    }
+
+    /// Runs all of `input` through `deflater` with `flush`, taking `room` bytes of output at a time
+    fn run(deflater: &mut Deflater, mut input: &[u8], flush: Flush, room: usize) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut buffer = vec![0; room];
+        loop {
+            let (consumed, produced, status) = deflater.process(input, &mut buffer, flush);
+            input = &input[consumed..];
+            output.extend_from_slice(&buffer[..produced]);
+            if status == Status::StreamEnd || (input.is_empty() && produced < room) {
+                return output;
+            }
+        }
+    }
+
+    /// Everything an inflater makes of `stream`, which needn't be finished
+    fn decode(stream: &[u8]) -> (Vec<u8>, Status) {
+        let mut inflater = Inflater::new();
+        let mut output = Vec::new();
+        let mut buffer = [0; 4096];
+        let mut pos = 0;
+        loop {
+            let (consumed, produced, status) = inflater.process(&stream[pos..], &mut buffer).unwrap();
+            pos += consumed;
+            output.extend_from_slice(&buffer[..produced]);
+            if status == Status::StreamEnd || (consumed == 0 && produced == 0) {
+                return (output, status);
+            }
+        }
+    }
+
+    #[test]
+    fn sync_flushes_make_everything_so_far_decodable() {
+        let text = text();
+        for level in [0, 1, 6, 9] {
+            let mut deflater = Deflater::new(level);
+            let mut stream = Vec::new();
+            let mut pos = 0;
+            for len in [1, 100, 5000, 40_000, STREAM_CHUNK + 1, 3] {
+                stream.extend(run(&mut deflater, &text[pos..pos + len], Flush::Sync, 1 << 16));
+                pos += len;
+                assert!(stream.ends_with(&[0, 0, 0xFF, 0xFF]), "level {}", level);
+                assert_eq!(decode(&stream), (text[..pos].to_vec(), Status::Ok), "level {}", level);
+            }
+            stream.extend(run(&mut deflater, &[], Flush::Finish, 1 << 16));
+            assert_eq!(decode(&stream), (text[..pos].to_vec(), Status::StreamEnd), "level {}", level);
+        }
+    }
+
+    #[test]
+    fn a_second_sync_flush_adds_nothing() {
+        let mut deflater = Deflater::new(6);
+        assert!(!run(&mut deflater, b"hello", Flush::Sync, 100).is_empty());
+        assert!(run(&mut deflater, b"", Flush::Sync, 100).is_empty());
+        assert!(run(&mut deflater, b"", Flush::Full, 100).is_empty());
+    }
+
+    #[test]
+    fn full_flushes_forget_the_window() {
+        let text = &text()[..20_000];
+        let mut deflater = Deflater::new(6);
+        let mut stream = run(&mut deflater, text, Flush::Full, 1 << 16);
+        let restart = stream.len();
+        stream.extend(run(&mut deflater, text, Flush::Finish, 1 << 16));
+        assert_eq!(inflate(&stream, u64::MAX).unwrap(), text.repeat(2));
+        // What follows a full flush decodes on its own, although it repeats what came before
+        assert_eq!(inflate(&stream[restart..], u64::MAX).unwrap(), text);
+
+        // After only a sync flush the repeat refers back
+        let mut deflater = Deflater::new(6);
+        let mut stream = run(&mut deflater, text, Flush::Sync, 1 << 16);
+        let restart = stream.len();
+        stream.extend(run(&mut deflater, text, Flush::Finish, 1 << 16));
+        assert_eq!(inflate(&stream, u64::MAX).unwrap(), text.repeat(2));
+        assert!(inflate(&stream[restart..], u64::MAX).is_err());
+    }
+
+    #[test]
+    fn output_buffers_smaller_than_a_block() {
+        let text = &text()[..200_000];
+        let whole = run(&mut Deflater::new(6), text, Flush::Finish, 1 << 20);
+        assert_eq!(inflate(&whole, u64::MAX).unwrap(), text);
+        for room in [1, 3, 64] {
+            assert!(run(&mut Deflater::new(6), text, Flush::Finish, room) == whole, "{} bytes at a time", room);
+        }
+    }
+
+    #[test]
+    fn matches_one_shot_deflate() {
+        let text = &text()[..STREAM_CHUNK / 2];
+        for level in [0, 4, 9, ULTRA_LEVEL] {
+            assert_eq!(run(&mut Deflater::new(level), text, Flush::Finish, 1 << 16), deflate(text, level), "level {}", level);
+        }
+    }
+
+    #[test]
+    fn nothing_more_after_the_end() {
+        let mut deflater = Deflater::new(6);
+        run(&mut deflater, b"hello", Flush::Finish, 100);
+        assert_eq!(deflater.process(b"more", &mut [0; 100], Flush::Finish), (0, 0, Status::StreamEnd));
+    }
+}
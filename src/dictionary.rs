@@ -0,0 +1,161 @@
+// Builds preset dictionaries for deflate from a corpus of sample messages, after zstd's COVER algorithm.
+// Short segments of the corpus are scored by how many samples share their d-byte substrings (dmers),
+// and the best segment from each stretch of the corpus goes into the dictionary, best last, where the
+// distances to it are shortest. https://github.com/facebook/zstd/blob/dev/lib/dictBuilder/cover.c
+use std::collections::HashMap;
+use crate::lz77::WINDOW_SIZE;
+
+/// DEFLATE can only see this much of a dictionary
+pub const MAX_DICTIONARY_SIZE: usize = WINDOW_SIZE;
+
+/// Bytes per dmer, the unit segments are scored in; about as short as a match worth taking
+const DMER_LENGTH: usize = 6;
+/// Bytes per segment added to the dictionary
+const SEGMENT_LENGTH: usize = 32;
+
+fn dmer_at(data: &[u8], pos: usize) -> u64 {
+    data[pos..pos + DMER_LENGTH].iter().fold(0, |key, &byte| key << 8 | byte as u64)
+}
+
+/// Builds a dictionary of at most `size` bytes (capped at `MAX_DICTIONARY_SIZE`) from `samples`, each one
+/// message of the kind the dictionary will be used for
+pub fn build(samples: &[&[u8]], size: usize) -> Vec<u8> {
+    let size = size.min(MAX_DICTIONARY_SIZE);
+
+    // How many samples each dmer turns up in; something in a single sample won't help any other
+    let mut frequencies: HashMap<u64, u32> = HashMap::new();
+    let mut seen_in: HashMap<u64, usize> = HashMap::new();
+    for (index, sample) in samples.iter().enumerate() {
+        for pos in 0..(sample.len() + 1).saturating_sub(DMER_LENGTH) {
+            let dmer = dmer_at(sample, pos);
+            if seen_in.insert(dmer, index) != Some(index) {
+                *frequencies.entry(dmer).or_insert(0) += 1;
+            }
+        }
+    }
+    frequencies.retain(|_, count| *count > 1);
+
+    // Split the corpus into as many stretches as there's room for segments and take the best of each,
+    // so the dictionary covers all of it rather than the one part with the most repetition
+    let corpus: Vec<u8> = samples.concat();
+    let epochs = (size / SEGMENT_LENGTH).max(1);
+    let epoch_length = (corpus.len() / epochs).max(SEGMENT_LENGTH);
+
+    let mut segments: Vec<(u64, &[u8])> = Vec::new();
+    for start in (0..corpus.len()).step_by(epoch_length) {
+        let epoch = &corpus[start..(start + epoch_length).min(corpus.len())];
+        if let Some((score, segment)) = best_segment(epoch, &frequencies) {
+            // Each dmer only counts once; later segments should bring something new
+            for pos in 0..=segment.len() - DMER_LENGTH {
+                frequencies.remove(&dmer_at(segment, pos));
+            }
+            segments.push((score, segment));
+        }
+    }
+
+    // Best segments last, and the worst dropped if there are too many
+    segments.sort_by_key(|&(score, _)| score);
+    let mut dictionary: Vec<u8> = segments.iter().flat_map(|&(_, segment)| segment.iter().copied()).collect();
+    let excess = dictionary.len().saturating_sub(size);
+    dictionary.drain(..excess);
+    dictionary
+}
+
+/// The segment of `epoch` whose distinct dmers are shared by the most samples, with its score
+fn best_segment<'a>(epoch: &'a [u8], frequencies: &HashMap<u64, u32>) -> Option<(u64, &'a [u8])> {
+    if epoch.len() < SEGMENT_LENGTH {
+        return None;
+    }
+    let dmers_per_segment = SEGMENT_LENGTH - DMER_LENGTH + 1;
+    let dmers: Vec<u64> = (0..=epoch.len() - DMER_LENGTH).map(|pos| dmer_at(epoch, pos)).collect();
+
+    // Slide a segment along, counting each dmer inside it once however often it repeats
+    let mut active: HashMap<u64, u32> = HashMap::new();
+    let mut score: u64 = 0;
+    let mut best: Option<(u64, usize)> = None;
+    for (pos, &dmer) in dmers.iter().enumerate() {
+        let count = active.entry(dmer).or_insert(0);
+        *count += 1;
+        if *count == 1 {
+            score += *frequencies.get(&dmer).unwrap_or(&0) as u64;
+        }
+
+        if pos >= dmers_per_segment {
+            let leaving = dmers[pos - dmers_per_segment];
+            let count = active.get_mut(&leaving).expect("leaving dmer is active");
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&leaving);
+                score -= *frequencies.get(&leaving).unwrap_or(&0) as u64;
+            }
+        }
+
+        if pos + 1 >= dmers_per_segment && score > best.map_or(0, |(best_score, _)| best_score) {
+            best = Some((score, pos + 1 - dmers_per_segment));
+        }
+    }
+
+    best.map(|(score, start)| (score, &epoch[start..start + SEGMENT_LENGTH]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zlib;
+
+    /// Small JSON records that share their keys and little else
+    fn samples() -> Vec<Vec<u8>> {
+        (0..200).map(|i| {
+            format!(r#"{{"id": {}, "user": "user{}", "status": "{}", "created_at": "2024-01-{:02}T00:00:00Z"}}"#,
+                i, i * 7919 % 1000, ["active", "suspended", "pending"][i % 3], i % 28 + 1).into_bytes()
+        }).collect()
+    }
+
+    #[test]
+    fn keeps_to_the_size() {
+        let samples = samples();
+        let samples: Vec<&[u8]> = samples.iter().map(|sample| &sample[..]).collect();
+        for &size in &[0, 1, 100, 1000] {
+            assert!(build(&samples, size).len() <= size, "{} bytes", size);
+        }
+        let big: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        assert!(build(&[&big, &big], 1 << 20).len() <= MAX_DICTIONARY_SIZE);
+    }
+
+    #[test]
+    fn nothing_shared_nothing_to_build() {
+        assert!(build(&[], 1000).is_empty());
+        assert!(build(&[b"only one sample, however long it is and however much it repeats itself"], 1000).is_empty());
+    }
+
+    #[test]
+    fn takes_what_samples_share() {
+        let samples = samples();
+        let samples: Vec<&[u8]> = samples.iter().map(|sample| &sample[..]).collect();
+        let dictionary = build(&samples, 256);
+        assert!(!dictionary.is_empty());
+        let corpus = samples.concat();
+        // Whole segments, bar perhaps the first which may have been cut short at the front
+        for segment in dictionary.rchunks(SEGMENT_LENGTH) {
+            assert!(corpus.windows(segment.len()).any(|window| window == segment));
+        }
+        // The key everything has is worth more than any one value
+        assert!(dictionary.windows(12).any(|window| window == b"\"created_at\""));
+    }
+
+    #[test]
+    fn dictionary_helps_compression() {
+        let samples = samples();
+        let (training, messages) = samples.split_at(150);
+        let training: Vec<&[u8]> = training.iter().map(|sample| &sample[..]).collect();
+        let dictionary = build(&training, 1024);
+
+        let plain: usize = messages.iter().map(|message| zlib::compress(message, 9).len()).sum();
+        let primed: usize = messages.iter().map(|message| {
+            let compressed = zlib::compress_with_dictionary(message, &dictionary, 9);
+            assert_eq!(&zlib::decompress_with_dictionary(&compressed, &dictionary, 1000).unwrap(), message);
+            compressed.len()
+        }).sum();
+        assert!(primed * 2 < plain, "{} bytes with the dictionary, {} without", primed, plain);
+    }
+}
@@ -0,0 +1,100 @@
+// MS-DOS date/time fields as stored in zip headers
+// https://docs.microsoft.com/en-us/windows/win32/api/winbase/nf-winbase-dosdatetimetofiletime
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A broken-down local-less timestamp, at the 2 second resolution DOS stores
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DosDateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8
+}
+
+impl DosDateTime {
+    /// Decodes the (date, time) pair from a zip header
+    pub fn from_dos(date: u16, time: u16) -> DosDateTime {
+        DosDateTime {
+            year: 1980 + (date >> 9),
+            month: ((date >> 5) & 0x0F) as u8,
+            day: (date & 0x1F) as u8,
+            hour: (time >> 11) as u8,
+            minute: ((time >> 5) & 0x3F) as u8,
+            second: ((time & 0x1F) * 2) as u8
+        }
+    }
+
+    /// Encodes into a (date, time) pair, clamping to the 1980-2107 range DOS can represent
+    pub fn to_dos(&self) -> (u16, u16) {
+        if self.year < 1980 {
+            return (1 << 5 | 1, 0);
+        }
+        let year = (self.year - 1980).min(127);
+        let date = year << 9 | (self.month as u16) << 5 | self.day as u16;
+        let time = (self.hour as u16) << 11 | (self.minute as u16) << 5 | ((self.second as u16) / 2);
+        (date, time)
+    }
+
+    /// Converts seconds since the unix epoch, treating them as UTC
+    pub fn from_unix(seconds: i64) -> DosDateTime {
+        let days = seconds.div_euclid(86400);
+        let secs_of_day = seconds.rem_euclid(86400);
+        let (year, month, day) = civil_from_days(days);
+        DosDateTime {
+            year: year.clamp(0, u16::MAX as i64) as u16,
+            month,
+            day,
+            hour: (secs_of_day / 3600) as u8,
+            minute: (secs_of_day / 60 % 60) as u8,
+            second: (secs_of_day % 60) as u8
+        }
+    }
+
+    pub fn from_system_time(time: SystemTime) -> DosDateTime {
+        let seconds = match time.duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs() as i64,
+            Err(_) => 0
+        };
+        DosDateTime::from_unix(seconds)
+    }
+
+    /// Seconds since the unix epoch, treating the fields as UTC
+    pub fn to_unix(&self) -> i64 {
+        let days = days_from_civil(self.year as i64, self.month, self.day);
+        days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64
+    }
+}
+
+impl fmt::Display for DosDateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+// Calendar conversions from http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = month as i64;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
@@ -0,0 +1,180 @@
+// Extra fields: a list of (header id, length, data) blocks after the file name in both headers
+// Header ids are listed in section 4.5 of https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT
+// and in proginfo/extrafld.txt of Info-ZIP
+
+pub const ZIP64_ID: u16 = 0x0001;
+pub const NTFS_ID: u16 = 0x000a;
+pub const EXTENDED_TIMESTAMP_ID: u16 = 0x5455;
+pub const UNICODE_PATH_ID: u16 = 0x7075;
+pub const UNIX_IDS_ID: u16 = 0x7875;
+
+/// Which of the 32 bit header fields were saturated to 0xFFFFFFFF (or 0xFFFF for the disk),
+/// since the zip64 field only carries replacements for those, in this order.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Zip64Fields {
+    pub uncompressed_size: bool,
+    pub compressed_size: bool,
+    pub local_header_offset: bool,
+    pub disk_start: bool
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtraField {
+    /// 0x0001, 64 bit replacements for saturated header fields
+    Zip64 {
+        uncompressed_size: Option<u64>,
+        compressed_size: Option<u64>,
+        local_header_offset: Option<u64>,
+        disk_start: Option<u32>
+    },
+    /// 0x000a, NTFS FILETIMEs (100ns ticks since 1601-01-01)
+    Ntfs { modified: u64, accessed: u64, created: u64 },
+    /// 0x5455, unix timestamps; the central directory copy usually only carries `modified`
+    ExtendedTimestamp { flags: u8, modified: Option<i32>, accessed: Option<i32>, created: Option<i32> },
+    /// 0x7075, a UTF-8 name along with the CRC-32 of the header name it replaces
+    UnicodePath { name_crc32: u32, name: String },
+    /// 0x7875, Info-ZIP unix uid/gid
+    UnixIds { uid: u64, gid: u64 },
+    /// Anything not understood, or a known id whose data didn't parse
+    Unknown { id: u16, data: Vec<u8> }
+}
+
+impl ExtraField {
+    pub fn id(&self) -> u16 {
+        match self {
+            ExtraField::Zip64 { .. } => ZIP64_ID,
+            ExtraField::Ntfs { .. } => NTFS_ID,
+            ExtraField::ExtendedTimestamp { .. } => EXTENDED_TIMESTAMP_ID,
+            ExtraField::UnicodePath { .. } => UNICODE_PATH_ID,
+            ExtraField::UnixIds { .. } => UNIX_IDS_ID,
+            ExtraField::Unknown { id, .. } => *id
+        }
+    }
+}
+
+/// Little-endian reads over a block's data that stop cleanly at the end
+struct FieldReader<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl FieldReader<'_> {
+    fn take(&mut self, count: usize) -> Option<&[u8]> {
+        let bytes = self.data.get(self.pos..self.pos + count)?;
+        self.pos += count;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.take(8).map(|b| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(b);
+            u64::from_le_bytes(bytes)
+        })
+    }
+
+    /// A little-endian integer of `size` bytes, as Info-ZIP stores uid/gid
+    fn uint(&mut self, size: usize) -> Option<u64> {
+        if size > 8 {
+            return None;
+        }
+        let bytes = self.take(size)?;
+        Some(bytes.iter().rev().fold(0u64, |acc, &b| acc << 8 | b as u64))
+    }
+}
+
+/// Splits an extra field area into its blocks and decodes the ones we know about.
+/// A trailing block whose length runs past the end is returned as `Unknown` with whatever data there is.
+pub fn parse(data: &[u8], zip64: Zip64Fields) -> Vec<ExtraField> {
+    let mut fields = Vec::new();
+    let mut pos = 0;
+
+    while pos + 4 <= data.len() {
+        let id = u16::from_le_bytes([data[pos], data[pos + 1]]);
+        let len = u16::from_le_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let body = &data[pos + 4..data.len().min(pos + 4 + len)];
+        pos += 4 + len;
+
+        let parsed = match id {
+            ZIP64_ID => parse_zip64(body, zip64),
+            NTFS_ID => parse_ntfs(body),
+            EXTENDED_TIMESTAMP_ID => parse_timestamp(body),
+            UNICODE_PATH_ID => parse_unicode_path(body),
+            UNIX_IDS_ID => parse_unix_ids(body),
+            _ => None
+        };
+        fields.push(parsed.unwrap_or_else(|| ExtraField::Unknown { id, data: body.to_vec() }));
+    }
+
+    fields
+}
+
+fn parse_zip64(body: &[u8], wanted: Zip64Fields) -> Option<ExtraField> {
+    let mut reader = FieldReader { data: body, pos: 0 };
+    let uncompressed_size = if wanted.uncompressed_size { Some(reader.u64()?) } else { None };
+    let compressed_size = if wanted.compressed_size { Some(reader.u64()?) } else { None };
+    let local_header_offset = if wanted.local_header_offset { Some(reader.u64()?) } else { None };
+    let disk_start = if wanted.disk_start { Some(reader.u32()?) } else { None };
+    Some(ExtraField::Zip64 { uncompressed_size, compressed_size, local_header_offset, disk_start })
+}
+
+fn parse_ntfs(body: &[u8]) -> Option<ExtraField> {
+    let mut reader = FieldReader { data: body, pos: 0 };
+    reader.u32()?; // reserved
+    while let Some(tag) = reader.u16() {
+        let size = reader.u16()? as usize;
+        if tag == 1 && size == 24 {
+            let modified = reader.u64()?;
+            let accessed = reader.u64()?;
+            let created = reader.u64()?;
+            return Some(ExtraField::Ntfs { modified, accessed, created });
+        }
+        reader.take(size)?;
+    }
+    None
+}
+
+fn parse_timestamp(body: &[u8]) -> Option<ExtraField> {
+    let mut reader = FieldReader { data: body, pos: 0 };
+    let flags = reader.u8()?;
+    // The flags say which times exist in the local header; the central copy is cut short after the first
+    let mut time = |bit: u8| if flags & bit != 0 { reader.u32().map(|t| t as i32) } else { None };
+    let modified = time(1);
+    let accessed = time(2);
+    let created = time(4);
+    Some(ExtraField::ExtendedTimestamp { flags, modified, accessed, created })
+}
+
+fn parse_unicode_path(body: &[u8]) -> Option<ExtraField> {
+    let mut reader = FieldReader { data: body, pos: 0 };
+    if reader.u8()? != 1 {
+        return None;
+    }
+    let name_crc32 = reader.u32()?;
+    let name = String::from_utf8(body[reader.pos..].to_vec()).ok()?;
+    Some(ExtraField::UnicodePath { name_crc32, name })
+}
+
+fn parse_unix_ids(body: &[u8]) -> Option<ExtraField> {
+    let mut reader = FieldReader { data: body, pos: 0 };
+    if reader.u8()? != 1 {
+        return None;
+    }
+    let uid_size = reader.u8()? as usize;
+    let uid = reader.uint(uid_size)?;
+    let gid_size = reader.u8()? as usize;
+    let gid = reader.uint(gid_size)?;
+    Some(ExtraField::UnixIds { uid, gid })
+}
@@ -0,0 +1,386 @@
+// gzip container, RFC 1952 https://tools.ietf.org/html/rfc1952
+// A gzip file is one or more members, each a header, a raw DEFLATE stream, then the CRC-32 and size
+// (mod 2^32) of what it inflates to. Decompressing a file gives the members' data back to back.
+use std::fmt;
+use crate::crc32;
+use crate::deflate;
+use crate::inflate;
+use crate::inflate::InflateError;
+
+const MAGIC: [u8; 2] = [0x1F, 0x8B];
+/// CM = 8, the only compression method gzip defines
+const METHOD_DEFLATE: u8 = 8;
+
+const FTEXT: u8 = 0x01;
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+const RESERVED_FLAGS: u8 = 0xE0;
+
+/// XFL values telling a reader how hard the compressor tried
+const XFL_SLOWEST: u8 = 2;
+const XFL_FASTEST: u8 = 4;
+
+/// OS 255, unknown; the header says nothing about where the file came from
+pub const OS_UNKNOWN: u8 = 255;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GzipError {
+    /// The data doesn't start with the gzip magic bytes
+    InvalidMagic,
+    /// A compression method other than deflate
+    UnsupportedMethod(u8),
+    /// Flag bits the RFC reserves are set
+    ReservedFlags(u8),
+    /// The header ended early
+    TruncatedHeader,
+    /// FHCRC is set and doesn't match the header
+    HeaderChecksumMismatch { expected: u16, found: u16 },
+    /// The member ended before its CRC-32 and size trailer
+    TruncatedTrailer,
+    ChecksumMismatch { expected: u32, found: u32 },
+    /// ISIZE doesn't match the inflated length mod 2^32
+    SizeMismatch { expected: u32, found: u32 },
+    /// Bytes after the last member that aren't another member
+    TrailingData,
+    Inflate(InflateError)
+}
+
+impl fmt::Display for GzipError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GzipError::InvalidMagic => write!(f, "not in gzip format"),
+            GzipError::UnsupportedMethod(method) => write!(f, "unsupported compression method {}", method),
+            GzipError::ReservedFlags(flags) => write!(f, "reserved header flags set ({:#04X})", flags),
+            GzipError::TruncatedHeader => write!(f, "truncated gzip header"),
+            GzipError::HeaderChecksumMismatch { expected, found } =>
+                write!(f, "header CRC-16 mismatch (expected {:#06X}, found {:#06X})", expected, found),
+            GzipError::TruncatedTrailer => write!(f, "truncated gzip trailer"),
+            GzipError::ChecksumMismatch { expected, found } =>
+                write!(f, "CRC-32 mismatch (expected {:#010X}, found {:#010X})", expected, found),
+            GzipError::SizeMismatch { expected, found } =>
+                write!(f, "length mismatch (expected {}, found {})", expected, found),
+            GzipError::TrailingData => write!(f, "trailing garbage after gzip data"),
+            GzipError::Inflate(err) => write!(f, "{}", err)
+        }
+    }
+}
+
+impl std::error::Error for GzipError {}
+
+impl From<InflateError> for GzipError {
+    fn from(err: InflateError) -> GzipError {
+        GzipError::Inflate(err)
+    }
+}
+
+/// The optional fields of a member header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GzipHeader {
+    /// MTIME: modification time in seconds since the unix epoch, 0 when there isn't one
+    pub modified: u32,
+    /// FNAME: the original file name, without directories, ISO 8859-1 without the zero terminator
+    pub name: Option<Vec<u8>>,
+    /// FCOMMENT, in the same encoding as the name
+    pub comment: Option<Vec<u8>>,
+    /// FEXTRA: the raw extra field, subfields and all
+    pub extra: Option<Vec<u8>>,
+    /// FTEXT: the data is probably text
+    pub text: bool,
+    /// FHCRC: the header is followed by the low 16 bits of its CRC-32
+    pub header_crc: bool,
+    /// XFL, which only hints at the compression level used
+    pub extra_flags: u8,
+    pub os: u8
+}
+
+impl Default for GzipHeader {
+    fn default() -> GzipHeader {
+        GzipHeader {
+            modified: 0,
+            name: None,
+            comment: None,
+            extra: None,
+            text: false,
+            header_crc: false,
+            extra_flags: 0,
+            os: OS_UNKNOWN
+        }
+    }
+}
+
+/// One member of a gzip file, already checked against its trailer
+#[derive(Debug, Clone)]
+pub struct GzipMember {
+    pub header: GzipHeader,
+    pub data: Vec<u8>
+}
+
+/// Compresses `data` into a single member gzip file with `header`, deflating at `level`.
+/// XFL is filled in from the level.
+pub fn compress(data: &[u8], header: &GzipHeader, level: u8) -> Vec<u8> {
+    let mut output = write_header(header, level);
+    output.extend_from_slice(&deflate::deflate(data, level));
+    output.extend_from_slice(&crc32::checksum(data).to_le_bytes());
+    output.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    output
+}
+
+fn write_header(header: &GzipHeader, level: u8) -> Vec<u8> {
+    let mut flags = 0;
+    if header.text { flags |= FTEXT; }
+    if header.header_crc { flags |= FHCRC; }
+    if header.extra.is_some() { flags |= FEXTRA; }
+    if header.name.is_some() { flags |= FNAME; }
+    if header.comment.is_some() { flags |= FCOMMENT; }
+    let extra_flags = match level {
+        1 => XFL_FASTEST,
+        level if level >= deflate::MAX_LEVEL => XFL_SLOWEST,
+        _ => 0
+    };
+
+    let mut output = Vec::new();
+    output.extend_from_slice(&MAGIC);
+    output.push(METHOD_DEFLATE);
+    output.push(flags);
+    output.extend_from_slice(&header.modified.to_le_bytes());
+    output.push(extra_flags);
+    output.push(header.os);
+    if let Some(extra) = &header.extra {
+        let len = extra.len().min(u16::MAX as usize);
+        output.extend_from_slice(&(len as u16).to_le_bytes());
+        output.extend_from_slice(&extra[..len]);
+    }
+    // Both strings are zero terminated, so they can't hold a zero themselves
+    for text in [&header.name, &header.comment].iter().copied().flatten() {
+        output.extend(text.iter().copied().filter(|&byte| byte != 0));
+        output.push(0);
+    }
+    if header.header_crc {
+        let crc = crc32::checksum(&output) as u16;
+        output.extend_from_slice(&crc.to_le_bytes());
+    }
+    output
+}
+
+/// Decompresses every member of a gzip file and joins their data, refusing to produce more than
+/// `max_output` bytes in all
+pub fn decompress(data: &[u8], max_output: u64) -> Result<Vec<u8>, GzipError> {
+    let members = read_members(data, max_output)?;
+    Ok(members.into_iter().flat_map(|member| member.data).collect())
+}
+
+/// Reads each member of a gzip file in turn, checking its CRC-32 and size
+pub fn read_members(data: &[u8], max_output: u64) -> Result<Vec<GzipMember>, GzipError> {
+    let mut members = Vec::new();
+    let mut rest = data;
+    let mut budget = max_output;
+
+    loop {
+        if !members.is_empty() && rest.is_empty() {
+            return Ok(members);
+        }
+        let (header, header_len) = match read_header(rest) {
+            Err(GzipError::InvalidMagic) if !members.is_empty() => return Err(GzipError::TrailingData),
+            result => result?
+        };
+        let (member_data, used) = inflate::inflate_prefix(&rest[header_len..], budget)?;
+        rest = &rest[header_len + used..];
+
+        let trailer = rest.get(..8).ok_or(GzipError::TruncatedTrailer)?;
+        let expected = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        let found = crc32::checksum(&member_data);
+        if expected != found {
+            return Err(GzipError::ChecksumMismatch { expected, found });
+        }
+        let expected = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+        let found = member_data.len() as u32;
+        if expected != found {
+            return Err(GzipError::SizeMismatch { expected, found });
+        }
+        rest = &rest[8..];
+
+        budget -= member_data.len() as u64;
+        members.push(GzipMember { header, data: member_data });
+    }
+}
+
+/// Parses a member header, returning it along with its length in bytes
+pub fn read_header(data: &[u8]) -> Result<(GzipHeader, usize), GzipError> {
+    if data.len() < 2 || data[..2] != MAGIC {
+        return Err(GzipError::InvalidMagic);
+    }
+    let fixed = data.get(..10).ok_or(GzipError::TruncatedHeader)?;
+    if fixed[2] != METHOD_DEFLATE {
+        return Err(GzipError::UnsupportedMethod(fixed[2]));
+    }
+    let flags = fixed[3];
+    if flags & RESERVED_FLAGS != 0 {
+        return Err(GzipError::ReservedFlags(flags & RESERVED_FLAGS));
+    }
+
+    let mut header = GzipHeader {
+        modified: u32::from_le_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]),
+        text: flags & FTEXT != 0,
+        header_crc: flags & FHCRC != 0,
+        extra_flags: fixed[8],
+        os: fixed[9],
+        ..GzipHeader::default()
+    };
+    let mut pos = 10;
+
+    if flags & FEXTRA != 0 {
+        let len = data.get(pos..pos + 2).ok_or(GzipError::TruncatedHeader)?;
+        let len = u16::from_le_bytes([len[0], len[1]]) as usize;
+        header.extra = Some(data.get(pos + 2..pos + 2 + len).ok_or(GzipError::TruncatedHeader)?.to_vec());
+        pos += 2 + len;
+    }
+    if flags & FNAME != 0 {
+        let (name, len) = zero_terminated(&data[pos..])?;
+        header.name = Some(name);
+        pos += len;
+    }
+    if flags & FCOMMENT != 0 {
+        let (comment, len) = zero_terminated(&data[pos..])?;
+        header.comment = Some(comment);
+        pos += len;
+    }
+    if header.header_crc {
+        let stored = data.get(pos..pos + 2).ok_or(GzipError::TruncatedHeader)?;
+        let expected = u16::from_le_bytes([stored[0], stored[1]]);
+        let found = crc32::checksum(&data[..pos]) as u16;
+        if expected != found {
+            return Err(GzipError::HeaderChecksumMismatch { expected, found });
+        }
+        pos += 2;
+    }
+
+    Ok((header, pos))
+}
+
+/// A zero terminated string and the bytes it took up, terminator included
+fn zero_terminated(data: &[u8]) -> Result<(Vec<u8>, usize), GzipError> {
+    let end = data.iter().position(|&byte| byte == 0).ok_or(GzipError::TruncatedHeader)?;
+    Ok((data[..end].to_vec(), end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A member with every optional header field, the header CRC included, built with Python's zlib and
+    // accepted by gzip 1.12
+    const EVERY_FIELD: [u8; 67] = [
+        0x1F, 0x8B, 0x08, 0x1F, 0x00, 0xF1, 0x53, 0x65, 0x00, 0x03, 0x08, 0x00, 0x41, 0x50, 0x04, 0x00,
+        0x72, 0x69, 0x70, 0x21, 0x68, 0x65, 0x6C, 0x6C, 0x6F, 0x2E, 0x74, 0x78, 0x74, 0x00, 0x61, 0x20,
+        0x63, 0x6F, 0x6D, 0x6D, 0x65, 0x6E, 0x74, 0x00, 0x51, 0x20, 0xCB, 0x48, 0xCD, 0xC9, 0xC9, 0xD7,
+        0x51, 0xC8, 0x40, 0xA2, 0x14, 0xD2, 0xAB, 0x32, 0x0B, 0xB8, 0x00, 0x2A, 0x08, 0x44, 0xA6, 0x19,
+        0x00, 0x00, 0x00
+    ];
+    const EVERY_FIELD_HEADER_LEN: usize = 42;
+
+    fn every_field() -> GzipHeader {
+        GzipHeader {
+            modified: 1_700_000_000,
+            name: Some(b"hello.txt".to_vec()),
+            comment: Some(b"a comment".to_vec()),
+            extra: Some(b"AP\x04\x00rip!".to_vec()),
+            text: true,
+            header_crc: true,
+            extra_flags: 0,
+            os: 3
+        }
+    }
+
+    #[test]
+    fn reads_every_header_field() {
+        assert_eq!(read_header(&EVERY_FIELD), Ok((every_field(), EVERY_FIELD_HEADER_LEN)));
+        let members = read_members(&EVERY_FIELD, 100).unwrap();
+        assert_eq!(members.len(), 1);
+        assert_eq!(members[0].header, every_field());
+        assert_eq!(members[0].data, b"hello, hello, hello gzip\n");
+    }
+
+    #[test]
+    fn writes_every_header_field() {
+        let compressed = compress(b"hello, hello, hello gzip\n", &every_field(), 6);
+        assert_eq!(compressed[..EVERY_FIELD_HEADER_LEN], EVERY_FIELD[..EVERY_FIELD_HEADER_LEN]);
+        assert_eq!(decompress(&compressed, 100).unwrap(), b"hello, hello, hello gzip\n");
+    }
+
+    #[test]
+    fn rejects_a_bad_header_crc() {
+        let mut data = EVERY_FIELD;
+        // The name's first letter
+        data[20] = b'j';
+        assert!(matches!(read_header(&data), Err(GzipError::HeaderChecksumMismatch { expected: 0x2051, .. })));
+
+        let mut data = EVERY_FIELD;
+        data[EVERY_FIELD_HEADER_LEN - 1] ^= 0xFF;
+        assert!(matches!(read_header(&data), Err(GzipError::HeaderChecksumMismatch { found: 0x2051, .. })));
+    }
+
+    #[test]
+    fn rejects_truncated_headers() {
+        for len in 0..EVERY_FIELD_HEADER_LEN {
+            let expected = if len < 2 { GzipError::InvalidMagic } else { GzipError::TruncatedHeader };
+            assert_eq!(read_header(&EVERY_FIELD[..len]), Err(expected), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_methods_and_flags() {
+        let mut data = EVERY_FIELD;
+        data[2] = 7;
+        assert_eq!(read_header(&data), Err(GzipError::UnsupportedMethod(7)));
+        let mut data = EVERY_FIELD;
+        data[3] |= 0x40;
+        assert_eq!(read_header(&data), Err(GzipError::ReservedFlags(0x40)));
+    }
+
+    #[test]
+    fn reads_concatenated_members() {
+        let first = GzipHeader { name: Some(b"one".to_vec()), ..GzipHeader::default() };
+        let mut data = compress(b"first member, ", &first, 6);
+        data.extend_from_slice(&compress(b"", &GzipHeader::default(), 1));
+        data.extend_from_slice(&EVERY_FIELD);
+
+        let members = read_members(&data, 100).unwrap();
+        let headers: Vec<_> = members.iter().map(|member| member.header.name.clone()).collect();
+        assert_eq!(headers, [Some(b"one".to_vec()), None, Some(b"hello.txt".to_vec())]);
+        assert_eq!(decompress(&data, 100).unwrap(), b"first member, hello, hello, hello gzip\n");
+    }
+
+    #[test]
+    fn rejects_trailing_data() {
+        let mut data = EVERY_FIELD.to_vec();
+        data.extend_from_slice(b"\0\0\0\0");
+        assert_eq!(decompress(&data, 100), Err(GzipError::TrailingData));
+
+        // Something that starts like another member but stops short is truncated, not trailing
+        let mut data = EVERY_FIELD.to_vec();
+        data.extend_from_slice(&MAGIC);
+        assert_eq!(decompress(&data, 100), Err(GzipError::TruncatedHeader));
+    }
+
+    #[test]
+    fn checks_the_trailer() {
+        let mut data = EVERY_FIELD;
+        data[59] ^= 1;
+        assert!(matches!(decompress(&data, 100), Err(GzipError::ChecksumMismatch { .. })));
+        let mut data = EVERY_FIELD;
+        data[63] = 26;
+        assert_eq!(decompress(&data, 100), Err(GzipError::SizeMismatch { expected: 26, found: 25 }));
+        assert_eq!(decompress(&EVERY_FIELD[..66], 100), Err(GzipError::TruncatedTrailer));
+    }
+
+    #[test]
+    fn one_output_budget_across_members() {
+        let member = compress(&[b'a'; 600], &GzipHeader::default(), 6);
+        let data = [member.clone(), member].concat();
+        assert_eq!(decompress(&data, 1200).unwrap().len(), 1200);
+        assert_eq!(decompress(&data, 1199), Err(GzipError::Inflate(InflateError::OutputLimit)));
+        assert_eq!(decompress(&data, 600), Err(GzipError::Inflate(InflateError::OutputLimit)));
+    }
+}
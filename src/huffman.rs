@@ -2,123 +2,521 @@
 // https://www.techiedelight.com/huffman-coding/
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
-use std::collections::HashMap;
 use std::fmt;
 use std::cmp::Reverse; // Used for min heap, this fixed all my problems with all nodes on the left lol
+use crate::bits::{BitOrder, BitReader, BitWriter, EndOfInput};
+use crate::crc32;
 
+/// Number of symbols in an alphabet of raw bytes
+pub const BYTE_ALPHABET: usize = 256;
+
+/// Symbols are indexes into a frequency table: 0-255 for bytes, up to 285 for deflate literal/lengths, 0-29 for distances
 #[derive(Debug, Clone)]
 pub struct HuffCode {
-    pub val: char,
-    pub bitlength: u8, // number of bits used
+    pub val: u16,
+    pub bitlength: u8, // number of bits used, 0 if the symbol has no code
     pub code: u64,
     pub code_str: String
 }
 
 impl fmt::Display for HuffCode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let d = self.val.to_string(); 
-        let o = if self.val == '\n' {"\\n"} else {&d[..]};
+        // Printable bytes show as themselves, anything else as its symbol number
+        let o = match self.val {
+            10 => "\\n".to_string(),
+            32..=126 => (self.val as u8 as char).to_string(),
+            _ => format!("#{}", self.val)
+        };
         write!(f, "{:5} {:0width$b}:{}", o, self.code, self.bitlength, width=self.bitlength as usize)
     }
 }
 
 #[derive(Debug, Eq, Clone)]
 pub struct HuffmanNode {
-    pub freq_value: i32,
+    pub freq_value: u64,
     pub left: Option<Box<HuffmanNode>>,
     pub right: Option<Box<HuffmanNode>>,
-    pub value: Option<char> // only populated if it is a leaf
+    pub value: Option<u16>, // only populated if it is a leaf
+    /// Breaks ties between equal frequencies: leaves are numbered by symbol and merged nodes after them in the
+    /// order they're made, so the same frequencies always build the same tree
+    pub order: usize
 }
 
 impl Ord for HuffmanNode {
     fn cmp(&self, other: &Self) -> Ordering {
-        return self.freq_value.cmp(&other.freq_value);
+        self.freq_value.cmp(&other.freq_value).then(self.order.cmp(&other.order))
     }
 }
 
 impl PartialOrd for HuffmanNode {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        return Some(self.cmp(other));
+        Some(self.cmp(other))
     }
 }
 
 impl PartialEq for HuffmanNode {
     fn eq (&self, other: &Self) -> bool {
-        return self.freq_value == other.freq_value;
+        self.cmp(other) == Ordering::Equal
     }
 }
 
+/// Counts how often each byte value occurs, giving a frequency table for the byte alphabet
+pub fn byte_frequencies(data: &[u8]) -> Vec<u64> {
+    let mut frequencies = vec![0u64; BYTE_ALPHABET];
+    for &byte in data {
+        frequencies[byte as usize] += 1;
+    }
+    frequencies
+}
+
 impl HuffmanNode {
-    pub fn new(data: &str) -> HuffmanNode {
+    /// Builds the tree for an alphabet of `frequencies.len()` symbols, where `frequencies[symbol]` is how often
+    /// the symbol occurs. Symbols that never occur don't get a leaf.
+    /// With nothing to code there's no tree, and `NoSymbols` comes back; callers with an empty stream have
+    /// to say so some other way (the .huf header's length, for one).
+    pub fn new(frequencies: &[u64]) -> Result<HuffmanNode, HuffmanError> {
 
-        let mut freq_map = HashMap::new();
         let mut min_heap:BinaryHeap<Reverse<HuffmanNode>> = BinaryHeap::new();
 
-        // create a frequency map, and build each huffman node
-        for c in data.chars() {
-            if !freq_map.contains_key(&c){
-                freq_map.insert(c, HuffmanNode {freq_value: 1, value: Some(c), left: None, right: None});
+        // build a leaf for each symbol that's used
+        for (symbol, &freq) in frequencies.iter().enumerate() {
+            if freq > 0 {
+                min_heap.push(Reverse(HuffmanNode {freq_value: freq, value: Some(symbol as u16), left: None, right: None, order: symbol}));
             }
-            else{
-                let mut item = freq_map.get_mut(&c).unwrap();
-
-                item.freq_value = item.freq_value + 1;
-            }
-        }
-
-        for (_, v) in freq_map.into_iter(){
-            min_heap.push(Reverse(v));
         }
 
+        let mut next_order = frequencies.len();
         while let Some(node1) = min_heap.pop() {
-            let tmp_node2 = min_heap.pop();
+            let node2 = min_heap.pop();
 
-            if !tmp_node2.is_some(){
-                return node1.0;
+            // A lone symbol still hangs off a root of its own, so its code is one bit rather than none
+            if node2.is_none() && node1.0.value.is_none() {
+                return Ok(node1.0);
             }
 
-            let node2 = tmp_node2.unwrap();
-
             let merged_node = HuffmanNode {
-                freq_value: node1.0.freq_value + node2.0.freq_value,
+                freq_value: node1.0.freq_value + node2.as_ref().map_or(0, |node| node.0.freq_value),
                 value: None,
                 left: Some(Box::new(node1.0)),
-                right: Some(Box::new(node2.0))
+                right: node2.map(|node| Box::new(node.0)),
+                order: next_order
             };
+            next_order += 1;
 
             min_heap.push(Reverse(merged_node));
         }
 
-        // should never get down here.
-        return HuffmanNode {freq_value: 1, value: Some('d'), left: None, right: None}
+        Err(HuffmanError::NoSymbols)
+    }
+
+    /// The depth of every symbol's leaf, which is all a canonical code needs.
+    /// Indexed by symbol for an alphabet of `alphabet_size` symbols; symbols without a leaf get 0.
+    pub fn code_lengths(&self, alphabet_size: usize) -> Vec<u8> {
+        let mut lengths = vec![0u8; alphabet_size];
+        for code in gen_codes(self) {
+            lengths[code.val as usize] = code.bitlength;
+        }
+        lengths
+    }
+}
+
+/// Longest code a `HuffCode` can hold
+pub const MAX_CODE_BITS: u8 = 64;
+
+/// Optimal code lengths for `frequencies` where no code is longer than `max_bits`, using package-merge
+/// (Larmore & Hirschberg, "A fast algorithm for optimal length-limited Huffman codes").
+/// A plain Huffman tree can go as deep as the alphabet is large on skewed input, past DEFLATE's 15 bit
+/// literal limit, its 7 bit code length limit, or even what fits in a u64.
+/// `max_bits` is raised if it's too small to give every used symbol a code, and capped at `MAX_CODE_BITS`.
+/// Indexed by symbol; unused symbols get 0.
+pub fn limited_code_lengths(frequencies: &[u64], max_bits: u8) -> Vec<u8> {
+    let mut lengths = vec![0u8; frequencies.len()];
+
+    // Leaves sorted by weight, ties broken by symbol so the result doesn't depend on anything else
+    let mut leaves: Vec<(u64, u16)> = frequencies.iter().enumerate()
+        .filter(|(_, &freq)| freq > 0)
+        .map(|(symbol, &freq)| (freq, symbol as u16))
+        .collect();
+    leaves.sort();
+
+    if leaves.len() <= 1 {
+        // A lone symbol still needs a bit to be written at all
+        for &(_, symbol) in &leaves {
+            lengths[symbol as usize] = 1;
+        }
+        return lengths;
+    }
+
+    let needed_bits = (usize::BITS - (leaves.len() - 1).leading_zeros()) as u8;
+    let max_bits = max_bits.max(needed_bits).min(MAX_CODE_BITS);
+
+    // Each item is a weight and the leaves it's made of. Every time a leaf shows up in one of the
+    // 2n - 2 cheapest items of the final list, its code gets one bit longer.
+    let leaf_items: Vec<(u64, Vec<u16>)> = leaves.iter().map(|&(freq, symbol)| (freq, vec![symbol])).collect();
+    let mut list = leaf_items.clone();
+
+    for _ in 1..max_bits {
+        // Package: pair off the list, cheapest first, dropping any odd one out
+        let packages: Vec<(u64, Vec<u16>)> = list.chunks_exact(2).map(|pair| {
+            let mut symbols = pair[0].1.clone();
+            symbols.extend_from_slice(&pair[1].1);
+            (pair[0].0 + pair[1].0, symbols)
+        }).collect();
+
+        // Merge: the packages back in with a fresh copy of the leaves, leaves first on equal weight
+        let mut merged = Vec::with_capacity(leaf_items.len() + packages.len());
+        let mut leaf_iter = leaf_items.iter().peekable();
+        let mut package_iter = packages.into_iter().peekable();
+        loop {
+            let take_leaf = match (leaf_iter.peek(), package_iter.peek()) {
+                (Some(leaf), Some(package)) => leaf.0 <= package.0,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break
+            };
+            if take_leaf {
+                merged.push(leaf_iter.next().unwrap().clone());
+            } else {
+                merged.push(package_iter.next().unwrap());
+            }
+        }
+        list = merged;
+    }
+
+    for (_, symbols) in list.iter().take(2 * leaves.len() - 2) {
+        for &symbol in symbols {
+            lengths[symbol as usize] += 1;
+        }
+    }
+
+    lengths
+}
+
+/// Reverses the low `length` bits of `code`
+pub fn reverse_bits(code: u64, length: u8) -> u64 {
+    if length == 0 {
+        return 0;
+    }
+    code.reverse_bits() >> (64 - length as u32)
+}
+
+/// Builds canonical codes from code lengths alone (RFC 1951 section 3.2.2): shorter codes sort before longer
+/// ones, and codes of the same length are assigned consecutively in symbol order. That way a decoder only
+/// needs the lengths to rebuild the table, never the tree.
+/// Indexed by symbol; `code` comes back bit-reversed, ready to be written LSB-first the way DEFLATE packs
+/// codes, while `code_str` holds the canonical code MSB-first for reading.
+/// `lengths` has to describe a code that fits (like one from `HuffmanNode::code_lengths`).
+pub fn canonical_codes(lengths: &[u8]) -> Vec<HuffCode> {
+    let max_length = lengths.iter().copied().max().unwrap_or(0) as usize;
+
+    // Step 1: count the number of codes for each code length
+    let mut bl_count = vec![0u64; max_length + 1];
+    for &len in lengths {
+        bl_count[len as usize] += 1;
+    }
+    bl_count[0] = 0;
+
+    // Step 2: find the numerical value of the smallest code for each code length
+    let mut next_code = vec![0u64; max_length + 1];
+    let mut code: u64 = 0;
+    for bits in 1..=max_length {
+        code = (code + bl_count[bits - 1]) << 1;
+        next_code[bits] = code;
+    }
+
+    // Step 3: assign consecutive values to all codes of the same length
+    lengths.iter().enumerate().map(|(symbol, &len)| {
+        if len == 0 {
+            return HuffCode {val: symbol as u16, bitlength: 0, code: 0, code_str: String::new()};
+        }
+        let canonical = next_code[len as usize];
+        next_code[len as usize] += 1;
+        HuffCode {
+            val: symbol as u16,
+            bitlength: len,
+            code: reverse_bits(canonical, len),
+            code_str: format!("{:0width$b}", canonical, width = len as usize)
+        }
+    }).collect()
+}
+
+/// Longest code `Decoder` handles, DEFLATE's limit
+pub const MAX_DECODE_BITS: u8 = 15;
+
+/// Bits resolved by the first table lookup; longer codes take a second lookup in a table for their prefix
+const PRIMARY_BITS: u8 = 9;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HuffmanError {
+    /// The lengths describe an oversubscribed code, or one longer than `MAX_DECODE_BITS`
+    InvalidCodeLengths,
+    /// A bit pattern that isn't any symbol's code
+    InvalidCode,
+    /// The input ended partway through a code
+    UnexpectedEof,
+    /// A frequency table where nothing occurs, which has no tree
+    NoSymbols,
+    /// Not a .huf file
+    InvalidHeader,
+    /// A .huf file from a newer version of the format
+    UnsupportedVersion(u8),
+    /// The decoded data doesn't match the checksum stored with it
+    ChecksumMismatch { expected: u32, found: u32 }
+}
+
+impl fmt::Display for HuffmanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HuffmanError::InvalidCodeLengths => write!(f, "invalid huffman code lengths"),
+            HuffmanError::InvalidCode => write!(f, "invalid huffman code"),
+            HuffmanError::UnexpectedEof => write!(f, "unexpected end of huffman coded data"),
+            HuffmanError::NoSymbols => write!(f, "no symbols to build a huffman code for"),
+            HuffmanError::InvalidHeader => write!(f, "not a .huf file"),
+            HuffmanError::UnsupportedVersion(version) => write!(f, "unsupported .huf version {}", version),
+            HuffmanError::ChecksumMismatch { expected, found } =>
+                write!(f, "checksum mismatch: expected {:#010X}, found {:#010X}", expected, found),
+        }
+    }
+}
+
+impl std::error::Error for HuffmanError {}
+
+impl From<EndOfInput> for HuffmanError {
+    fn from(_: EndOfInput) -> HuffmanError {
+        HuffmanError::UnexpectedEof
     }
 }
 
+/// One slot of the decoding table. `length` 0 means no code maps here; a nonzero `subtable_bits` makes
+/// the slot a link, with `symbol` holding where that prefix's subtable starts.
+#[derive(Debug, Copy, Clone, Default)]
+struct TableEntry {
+    symbol: u16,
+    length: u8,
+    subtable_bits: u8
+}
+
+/// Table driven decoder for canonical codes, read LSB-first the way DEFLATE packs them.
+/// The primary table is indexed by the next `PRIMARY_BITS` of input, so most symbols take a single lookup;
+/// codes longer than that share their first bits with others and get a subtable per prefix, sized for the
+/// longest code under it, instead of blowing the primary table up to 2^15 slots.
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    table: Vec<TableEntry>,
+    primary_bits: u8,
+    max_length: u8,
+    used: usize,
+    complete: bool
+}
+
+impl Decoder {
+    /// Builds the tables from code lengths indexed by symbol, 0 meaning the symbol is unused.
+    /// Incomplete codes are accepted, their missing codes decode as `InvalidCode`; see `is_complete`.
+    pub fn new(lengths: &[u8]) -> Result<Decoder, HuffmanError> {
+        let mut counts = [0u32; MAX_DECODE_BITS as usize + 1];
+        for &len in lengths {
+            if len > MAX_DECODE_BITS {
+                return Err(HuffmanError::InvalidCodeLengths);
+            }
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut left: i64 = 1;
+        for &count in counts.iter().skip(1) {
+            left <<= 1;
+            left -= count as i64;
+            if left < 0 {
+                return Err(HuffmanError::InvalidCodeLengths);
+            }
+        }
+
+        let max_length = lengths.iter().copied().max().unwrap_or(0);
+        let primary_bits = PRIMARY_BITS.min(max_length);
+        let primary_mask = (1u32 << primary_bits) - 1;
+
+        // Canonical codes, as in canonical_codes but without building strings for every table rebuild
+        let mut next_code = [0u32; MAX_DECODE_BITS as usize + 1];
+        let mut code = 0;
+        for bits in 1..=MAX_DECODE_BITS as usize {
+            code = (code + counts[bits - 1]) << 1;
+            next_code[bits] = code;
+        }
+        let codes: Vec<(u16, u8, u32)> = lengths.iter().enumerate()
+            .filter(|(_, &len)| len > 0)
+            .map(|(symbol, &len)| {
+                let canonical = next_code[len as usize];
+                next_code[len as usize] += 1;
+                (symbol as u16, len, reverse_bits(canonical as u64, len) as u32)
+            })
+            .collect();
+
+        // Every prefix with codes longer than the primary table gets a subtable wide enough for the longest
+        let mut subtable_bits = vec![0u8; 1 << primary_bits];
+        for &(_, len, reversed) in &codes {
+            if len > primary_bits {
+                let prefix = (reversed & primary_mask) as usize;
+                subtable_bits[prefix] = subtable_bits[prefix].max(len - primary_bits);
+            }
+        }
+
+        let mut table = vec![TableEntry::default(); 1 << primary_bits];
+        for (prefix, &bits) in subtable_bits.iter().enumerate() {
+            if bits > 0 {
+                table[prefix] = TableEntry { symbol: table.len() as u16, length: 0, subtable_bits: bits };
+                table.resize(table.len() + (1 << bits), TableEntry::default());
+            }
+        }
+
+        // A code fills every slot whose index starts with it, whatever the bits after it happen to be
+        for &(symbol, len, reversed) in &codes {
+            let entry = TableEntry { symbol, length: len, subtable_bits: 0 };
+            if len <= primary_bits {
+                for rest in 0..1u32 << (primary_bits - len) {
+                    table[(reversed | rest << len) as usize] = entry;
+                }
+            } else {
+                let link = table[(reversed & primary_mask) as usize];
+                let sub_len = len - primary_bits;
+                for rest in 0..1u32 << (link.subtable_bits - sub_len) {
+                    table[link.symbol as usize + ((reversed >> primary_bits) | rest << sub_len) as usize] = entry;
+                }
+            }
+        }
+
+        Ok(Decoder { table, primary_bits, max_length, used: codes.len(), complete: left == 0 })
+    }
+
+    /// Whether every bit pattern decodes to something, as DEFLATE requires of all but single-code alphabets
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Number of symbols with a code
+    pub fn used_symbols(&self) -> usize {
+        self.used
+    }
+
+    /// Reads one symbol
+    pub fn decode(&self, input: &mut BitReader) -> Result<u16, HuffmanError> {
+        let bits = input.peek(self.max_length as u32);
+        let mut entry = self.table[(bits & ((1 << self.primary_bits) - 1)) as usize];
+        if entry.subtable_bits > 0 {
+            let index = (bits >> self.primary_bits) & ((1 << entry.subtable_bits) - 1);
+            entry = self.table[entry.symbol as usize + index as usize];
+        }
+        if entry.length == 0 {
+            // Zero padding past the end of the input can look like a bad code when it's really a short one
+            if !input.has_bits(self.max_length as u32) {
+                return Err(HuffmanError::UnexpectedEof);
+            }
+            return Err(HuffmanError::InvalidCode);
+        }
+        input.consume(entry.length as u32)?;
+        Ok(entry.symbol)
+    }
+}
+
+// .huf files: a standalone byte stream coded with a single canonical huffman code.
+//   magic "RHUF", version (1 byte), original length (u64 LE),
+//   256 code lengths (1 byte each, 0 for bytes that never occur),
+//   payload: the codes LSB-first as DEFLATE packs them, zero padded to a byte,
+//   CRC-32 of the original data (u32 LE).
+// The decoder stops after `original length` symbols, so the padding bits are never mistaken for data.
+// Empty input is a length of 0 with every code length 0 and no payload; a single distinct byte gets a 1 bit code.
+
+pub const HUF_MAGIC: [u8; 4] = *b"RHUF";
+pub const HUF_VERSION: u8 = 1;
+const HUF_HEADER_LEN: usize = 4 + 1 + 8 + BYTE_ALPHABET;
+const HUF_TRAILER_LEN: usize = 4;
+
+/// Codes `data` into a .huf file
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let lengths = limited_code_lengths(&byte_frequencies(data), MAX_DECODE_BITS);
+    let codes = canonical_codes(&lengths);
+
+    let mut payload = BitWriter::new();
+    for &byte in data {
+        let code = &codes[byte as usize];
+        payload.write_bits(code.code, code.bitlength as u32);
+    }
+
+    let mut output = Vec::with_capacity(HUF_HEADER_LEN + data.len() + HUF_TRAILER_LEN);
+    output.extend_from_slice(&HUF_MAGIC);
+    output.push(HUF_VERSION);
+    output.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    output.extend_from_slice(&lengths);
+    output.extend_from_slice(&payload.finish());
+    output.extend_from_slice(&crc32::checksum(data).to_le_bytes());
+    output
+}
+
+/// Decodes a .huf file back into the original bytes
+pub fn decompress(file: &[u8]) -> Result<Vec<u8>, HuffmanError> {
+    if file.len() < HUF_HEADER_LEN + HUF_TRAILER_LEN {
+        return Err(if file.starts_with(&HUF_MAGIC) { HuffmanError::UnexpectedEof } else { HuffmanError::InvalidHeader });
+    }
+    if file[..4] != HUF_MAGIC {
+        return Err(HuffmanError::InvalidHeader);
+    }
+    if file[4] != HUF_VERSION {
+        return Err(HuffmanError::UnsupportedVersion(file[4]));
+    }
+
+    let mut length_bytes = [0u8; 8];
+    length_bytes.copy_from_slice(&file[5..13]);
+    let original_length = u64::from_le_bytes(length_bytes);
+    let lengths = &file[13..HUF_HEADER_LEN];
+    let payload = &file[HUF_HEADER_LEN..file.len() - HUF_TRAILER_LEN];
+    let trailer = &file[file.len() - HUF_TRAILER_LEN..];
+    let expected = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+
+    // Every symbol takes at least a bit, which bounds the length before anything is allocated for it
+    if original_length > payload.len() as u64 * 8 {
+        return Err(HuffmanError::UnexpectedEof);
+    }
+
+    let decoder = Decoder::new(lengths)?;
+    let mut input = BitReader::new(payload);
+    let mut output = Vec::with_capacity(original_length as usize);
+    for _ in 0..original_length {
+        output.push(decoder.decode(&mut input)? as u8);
+    }
+
+    let found = crc32::checksum(&output);
+    if found != expected {
+        return Err(HuffmanError::ChecksumMismatch { expected, found });
+    }
+    Ok(output)
+}
+
 pub fn gen_codes(root_node: &HuffmanNode) -> Vec<HuffCode>{
     let mut out_codes: Vec<HuffCode> = Vec::new();
 
     recurse_codes(root_node, &mut out_codes, "".to_string(), 0, 0);
-    return out_codes;
+    out_codes
 }
 
-pub fn gen_code_map(root_node: &HuffmanNode) -> HashMap<char, HuffCode> {
-    let codes = gen_codes(root_node);
-    let mut out_map = HashMap::new();
+/// Codes indexed by symbol, for an alphabet of `alphabet_size` symbols.
+/// Symbols without a leaf in the tree get a zero bitlength.
+pub fn gen_code_table(root_node: &HuffmanNode, alphabet_size: usize) -> Vec<HuffCode> {
+    let mut table: Vec<HuffCode> = (0..alphabet_size)
+        .map(|symbol| HuffCode {val: symbol as u16, bitlength: 0, code: 0, code_str: String::new()})
+        .collect();
 
-    for code in codes {
-        out_map.insert(code.val, code);
+    for code in gen_codes(root_node) {
+        let symbol = code.val as usize;
+        table[symbol] = code;
     }
 
-    return out_map;
-
+    table
 }
 
 fn recurse_codes(node: &HuffmanNode, codes: &mut Vec<HuffCode>, location_str: String, location: u64, depth: u8){
 
     let loc_clone = location_str.to_owned();
-    if node.value.is_some() {
-        let char_val = node.value.unwrap();
-        codes.push(HuffCode {val: char_val, bitlength: depth, code: location, code_str: loc_clone.clone()})
+    if let Some(symbol) = node.value {
+        codes.push(HuffCode {val: symbol, bitlength: depth, code: location, code_str: loc_clone.clone()})
     }
 
     let left_code_str = format!("{}0", location_str).to_owned();
@@ -127,79 +525,275 @@ fn recurse_codes(node: &HuffmanNode, codes: &mut Vec<HuffCode>, location_str: St
     let left_code = location << 1;
     let right_code = (location << 1) | 1;
 
-    if node.left.is_some() {
-        recurse_codes(&node.left.as_ref().unwrap(), codes, left_code_str, left_code, depth + 1)
+    if let Some(left) = &node.left {
+        recurse_codes(left, codes, left_code_str, left_code, depth + 1)
     }
 
-    if node.right.is_some() {
-        recurse_codes(&node.right.as_ref().unwrap(), codes, right_code_str, right_code, depth + 1);
+    if let Some(right) = &node.right {
+        recurse_codes(right, codes, right_code_str, right_code, depth + 1);
     }
 
 }
 
-// We have a collection of HuffCodes.
-// Each HuffCode has a u64 code (which stores the actual binary data)
-// and u8 bitlength, which determines the length of the u64 code we are taking.
-// We are trying to concatenate all of these into a single vector of u8s.
-pub fn codes_to_bin(codes: &mut Vec<HuffCode>) -> Vec<u8> {
-    let mut output_tmp:Vec<u8> = Vec::new();
-    let most_significant = 0x8000000000000000 as u64;
+/// Concatenates `codes` MSB-first, each taking the low `bitlength` bits of its `code`, zero padding the
+/// last byte. Suits the tree codes from `gen_code_table`; the bit-reversed codes from `canonical_codes`
+/// go LSB-first instead, as `compress` writes them.
+pub fn codes_to_bin(codes: &[HuffCode]) -> Vec<u8> {
+    let mut output = BitWriter::with_order(BitOrder::MsbFirst);
+    for huff_code in codes {
+        output.write_bits(huff_code.code, huff_code.bitlength as u32);
+    }
+    output.finish()
+}
 
-    for huffCode in codes.into_iter() {
-        let mut code = huffCode.code;
-        let mut index = 0;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        code = code << (64 - huffCode.bitlength);
+    /// xorshift64, so the random tables are the same on every run
+    struct Rng(u64);
 
-        while index < huffCode.bitlength {
-            if code & most_significant == most_significant {
-                output_tmp.push(1);
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, bound: u64) -> u64 {
+            self.next() % bound
+        }
+    }
+
+    /// A frequency table of `size` symbols, some unused, with weights spread over several orders of magnitude
+    fn random_frequencies(rng: &mut Rng, size: usize) -> Vec<u64> {
+        (0..size).map(|_| match rng.below(4) {
+            0 => 0,
+            1 => 1 + rng.below(4),
+            2 => 1 + rng.below(100),
+            _ => 1 + rng.below(10_000)
+        }).collect()
+    }
+
+    /// Sum of 2^-length over the used symbols, scaled so a complete code comes to exactly 2^64
+    fn kraft_sum(lengths: &[u8]) -> u128 {
+        lengths.iter().filter(|&&len| len > 0).map(|&len| 1u128 << (64 - len)).sum()
+    }
+
+    fn cost(frequencies: &[u64], lengths: &[u8]) -> u64 {
+        frequencies.iter().zip(lengths).map(|(&freq, &len)| freq * len as u64).sum()
+    }
+
+    #[test]
+    fn empty_input() {
+        assert_eq!(HuffmanNode::new(&[0; BYTE_ALPHABET]).unwrap_err(), HuffmanError::NoSymbols);
+        assert!(limited_code_lengths(&[0; BYTE_ALPHABET], MAX_DECODE_BITS).iter().all(|&len| len == 0));
+
+        let file = compress(&[]);
+        assert_eq!(file.len(), HUF_HEADER_LEN + HUF_TRAILER_LEN);
+        assert_eq!(decompress(&file).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn one_distinct_byte() {
+        let data = vec![b'z'; 1000];
+        let frequencies = byte_frequencies(&data);
+
+        let tree = HuffmanNode::new(&frequencies).unwrap();
+        let lengths = tree.code_lengths(BYTE_ALPHABET);
+        assert_eq!(lengths[b'z' as usize], 1);
+        assert_eq!(lengths.iter().filter(|&&len| len > 0).count(), 1);
+        assert_eq!(limited_code_lengths(&frequencies, MAX_DECODE_BITS), lengths);
+
+        let file = compress(&data);
+        assert_eq!(file.len(), HUF_HEADER_LEN + 1000 / 8 + HUF_TRAILER_LEN);
+        assert_eq!(decompress(&file).unwrap(), data);
+    }
+
+    #[test]
+    fn limited_lengths_match_the_tree_when_unconstrained() {
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        for _ in 0..200 {
+            let size = 2 + rng.below(300) as usize;
+            let frequencies = random_frequencies(&mut rng, size);
+            let tree_lengths = match HuffmanNode::new(&frequencies) {
+                Ok(tree) => tree.code_lengths(size),
+                Err(HuffmanError::NoSymbols) => continue,
+                Err(err) => panic!("{}", err)
+            };
+            let limited = limited_code_lengths(&frequencies, MAX_CODE_BITS);
+
+            for (&freq, &len) in frequencies.iter().zip(&limited) {
+                assert_eq!(freq > 0, len > 0);
             }
-            else {
-                output_tmp.push(0);
+            // Huffman codes aren't unique, but their cost is
+            assert_eq!(cost(&frequencies, &limited), cost(&frequencies, &tree_lengths));
+            if tree_lengths.iter().filter(|&&len| len > 0).count() > 1 {
+                assert_eq!(kraft_sum(&tree_lengths), 1 << 64);
+                assert_eq!(kraft_sum(&limited), 1 << 64);
             }
-            index += 1;
-            code = code << 1;
         }
     }
-    
-    let mut output:Vec<u8> = Vec::new();
-    let mut index = 0;
-    let mut tmp_byte:u8 = 0;
 
-    while index < output_tmp.len() {
-        // println!("{}", output_tmp[index]);
-        if output_tmp[index] == 1 {
-            tmp_byte = tmp_byte | 1;
+    #[test]
+    fn limited_lengths_respect_the_limit() {
+        let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+        // Fibonacci weights make the deepest possible tree, one level per symbol
+        let mut fibonacci = vec![1u64, 1];
+        while fibonacci.len() < 40 {
+            fibonacci.push(fibonacci[fibonacci.len() - 1] + fibonacci[fibonacci.len() - 2]);
+        }
+        assert!(HuffmanNode::new(&fibonacci).unwrap().code_lengths(40).iter().any(|&len| len > MAX_DECODE_BITS));
+
+        let mut tables = vec![fibonacci];
+        for _ in 0..100 {
+            let size = 2 + rng.below(300) as usize;
+            tables.push(random_frequencies(&mut rng, size));
         }
-        else {
-            tmp_byte = tmp_byte & 0b11111110;
+        for frequencies in &tables {
+            for max_bits in [7, 9, MAX_DECODE_BITS] {
+                let lengths = limited_code_lengths(frequencies, max_bits);
+                let used = frequencies.iter().filter(|&&freq| freq > 0).count();
+                // Too small a limit to give every symbol a code gets raised to the smallest that does
+                let limit = max_bits.max((usize::BITS - used.saturating_sub(1).leading_zeros()) as u8);
+                assert!(lengths.iter().all(|&len| len <= limit));
+                if used > 1 {
+                    assert_eq!(kraft_sum(&lengths), 1 << 64);
+                }
+                // Never worse than the limit forces it to be
+                let unlimited = limited_code_lengths(frequencies, MAX_CODE_BITS);
+                assert!(cost(frequencies, &lengths) >= cost(frequencies, &unlimited));
+            }
         }
+    }
 
-        if index % 8 == 7 || index + 1 == output_tmp.len() {
-            // println!("Pushing!: {:08b} @ i:{}", tmp_byte, index);
-            if index + 1 == output_tmp.len() {
-                tmp_byte = tmp_byte << (8 - (output_tmp.len() % 8));
+    #[test]
+    fn compress_round_trips_random_frequencies() {
+        let mut rng = Rng(0xD1B5_4A32_D192_ED03);
+        for round in 0..50 {
+            let frequencies = random_frequencies(&mut rng, BYTE_ALPHABET);
+            let total: u64 = frequencies.iter().sum();
+            if total == 0 {
+                continue;
             }
-            output.push(tmp_byte);
-            tmp_byte = 0;
+            // Bytes drawn with the table's weights, so the code lengths follow it
+            let data: Vec<u8> = (0..1 + rng.below(5000)).map(|_| {
+                let mut pick = rng.below(total);
+                frequencies.iter().position(|&freq| {
+                    if pick < freq {
+                        true
+                    } else {
+                        pick -= freq;
+                        false
+                    }
+                }).unwrap() as u8
+            }).collect();
+
+            let file = compress(&data);
+            assert_eq!(decompress(&file).unwrap(), data, "round {}", round);
+        }
+    }
+
+    /// Writes `symbols` with the canonical codes for `lengths`, then reads them back through a `Decoder`
+    fn decoder_round_trip(lengths: &[u8], symbols: &[u16]) {
+        let codes = canonical_codes(lengths);
+        let mut writer = BitWriter::new();
+        for &symbol in symbols {
+            let code = &codes[symbol as usize];
+            writer.write_bits(code.code, code.bitlength as u32);
         }
+        let bytes = writer.finish();
 
-        index += 1;
+        let decoder = Decoder::new(lengths).unwrap();
+        let mut input = BitReader::new(&bytes);
+        for &symbol in symbols {
+            assert_eq!(decoder.decode(&mut input).unwrap(), symbol);
+        }
+    }
 
+    #[test]
+    fn decoder_handles_codes_up_to_fifteen_bits() {
+        // 1, 2, ... 15 bits with a second 15 bit code to complete it: one code under each subtable depth
+        let mut lengths: Vec<u8> = (1..=MAX_DECODE_BITS).collect();
+        lengths.push(MAX_DECODE_BITS);
+        let decoder = Decoder::new(&lengths).unwrap();
+        assert!(decoder.is_complete());
+        assert_eq!(decoder.used_symbols(), lengths.len());
+
+        let symbols: Vec<u16> = (0..lengths.len() as u16).chain((0..lengths.len() as u16).rev()).collect();
+        decoder_round_trip(&lengths, &symbols);
+    }
 
-        tmp_byte = tmp_byte << 1;
+    #[test]
+    fn decoder_round_trips_random_long_codes() {
+        let mut rng = Rng(0x5851_F42D_4C95_7F2D);
+        let mut fibonacci = vec![1u64, 1];
+        while fibonacci.len() < 30 {
+            fibonacci.push(fibonacci[fibonacci.len() - 1] + fibonacci[fibonacci.len() - 2]);
+        }
+
+        let mut tables = vec![fibonacci];
+        for _ in 0..100 {
+            let size = 2 + rng.below(286) as usize;
+            let mut frequencies = random_frequencies(&mut rng, size);
+            // A few very rare symbols push the code past the primary table
+            for _ in 0..rng.below(8) {
+                let symbol = rng.below(size as u64) as usize;
+                frequencies[symbol] = 1_000_000;
+            }
+            tables.push(frequencies);
+        }
+
+        let mut longest = 0;
+        for frequencies in &tables {
+            let lengths = limited_code_lengths(frequencies, MAX_DECODE_BITS);
+            longest = longest.max(*lengths.iter().max().unwrap());
+            let used: Vec<u16> = (0..lengths.len() as u16).filter(|&symbol| lengths[symbol as usize] > 0).collect();
+            if used.is_empty() {
+                continue;
+            }
+            let symbols: Vec<u16> = (0..2000).map(|_| used[rng.below(used.len() as u64) as usize]).collect();
+            decoder_round_trip(&lengths, &symbols);
+        }
+        assert_eq!(longest, MAX_DECODE_BITS);
+        assert!(longest > PRIMARY_BITS);
     }
 
-    // println!("==========\nOutput binary");
-    // for b in output.clone() {
-    //     print!("{:08b}", b);
-    // }
-    // println!("\ncode_str concat comparison");
-    // for c in codes.into_iter() {
-    //     print!("{}", c.code_str);
-    // }
-    // println!();
+    #[test]
+    fn decoder_rejects_oversubscribed_lengths() {
+        assert_eq!(Decoder::new(&[1, 1, 1]).unwrap_err(), HuffmanError::InvalidCodeLengths);
+        assert_eq!(Decoder::new(&[1, 2, 2, 2]).unwrap_err(), HuffmanError::InvalidCodeLengths);
+
+        // One code too many, only noticed at the deepest level
+        let mut lengths: Vec<u8> = (1..=MAX_DECODE_BITS).collect();
+        lengths.extend_from_slice(&[MAX_DECODE_BITS, MAX_DECODE_BITS]);
+        assert_eq!(Decoder::new(&lengths).unwrap_err(), HuffmanError::InvalidCodeLengths);
 
-    return output;
-}
\ No newline at end of file
+        assert_eq!(Decoder::new(&[1, MAX_DECODE_BITS + 1]).unwrap_err(), HuffmanError::InvalidCodeLengths);
+    }
+
+    #[test]
+    fn decoder_rejects_codes_missing_from_incomplete_lengths() {
+        // 0 and 10 are codes, 11 isn't
+        let decoder = Decoder::new(&[1, 2]).unwrap();
+        assert!(!decoder.is_complete());
+        let mut input = BitReader::new(&[0b0001_1001]);
+        assert_eq!(decoder.decode(&mut input).unwrap(), 1);
+        assert_eq!(decoder.decode(&mut input).unwrap(), 0);
+        assert_eq!(decoder.decode(&mut input).unwrap_err(), HuffmanError::InvalidCode);
+
+        // The same past the primary table: only one 15 bit code hangs off the all ones prefix
+        let mut lengths: Vec<u8> = (1..MAX_DECODE_BITS).collect();
+        lengths.push(MAX_DECODE_BITS);
+        let decoder = Decoder::new(&lengths).unwrap();
+        assert!(!decoder.is_complete());
+        decoder_round_trip(&lengths, &[14, 0, 13, 14]);
+        let mut input = BitReader::new(&[0xFF, 0xFF]);
+        assert_eq!(decoder.decode(&mut input).unwrap_err(), HuffmanError::InvalidCode);
+
+        // Running out of input partway through a long code isn't mistaken for a bad code
+        let mut input = BitReader::new(&[0xFF]);
+        assert_eq!(decoder.decode(&mut input).unwrap_err(), HuffmanError::UnexpectedEof);
+    }
+}
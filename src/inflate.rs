@@ -0,0 +1,705 @@
+// DEFLATE decoder, RFC 1951 https://tools.ietf.org/html/rfc1951
+// Structure follows zlib's contrib/puff, with symbols decoded through huffman::Decoder's lookup tables.
+use std::fmt;
+use crate::bits::{BitReader, EndOfInput};
+use crate::deflate::dictionary_window;
+use crate::huffman::{Decoder, HuffmanError};
+use crate::lz77::WINDOW_SIZE;
+use crate::stream::Status;
+
+pub(crate) const MAX_LIT_CODES: usize = 286;
+pub(crate) const MAX_DIST_CODES: usize = 30;
+const FIXED_LIT_CODES: usize = 288;
+
+/// Most undecoded input an `Inflater` holds at once; a block header or symbol never needs more than a few hundred bytes
+const MAX_BUFFERED_INPUT: usize = 1 << 14;
+
+pub(crate) const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258
+];
+pub(crate) const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0
+];
+pub(crate) const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577
+];
+pub(crate) const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13
+];
+
+/// Deflate64 (zip method 9) widens the window to 64 KiB, uses distance codes 30 and 31 to reach it, and
+/// turns length code 285 from exactly 258 into 3 plus 16 extra bits
+const DEFLATE64_WINDOW_SIZE: usize = 65536;
+const DEFLATE64_DIST_CODES: usize = 32;
+const DEFLATE64_DIST_BASE: [u16; 2] = [32769, 49153];
+const DEFLATE64_DIST_EXTRA: u8 = 14;
+const DEFLATE64_LONG_LENGTH_BASE: usize = 3;
+const DEFLATE64_LONG_LENGTH_EXTRA: u8 = 16;
+
+/// Order in which the code length code lengths are stored in a dynamic block header
+pub(crate) const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InflateError {
+    /// The input ended before the final block did
+    UnexpectedEof,
+    /// Block type 3 is reserved
+    InvalidBlockType,
+    /// A stored block's LEN and NLEN fields don't complement each other
+    StoredLengthMismatch,
+    /// A dynamic block describes an incomplete or oversubscribed code
+    InvalidCodeLengths,
+    /// A bit pattern that doesn't map to any symbol, or a symbol outside the alphabet
+    InvalidSymbol,
+    /// A back-reference points before the start of the output
+    InvalidDistance,
+    /// Inflating would produce more than the allowed number of bytes
+    OutputLimit,
+}
+
+impl fmt::Display for InflateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            InflateError::UnexpectedEof => "unexpected end of compressed data",
+            InflateError::InvalidBlockType => "invalid block type",
+            InflateError::StoredLengthMismatch => "stored block length does not match its complement",
+            InflateError::InvalidCodeLengths => "invalid huffman code lengths",
+            InflateError::InvalidSymbol => "invalid huffman symbol",
+            InflateError::InvalidDistance => "distance too far back",
+            InflateError::OutputLimit => "output limit exceeded",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+impl std::error::Error for InflateError {}
+
+impl From<EndOfInput> for InflateError {
+    fn from(_: EndOfInput) -> InflateError {
+        InflateError::UnexpectedEof
+    }
+}
+
+impl From<HuffmanError> for InflateError {
+    fn from(err: HuffmanError) -> InflateError {
+        match err {
+            HuffmanError::InvalidCodeLengths => InflateError::InvalidCodeLengths,
+            HuffmanError::UnexpectedEof => InflateError::UnexpectedEof,
+            // The rest come from building trees and reading .huf files, never from decoding a symbol
+            _ => InflateError::InvalidSymbol
+        }
+    }
+}
+
+/// Plain DEFLATE, or PKWARE's Deflate64
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Variant {
+    Deflate,
+    Deflate64
+}
+
+impl Variant {
+    fn window_size(self) -> usize {
+        match self {
+            Variant::Deflate => WINDOW_SIZE,
+            Variant::Deflate64 => DEFLATE64_WINDOW_SIZE
+        }
+    }
+
+    fn dist_codes(self) -> usize {
+        match self {
+            Variant::Deflate => MAX_DIST_CODES,
+            Variant::Deflate64 => DEFLATE64_DIST_CODES
+        }
+    }
+}
+
+/// Inflates a raw DEFLATE stream, refusing to produce more than `max_output` bytes.
+/// The limit is checked as data is produced, so a stream can never allocate past it.
+pub fn inflate(data: &[u8], max_output: u64) -> Result<Vec<u8>, InflateError> {
+    inflate_prefix(data, max_output).map(|(output, _)| output)
+}
+
+/// Inflates the DEFLATE stream at the start of `data`, as `inflate` does, also returning how many bytes
+/// of `data` it took up; containers keep their trailers after it.
+pub fn inflate_prefix(data: &[u8], max_output: u64) -> Result<(Vec<u8>, usize), InflateError> {
+    inflate_stream(Inflater::new(), data, max_output)
+}
+
+/// Inflates a raw Deflate64 stream, zip method 9, as `inflate` does plain DEFLATE
+pub fn inflate64(data: &[u8], max_output: u64) -> Result<Vec<u8>, InflateError> {
+    inflate_stream(Inflater::deflate64(), data, max_output).map(|(output, _)| output)
+}
+
+/// `inflate_prefix` for a stream compressed against a preset dictionary
+pub fn inflate_prefix_with_dictionary(data: &[u8], dictionary: &[u8], max_output: u64) -> Result<(Vec<u8>, usize), InflateError> {
+    inflate_stream(Inflater::with_dictionary(dictionary), data, max_output)
+}
+
+fn inflate_stream(mut inflater: Inflater, data: &[u8], max_output: u64) -> Result<(Vec<u8>, usize), InflateError> {
+    let mut output: Vec<u8> = Vec::new();
+    let mut chunk = vec![0u8; WINDOW_SIZE];
+    let mut input = data;
+
+    loop {
+        let (consumed, produced, status) = inflater.process(input, &mut chunk)?;
+        input = &input[consumed..];
+        if output.len() as u64 + produced as u64 > max_output {
+            return Err(InflateError::OutputLimit);
+        }
+        output.extend_from_slice(&chunk[..produced]);
+
+        if status == Status::StreamEnd {
+            let used = data.len() - input.len() - inflater.unused_input().len();
+            return Ok((output, used));
+        }
+        if consumed == 0 && produced == 0 {
+            return Err(InflateError::UnexpectedEof);
+        }
+    }
+}
+
+/// Where an `Inflater` has got to in the stream
+enum State {
+    /// Next up is a block header, and for dynamic blocks the code description after it
+    Header,
+    /// Partway through a stored block, with this many bytes still to copy
+    Stored(usize),
+    /// Decoding a huffman coded block with these literal/length and distance codes
+    Codes(Box<(Decoder, Decoder)>),
+    /// The final block has ended
+    Done
+}
+
+/// Incremental DEFLATE decoder: feed it compressed data in pieces of any size and it hands back what it
+/// can decode so far. Input that ends partway through a symbol or block header is held over until the
+/// rest arrives, so a stream can be decoded as it comes off a socket.
+pub struct Inflater {
+    variant: Variant,
+    state: State,
+    last_block: bool,
+    /// Input taken in; decoding has got to `bit_offset` bits into the byte at `input_pos`, and
+    /// everything before that is only kept until compacting it away is cheaper than leaving it
+    input: Vec<u8>,
+    input_pos: usize,
+    bit_offset: u32,
+    /// Recent output, kept for back-references, and how much of it has been handed over
+    window: Vec<u8>,
+    delivered: usize
+}
+
+impl Inflater {
+    pub fn new() -> Inflater {
+        Inflater {
+            variant: Variant::Deflate,
+            state: State::Header,
+            last_block: false,
+            input: Vec::new(),
+            input_pos: 0,
+            bit_offset: 0,
+            window: Vec::new(),
+            delivered: 0
+        }
+    }
+
+    /// A decoder for Deflate64 streams
+    pub fn deflate64() -> Inflater {
+        Inflater { variant: Variant::Deflate64, ..Inflater::new() }
+    }
+
+    /// A decoder for a stream compressed against a preset dictionary, whose back-references may reach
+    /// into it before any output exists
+    pub fn with_dictionary(dictionary: &[u8]) -> Inflater {
+        let mut inflater = Inflater::new();
+        inflater.window = dictionary_window(dictionary).to_vec();
+        inflater.delivered = inflater.window.len();
+        inflater
+    }
+
+    /// Decodes as much of `input` into `output` as there's room for, returning how many bytes of each
+    /// were used and whether the stream has ended.
+    /// Input is only taken once earlier output has been handed over, and then only up to a small buffer's
+    /// worth, so call again with the rest and more room; `Status::StreamEnd` means the final block is done
+    /// and all of it delivered.
+    pub fn process(&mut self, input: &[u8], output: &mut [u8]) -> Result<(usize, usize, Status), InflateError> {
+        let mut produced = self.deliver(output);
+        let mut consumed = 0;
+        if self.delivered == self.window.len() && !matches!(self.state, State::Done) {
+            // Shifting out the used prefix only once it outweighs what's left keeps this linear overall
+            if self.input_pos > self.input.len() - self.input_pos {
+                self.input.drain(..self.input_pos);
+                self.input_pos = 0;
+            }
+            let buffered = self.input.len() - self.input_pos;
+            consumed = input.len().min(MAX_BUFFERED_INPUT.saturating_sub(buffered));
+            self.input.extend_from_slice(&input[..consumed]);
+            self.run(output.len() - produced)?;
+            produced += self.deliver(&mut output[produced..]);
+        }
+
+        let status = if matches!(self.state, State::Done) && self.delivered == self.window.len() {
+            Status::StreamEnd
+        } else {
+            Status::Ok
+        };
+        Ok((consumed, produced, status))
+    }
+
+    /// Whether the final block has been decoded
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+
+    /// Input taken in past the end of the stream, such as a container's trailer
+    pub fn unused_input(&self) -> &[u8] {
+        if !self.is_done() {
+            return &[];
+        }
+        let skip = if self.bit_offset > 0 { 1 } else { 0 };
+        &self.input[(self.input_pos + skip).min(self.input.len())..]
+    }
+
+    /// Decodes until there's `room` bytes of undelivered output, the input runs out, or the stream ends
+    fn run(&mut self, room: usize) -> Result<(), InflateError> {
+        let mut reader = BitReader::new(&self.input[self.input_pos..]);
+        reader.bits(self.bit_offset)?;
+
+        while !matches!(self.state, State::Done) && self.window.len() - self.delivered < room {
+            let checkpoint = reader.clone();
+            let budget = room - (self.window.len() - self.delivered);
+            match step(&mut reader, self.variant, &mut self.state, &mut self.last_block, &mut self.window, budget) {
+                Ok(()) => {},
+                // Wait for the rest of whatever was being read, then read it again from the top
+                Err(InflateError::UnexpectedEof) => {
+                    reader = checkpoint;
+                    break;
+                },
+                Err(err) => return Err(err)
+            }
+        }
+
+        let used = reader.bit_position();
+        self.input_pos += used / 8;
+        self.bit_offset = (used % 8) as u32;
+        Ok(())
+    }
+
+    /// Copies undelivered output into `output`, trimming the window back once enough of it is old
+    fn deliver(&mut self, output: &mut [u8]) -> usize {
+        let count = output.len().min(self.window.len() - self.delivered);
+        output[..count].copy_from_slice(&self.window[self.delivered..self.delivered + count]);
+        self.delivered += count;
+
+        let window_size = self.variant.window_size();
+        if self.delivered > 2 * window_size {
+            let excess = self.delivered - window_size;
+            self.window.drain(..excess);
+            self.delivered -= excess;
+        }
+        count
+    }
+}
+
+impl Default for Inflater {
+    fn default() -> Inflater {
+        Inflater::new()
+    }
+}
+
+/// Reads one block header, one symbol, or up to `room` bytes of a stored block.
+/// Nothing changes until the whole step has been read, so on UnexpectedEof it can be retried as is.
+fn step(input: &mut BitReader, variant: Variant, state: &mut State, last_block: &mut bool, window: &mut Vec<u8>, room: usize) -> Result<(), InflateError> {
+    match state {
+        State::Header => {
+            let last = input.bits(1)? == 1;
+            let next = match input.bits(2)? {
+                0 => State::Stored(stored_header(input)?),
+                1 => State::Codes(Box::new(fixed_tables(variant))),
+                2 => State::Codes(Box::new(dynamic_tables(input, variant)?)),
+                _ => return Err(InflateError::InvalidBlockType)
+            };
+            *last_block = last;
+            *state = next;
+        },
+        State::Stored(0) => *state = end_of_block(*last_block),
+        State::Stored(remaining) => {
+            let count = (*remaining).min(input.remaining_bytes()).min(room);
+            if count == 0 {
+                return Err(InflateError::UnexpectedEof);
+            }
+            window.extend_from_slice(input.read_bytes(count)?);
+            *remaining -= count;
+        },
+        State::Codes(tables) => {
+            let (lit, dist) = &**tables;
+            match symbol(input, variant, lit, dist, window.len())? {
+                Symbol::Literal(byte) => window.push(byte),
+                Symbol::EndOfBlock => *state = end_of_block(*last_block),
+                Symbol::Copy { len, distance } => {
+                    let start = window.len() - distance;
+                    for i in 0..len {
+                        let byte = window[start + i];
+                        window.push(byte);
+                    }
+                }
+            }
+        },
+        State::Done => {}
+    }
+    Ok(())
+}
+
+fn end_of_block(last_block: bool) -> State {
+    if last_block { State::Done } else { State::Header }
+}
+
+/// Reads a stored block's LEN and NLEN, returning the length
+fn stored_header(input: &mut BitReader) -> Result<usize, InflateError> {
+    input.align_to_byte();
+    let header = input.read_bytes(4)?;
+    let len = u16::from_le_bytes([header[0], header[1]]);
+    let nlen = u16::from_le_bytes([header[2], header[3]]);
+    if len != !nlen {
+        return Err(InflateError::StoredLengthMismatch);
+    }
+    Ok(len as usize)
+}
+
+fn fixed_tables(variant: Variant) -> (Decoder, Decoder) {
+    let mut lengths = [0u8; FIXED_LIT_CODES];
+    for (symbol, len) in lengths.iter_mut().enumerate() {
+        *len = match symbol {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8
+        };
+    }
+    let lit = Decoder::new(&lengths).expect("fixed literal code is valid");
+    let dist = Decoder::new(&[5u8; DEFLATE64_DIST_CODES][..variant.dist_codes()]).expect("fixed distance code is valid");
+    (lit, dist)
+}
+
+fn dynamic_tables(input: &mut BitReader, variant: Variant) -> Result<(Decoder, Decoder), InflateError> {
+    let nlen = input.bits(5)? as usize + 257;
+    let ndist = input.bits(5)? as usize + 1;
+    let ncode = input.bits(4)? as usize + 4;
+    if nlen > MAX_LIT_CODES || ndist > variant.dist_codes() {
+        return Err(InflateError::InvalidCodeLengths);
+    }
+
+    let mut lengths = [0u8; MAX_LIT_CODES + DEFLATE64_DIST_CODES];
+    for &index in CODE_LENGTH_ORDER.iter().take(ncode) {
+        lengths[index] = input.bits(3)? as u8;
+    }
+    let lencode = Decoder::new(&lengths[..19])?;
+    if !lencode.is_complete() {
+        return Err(InflateError::InvalidCodeLengths);
+    }
+
+    let mut index = 0;
+    while index < nlen + ndist {
+        let symbol = lencode.decode(input)?;
+        if symbol < 16 {
+            lengths[index] = symbol as u8;
+            index += 1;
+            continue;
+        }
+
+        let (value, repeat) = match symbol {
+            16 => {
+                if index == 0 {
+                    return Err(InflateError::InvalidCodeLengths);
+                }
+                (lengths[index - 1], 3 + input.bits(2)? as usize)
+            },
+            17 => (0, 3 + input.bits(3)? as usize),
+            _ => (0, 11 + input.bits(7)? as usize)
+        };
+        if index + repeat > nlen + ndist {
+            return Err(InflateError::InvalidCodeLengths);
+        }
+        for len in lengths[index..index + repeat].iter_mut() {
+            *len = value;
+        }
+        index += repeat;
+    }
+
+    // A block without an end-of-block code can never finish
+    if lengths[256] == 0 {
+        return Err(InflateError::InvalidCodeLengths);
+    }
+
+    // Incomplete codes are only allowed when they consist of a single code (or none, for distances)
+    let lit = Decoder::new(&lengths[..nlen])?;
+    if !lit.is_complete() && lit.used_symbols() > 1 {
+        return Err(InflateError::InvalidCodeLengths);
+    }
+    let dist = Decoder::new(&lengths[nlen..nlen + ndist])?;
+    if !dist.is_complete() && dist.used_symbols() > 1 {
+        return Err(InflateError::InvalidCodeLengths);
+    }
+
+    Ok((lit, dist))
+}
+
+enum Symbol {
+    Literal(u8),
+    EndOfBlock,
+    Copy { len: usize, distance: usize }
+}
+
+/// Reads a literal/length symbol, plus the distance after a length. `available` is how much output a
+/// back-reference can reach into.
+fn symbol(input: &mut BitReader, variant: Variant, lit: &Decoder, dist: &Decoder, available: usize) -> Result<Symbol, InflateError> {
+    let symbol = lit.decode(input)? as usize;
+    if symbol < 256 {
+        return Ok(Symbol::Literal(symbol as u8));
+    }
+    if symbol == 256 {
+        return Ok(Symbol::EndOfBlock);
+    }
+
+    let symbol = symbol - 257;
+    if symbol >= LENGTH_BASE.len() {
+        return Err(InflateError::InvalidSymbol);
+    }
+    let (base, extra) = match symbol {
+        28 if variant == Variant::Deflate64 => (DEFLATE64_LONG_LENGTH_BASE, DEFLATE64_LONG_LENGTH_EXTRA),
+        _ => (LENGTH_BASE[symbol] as usize, LENGTH_EXTRA[symbol])
+    };
+    let len = base + input.bits(extra as u32)? as usize;
+
+    let symbol = dist.decode(input)? as usize;
+    let (base, extra) = match symbol {
+        _ if symbol < DIST_BASE.len() => (DIST_BASE[symbol], DIST_EXTRA[symbol]),
+        _ if symbol < variant.dist_codes() => (DEFLATE64_DIST_BASE[symbol - DIST_BASE.len()], DEFLATE64_DIST_EXTRA),
+        _ => return Err(InflateError::InvalidSymbol)
+    };
+    let distance = base as usize + input.bits(extra as u32)? as usize;
+    if distance > available {
+        return Err(InflateError::InvalidDistance);
+    }
+    Ok(Symbol::Copy { len, distance })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bits::BitWriter;
+    use crate::deflate::deflate;
+
+    /// Writes a huffman code, which goes out first bit first unlike other fields
+    fn write_code(bits: &mut BitWriter, code: u32, len: u32) {
+        let reversed = code.reverse_bits() >> (32 - len);
+        bits.write_bits(reversed as u64, len);
+    }
+
+    /// A pseudo-random history for the copies to reach back into
+    fn history(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect()
+    }
+
+    fn write_stored(bits: &mut BitWriter, data: &[u8]) {
+        for chunk in data.chunks(u16::MAX as usize) {
+            bits.write_bits(0, 3);
+            bits.align_to_byte();
+            bits.write_bytes(&(chunk.len() as u16).to_le_bytes());
+            bits.write_bytes(&(!(chunk.len() as u16)).to_le_bytes());
+            bits.write_bytes(chunk);
+        }
+    }
+
+    fn copy(output: &mut Vec<u8>, len: usize, distance: usize) {
+        for _ in 0..len {
+            output.push(output[output.len() - distance]);
+        }
+    }
+
+    /// Two stored blocks of history then a fixed huffman block of Deflate64-only copies: length code 285
+    /// with 16 extra bits, and distance codes 30 and 31 out to the full 64K window
+    fn fixed_deflate64() -> (Vec<u8>, Vec<u8>) {
+        let mut expected = history(70_000);
+        let mut bits = BitWriter::new();
+        write_stored(&mut bits, &expected);
+
+        bits.write_bits(1, 1);
+        bits.write_bits(1, 2);
+        // (length extra bits, distance code, distance extra bits)
+        for &(length_extra, distance_code, distance_extra) in &[(0xFFFF, 31, 0x3FFF), (5, 30, 0), (1000, 31, 12345), (0, 30, 0x3FFF)] {
+            write_code(&mut bits, 0b1100_0101, 8);
+            bits.write_bits(length_extra, 16);
+            write_code(&mut bits, distance_code, 5);
+            bits.write_bits(distance_extra, 14);
+            let base = if distance_code == 30 { 32769 } else { 49153 };
+            copy(&mut expected, 3 + length_extra as usize, base + distance_extra as usize);
+        }
+        write_code(&mut bits, 0x30 + b'!' as u32, 8);
+        expected.push(b'!');
+        write_code(&mut bits, 0, 7);
+        (bits.finish(), expected)
+    }
+
+    #[test]
+    fn deflate64_fixed_block() {
+        let (stream, expected) = fixed_deflate64();
+        let output = inflate64(&stream, u64::MAX).unwrap();
+        assert_eq!(output.len(), expected.len());
+        assert!(output == expected);
+        // The first copy reaches back exactly 65536 bytes for 65538 of them
+        assert_eq!(expected.len(), 70_000 + 65_538 + 8 + 1003 + 3 + 1);
+    }
+
+    #[test]
+    fn deflate64_codes_are_not_deflate() {
+        // To plain Deflate, 285 is a bare 258 and distance codes 30 and 31 don't exist
+        let (stream, _) = fixed_deflate64();
+        assert!(inflate(&stream, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn deflate64_distance_before_the_start() {
+        let stream = |distance: u64| {
+            let mut bits = BitWriter::new();
+            write_stored(&mut bits, &history(60_000));
+            bits.write_bits(1, 1);
+            bits.write_bits(1, 2);
+            write_code(&mut bits, 0b1100_0101, 8);
+            bits.write_bits(0, 16);
+            write_code(&mut bits, 31, 5);
+            bits.write_bits(distance - 49153, 14);
+            write_code(&mut bits, 0, 7);
+            bits.finish()
+        };
+        assert_eq!(inflate64(&stream(60_000), u64::MAX).unwrap().len(), 60_003);
+        assert_eq!(inflate64(&stream(60_001), u64::MAX), Err(InflateError::InvalidDistance));
+    }
+
+    #[test]
+    fn deflate64_dynamic_block() {
+        let mut expected = history(66_000);
+        let mut bits = BitWriter::new();
+        write_stored(&mut bits, &expected);
+
+        // Dynamic, with all 286 length codes and all 32 distance codes, only 256 and 285 and distances 30
+        // and 31 used. Code length codes: 18 (a run of zeros) is 0, 0 is 10 and 1 is 11.
+        bits.write_bits(1, 1);
+        bits.write_bits(2, 2);
+        bits.write_bits(286 - 257, 5);
+        bits.write_bits(32 - 1, 5);
+        bits.write_bits(18 - 4, 4);
+        for &index in &CODE_LENGTH_ORDER[..18] {
+            let len = match index {
+                18 => 1,
+                0 | 1 => 2,
+                _ => 0
+            };
+            bits.write_bits(len, 3);
+        }
+        let zeros = |bits: &mut BitWriter, count: u64| {
+            write_code(bits, 0, 1);
+            bits.write_bits(count - 11, 7);
+        };
+        let one = |bits: &mut BitWriter| write_code(bits, 0b11, 2);
+        zeros(&mut bits, 138);
+        zeros(&mut bits, 118);
+        one(&mut bits);
+        zeros(&mut bits, 28);
+        one(&mut bits);
+        // The run crosses from the length codes into the distance codes
+        write_code(&mut bits, 0b10, 2);
+        zeros(&mut bits, 29);
+        one(&mut bits);
+        one(&mut bits);
+
+        for &(length_extra, distance_code, distance_extra) in &[(40_000, 1, 0x3FFF), (0xFFFF, 0, 0)] {
+            write_code(&mut bits, 1, 1);
+            bits.write_bits(length_extra, 16);
+            write_code(&mut bits, distance_code, 1);
+            bits.write_bits(distance_extra, 14);
+            let base = if distance_code == 0 { 32769 } else { 49153 };
+            copy(&mut expected, 3 + length_extra as usize, base + distance_extra as usize);
+        }
+        write_code(&mut bits, 0, 1);
+
+        let output = inflate64(&bits.finish(), u64::MAX).unwrap();
+        assert_eq!(output.len(), expected.len());
+        assert!(output == expected);
+    }
+
+    /// Feeds `stream` to an inflater `chunk` bytes at a time with `room` bytes of output, returning the
+    /// output and the input left over past the end of the stream
+    fn inflate_in_pieces(mut inflater: Inflater, stream: &[u8], chunk: usize, room: usize) -> (Vec<u8>, Vec<u8>) {
+        let mut output = Vec::new();
+        let mut buffer = vec![0; room];
+        let mut pos = 0usize;
+        loop {
+            let end = pos.saturating_add(chunk).min(stream.len());
+            let (consumed, produced, status) = inflater.process(&stream[pos..end], &mut buffer).unwrap();
+            assert!(consumed <= MAX_BUFFERED_INPUT);
+            pos += consumed;
+            output.extend_from_slice(&buffer[..produced]);
+            if status == Status::StreamEnd {
+                let mut unused = inflater.unused_input().to_vec();
+                unused.extend_from_slice(&stream[pos..]);
+                return (output, unused);
+            }
+            assert!(consumed > 0 || produced > 0, "stuck {} bytes in", pos);
+        }
+    }
+
+    fn text() -> Vec<u8> {
+        std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/english3.txt")).unwrap()
+    }
+
+    #[test]
+    fn stored_blocks_larger_than_the_input_buffer() {
+        let data = history(200_000);
+        let stream = deflate(&data, 0);
+        for &room in &[1000, 1 << 20] {
+            assert!(inflate_in_pieces(Inflater::new(), &stream, usize::MAX, room).0 == data, "{} bytes of room", room);
+        }
+    }
+
+    #[test]
+    fn input_in_small_chunks() {
+        let text = &text()[..100_000];
+        for &level in &[0, 1, 9] {
+            let stream = deflate(text, level);
+            for &chunk in &[1, 7, 1000] {
+                let (output, unused) = inflate_in_pieces(Inflater::new(), &stream, chunk, 4096);
+                assert!(output == text, "level {} in {} byte chunks", level, chunk);
+                assert!(unused.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn output_a_byte_at_a_time() {
+        let text = &text()[..20_000];
+        let (output, _) = inflate_in_pieces(Inflater::new(), &deflate(text, 6), 100, 1);
+        assert!(output == text);
+    }
+
+    #[test]
+    fn input_past_the_end_is_left_over() {
+        let text = &text()[..50_000];
+        let mut stream = deflate(text, 6);
+        stream.extend_from_slice(b"trailer");
+        for &chunk in &[1, 3, usize::MAX] {
+            let (output, unused) = inflate_in_pieces(Inflater::new(), &stream, chunk, 4096);
+            assert!(output == text);
+            assert_eq!(unused, b"trailer", "{} byte chunks", chunk);
+        }
+    }
+}
@@ -0,0 +1,417 @@
+// The methods PKZIP used before deflate: shrink (1), reduce (2-5) and implode (6), decompression only
+// https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT sections 5.1 to 5.3
+// Shrink is LZW with 9 to 13 bit codes, where instead of resetting the whole table a partial clear frees
+// every code that isn't a prefix of another. Reduce replaces bytes by their index in a follower set of
+// the byte before, then expands DLE escaped back references. Implode is LZ77 over a 4K or 8K window with
+// Shannon-Fano coded lengths, distances and optionally literals. All three pack bits LSB-first and have
+// no end marker, so the decoders stop at the entry's uncompressed size.
+use std::fmt;
+use crate::bits::{BitReader, EndOfInput};
+
+/// Everything that can go wrong undoing one of the legacy methods
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LegacyError {
+    /// A shrink code with no string to stand for, a control code that isn't 1 or 2, or no free code left to add
+    InvalidCode,
+    /// A reduce follower set longer than the 32 bytes the format allows
+    InvalidFollowerSet,
+    /// An implode Shannon-Fano tree with the wrong number of lengths or more codes than fit
+    InvalidTree,
+    /// Bits that match no code in an implode tree
+    InvalidSymbol,
+    UnexpectedEof,
+    /// Decompressing would produce more than the allowed number of bytes
+    OutputLimit
+}
+
+impl fmt::Display for LegacyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LegacyError::InvalidCode => write!(f, "invalid shrink code"),
+            LegacyError::InvalidFollowerSet => write!(f, "reduce follower set too large"),
+            LegacyError::InvalidTree => write!(f, "invalid Shannon-Fano tree"),
+            LegacyError::InvalidSymbol => write!(f, "invalid Shannon-Fano code"),
+            LegacyError::UnexpectedEof => write!(f, "unexpected end of compressed data"),
+            LegacyError::OutputLimit => write!(f, "output exceeds the size limit")
+        }
+    }
+}
+
+impl std::error::Error for LegacyError {}
+
+impl From<EndOfInput> for LegacyError {
+    fn from(_: EndOfInput) -> LegacyError {
+        LegacyError::UnexpectedEof
+    }
+}
+
+fn check_size(size: u64, max_output: u64) -> Result<(), LegacyError> {
+    if size > max_output {
+        return Err(LegacyError::OutputLimit);
+    }
+    Ok(())
+}
+
+/// Appends `length` bytes starting `distance` back, stopping at `size`.
+/// Reduce and implode both let a match reach back past the start of the output, which reads as zeros.
+fn copy_match(output: &mut Vec<u8>, distance: usize, length: usize, size: usize) {
+    for _ in 0..length.min(size - output.len()) {
+        let byte = if distance > output.len() { 0 } else { output[output.len() - distance] };
+        output.push(byte);
+    }
+}
+
+const SHRINK_MIN_BITS: u32 = 9;
+const SHRINK_MAX_BITS: u32 = 13;
+const SHRINK_CONTROL: usize = 256;
+const SHRINK_FIRST_FREE: usize = 257;
+const SHRINK_CODES: usize = 1 << SHRINK_MAX_BITS;
+/// What follows the control code: widen codes by a bit, or free the leaves of the table
+const SHRINK_WIDEN: u32 = 1;
+const SHRINK_PARTIAL_CLEAR: u32 = 2;
+
+/// Each code's prefix slot holds the code it extends, or `SHRINK_FREE` for an unused code. Partial clears
+/// borrow a flag bit in it, the way Info-ZIP's unshrink does, and since only codes up to the last one
+/// handed out are cleared again, a flag left on an older code lingers and changes what it means later.
+/// Streams from careful encoders never notice; the rest decode as Info-ZIP decodes them.
+const SHRINK_FREE: u16 = 0x2000;
+const SHRINK_HAS_CHILD: u16 = 0x4000;
+const SHRINK_CODE_MASK: u16 = 0x1FFF;
+
+/// Undoes method 1, producing exactly `size` bytes
+pub fn unshrink(data: &[u8], size: u64, max_output: u64) -> Result<Vec<u8>, LegacyError> {
+    check_size(size, max_output)?;
+    let size = size as usize;
+    let mut input = BitReader::new(data);
+    let mut output = Vec::new();
+
+    let mut prefix = vec![SHRINK_FREE; SHRINK_CODES];
+    let mut value = vec![0u8; SHRINK_CODES];
+    for literal in 0..SHRINK_CONTROL {
+        prefix[literal] = SHRINK_CONTROL as u16;
+        value[literal] = literal as u8;
+    }
+    // The last code handed out; new codes are looked for above it, and a partial clear looks no further
+    let mut last_used = SHRINK_CONTROL;
+    let mut code_bits = SHRINK_MIN_BITS;
+    // The previous code, and where its string starts in the output
+    let mut previous: Option<(usize, usize)> = None;
+    let mut reversed = Vec::new();
+
+    while output.len() < size {
+        let code = input.bits(code_bits)? as usize;
+        if code == SHRINK_CONTROL {
+            match input.bits(code_bits)? {
+                SHRINK_WIDEN if code_bits < SHRINK_MAX_BITS => code_bits += 1,
+                SHRINK_PARTIAL_CLEAR => {
+                    for code in SHRINK_FIRST_FREE..=last_used {
+                        let parent = (prefix[code] & SHRINK_CODE_MASK) as usize;
+                        if parent > SHRINK_CONTROL {
+                            prefix[parent] |= SHRINK_HAS_CHILD;
+                        }
+                    }
+                    for slot in &mut prefix[SHRINK_FIRST_FREE..=last_used] {
+                        if *slot & SHRINK_HAS_CHILD != 0 {
+                            *slot &= !SHRINK_HAS_CHILD;
+                        } else {
+                            *slot = SHRINK_FREE;
+                        }
+                    }
+                    last_used = SHRINK_CONTROL;
+                },
+                _ => return Err(LegacyError::InvalidCode)
+            }
+            continue;
+        }
+
+        // Walk the prefixes back to a literal. A free code, whether it's the one about to be added (LZW's
+        // KwKwK case) or a prefix freed by a partial clear, stands for the first byte of the previous
+        // string with the previous code's string in front of it, as PKZIP and Info-ZIP have it.
+        let start = output.len();
+        reversed.clear();
+        let mut current = code;
+        while current != SHRINK_CONTROL {
+            // Reused codes can make a prefix its own ancestor
+            if reversed.len() >= SHRINK_CODES {
+                return Err(LegacyError::InvalidCode);
+            }
+            if prefix[current] == SHRINK_FREE {
+                let (previous_code, previous_start) = previous.ok_or(LegacyError::InvalidCode)?;
+                reversed.push(output[previous_start]);
+                current = previous_code;
+            } else {
+                reversed.push(value[current]);
+                current = (prefix[current] & SHRINK_CODE_MASK) as usize;
+            }
+        }
+        output.extend(reversed.iter().rev());
+
+        if let Some((previous_code, _)) = previous {
+            let new_code = (last_used + 1..SHRINK_CODES).find(|&code| prefix[code] == SHRINK_FREE)
+                .ok_or(LegacyError::InvalidCode)?;
+            prefix[new_code] = previous_code as u16;
+            value[new_code] = output[start];
+            last_used = new_code;
+        }
+        previous = Some((code, start));
+    }
+
+    output.truncate(size);
+    Ok(output)
+}
+
+/// Escapes a back reference in reduced data; a zero after it stands for the byte itself
+const REDUCE_DLE: u8 = 144;
+const REDUCE_MAX_FOLLOWERS: usize = 32;
+
+/// Bits needed for an index into a follower set of `count` bytes
+fn follower_index_bits(count: usize) -> u32 {
+    match count {
+        0 => 0,
+        1..=2 => 1,
+        3..=4 => 2,
+        5..=8 => 3,
+        9..=16 => 4,
+        _ => 5
+    }
+}
+
+/// Undoes methods 2 to 5, compression factors 1 to 4, producing exactly `size` bytes
+pub fn unreduce(data: &[u8], factor: u8, size: u64, max_output: u64) -> Result<Vec<u8>, LegacyError> {
+    check_size(size, max_output)?;
+    let size = size as usize;
+    let mut input = BitReader::new(data);
+    let mut output = Vec::new();
+
+    // Stored from byte 255 down to byte 0
+    let mut followers = vec![Vec::new(); 256];
+    for set in followers.iter_mut().rev() {
+        let count = input.bits(6)? as usize;
+        if count > REDUCE_MAX_FOLLOWERS {
+            return Err(LegacyError::InvalidFollowerSet);
+        }
+        for _ in 0..count {
+            set.push(input.bits(8)? as u8);
+        }
+    }
+
+    // The factor is how many high bits of the length byte go to the distance instead
+    let length_mask = 0xFF >> factor;
+    let mut last = 0u8;
+    // A back reference is DLE, then a byte with the length and high distance bits, an extra length byte if
+    // those length bits are all ones, then the low distance byte
+    let mut length_byte: Option<u8> = None;
+    let mut length = 0usize;
+    let mut escaped = false;
+    let mut needs_extra_length = false;
+
+    while output.len() < size {
+        let set = &followers[last as usize];
+        let byte = if set.is_empty() || input.bits(1)? == 1 {
+            input.bits(8)? as u8
+        } else {
+            let index = input.bits(follower_index_bits(set.len()))? as usize;
+            *set.get(index).ok_or(LegacyError::InvalidFollowerSet)?
+        };
+        last = byte;
+
+        if !escaped {
+            if byte == REDUCE_DLE {
+                escaped = true;
+            } else {
+                output.push(byte);
+            }
+        } else if let Some(high) = length_byte {
+            if needs_extra_length {
+                length += byte as usize;
+                needs_extra_length = false;
+            } else {
+                let distance = ((high >> (8 - factor)) as usize) * 256 + byte as usize + 1;
+                copy_match(&mut output, distance, length + 3, size);
+                length_byte = None;
+                escaped = false;
+            }
+        } else if byte == 0 {
+            output.push(REDUCE_DLE);
+            escaped = false;
+        } else {
+            length = (byte & length_mask) as usize;
+            needs_extra_length = length == length_mask as usize;
+            length_byte = Some(byte);
+        }
+    }
+
+    Ok(output)
+}
+
+/// Entry flag bits picking how an imploded entry was coded
+pub const IMPLODE_8K_WINDOW_FLAG: u16 = 0x0002;
+pub const IMPLODE_LITERAL_TREE_FLAG: u16 = 0x0004;
+const SHANNON_FANO_MAX_BITS: usize = 16;
+
+/// A Shannon-Fano code as a canonical code over the inverted bits, the way Info-ZIP decodes it
+struct ShannonFano {
+    /// How many codes there are of each length
+    counts: [u16; SHANNON_FANO_MAX_BITS + 1],
+    /// Symbols ordered by code length, then value
+    symbols: Vec<u16>
+}
+
+impl ShannonFano {
+    /// Reads a tree for `symbol_count` symbols: a byte giving how many bytes follow, less one, each holding
+    /// a code length less one in its low nibble and how many symbols in a row have it, less one, in its high
+    fn read(input: &mut BitReader, symbol_count: usize) -> Result<ShannonFano, LegacyError> {
+        let mut lengths = Vec::with_capacity(symbol_count);
+        let byte_count = input.bits(8)? as usize + 1;
+        for _ in 0..byte_count {
+            let byte = input.bits(8)? as usize;
+            let repeat = (byte >> 4) + 1;
+            if lengths.len() + repeat > symbol_count {
+                return Err(LegacyError::InvalidTree);
+            }
+            lengths.extend(std::iter::repeat((byte & 0xF) + 1).take(repeat));
+        }
+        if lengths.len() != symbol_count {
+            return Err(LegacyError::InvalidTree);
+        }
+
+        let mut counts = [0u16; SHANNON_FANO_MAX_BITS + 1];
+        for &length in &lengths {
+            counts[length] += 1;
+        }
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = left * 2 - count as i32;
+            if left < 0 {
+                return Err(LegacyError::InvalidTree);
+            }
+        }
+
+        let mut symbols: Vec<u16> = (0..symbol_count as u16).collect();
+        symbols.sort_by_key(|&symbol| lengths[symbol as usize]);
+        Ok(ShannonFano { counts, symbols })
+    }
+
+    fn decode(&self, input: &mut BitReader) -> Result<u16, LegacyError> {
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+        for &count in &self.counts[1..] {
+            code |= (input.bits(1)? ^ 1) as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(LegacyError::InvalidSymbol)
+    }
+}
+
+/// Undoes method 6, producing exactly `size` bytes. `flags` is the entry's general purpose flags, which say
+/// how big the window is and whether literals are coded.
+pub fn explode(data: &[u8], flags: u16, size: u64, max_output: u64) -> Result<Vec<u8>, LegacyError> {
+    check_size(size, max_output)?;
+    let size = size as usize;
+    let mut input = BitReader::new(data);
+    let mut output = Vec::new();
+
+    let literal_tree = if flags & IMPLODE_LITERAL_TREE_FLAG != 0 { Some(ShannonFano::read(&mut input, 256)?) } else { None };
+    let length_tree = ShannonFano::read(&mut input, 64)?;
+    let distance_tree = ShannonFano::read(&mut input, 64)?;
+    let distance_low_bits = if flags & IMPLODE_8K_WINDOW_FLAG != 0 { 7 } else { 6 };
+    let min_length = if literal_tree.is_some() { 3 } else { 2 };
+
+    while output.len() < size {
+        if input.bits(1)? == 1 {
+            let literal = match &literal_tree {
+                Some(tree) => tree.decode(&mut input)? as u8,
+                None => input.bits(8)? as u8
+            };
+            output.push(literal);
+            continue;
+        }
+
+        let low = input.bits(distance_low_bits)? as usize;
+        let high = distance_tree.decode(&mut input)? as usize;
+        let distance = (high << distance_low_bits | low) + 1;
+        let mut length = length_tree.decode(&mut input)? as usize;
+        if length == 63 {
+            length += input.bits(8)? as usize;
+        }
+        copy_match(&mut output, distance, length + min_length, size);
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::CompressionMethod;
+    use crate::crc32;
+    use crate::ziparchive::ZipArchive;
+
+    // resources/legacy.zip holds the same 4512 bytes (a word list, every byte value, runs of DLE and a long
+    // repeat) under each legacy method and option. Unzip 6.00 tests the shrunk and imploded entries as
+    // good; it no longer does reduce, so those were checked against a port of Info-ZIP's unreduce.c.
+    // shrunk.txt has partial clears every 300 codes. shrunk-freed.txt comes from an encoder that keeps
+    // using codes whose prefix a partial clear freed, and expects what Info-ZIP makes of that, which isn't
+    // the input any more.
+    const FIXTURE_CRC: u32 = 0x314A_D119;
+    const FIXTURE_SIZE: usize = 4512;
+
+    fn fixture() -> ZipArchive {
+        ZipArchive::new(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/legacy.zip")).unwrap()
+    }
+
+    fn check(name: &str, method: CompressionMethod, flags: u16, size: usize, crc: u32) {
+        let mut archive = fixture();
+        let entry = archive.entries().into_iter().find(|entry| entry.name == name).unwrap();
+        assert_eq!(entry.compression_method, method);
+        assert_eq!(entry.flags, flags);
+
+        let data = archive.read_entry(entry.index).unwrap();
+        assert_eq!(data.len(), size, "{}", name);
+        assert_eq!(crc32::checksum(&data), crc, "{}", name);
+    }
+
+    #[test]
+    fn shrunk() {
+        check("shrunk.txt", CompressionMethod::Shrunk, 0, FIXTURE_SIZE, FIXTURE_CRC);
+    }
+
+    #[test]
+    fn shrunk_with_freed_prefixes() {
+        check("shrunk-freed.txt", CompressionMethod::Shrunk, 0, 4505, 0x01B2_14C3);
+    }
+
+    #[test]
+    fn reduced() {
+        for factor in 1..=4 {
+            check(&format!("reduced{}.txt", factor), CompressionMethod::Reduced(factor), 0, FIXTURE_SIZE, FIXTURE_CRC);
+        }
+    }
+
+    #[test]
+    fn imploded() {
+        check("imploded-4k.txt", CompressionMethod::Imploded, 0, FIXTURE_SIZE, FIXTURE_CRC);
+        check("imploded-4k-literals.txt", CompressionMethod::Imploded, IMPLODE_LITERAL_TREE_FLAG, FIXTURE_SIZE, FIXTURE_CRC);
+        check("imploded-8k.txt", CompressionMethod::Imploded, IMPLODE_8K_WINDOW_FLAG, FIXTURE_SIZE, FIXTURE_CRC);
+        check("imploded-8k-literals.txt", CompressionMethod::Imploded, IMPLODE_8K_WINDOW_FLAG | IMPLODE_LITERAL_TREE_FLAG, FIXTURE_SIZE, FIXTURE_CRC);
+    }
+
+    #[test]
+    fn unshrink_rejects_a_full_table() {
+        // Every literal after the first adds a code, so without a partial clear the table runs out
+        let mut writer = crate::bits::BitWriter::new();
+        for index in 0..(SHRINK_CODES - SHRINK_FIRST_FREE + 2) as u64 {
+            writer.write_bits(index % 256, SHRINK_MIN_BITS);
+        }
+        let data = writer.finish();
+        assert_eq!(unshrink(&data, data.len() as u64 * 8, u64::MAX).unwrap_err(), LegacyError::InvalidCode);
+        assert_eq!(unshrink(&data, SHRINK_CODES as u64 - 1 - 256, u64::MAX).unwrap().len(), SHRINK_CODES - 1 - 256);
+    }
+}
@@ -0,0 +1,28 @@
+// RIP - Rust (Z)ip Archiver
+// Zip compression_method flags: https://users.cs.jmu.edu/buchhofp/forensics/formats/pkzip.html
+// RFC for DEFLATE https://tools.ietf.org/html/rfc1951
+pub mod ziparchive;
+pub mod zipwriter;
+pub mod codec;
+pub mod bits;
+pub mod huffman;
+pub mod inflate;
+pub mod deflate;
+pub mod stream;
+pub mod gzip;
+pub mod zlib;
+pub mod bzip2;
+pub mod lzma;
+pub mod zstd;
+pub mod legacy;
+pub mod lz77;
+pub mod optimal;
+pub mod dictionary;
+pub mod limits;
+pub mod crc32;
+pub mod adler32;
+pub mod xxhash64;
+pub mod dostime;
+pub mod extrafield;
+pub mod json;
+pub mod trace;
@@ -0,0 +1,99 @@
+use std::fmt;
+
+/// Entries this small may exceed `max_ratio`; tiny files of repeated bytes legitimately compress very well.
+const RATIO_GRACE_BYTES: u64 = 64 * 1024;
+
+/// Bounds applied while reading an archive from an untrusted source.
+/// Sizes are enforced against the bytes actually produced while decompressing, never against the
+/// sizes the headers claim.
+#[derive(Debug, Copy, Clone)]
+pub struct ReadLimits {
+    /// Most entries the central directory may list
+    pub max_entries: u64,
+    /// Most bytes a single entry may decompress to
+    pub max_entry_size: u64,
+    /// Most bytes all entries read from the archive may decompress to, combined
+    pub max_total_size: u64,
+    /// Largest allowed uncompressed:compressed ratio for an entry
+    pub max_ratio: u64,
+    /// How many archives deep `ZipArchive::open_nested` may go
+    pub max_nesting: u32,
+}
+
+impl Default for ReadLimits {
+    fn default() -> ReadLimits {
+        ReadLimits {
+            max_entries: 65_535,
+            max_entry_size: 1 << 30,
+            max_total_size: 4 << 30,
+            max_ratio: 100,
+            max_nesting: 4,
+        }
+    }
+}
+
+impl ReadLimits {
+    /// No limits at all, for archives that are already trusted
+    pub fn unlimited() -> ReadLimits {
+        ReadLimits {
+            max_entries: u64::MAX,
+            max_entry_size: u64::MAX,
+            max_total_size: u64::MAX,
+            max_ratio: u64::MAX,
+            max_nesting: u32::MAX,
+        }
+    }
+
+    /// Works out how many bytes an entry may decompress to, given its compressed size and how much
+    /// has already been decompressed from the archive.
+    /// Returns the budget along with the limit that sets it, so a caller can report which one tripped.
+    pub fn entry_budget(&self, compressed_size: u64, total_used: u64) -> (u64, Limit) {
+        let mut budget = (self.max_entry_size, Limit::EntrySize);
+
+        let total_left = self.max_total_size.saturating_sub(total_used);
+        if total_left < budget.0 {
+            budget = (total_left, Limit::TotalSize);
+        }
+
+        let ratio_cap = compressed_size.saturating_mul(self.max_ratio).max(RATIO_GRACE_BYTES);
+        if ratio_cap < budget.0 {
+            budget = (ratio_cap, Limit::Ratio);
+        }
+
+        budget
+    }
+
+    /// The configured maximum for `limit`, for error messages
+    pub fn value_of(&self, limit: Limit) -> u64 {
+        match limit {
+            Limit::Entries => self.max_entries,
+            Limit::EntrySize => self.max_entry_size,
+            Limit::TotalSize => self.max_total_size,
+            Limit::Ratio => self.max_ratio,
+            Limit::Nesting => self.max_nesting as u64,
+        }
+    }
+}
+
+/// Identifies one of the bounds in `ReadLimits`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Limit {
+    Entries,
+    EntrySize,
+    TotalSize,
+    Ratio,
+    Nesting,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Limit::Entries => "entry count",
+            Limit::EntrySize => "uncompressed entry size",
+            Limit::TotalSize => "total uncompressed size",
+            Limit::Ratio => "compression ratio",
+            Limit::Nesting => "archive nesting depth",
+        };
+        write!(f, "{}", name)
+    }
+}
@@ -0,0 +1,274 @@
+// LZ77 match finding for the deflate encoder, over DEFLATE's 32 KiB window.
+// Hash chains, lazy matching and the per-level tuning all follow zlib's deflate.c.
+// https://github.com/madler/zlib/blob/master/deflate.c
+
+pub const WINDOW_SIZE: usize = 32768;
+pub const MIN_MATCH: usize = 3;
+pub const MAX_MATCH: usize = 258;
+
+/// Farthest back a match may reach. One short of the window so a chain never meets the slot it's being
+/// inserted into.
+const MAX_DISTANCE: usize = WINDOW_SIZE - 1;
+
+const HASH_BITS: u32 = 15;
+const HASH_SIZE: usize = 1 << HASH_BITS;
+const NIL: usize = usize::MAX;
+
+/// A minimum length match this far back costs more to code than its three literals
+const TOO_FAR: usize = 4096;
+
+/// What the match finder hands the huffman stage
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Token {
+    Literal(u8),
+    /// Copy `length` (3-258) bytes from `distance` (1-32768) back
+    Match { length: u16, distance: u16 }
+}
+
+/// How hard a level searches, zlib's configuration_table
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LevelConfig {
+    /// Once the previous match is this long, only search a quarter of the chain
+    pub good_length: usize,
+    /// Lazy levels: don't look for a better match after one this long.
+    /// Greedy levels: only index the strings inside matches up to this long.
+    pub max_lazy: usize,
+    /// Stop searching as soon as a match is this long
+    pub nice_length: usize,
+    /// How many chain links to follow per search
+    pub max_chain: usize,
+    /// Whether to hold a match back a byte to see if the next one starts a longer match
+    pub lazy: bool
+}
+
+/// The search settings for `level` 1-9, higher being slower and smaller; levels past 9 get 9's.
+/// Level 0 does no matching at all, so it has no config.
+pub fn level_config(level: u8) -> Option<LevelConfig> {
+    let (good_length, max_lazy, nice_length, max_chain, lazy) = match level {
+        0 => return None,
+        1 => (4, 4, 8, 4, false),
+        2 => (4, 5, 16, 8, false),
+        3 => (4, 6, 32, 32, false),
+        4 => (4, 4, 16, 16, true),
+        5 => (8, 16, 32, 32, true),
+        6 => (8, 16, 128, 128, true),
+        7 => (8, 32, 128, 256, true),
+        8 => (32, 128, 258, 1024, true),
+        _ => (32, 258, 258, 4096, true)
+    };
+    Some(LevelConfig { good_length, max_lazy, nice_length, max_chain, lazy })
+}
+
+/// Hash chains over every position inserted so far: `head` holds the latest position for each hash of three
+/// bytes, `prev` links each position in the window to the one before it with the same hash.
+pub(crate) struct MatchFinder<'a> {
+    data: &'a [u8],
+    head: Vec<usize>,
+    prev: Vec<usize>,
+    config: LevelConfig
+}
+
+impl MatchFinder<'_> {
+    pub(crate) fn new(data: &[u8], config: LevelConfig) -> MatchFinder<'_> {
+        MatchFinder { data, head: vec![NIL; HASH_SIZE], prev: vec![NIL; WINDOW_SIZE], config }
+    }
+
+    /// Indexes the window's worth of `data[..start]` that matches from `start` on could reach
+    pub(crate) fn insert_history(&mut self, start: usize) {
+        for pos in start.saturating_sub(WINDOW_SIZE)..start {
+            self.insert(pos);
+        }
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let bytes = &self.data[pos..pos + MIN_MATCH];
+        (((bytes[0] as usize) << 10) ^ ((bytes[1] as usize) << 5) ^ bytes[2] as usize) & (HASH_SIZE - 1)
+    }
+
+    /// Adds `pos` to its chain, returning the previous head of that chain (or NIL)
+    pub(crate) fn insert(&mut self, pos: usize) -> usize {
+        if pos + MIN_MATCH > self.data.len() {
+            return NIL;
+        }
+        let hash = self.hash(pos);
+        let head = self.head[hash];
+        self.prev[pos % WINDOW_SIZE] = head;
+        self.head[hash] = pos;
+        head
+    }
+
+    /// The longest match for `pos` starting from chain entry `candidate`, if it beats `prev_length`.
+    /// Returns the length found (`prev_length` when nothing longer turned up) and its distance.
+    fn longest_match(&self, pos: usize, mut candidate: usize, prev_length: usize) -> (usize, usize) {
+        let data = self.data;
+        let max_length = MAX_MATCH.min(data.len() - pos);
+        let limit = pos.saturating_sub(MAX_DISTANCE);
+        let mut chain = self.config.max_chain;
+        if prev_length >= self.config.good_length {
+            chain >>= 2;
+        }
+        let nice_length = self.config.nice_length.min(max_length);
+
+        let mut best_length = prev_length;
+        let mut best_distance = 0;
+        while candidate != NIL && candidate >= limit && candidate < pos && chain > 0 {
+            // Only bother comparing if this could beat the best so far
+            if best_length < max_length && data[candidate + best_length] == data[pos + best_length] {
+                let length = data[candidate..candidate + max_length].iter()
+                    .zip(&data[pos..pos + max_length])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if length > best_length {
+                    best_length = length;
+                    best_distance = pos - candidate;
+                    if length >= nice_length {
+                        break;
+                    }
+                }
+            }
+
+            // Stale links from before the window can point anywhere; chains only ever go backwards
+            let next = self.prev[candidate % WINDOW_SIZE];
+            if next >= candidate {
+                break;
+            }
+            candidate = next;
+            chain -= 1;
+        }
+
+        (best_length, best_distance)
+    }
+
+    /// Every match at `pos` worth knowing about, walking the whole chain from `candidate`: a (length, distance)
+    /// pair each time a candidate beats the longest so far. Lengths and distances both increase along the
+    /// list, so the shortest distance for any length is the first pair that reaches it.
+    pub(crate) fn matches(&self, pos: usize, mut candidate: usize, found: &mut Vec<(u16, u16)>) {
+        let data = self.data;
+        let max_length = MAX_MATCH.min(data.len() - pos);
+        let limit = pos.saturating_sub(MAX_DISTANCE);
+        let mut chain = self.config.max_chain;
+
+        let mut best_length = MIN_MATCH - 1;
+        while candidate != NIL && candidate >= limit && candidate < pos && chain > 0 && best_length < max_length {
+            if data[candidate + best_length] == data[pos + best_length] {
+                let length = data[candidate..candidate + max_length].iter()
+                    .zip(&data[pos..pos + max_length])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if length > best_length {
+                    best_length = length;
+                    found.push((length as u16, (pos - candidate) as u16));
+                }
+            }
+
+            let next = self.prev[candidate % WINDOW_SIZE];
+            if next >= candidate {
+                break;
+            }
+            candidate = next;
+            chain -= 1;
+        }
+    }
+}
+
+/// Splits `data` into literals and matches at compression `level` (1-9; 0 gives only literals)
+pub fn tokenize(data: &[u8], level: u8) -> Vec<Token> {
+    tokenize_from(data, 0, level)
+}
+
+/// Tokenizes `data[start..]`, letting matches reach back into `data[..start]`: input already sent on a
+/// stream, whose window the decoder still has
+pub fn tokenize_from(data: &[u8], start: usize, level: u8) -> Vec<Token> {
+    match level_config(level) {
+        None => data[start..].iter().map(|&byte| Token::Literal(byte)).collect(),
+        Some(config) if config.lazy => tokenize_lazy(data, start, config),
+        Some(config) => tokenize_greedy(data, start, config)
+    }
+}
+
+fn match_token(length: usize, distance: usize) -> Token {
+    Token::Match { length: length as u16, distance: distance as u16 }
+}
+
+/// zlib's deflate_fast: take the first match found at each position
+fn tokenize_greedy(data: &[u8], start: usize, config: LevelConfig) -> Vec<Token> {
+    let mut finder = MatchFinder::new(data, config);
+    finder.insert_history(start);
+    let mut tokens = Vec::with_capacity((data.len() - start) / 2);
+
+    let mut pos = start;
+    while pos < data.len() {
+        let head = finder.insert(pos);
+        let (length, distance) = if head != NIL { finder.longest_match(pos, head, MIN_MATCH - 1) } else { (0, 0) };
+
+        if length >= MIN_MATCH {
+            tokens.push(match_token(length, distance));
+            // Indexing every string inside a long match is slow for little gain
+            if length <= config.max_lazy {
+                for inside in pos + 1..pos + length {
+                    finder.insert(inside);
+                }
+            }
+            pos += length;
+        } else {
+            tokens.push(Token::Literal(data[pos]));
+            pos += 1;
+        }
+    }
+
+    tokens
+}
+
+/// zlib's deflate_slow: hold each match back a byte, and drop it if the next position starts a longer one
+fn tokenize_lazy(data: &[u8], start: usize, config: LevelConfig) -> Vec<Token> {
+    let mut finder = MatchFinder::new(data, config);
+    finder.insert_history(start);
+    let mut tokens = Vec::with_capacity((data.len() - start) / 2);
+
+    let mut prev_length = MIN_MATCH - 1;
+    let mut prev_distance = 0;
+    // Whether data[pos - 1] still needs a token
+    let mut pending = false;
+
+    let mut pos = start;
+    while pos < data.len() {
+        let head = finder.insert(pos);
+        let (mut length, mut distance) = (MIN_MATCH - 1, 0);
+        if head != NIL && prev_length < config.max_lazy {
+            let (found_length, found_distance) = finder.longest_match(pos, head, prev_length);
+            if found_length > prev_length {
+                length = found_length;
+                distance = found_distance;
+            }
+            if length == MIN_MATCH && distance > TOO_FAR {
+                length = MIN_MATCH - 1;
+            }
+        }
+
+        if prev_length >= MIN_MATCH && length <= prev_length {
+            // The held back match wins; it started at pos - 1, and pos is already indexed
+            tokens.push(match_token(prev_length, prev_distance));
+            let end = pos - 1 + prev_length;
+            for inside in pos + 1..end {
+                finder.insert(inside);
+            }
+            pos = end;
+            pending = false;
+            prev_length = MIN_MATCH - 1;
+            continue;
+        }
+
+        if pending {
+            tokens.push(Token::Literal(data[pos - 1]));
+        }
+        pending = true;
+        prev_length = length;
+        prev_distance = distance;
+        pos += 1;
+    }
+
+    if pending {
+        tokens.push(Token::Literal(data[data.len() - 1]));
+    }
+    tokens
+}
@@ -0,0 +1,989 @@
+// LZMA, zip method 14. The format is defined by the LZMA SDK's lzma-specification.txt
+// https://www.7-zip.org/sdk.html and zip wraps it as in APPNOTE.TXT section 5.8.8
+// https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT
+// An LZ77 parse of literals, matches and repeats of the last four distances, every decision and every bit
+// coded with an adaptive binary range coder whose probabilities depend on what came before.
+// In a zip entry the stream follows a 4 byte header (encoder version, properties length) and the 5 property
+// bytes; general purpose bit 1 says the stream ends with an end marker rather than at the entry's size.
+use std::fmt;
+
+/// Compression levels for the encoder; the level picks the dictionary size and how hard matches are searched
+pub const MIN_LEVEL: u8 = 1;
+pub const MAX_LEVEL: u8 = 9;
+
+/// Zip general purpose flag bit 1 for method 14: the stream ends with an end marker
+pub const END_MARKER_FLAG: u16 = 0x0002;
+
+/// What goes in the version bytes of the zip header; readers ignore it. 7-Zip 9.20's LZMA SDK.
+const ZIP_HEADER_VERSION: [u8; 2] = [9, 20];
+const PROPERTIES_SIZE: usize = 5;
+
+/// Decoders treat any smaller dictionary as this size
+const MIN_DICTIONARY_SIZE: u32 = 1 << 12;
+
+const STATES: usize = 12;
+/// States below this follow a literal; from it on, a match or repeat
+const LITERAL_STATES: usize = 7;
+const POS_BITS_MAX: usize = 4;
+
+const MIN_MATCH_LEN: usize = 2;
+const MAX_MATCH_LEN: usize = 273;
+const LEN_LOW_BITS: u32 = 3;
+const LEN_MID_BITS: u32 = 3;
+const LEN_HIGH_BITS: u32 = 8;
+const LEN_LOW_SYMBOLS: u32 = 1 << LEN_LOW_BITS;
+const LEN_MID_SYMBOLS: u32 = 1 << LEN_MID_BITS;
+
+/// Distances are coded by slot, 6 bits chosen by the match length (up to 5) ...
+const LEN_TO_POS_STATES: usize = 4;
+const POS_SLOT_BITS: u32 = 6;
+/// ... then slots below this code their low bits with a reverse bit tree, ...
+const END_POS_MODEL_INDEX: u32 = 14;
+const FULL_DISTANCES: usize = 1 << (END_POS_MODEL_INDEX >> 1);
+/// ... and slots from it on as direct bits with the lowest 4 in a reverse bit tree
+const ALIGN_BITS: u32 = 4;
+/// The distance an end marker codes
+const END_MARKER: u32 = 0xFFFF_FFFF;
+
+const PROBABILITY_BITS: u32 = 11;
+const PROBABILITY_INIT: u16 = 1 << (PROBABILITY_BITS - 1);
+const MOVE_BITS: u32 = 5;
+/// The range is renormalized whenever it falls below this
+const TOP: u32 = 1 << 24;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LzmaError {
+    /// The zip LZMA header is truncated or gives a properties length other than 5
+    InvalidHeader,
+    /// The lc/lp/pb properties byte is out of range
+    InvalidProperties(u8),
+    /// The range coder state is impossible for a valid stream
+    Corrupt,
+    /// A match reaches back before the start of the data or past the dictionary
+    InvalidDistance,
+    /// The data goes on past the entry's uncompressed size
+    ExceedsSize(u64),
+    /// Data ended by an end marker doesn't have the entry's uncompressed size
+    SizeMismatch { expected: u64, found: u64 },
+    UnexpectedEof,
+    /// Decompressing would produce more than the allowed number of bytes
+    OutputLimit
+}
+
+impl fmt::Display for LzmaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LzmaError::InvalidHeader => write!(f, "invalid zip LZMA header"),
+            LzmaError::InvalidProperties(byte) => write!(f, "invalid LZMA properties ({:#04X})", byte),
+            LzmaError::Corrupt => write!(f, "corrupt LZMA stream"),
+            LzmaError::InvalidDistance => write!(f, "match distance out of range"),
+            LzmaError::ExceedsSize(size) => write!(f, "data continues past its size of {} bytes", size),
+            LzmaError::SizeMismatch { expected, found } =>
+                write!(f, "length mismatch (expected {}, found {})", expected, found),
+            LzmaError::UnexpectedEof => write!(f, "unexpected end of compressed data"),
+            LzmaError::OutputLimit => write!(f, "output exceeds the size limit")
+        }
+    }
+}
+
+impl std::error::Error for LzmaError {}
+
+/// The literal context bits, literal position bits, position bits and dictionary size a stream is coded with
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Properties {
+    pub lc: u8,
+    pub lp: u8,
+    pub pb: u8,
+    pub dict_size: u32
+}
+
+impl Properties {
+    /// The encoder's defaults for `level`, with the dictionary no bigger than `data_len` needs
+    pub fn for_level(level: u8, data_len: usize) -> Properties {
+        let config = level_config(level);
+        let needed = (data_len.min(u32::MAX as usize) as u32).max(MIN_DICTIONARY_SIZE).checked_next_power_of_two().unwrap_or(u32::MAX);
+        Properties { lc: 3, lp: 0, pb: 2, dict_size: config.dict_size.min(needed) }
+    }
+
+    pub fn parse(bytes: [u8; PROPERTIES_SIZE]) -> Result<Properties, LzmaError> {
+        let mut byte = bytes[0];
+        if byte >= 9 * 5 * 5 {
+            return Err(LzmaError::InvalidProperties(byte));
+        }
+        let lc = byte % 9;
+        byte /= 9;
+        let lp = byte % 5;
+        let pb = byte / 5;
+        let dict_size = u32::from_le_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]);
+        Ok(Properties { lc, lp, pb, dict_size })
+    }
+
+    pub fn to_bytes(&self) -> [u8; PROPERTIES_SIZE] {
+        let size = self.dict_size.to_le_bytes();
+        [(self.pb * 5 + self.lp) * 9 + self.lc, size[0], size[1], size[2], size[3]]
+    }
+}
+
+fn after_literal(state: usize) -> usize {
+    match state {
+        0..=3 => 0,
+        4..=9 => state - 3,
+        _ => state - 6
+    }
+}
+
+fn after_match(state: usize) -> usize {
+    if state < LITERAL_STATES { 7 } else { 10 }
+}
+
+fn after_rep(state: usize) -> usize {
+    if state < LITERAL_STATES { 8 } else { 11 }
+}
+
+fn after_short_rep(state: usize) -> usize {
+    if state < LITERAL_STATES { 9 } else { 11 }
+}
+
+/// Which of the length-dependent distance slot models a match of `len` uses
+fn len_to_pos_state(len: usize) -> usize {
+    (len - MIN_MATCH_LEN).min(LEN_TO_POS_STATES - 1)
+}
+
+/// The slot of a zero-based distance: its bit length and the bit below the top one
+fn distance_slot(distance: u32) -> u32 {
+    if distance < 4 {
+        return distance;
+    }
+    let bits = 31 - distance.leading_zeros();
+    (bits << 1) | ((distance >> (bits - 1)) & 1)
+}
+
+struct LengthModel {
+    choice: u16,
+    choice2: u16,
+    low: [[u16; LEN_LOW_SYMBOLS as usize]; 1 << POS_BITS_MAX],
+    mid: [[u16; LEN_MID_SYMBOLS as usize]; 1 << POS_BITS_MAX],
+    high: [u16; 1 << LEN_HIGH_BITS]
+}
+
+impl LengthModel {
+    fn new() -> LengthModel {
+        LengthModel {
+            choice: PROBABILITY_INIT,
+            choice2: PROBABILITY_INIT,
+            low: [[PROBABILITY_INIT; LEN_LOW_SYMBOLS as usize]; 1 << POS_BITS_MAX],
+            mid: [[PROBABILITY_INIT; LEN_MID_SYMBOLS as usize]; 1 << POS_BITS_MAX],
+            high: [PROBABILITY_INIT; 1 << LEN_HIGH_BITS]
+        }
+    }
+
+    /// A match length, less `MIN_MATCH_LEN`
+    fn decode(&mut self, rc: &mut RangeDecoder, pos_state: usize) -> u32 {
+        if rc.bit(&mut self.choice) == 0 {
+            rc.tree(&mut self.low[pos_state], LEN_LOW_BITS)
+        } else if rc.bit(&mut self.choice2) == 0 {
+            LEN_LOW_SYMBOLS + rc.tree(&mut self.mid[pos_state], LEN_MID_BITS)
+        } else {
+            LEN_LOW_SYMBOLS + LEN_MID_SYMBOLS + rc.tree(&mut self.high, LEN_HIGH_BITS)
+        }
+    }
+
+    fn encode(&mut self, rc: &mut RangeEncoder, len: u32, pos_state: usize) {
+        if len < LEN_LOW_SYMBOLS {
+            rc.bit(&mut self.choice, 0);
+            rc.tree(&mut self.low[pos_state], LEN_LOW_BITS, len);
+        } else if len < LEN_LOW_SYMBOLS + LEN_MID_SYMBOLS {
+            rc.bit(&mut self.choice, 1);
+            rc.bit(&mut self.choice2, 0);
+            rc.tree(&mut self.mid[pos_state], LEN_MID_BITS, len - LEN_LOW_SYMBOLS);
+        } else {
+            rc.bit(&mut self.choice, 1);
+            rc.bit(&mut self.choice2, 1);
+            rc.tree(&mut self.high, LEN_HIGH_BITS, len - LEN_LOW_SYMBOLS - LEN_MID_SYMBOLS);
+        }
+    }
+}
+
+/// Every probability the coder adapts, shared by the decoder and the encoder
+struct Model {
+    lc: u32,
+    lp_mask: usize,
+    pos_mask: usize,
+    literal: Vec<u16>,
+    is_match: [u16; STATES << POS_BITS_MAX],
+    is_rep: [u16; STATES],
+    is_rep_g0: [u16; STATES],
+    is_rep_g1: [u16; STATES],
+    is_rep_g2: [u16; STATES],
+    is_rep0_long: [u16; STATES << POS_BITS_MAX],
+    pos_slot: [[u16; 1 << POS_SLOT_BITS]; LEN_TO_POS_STATES],
+    pos_special: [u16; 1 + FULL_DISTANCES - END_POS_MODEL_INDEX as usize],
+    align: [u16; 1 << ALIGN_BITS],
+    length: LengthModel,
+    rep_length: LengthModel
+}
+
+impl Model {
+    fn new(props: &Properties) -> Model {
+        Model {
+            lc: props.lc as u32,
+            lp_mask: (1 << props.lp) - 1,
+            pos_mask: (1 << props.pb) - 1,
+            literal: vec![PROBABILITY_INIT; 0x300 << (props.lc + props.lp)],
+            is_match: [PROBABILITY_INIT; STATES << POS_BITS_MAX],
+            is_rep: [PROBABILITY_INIT; STATES],
+            is_rep_g0: [PROBABILITY_INIT; STATES],
+            is_rep_g1: [PROBABILITY_INIT; STATES],
+            is_rep_g2: [PROBABILITY_INIT; STATES],
+            is_rep0_long: [PROBABILITY_INIT; STATES << POS_BITS_MAX],
+            pos_slot: [[PROBABILITY_INIT; 1 << POS_SLOT_BITS]; LEN_TO_POS_STATES],
+            pos_special: [PROBABILITY_INIT; 1 + FULL_DISTANCES - END_POS_MODEL_INDEX as usize],
+            align: [PROBABILITY_INIT; 1 << ALIGN_BITS],
+            length: LengthModel::new(),
+            rep_length: LengthModel::new()
+        }
+    }
+
+    /// The 0x300 literal probabilities for the byte at `pos`, which follows `previous`
+    fn literal_probs(&mut self, pos: usize, previous: u8) -> &mut [u16] {
+        let context = ((pos & self.lp_mask) << self.lc) + (previous as usize >> (8 - self.lc));
+        &mut self.literal[0x300 * context..0x300 * (context + 1)]
+    }
+}
+
+struct RangeDecoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    range: u32,
+    code: u32,
+    /// Set once a byte past the end was needed; zeros are read instead and the caller checks this
+    past_end: bool
+}
+
+impl<'a> RangeDecoder<'a> {
+    fn new(data: &'a [u8]) -> Result<RangeDecoder<'a>, LzmaError> {
+        let init = data.get(..5).ok_or(LzmaError::UnexpectedEof)?;
+        if init[0] != 0 {
+            return Err(LzmaError::Corrupt);
+        }
+        let code = u32::from_be_bytes([init[1], init[2], init[3], init[4]]);
+        Ok(RangeDecoder { data, pos: 5, range: 0xFFFF_FFFF, code, past_end: false })
+    }
+
+    fn normalize(&mut self) {
+        if self.range < TOP {
+            let byte = match self.data.get(self.pos) {
+                Some(&byte) => byte,
+                None => {
+                    self.past_end = true;
+                    0
+                }
+            };
+            self.pos += 1;
+            self.range <<= 8;
+            self.code = (self.code << 8) | byte as u32;
+        }
+    }
+
+    fn bit(&mut self, prob: &mut u16) -> u32 {
+        let bound = (self.range >> PROBABILITY_BITS) * *prob as u32;
+        let bit = if self.code < bound {
+            *prob += ((1 << PROBABILITY_BITS) - *prob) >> MOVE_BITS;
+            self.range = bound;
+            0
+        } else {
+            *prob -= *prob >> MOVE_BITS;
+            self.code -= bound;
+            self.range -= bound;
+            1
+        };
+        self.normalize();
+        bit
+    }
+
+    /// `count` bits at even odds, most significant first
+    fn direct(&mut self, count: u32) -> Result<u32, LzmaError> {
+        let mut value = 0;
+        for _ in 0..count {
+            self.range >>= 1;
+            let bit = (self.code >= self.range) as u32;
+            if bit == 1 {
+                self.code -= self.range;
+            }
+            if self.code == self.range {
+                return Err(LzmaError::Corrupt);
+            }
+            value = (value << 1) | bit;
+            self.normalize();
+        }
+        Ok(value)
+    }
+
+    /// A `bits` wide value coded most significant bit first through a tree of probabilities
+    fn tree(&mut self, probs: &mut [u16], bits: u32) -> u32 {
+        let mut node = 1;
+        for _ in 0..bits {
+            node = (node << 1) | self.bit(&mut probs[node as usize]);
+        }
+        node - (1 << bits)
+    }
+
+    /// Like `tree`, least significant bit first
+    fn reverse_tree(&mut self, probs: &mut [u16], bits: u32) -> u32 {
+        let mut node = 1;
+        let mut value = 0;
+        for i in 0..bits {
+            let bit = self.bit(&mut probs[node as usize]);
+            node = (node << 1) | bit;
+            value |= bit << i;
+        }
+        value
+    }
+
+    /// A stream that ends cleanly leaves the code at zero
+    fn finished(&self) -> bool {
+        self.code == 0 && !self.past_end
+    }
+}
+
+/// Decompresses a raw LZMA stream (no header) coded with `props`. With a `size` the data stops there,
+/// end marker or not; without one it has to end with a marker. Never produces more than `max_output` bytes.
+pub fn decompress(data: &[u8], props: &Properties, size: Option<u64>, max_output: u64) -> Result<Vec<u8>, LzmaError> {
+    let limit = match size {
+        Some(size) if size > max_output => return Err(LzmaError::OutputLimit),
+        Some(size) => size,
+        None => max_output
+    };
+    // What to complain about when the data would go past `limit`
+    let overrun = || size.map_or(LzmaError::OutputLimit, LzmaError::ExceedsSize);
+
+    let mut rc = RangeDecoder::new(data)?;
+    let mut model = Model::new(props);
+    let dict_size = props.dict_size.max(MIN_DICTIONARY_SIZE) as usize;
+    let mut output: Vec<u8> = Vec::new();
+    let mut state = 0;
+    let mut reps = [0u32; 4];
+
+    loop {
+        if rc.past_end {
+            return Err(LzmaError::UnexpectedEof);
+        }
+        let remaining = limit - output.len() as u64;
+        if size.is_some() && remaining == 0 && rc.finished() {
+            return Ok(output);
+        }
+
+        let pos = output.len();
+        let pos_state = pos & model.pos_mask;
+        if rc.bit(&mut model.is_match[(state << POS_BITS_MAX) + pos_state]) == 0 {
+            if remaining == 0 {
+                return Err(overrun());
+            }
+            let previous = output.last().copied().unwrap_or(0);
+            let match_byte = if state >= LITERAL_STATES { Some(output[pos - reps[0] as usize - 1]) } else { None };
+            output.push(decode_literal(&mut rc, model.literal_probs(pos, previous), match_byte));
+            state = after_literal(state);
+            continue;
+        }
+
+        let len;
+        if rc.bit(&mut model.is_rep[state]) == 1 {
+            if remaining == 0 {
+                return Err(overrun());
+            }
+            // Repeats start out at distance 1, which needs something to repeat
+            if output.is_empty() {
+                return Err(LzmaError::InvalidDistance);
+            }
+            if rc.bit(&mut model.is_rep_g0[state]) == 0 {
+                if rc.bit(&mut model.is_rep0_long[(state << POS_BITS_MAX) + pos_state]) == 0 {
+                    state = after_short_rep(state);
+                    output.push(output[pos - reps[0] as usize - 1]);
+                    continue;
+                }
+            } else {
+                let distance;
+                if rc.bit(&mut model.is_rep_g1[state]) == 0 {
+                    distance = reps[1];
+                } else {
+                    if rc.bit(&mut model.is_rep_g2[state]) == 0 {
+                        distance = reps[2];
+                    } else {
+                        distance = reps[3];
+                        reps[3] = reps[2];
+                    }
+                    reps[2] = reps[1];
+                }
+                reps[1] = reps[0];
+                reps[0] = distance;
+            }
+            len = model.rep_length.decode(&mut rc, pos_state) as usize + MIN_MATCH_LEN;
+            state = after_rep(state);
+        } else {
+            reps[3] = reps[2];
+            reps[2] = reps[1];
+            reps[1] = reps[0];
+            len = model.length.decode(&mut rc, pos_state) as usize + MIN_MATCH_LEN;
+            state = after_match(state);
+            reps[0] = decode_distance(&mut rc, &mut model, len)?;
+
+            if reps[0] == END_MARKER {
+                if !rc.finished() {
+                    return Err(if rc.past_end { LzmaError::UnexpectedEof } else { LzmaError::Corrupt });
+                }
+                return match size {
+                    Some(size) if size != output.len() as u64 =>
+                        Err(LzmaError::SizeMismatch { expected: size, found: output.len() as u64 }),
+                    _ => Ok(output)
+                };
+            }
+            if remaining == 0 {
+                return Err(overrun());
+            }
+            if reps[0] as usize >= dict_size || reps[0] as usize >= pos {
+                return Err(LzmaError::InvalidDistance);
+            }
+        }
+
+        if len as u64 > remaining {
+            return Err(overrun());
+        }
+        let from = pos - reps[0] as usize - 1;
+        for i in 0..len {
+            output.push(output[from + i]);
+        }
+    }
+}
+
+/// A literal; after a match or repeat it's coded against `match_byte`, the byte at the last distance,
+/// for as long as their bits agree
+fn decode_literal(rc: &mut RangeDecoder, probs: &mut [u16], match_byte: Option<u8>) -> u8 {
+    let mut symbol = 1usize;
+    if let Some(match_byte) = match_byte {
+        for i in (0..8).rev() {
+            let match_bit = ((match_byte >> i) & 1) as usize;
+            let bit = rc.bit(&mut probs[((1 + match_bit) << 8) + symbol]) as usize;
+            symbol = (symbol << 1) | bit;
+            if bit != match_bit {
+                break;
+            }
+        }
+    }
+    while symbol < 0x100 {
+        symbol = (symbol << 1) | rc.bit(&mut probs[symbol]) as usize;
+    }
+    symbol as u8
+}
+
+/// A zero-based match distance
+fn decode_distance(rc: &mut RangeDecoder, model: &mut Model, len: usize) -> Result<u32, LzmaError> {
+    let slot = rc.tree(&mut model.pos_slot[len_to_pos_state(len)], POS_SLOT_BITS);
+    if slot < 4 {
+        return Ok(slot);
+    }
+    let footer_bits = (slot >> 1) - 1;
+    let base = (2 | (slot & 1)) << footer_bits;
+    if slot < END_POS_MODEL_INDEX {
+        let probs = &mut model.pos_special[(base - slot) as usize..];
+        return Ok(base + rc.reverse_tree(probs, footer_bits));
+    }
+    let high = rc.direct(footer_bits - ALIGN_BITS)? << ALIGN_BITS;
+    Ok(base + high + rc.reverse_tree(&mut model.align, ALIGN_BITS))
+}
+
+/// Reads the zip LZMA header, returning the properties and the header's length
+pub fn read_zip_header(data: &[u8]) -> Result<(Properties, usize), LzmaError> {
+    let header = data.get(..4 + PROPERTIES_SIZE).ok_or(LzmaError::InvalidHeader)?;
+    if u16::from_le_bytes([header[2], header[3]]) as usize != PROPERTIES_SIZE {
+        return Err(LzmaError::InvalidHeader);
+    }
+    let props = Properties::parse([header[4], header[5], header[6], header[7], header[8]])?;
+    Ok((props, header.len()))
+}
+
+/// Decompresses a method 14 zip entry of `size` bytes. `end_marker` is general purpose bit 1: the stream runs
+/// to its end marker, which has to fall at `size`.
+pub fn decompress_zip(data: &[u8], size: u64, end_marker: bool, max_output: u64) -> Result<Vec<u8>, LzmaError> {
+    if size > max_output {
+        return Err(LzmaError::OutputLimit);
+    }
+    let (props, header_len) = read_zip_header(data)?;
+    let stream = &data[header_len..];
+    if end_marker {
+        let output = decompress(stream, &props, None, max_output)?;
+        if output.len() as u64 != size {
+            return Err(LzmaError::SizeMismatch { expected: size, found: output.len() as u64 });
+        }
+        Ok(output)
+    } else {
+        decompress(stream, &props, Some(size), max_output)
+    }
+}
+
+struct RangeEncoder {
+    output: Vec<u8>,
+    low: u64,
+    range: u32,
+    /// The last byte shifted out of `low`, held back along with `pending` 0xFF bytes in case a carry
+    /// comes along and bumps them
+    cache: u8,
+    pending: u64
+}
+
+impl RangeEncoder {
+    fn new() -> RangeEncoder {
+        RangeEncoder { output: Vec::new(), low: 0, range: 0xFFFF_FFFF, cache: 0, pending: 1 }
+    }
+
+    fn shift_low(&mut self) {
+        if (self.low as u32) < 0xFF00_0000 || self.low >> 32 != 0 {
+            let carry = (self.low >> 32) as u8;
+            let mut byte = self.cache;
+            while self.pending > 0 {
+                self.output.push(byte.wrapping_add(carry));
+                byte = 0xFF;
+                self.pending -= 1;
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.pending += 1;
+        self.low = (self.low & 0x00FF_FFFF) << 8;
+    }
+
+    fn bit(&mut self, prob: &mut u16, bit: u32) {
+        let bound = (self.range >> PROBABILITY_BITS) * *prob as u32;
+        if bit == 0 {
+            *prob += ((1 << PROBABILITY_BITS) - *prob) >> MOVE_BITS;
+            self.range = bound;
+        } else {
+            *prob -= *prob >> MOVE_BITS;
+            self.low += bound as u64;
+            self.range -= bound;
+        }
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+
+    fn direct(&mut self, value: u32, count: u32) {
+        for i in (0..count).rev() {
+            self.range >>= 1;
+            if (value >> i) & 1 == 1 {
+                self.low += self.range as u64;
+            }
+            while self.range < TOP {
+                self.range <<= 8;
+                self.shift_low();
+            }
+        }
+    }
+
+    fn tree(&mut self, probs: &mut [u16], bits: u32, value: u32) {
+        let mut node = 1;
+        for i in (0..bits).rev() {
+            let bit = (value >> i) & 1;
+            self.bit(&mut probs[node as usize], bit);
+            node = (node << 1) | bit;
+        }
+    }
+
+    fn reverse_tree(&mut self, probs: &mut [u16], bits: u32, value: u32) {
+        let mut node = 1;
+        for i in 0..bits {
+            let bit = (value >> i) & 1;
+            self.bit(&mut probs[node as usize], bit);
+            node = (node << 1) | bit;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for _ in 0..5 {
+            self.shift_low();
+        }
+        self.output
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct LevelConfig {
+    dict_size: u32,
+    /// Hash chain links followed looking for a match
+    max_chain: usize,
+    /// A match this long is taken without looking any further
+    nice_length: usize
+}
+
+fn level_config(level: u8) -> LevelConfig {
+    let (dict_bits, max_chain, nice_length) = match level.clamp(MIN_LEVEL, MAX_LEVEL) {
+        1 => (18, 4, 16),
+        2 => (20, 8, 32),
+        3 => (21, 16, 32),
+        4 => (22, 24, 48),
+        5 => (23, 32, 64),
+        6 => (23, 48, 96),
+        7 => (24, 96, 128),
+        8 => (25, 192, 192),
+        _ => (26, 384, MAX_MATCH_LEN)
+    };
+    LevelConfig { dict_size: 1 << dict_bits, max_chain, nice_length }
+}
+
+const HASH_BITS: u32 = 17;
+const NIL: u32 = u32::MAX;
+
+/// Hash chains over 3 byte prefixes, covering the whole input so distances can reach across the dictionary
+struct MatchFinder<'a> {
+    data: &'a [u8],
+    head: Vec<u32>,
+    prev: Vec<u32>,
+    /// The next position to insert
+    next: usize,
+    dict_size: usize,
+    config: LevelConfig
+}
+
+impl<'a> MatchFinder<'a> {
+    fn new(data: &'a [u8], dict_size: u32, config: LevelConfig) -> MatchFinder<'a> {
+        MatchFinder { data, head: vec![NIL; 1 << HASH_BITS], prev: vec![NIL; data.len()], next: 0, dict_size: dict_size as usize, config }
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let bytes = &self.data[pos..pos + 3];
+        let key = (bytes[0] as u32) | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16;
+        (key.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+    }
+
+    /// Inserts every position up to and including `pos`
+    fn insert_through(&mut self, pos: usize) {
+        while self.next <= pos {
+            if self.next + 3 <= self.data.len() {
+                let hash = self.hash(self.next);
+                self.prev[self.next] = self.head[hash];
+                self.head[hash] = self.next as u32;
+            }
+            self.next += 1;
+        }
+    }
+
+    /// The longest match at `pos` of at least 3 bytes as (length, zero-based distance), or (0, 0)
+    fn find(&mut self, pos: usize) -> (usize, u32) {
+        self.insert_through(pos);
+        let data = self.data;
+        let max_length = MAX_MATCH_LEN.min(data.len() - pos);
+        if max_length < 3 {
+            return (0, 0);
+        }
+        let mut best = (0, 0);
+        let mut candidate = self.prev[pos];
+        let mut chain = self.config.max_chain;
+        while candidate != NIL && chain > 0 {
+            let from = candidate as usize;
+            if pos - from > self.dict_size {
+                break;
+            }
+            if data[from + best.0] == data[pos + best.0] {
+                let length = common_length(data, from, pos, max_length);
+                if length > best.0 {
+                    best = (length, (pos - from - 1) as u32);
+                    if length >= self.config.nice_length.min(max_length) {
+                        break;
+                    }
+                }
+            }
+            candidate = self.prev[from];
+            chain -= 1;
+        }
+        if best.0 < 3 { (0, 0) } else { best }
+    }
+}
+
+/// How many bytes from `a` and `b` agree, up to `max`
+fn common_length(data: &[u8], a: usize, b: usize, max: usize) -> usize {
+    data[a..a + max].iter().zip(&data[b..b + max]).take_while(|(x, y)| x == y).count()
+}
+
+/// `small` is a distance so much shorter than `big` that a match one byte shorter at it is the better deal,
+/// as 7-Zip's fast mode reckons it
+fn much_closer(small: u32, big: u32) -> bool {
+    (big >> 7) > small
+}
+
+struct Encoder<'a> {
+    data: &'a [u8],
+    rc: RangeEncoder,
+    model: Model,
+    state: usize,
+    reps: [u32; 4]
+}
+
+impl<'a> Encoder<'a> {
+    fn literal(&mut self, pos: usize) {
+        let pos_state = pos & self.model.pos_mask;
+        self.rc.bit(&mut self.model.is_match[(self.state << POS_BITS_MAX) + pos_state], 0);
+        let previous = if pos > 0 { self.data[pos - 1] } else { 0 };
+        let match_byte = if self.state >= LITERAL_STATES { Some(self.data[pos - self.reps[0] as usize - 1]) } else { None };
+        let byte = self.data[pos];
+        let probs = self.model.literal_probs(pos, previous);
+
+        let mut symbol = 1usize;
+        let mut matched = match_byte.is_some();
+        let match_byte = match_byte.unwrap_or(0);
+        for i in (0..8).rev() {
+            let bit = ((byte >> i) & 1) as usize;
+            if matched {
+                let match_bit = ((match_byte >> i) & 1) as usize;
+                self.rc.bit(&mut probs[((1 + match_bit) << 8) + symbol], bit as u32);
+                matched = bit == match_bit;
+            } else {
+                self.rc.bit(&mut probs[symbol], bit as u32);
+            }
+            symbol = (symbol << 1) | bit;
+        }
+        self.state = after_literal(self.state);
+    }
+
+    /// A new match at zero-based `distance`
+    fn new_match(&mut self, pos: usize, distance: u32, len: usize) {
+        let pos_state = pos & self.model.pos_mask;
+        self.rc.bit(&mut self.model.is_match[(self.state << POS_BITS_MAX) + pos_state], 1);
+        self.rc.bit(&mut self.model.is_rep[self.state], 0);
+        self.model.length.encode(&mut self.rc, (len - MIN_MATCH_LEN) as u32, pos_state);
+
+        let slot = distance_slot(distance);
+        self.rc.tree(&mut self.model.pos_slot[len_to_pos_state(len)], POS_SLOT_BITS, slot);
+        if slot >= 4 {
+            let footer_bits = (slot >> 1) - 1;
+            let base = (2 | (slot & 1)) << footer_bits;
+            let reduced = distance - base;
+            if slot < END_POS_MODEL_INDEX {
+                let probs = &mut self.model.pos_special[(base - slot) as usize..];
+                self.rc.reverse_tree(probs, footer_bits, reduced);
+            } else {
+                self.rc.direct(reduced >> ALIGN_BITS, footer_bits - ALIGN_BITS);
+                self.rc.reverse_tree(&mut self.model.align, ALIGN_BITS, reduced & ((1 << ALIGN_BITS) - 1));
+            }
+        }
+
+        self.reps = [distance, self.reps[0], self.reps[1], self.reps[2]];
+        self.state = after_match(self.state);
+    }
+
+    /// A match at the `index`th most recent distance
+    fn rep_match(&mut self, pos: usize, index: usize, len: usize) {
+        let pos_state = pos & self.model.pos_mask;
+        self.rc.bit(&mut self.model.is_match[(self.state << POS_BITS_MAX) + pos_state], 1);
+        self.rc.bit(&mut self.model.is_rep[self.state], 1);
+        if index == 0 {
+            self.rc.bit(&mut self.model.is_rep_g0[self.state], 0);
+            self.rc.bit(&mut self.model.is_rep0_long[(self.state << POS_BITS_MAX) + pos_state], 1);
+        } else {
+            self.rc.bit(&mut self.model.is_rep_g0[self.state], 1);
+            if index == 1 {
+                self.rc.bit(&mut self.model.is_rep_g1[self.state], 0);
+            } else {
+                self.rc.bit(&mut self.model.is_rep_g1[self.state], 1);
+                self.rc.bit(&mut self.model.is_rep_g2[self.state], (index - 2) as u32);
+            }
+            let distance = self.reps[index];
+            self.reps.copy_within(0..index, 1);
+            self.reps[0] = distance;
+        }
+        self.model.rep_length.encode(&mut self.rc, (len - MIN_MATCH_LEN) as u32, pos_state);
+        self.state = after_rep(self.state);
+    }
+
+    /// The longest repeat at `pos` as (length, index), length 0 when there's none of at least 2
+    fn longest_rep(&self, pos: usize, max_length: usize) -> (usize, usize) {
+        let mut best = (0, 0);
+        for (index, &distance) in self.reps.iter().enumerate() {
+            let distance = distance as usize + 1;
+            if distance > pos {
+                continue;
+            }
+            let length = common_length(self.data, pos - distance, pos, max_length);
+            if length > best.0 {
+                best = (length, index);
+            }
+        }
+        if best.0 < MIN_MATCH_LEN { (0, 0) } else { best }
+    }
+}
+
+/// Compresses `data` into a raw LZMA stream with `props`, searching for matches as hard as `level` says,
+/// and ends it with an end marker
+pub fn compress(data: &[u8], props: &Properties, level: u8) -> Vec<u8> {
+    let config = level_config(level);
+    let mut finder = MatchFinder::new(data, props.dict_size, config);
+    let mut encoder = Encoder { data, rc: RangeEncoder::new(), model: Model::new(props), state: 0, reps: [0; 4] };
+
+    // The lookahead's match for the next position, when the last step took a literal because of it
+    let mut ahead: Option<(usize, u32)> = None;
+    let mut pos = 0;
+    while pos < data.len() {
+        let max_length = MAX_MATCH_LEN.min(data.len() - pos);
+        let (mut main_len, main_distance) = ahead.take().unwrap_or_else(|| finder.find(pos));
+        let (rep_len, rep_index) = encoder.longest_rep(pos, max_length);
+        // A short match far back costs more than its literals
+        if main_len == 3 && main_distance >= 1 << 14 {
+            main_len = 0;
+        }
+
+        let nice_length = config.nice_length.min(max_length);
+        let take_rep = rep_len >= nice_length || (main_len < nice_length && rep_len >= MIN_MATCH_LEN
+            && (rep_len + 1 >= main_len
+                || (rep_len + 2 >= main_len && main_distance >= 1 << 9)
+                || (rep_len + 3 >= main_len && main_distance >= 1 << 15)));
+        if take_rep {
+            encoder.rep_match(pos, rep_index, rep_len);
+            pos += rep_len;
+            continue;
+        }
+        if main_len == 0 {
+            encoder.literal(pos);
+            pos += 1;
+            continue;
+        }
+
+        // Hold the match back for a literal if the next position does better
+        if main_len < nice_length && pos + 1 < data.len() {
+            let (next_len, next_distance) = finder.find(pos + 1);
+            let next_better = (next_len >= main_len && next_distance < main_distance)
+                || (next_len == main_len + 1 && !much_closer(main_distance, next_distance))
+                || next_len > main_len + 1
+                || (next_len + 1 >= main_len && main_len >= 3 && much_closer(next_distance, main_distance));
+            let next_rep = encoder.longest_rep(pos + 1, MAX_MATCH_LEN.min(data.len() - pos - 1)).0 + 1 >= main_len;
+            if next_better || next_rep {
+                encoder.literal(pos);
+                ahead = Some((next_len, next_distance));
+                pos += 1;
+                continue;
+            }
+        }
+
+        encoder.new_match(pos, main_distance, main_len);
+        pos += main_len;
+    }
+
+    // The end marker: a length 2 match at the all-ones distance
+    encoder.new_match(pos, END_MARKER, MIN_MATCH_LEN);
+    encoder.rc.finish()
+}
+
+/// Compresses `data` as a method 14 zip entry: the zip LZMA header then a stream ending in an end marker,
+/// so the entry needs `END_MARKER_FLAG` set
+pub fn compress_zip(data: &[u8], level: u8) -> Vec<u8> {
+    let props = Properties::for_level(level, data.len());
+    let mut output = Vec::new();
+    output.extend_from_slice(&ZIP_HEADER_VERSION);
+    output.extend_from_slice(&(PROPERTIES_SIZE as u16).to_le_bytes());
+    output.extend_from_slice(&props.to_bytes());
+    output.extend_from_slice(&compress(data, &props, level));
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::CompressionMethod;
+    use crate::crc32;
+    use crate::ziparchive::ZipArchive;
+
+    // resources/lzma.zip holds the same 18024 bytes (text, every byte value, a run of zeros, more text) as
+    // written by liblzma 5.8.2: known-size.txt by its LZMA1EXT encoder told the size, so without an end
+    // marker and with bit 1 clear; end-marker.txt and lc0-lp2-pb0.txt by its raw LZMA1 encoder, with one.
+    const FIXTURE_CRC: u32 = 0xE16B_CCBE;
+    const FIXTURE_SIZE: usize = 18024;
+
+    fn fixture() -> ZipArchive {
+        ZipArchive::new(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/lzma.zip")).unwrap()
+    }
+
+    /// The entry's data as stored, header and all
+    fn compressed(name: &str) -> Vec<u8> {
+        let entry = fixture().entries().into_iter().find(|entry| entry.name == name).unwrap();
+        let zip = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/lzma.zip")).unwrap();
+        zip[entry.data_offset as usize..(entry.data_offset + entry.compressed_size) as usize].to_vec()
+    }
+
+    fn check(name: &str, flags: u16) {
+        let mut archive = fixture();
+        let entry = archive.entries().into_iter().find(|entry| entry.name == name).unwrap();
+        assert_eq!(entry.compression_method, CompressionMethod::Lzma);
+        assert_eq!(entry.flags, flags);
+
+        let data = archive.read_entry(entry.index).unwrap();
+        assert_eq!(data.len(), FIXTURE_SIZE, "{}", name);
+        assert_eq!(crc32::checksum(&data), FIXTURE_CRC, "{}", name);
+    }
+
+    #[test]
+    fn known_size_without_end_marker() {
+        check("known-size.txt", 0);
+    }
+
+    #[test]
+    fn end_marker() {
+        check("end-marker.txt", END_MARKER_FLAG);
+    }
+
+    #[test]
+    fn other_properties() {
+        check("lc0-lp2-pb0.txt", END_MARKER_FLAG);
+        let (props, _) = read_zip_header(&compressed("lc0-lp2-pb0.txt")).unwrap();
+        assert_eq!(props, Properties { lc: 0, lp: 2, pb: 0, dict_size: 1 << 16 });
+    }
+
+    #[test]
+    fn rejects_data_past_the_size() {
+        let data = compressed("known-size.txt");
+        let size = FIXTURE_SIZE as u64 - 1;
+        assert!(matches!(decompress_zip(&data, size, false, u64::MAX), Err(LzmaError::ExceedsSize(found)) if found == size));
+    }
+
+    #[test]
+    fn rejects_an_end_marker_at_the_wrong_size() {
+        let data = compressed("end-marker.txt");
+        let size = FIXTURE_SIZE as u64 + 1;
+        assert!(matches!(decompress_zip(&data, size, true, u64::MAX), Err(LzmaError::SizeMismatch { .. })));
+        // Short of the end marker, the stream just ends early
+        assert!(decompress_zip(&data, size, false, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn round_trips_at_every_level() {
+        let text = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/red.txt")).unwrap();
+        for level in MIN_LEVEL..=MAX_LEVEL {
+            let compressed = compress_zip(&text, level);
+            let size = text.len() as u64;
+            assert_eq!(decompress_zip(&compressed, size, true, size).unwrap(), text, "level {}", level);
+            // With the size known the end marker is optional
+            assert_eq!(decompress_zip(&compressed, size, false, size).unwrap(), text, "level {}", level);
+        }
+    }
+
+    #[test]
+    fn round_trips_empty_and_repetitive_data() {
+        for data in &[Vec::new(), vec![b'a'], vec![0; 100_000], b"abcabcabd".repeat(5000)] {
+            let compressed = compress_zip(data, 5);
+            assert_eq!(&decompress_zip(&compressed, data.len() as u64, true, u64::MAX).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn limits_the_output() {
+        let compressed = compress_zip(&[0; 10_000], 5);
+        assert!(matches!(decompress_zip(&compressed, 10_000, true, 9_999), Err(LzmaError::OutputLimit)));
+        let (props, header_len) = read_zip_header(&compressed).unwrap();
+        assert!(matches!(decompress(&compressed[header_len..], &props, None, 9_999), Err(LzmaError::OutputLimit)));
+    }
+
+    #[test]
+    fn rejects_bad_properties() {
+        let mut compressed = compress_zip(b"abc", 5);
+        compressed[4] = 225;
+        assert!(matches!(read_zip_header(&compressed), Err(LzmaError::InvalidProperties(225))));
+        compressed[2] = 4;
+        assert!(matches!(read_zip_header(&compressed), Err(LzmaError::InvalidHeader)));
+    }
+}
@@ -1,42 +1,768 @@
-mod ziparchive;
-mod huffman;
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::env;
+use std::fmt;
+use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
-use crate::huffman::HuffmanNode;
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use rip::adler32;
+use rip::bzip2;
+use rip::codec::CompressionMethod;
+use rip::deflate;
+use rip::dictionary;
+use rip::dostime::DosDateTime;
+use rip::extrafield::ExtraField;
+use rip::gzip;
+use rip::gzip::GzipHeader;
+use rip::huffman;
+use rip::json::Json;
+use rip::limits::ReadLimits;
+use rip::trace::ParseEvent;
+use rip::ziparchive::{ZipArchive, ZipEntry, ZipError};
+use rip::zlib;
+use rip::zipwriter::ZipWriter;
 
-// Zip compression_method flags: https://users.cs.jmu.edu/buchhofp/forensics/formats/pkzip.html
-// RFC for DEFLATE https://tools.ietf.org/html/rfc1951
 // https://www2.cs.duke.edu/csed/poop/huff/info/
 
+const USAGE: &str = "usage:
+    rip list <archive> [--format json]  list the entries in an archive
+    rip extract <archive> [-d <dir>] [limits]
+                                        extract every entry, into <dir> or the current directory
+    rip create [-0..-9|--ultra] [--bzip2|--lzma|--zstd] <archive> <paths>...
+                                        create an archive from files and directories, deflating
+                                        at the given level (default 6, -0 stores, --ultra is
+                                        slowest and smallest), or with bzip2 in blocks of
+                                        level * 100k, LZMA or zstd
+    rip test <archive> [limits]         decompress every entry and check its CRC-32
+    rip info <archive> [--format json]  show the end of central directory record and method stats
+    rip dump <archive>                  hex dump every structure in the archive, in file order
+    rip huffman [-d] <file>             huffman code a file into <file>.huf, or decode one with -d
+    rip gzip [-0..-9|--ultra] <file>    compress a file into <file>.gz
+    rip gunzip <file.gz> [limits]       decompress a .gz file, checking every member's CRC-32
+    rip bzip2 [-1..-9] <file>           compress a file into <file>.bz2, in blocks of level * 100k
+    rip bunzip2 <file.bz2> [limits]     decompress a .bz2 file, checking every block's CRC
+    rip dictionary [--size <bytes>] <corpus> <output>
+                                        build a preset deflate dictionary (at most 32 KiB) from a
+                                        corpus with one sample message per line
+
+Nothing decompressed is capped unless asked with [limits]:
+    --max-size <bytes>                  most bytes any one entry or file may decompress to
+    --max-ratio <n>                     most times its compressed size an entry or file may
+                                        decompress to";
+
+/// Exit code for a command that ran but failed
+const EXIT_FAILURE: i32 = 1;
+/// Exit code for a command line that couldn't be understood
+const EXIT_USAGE: i32 = 2;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Format {
+    Text,
+    Json
+}
+
+enum CliError {
+    Usage(String),
+    Failed(String)
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Usage(msg) => write!(f, "{}\n\n{}", msg, USAGE),
+            CliError::Failed(msg) => write!(f, "{}", msg)
+        }
+    }
+}
+
+impl From<ZipError> for CliError {
+    fn from(err: ZipError) -> CliError {
+        CliError::Failed(err.to_string())
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(err: io::Error) -> CliError {
+        CliError::Failed(err.to_string())
+    }
+}
+
 fn main() {
-    let _ = ziparchive::ZipArchive::new("./resources/testarchive.zip");
-    //y.print_all_data();
-    test_huffman("red.txt");
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if let Err(err) = run(&args) {
+        eprintln!("rip: {}", err);
+        process::exit(match err {
+            CliError::Usage(_) => EXIT_USAGE,
+            CliError::Failed(_) => EXIT_FAILURE
+        });
+    }
+}
+
+fn run(args: &[String]) -> Result<(), CliError> {
+    let command = args.first().ok_or_else(|| CliError::Usage("no command given".to_string()))?;
+    let rest = &args[1..];
+
+    match command.as_str() {
+        "list" => {
+            let (path, format) = path_and_format(rest)?;
+            list(path, format)
+        },
+        "extract" => extract(rest),
+        "create" => create(rest),
+        "test" => {
+            let (path, limits) = path_and_limits(rest)?;
+            test(path, limits)
+        },
+        "info" => {
+            let (path, format) = path_and_format(rest)?;
+            info(path, format)
+        },
+        "dump" => dump(single_path(rest)?),
+        "huffman" => huffman_file(rest),
+        "gzip" => gzip_file(rest),
+        "gunzip" => {
+            let (path, limits) = path_and_limits(rest)?;
+            gunzip_file(path, limits)
+        },
+        "dictionary" => build_dictionary(rest),
+        "bzip2" => bzip2_file(rest),
+        "bunzip2" => {
+            let (path, limits) = path_and_limits(rest)?;
+            bunzip2_file(path, limits)
+        },
+        "help" | "-h" | "--help" => {
+            println!("{}", USAGE);
+            Ok(())
+        },
+        other => Err(CliError::Usage(format!("unknown command '{}'", other)))
+    }
+}
+
+fn single_path(args: &[String]) -> Result<&str, CliError> {
+    match args {
+        [path] => Ok(path),
+        [] => Err(CliError::Usage("missing archive path".to_string())),
+        _ => Err(CliError::Usage(format!("unexpected argument '{}'", args[1])))
+    }
+}
+
+/// An archive path along with an optional `--format text|json`
+fn path_and_format(args: &[String]) -> Result<(&str, Format), CliError> {
+    let mut path = None;
+    let mut format = Format::Text;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let value = match arg.as_str() {
+            "--format" => Some(iter.next().ok_or_else(|| CliError::Usage("--format needs a value".to_string()))?.as_str()),
+            _ => arg.strip_prefix("--format=")
+        };
+
+        match value {
+            Some("json") => format = Format::Json,
+            Some("text") => format = Format::Text,
+            Some(other) => return Err(CliError::Usage(format!("unknown format '{}'", other))),
+            None if path.is_none() => path = Some(arg.as_str()),
+            None => return Err(CliError::Usage(format!("unexpected argument '{}'", arg)))
+        }
+    }
+
+    let path = path.ok_or_else(|| CliError::Usage("missing archive path".to_string()))?;
+    Ok((path, format))
+}
+
+/// Reads `--max-size <bytes>` or `--max-ratio <n>` into `limits`, taking the value from `iter`.
+/// Returns false for any other argument.
+fn limit_option<'a>(arg: &str, iter: &mut impl Iterator<Item = &'a String>, limits: &mut ReadLimits) -> Result<bool, CliError> {
+    let limit = match arg {
+        "--max-size" => &mut limits.max_entry_size,
+        "--max-ratio" => &mut limits.max_ratio,
+        _ => return Ok(false)
+    };
+    let value = iter.next().ok_or_else(|| CliError::Usage(format!("{} needs a value", arg)))?;
+    *limit = value.parse().map_err(|_| CliError::Usage(format!("invalid value '{}' for {}", value, arg)))?;
+    Ok(true)
+}
+
+/// A path along with any limit options. The files named on the command line are the user's own
+/// choice, so unlike `ZipArchive::new` nothing is limited unless an option says so.
+fn path_and_limits(args: &[String]) -> Result<(&str, ReadLimits), CliError> {
+    let mut path = None;
+    let mut limits = ReadLimits::unlimited();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            _ if limit_option(arg, &mut iter, &mut limits)? => {},
+            _ if arg.starts_with('-') => return Err(CliError::Usage(format!("unknown option '{}'", arg))),
+            _ if path.is_none() => path = Some(arg.as_str()),
+            _ => return Err(CliError::Usage(format!("unexpected argument '{}'", arg)))
+        }
+    }
+
+    let path = path.ok_or_else(|| CliError::Usage("missing archive path".to_string()))?;
+    Ok((path, limits))
 }
 
+fn open_archive(path: &str, limits: ReadLimits) -> Result<ZipArchive, CliError> {
+    ZipArchive::with_limits(path, limits).map_err(|err| CliError::Failed(format!("{}: {}", path, err)))
+}
+
+fn list(path: &str, format: Format) -> Result<(), CliError> {
+    let archive = open_archive(path, ReadLimits::unlimited())?;
+
+    if format == Format::Json {
+        let entries: Vec<Json> = archive.entries().iter().map(entry_json).collect();
+        println!("{}", Json::object().field("archive", archive.name()).field("entries", entries));
+        return Ok(());
+    }
+
+    println!("{:>10}  {:<9} {:>10}  {:>4}  {:<19}  {:<8}  Name", "Length", "Method", "Size", "Cmpr", "Modified", "CRC-32");
+    let mut total_size = 0;
+    let mut total_compressed = 0;
+    for entry in archive.entries() {
+        println!("{:>10}  {:<9} {:>10}  {:>3}%  {}  {:08x}  {}",
+            entry.uncompressed_size,
+            entry.compression_method.name(),
+            entry.compressed_size,
+            savings(entry.compressed_size, entry.uncompressed_size),
+            entry.modified,
+            entry.crc32,
+            entry.name);
+        total_size += entry.uncompressed_size;
+        total_compressed += entry.compressed_size;
+    }
+    println!("{:>10}  {:<9} {:>10}  {:>3}%  {} files", total_size, "", total_compressed, savings(total_compressed, total_size), archive.len());
+
+    Ok(())
+}
+
+/// Percentage of space saved by compression
+fn savings(compressed: u64, uncompressed: u64) -> i64 {
+    if uncompressed == 0 {
+        return 0;
+    }
+    100 - (compressed as i64 * 100 / uncompressed as i64)
+}
+
+fn extract(args: &[String]) -> Result<(), CliError> {
+    let mut archive_path = None;
+    let mut out_dir = PathBuf::from(".");
+    let mut limits = ReadLimits::unlimited();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-d" => {
+                let dir = iter.next().ok_or_else(|| CliError::Usage("-d needs a directory".to_string()))?;
+                out_dir = PathBuf::from(dir);
+            },
+            _ if limit_option(arg, &mut iter, &mut limits)? => {},
+            _ if archive_path.is_none() => archive_path = Some(arg.as_str()),
+            _ => return Err(CliError::Usage(format!("unexpected argument '{}'", arg)))
+        }
+    }
+    let archive_path = archive_path.ok_or_else(|| CliError::Usage("missing archive path".to_string()))?;
 
-fn test_huffman(resource_file: &str){
-    let path_string = format!("./resources/{}", resource_file).clone();
-    let path = Path::new(&path_string);
+    let mut archive = open_archive(archive_path, limits)?;
+    for entry in archive.entries() {
+        let relative = safe_entry_path(&entry.name)
+            .ok_or_else(|| CliError::Failed(format!("{}: refusing to extract unsafe path '{}'", archive_path, entry.name)))?;
+        let target = out_dir.join(relative);
 
-    let mut file = File::open(path).expect("Failed to open file");
+        if entry.is_dir() {
+            fs::create_dir_all(&target)?;
+            continue;
+        }
 
-    let mut file_data = String::new();
-    file.read_to_string(&mut file_data).expect("Couldn't read file");
+        let data = archive.read_entry(entry.index).map_err(|err| CliError::Failed(format!("{}: {}", entry.name, err)))?;
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = File::create(&target)?;
+        (&file).write_all(&data)?;
+        let modified = std::time::UNIX_EPOCH + std::time::Duration::from_secs(entry.modified.to_unix().max(0) as u64);
+        file.set_modified(modified)?;
+        println!("  inflating: {}", target.display());
+    }
 
-    
-    let huffman_tree_root = huffman::HuffmanNode::new(&file_data);
-    let code_map = huffman::gen_code_map(&huffman_tree_root);
+    Ok(())
+}
 
-    let mut codes_to_write:Vec<huffman::HuffCode> = Vec::new();
+/// Turns an entry name into a relative path, or None if it would escape the output directory
+fn safe_entry_path(name: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in Path::new(&name.replace('\\', "/")).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {},
+            _ => return None
+        }
+    }
+    if path.as_os_str().is_empty() {
+        return None;
+    }
+    Some(path)
+}
 
-    for code_key in file_data.chars() {
-        codes_to_write.push(code_map[&code_key].clone())
+fn create(args: &[String]) -> Result<(), CliError> {
+    let mut level = deflate::DEFAULT_LEVEL;
+    let mut method = CompressionMethod::Deflated;
+    let mut paths: Vec<&String> = Vec::new();
+    for arg in args {
+        let chosen = match arg.as_str() {
+            "--bzip2" => Some(CompressionMethod::Bzip2),
+            "--lzma" => Some(CompressionMethod::Lzma),
+            "--zstd" => Some(CompressionMethod::Zstd),
+            _ => None
+        };
+        if let Some(chosen) = chosen {
+            method = chosen;
+            continue;
+        }
+        match level_option(arg) {
+            Some(value) => level = value,
+            None if arg.starts_with('-') => return Err(CliError::Usage(format!("unknown option '{}'", arg))),
+            None => paths.push(arg)
+        }
     }
 
-    let compressed = huffman::codes_to_bin(&mut codes_to_write);
+    let (archive_path, inputs) = match paths.as_slice() {
+        [archive_path, inputs @ ..] if !inputs.is_empty() => (*archive_path, inputs),
+        [_] => return Err(CliError::Usage("nothing to add to the archive".to_string())),
+        _ => return Err(CliError::Usage("missing archive path".to_string()))
+    };
+
+    let mut writer = ZipWriter::new(io::BufWriter::new(File::create(archive_path)?));
+    writer.set_level(level);
+    writer.set_method(method)?;
+    // The archive may well be somewhere under the inputs, and mustn't end up inside itself
+    let archive = fs::canonicalize(archive_path)?;
+    let result = inputs.iter()
+        .try_for_each(|input| add_path(&mut writer, Path::new(input), &archive))
+        .and_then(|_| writer.finish().map(|_| ()).map_err(CliError::from));
 
-    println!("Compressed! Orig: {}bytes Compressed: {}bytes", file_data.len(), compressed.len())
+    // Don't leave a half written archive behind
+    if result.is_err() {
+        let _ = fs::remove_file(archive_path);
+    }
+    result
+}
 
-}
\ No newline at end of file
+/// The deflate level a `-0`..`-9` or `--ultra` option asks for
+fn level_option(arg: &str) -> Option<u8> {
+    if arg == "--ultra" {
+        return Some(deflate::ULTRA_LEVEL);
+    }
+    arg.strip_prefix('-')
+        .and_then(|digit| digit.parse::<u8>().ok())
+        .filter(|&level| level <= deflate::MAX_LEVEL)
+}
+
+/// Adds the file or directory tree at `path`, leaving out `archive`, the canonical path of the archive being written
+fn add_path<W: Write>(writer: &mut ZipWriter<W>, path: &Path, archive: &Path) -> Result<(), CliError> {
+    // "." stands for what's in the current directory, with no entry of its own
+    if path.components().all(|component| component == Component::CurDir) {
+        return add_children(writer, path, archive);
+    }
+    if fs::canonicalize(path).is_ok_and(|canonical| canonical == archive) {
+        return Ok(());
+    }
+
+    let name = archive_name(path)
+        .ok_or_else(|| CliError::Failed(format!("{}: can't store a path outside the current directory", path.display())))?;
+    let metadata = fs::metadata(path).map_err(|err| CliError::Failed(format!("{}: {}", path.display(), err)))?;
+    let modified = DosDateTime::from_system_time(metadata.modified()?);
+
+    if metadata.is_dir() {
+        writer.add_directory(&name, modified)?;
+        println!("  adding: {}/", name);
+        add_children(writer, path, archive)?;
+    } else {
+        let data = fs::read(path)?;
+        writer.add_file(&name, &data, modified)?;
+        println!("  adding: {}", name);
+    }
+
+    Ok(())
+}
+
+/// Adds everything in the directory at `path`, in name order
+fn add_children<W: Write>(writer: &mut ZipWriter<W>, path: &Path, archive: &Path) -> Result<(), CliError> {
+    let mut children: Vec<PathBuf> = fs::read_dir(path)?.map(|child| child.map(|c| c.path())).collect::<Result<_, _>>()?;
+    children.sort();
+    for child in children {
+        add_path(writer, &child, archive)?;
+    }
+    Ok(())
+}
+
+/// The name a path is stored under: relative, with forward slashes
+fn archive_name(path: &Path) -> Option<String> {
+    let mut parts: Vec<String> = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_string_lossy().into_owned()),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {},
+            Component::ParentDir => return None
+        }
+    }
+    if parts.is_empty() {
+        return None;
+    }
+    Some(parts.join("/"))
+}
+
+fn test(path: &str, limits: ReadLimits) -> Result<(), CliError> {
+    let mut archive = open_archive(path, limits)?;
+
+    let mut failures = 0;
+    for entry in archive.entries() {
+        if entry.is_dir() {
+            continue;
+        }
+        match archive.read_entry(entry.index) {
+            Ok(_) => println!("    testing: {:<40} OK", entry.name),
+            Err(err) => {
+                println!("    testing: {:<40} FAILED", entry.name);
+                eprintln!("rip: {}: {}", entry.name, err);
+                failures += 1;
+            }
+        }
+    }
+
+    if failures > 0 {
+        return Err(CliError::Failed(format!("{}: {} of {} entries failed", path, failures, archive.len())));
+    }
+    println!("No errors detected in {}", path);
+    Ok(())
+}
+
+fn info(path: &str, format: Format) -> Result<(), CliError> {
+    let archive = open_archive(path, ReadLimits::unlimited())?;
+    let end = archive.end_record();
+
+    // method -> (entries, compressed bytes, uncompressed bytes)
+    let mut stats: BTreeMap<u16, (u64, u64, u64)> = BTreeMap::new();
+    for entry in archive.entries() {
+        let stat = stats.entry(entry.compression_method.id()).or_insert((0, 0, 0));
+        stat.0 += 1;
+        stat.1 += entry.compressed_size;
+        stat.2 += entry.uncompressed_size;
+    }
+
+    if format == Format::Json {
+        let end_json = Json::object()
+            .field("offset", end.offset)
+            .field("disk_number", end.disk_number)
+            .field("central_directory_disk", end.central_directory_disk)
+            .field("entries_on_disk", end.entries_on_disk)
+            .field("total_entries", end.total_entries)
+            .field("central_directory_size", end.central_directory_size)
+            .field("central_directory_offset", end.central_directory_offset)
+            .field("comment", String::from_utf8_lossy(&end.comment).into_owned());
+        let zip64_json = archive.zip64_end_record().map(|zip64| Json::object()
+            .field("locator_offset", zip64.locator_offset)
+            .field("disk_with_record", zip64.disk_with_record)
+            .field("total_disks", zip64.total_disks)
+            .field("offset", zip64.offset)
+            .field("record_size", zip64.record_size)
+            .field("version_made_by", zip64.version_made_by)
+            .field("version_needed", zip64.version_needed)
+            .field("disk_number", zip64.disk_number)
+            .field("central_directory_disk", zip64.central_directory_disk)
+            .field("entries_on_disk", zip64.entries_on_disk)
+            .field("total_entries", zip64.total_entries)
+            .field("central_directory_size", zip64.central_directory_size)
+            .field("central_directory_offset", zip64.central_directory_offset));
+        let methods: Vec<Json> = stats.iter().map(|(&method, &(count, compressed, uncompressed))| Json::object()
+            .field("method", method)
+            .field("name", CompressionMethod::from_id(method).name())
+            .field("entries", count)
+            .field("compressed_size", compressed)
+            .field("uncompressed_size", uncompressed)).collect();
+
+        println!("{}", Json::object()
+            .field("archive", archive.name())
+            .field("end_of_central_directory", end_json)
+            .field("zip64", zip64_json)
+            .field("methods", methods));
+        return Ok(());
+    }
+
+    println!("Archive:                    {}", archive.name());
+    println!("End of central directory:   {:#X}", end.offset);
+    println!("This disk:                  {}", end.disk_number);
+    println!("Central directory disk:     {}", end.central_directory_disk);
+    println!("Entries on this disk:       {}", end.entries_on_disk);
+    println!("Total entries:              {}", end.total_entries);
+    println!("Central directory size:     {}", end.central_directory_size);
+    println!("Central directory offset:   {:#X}", end.central_directory_offset);
+    println!("Comment:                    {}", String::from_utf8_lossy(&end.comment));
+    if let Some(zip64) = archive.zip64_end_record() {
+        println!("Zip64 locator:              {:#X}", zip64.locator_offset);
+        println!("Zip64 end record:           {:#X}", zip64.offset);
+        println!("Zip64 total entries:        {}", zip64.total_entries);
+        println!("Zip64 directory size:       {}", zip64.central_directory_size);
+        println!("Zip64 directory offset:     {:#X}", zip64.central_directory_offset);
+    }
+
+    println!();
+    println!("{:>6}  {:<9}  {:>7}  {:>12}  {:>12}  {:>4}", "Method", "Name", "Entries", "Compressed", "Uncompressed", "Cmpr");
+    for (method, (count, compressed, uncompressed)) in stats {
+        println!("{:>6}  {:<9}  {:>7}  {:>12}  {:>12}  {:>3}%", method, CompressionMethod::from_id(method).name(), count, compressed, uncompressed, savings(compressed, uncompressed));
+    }
+
+    Ok(())
+}
+
+/// Most bytes of a structure to show in `rip dump`; file data is usually far longer than is useful to see
+const DUMP_MAX_BYTES: usize = 64;
+
+fn dump(path: &str) -> Result<(), CliError> {
+    let data = fs::read(path).map_err(|err| CliError::Failed(format!("{}: {}", path, err)))?;
+
+    let file = fs::File::open(path).map_err(|err| CliError::Failed(format!("{}: {}", path, err)))?;
+
+    let mut events: Vec<ParseEvent> = Vec::new();
+    let mut observer = |event: &ParseEvent| events.push(*event);
+    ZipArchive::from_reader_traced(file, path, ReadLimits::unlimited(), &mut observer)
+        .map_err(|err| CliError::Failed(format!("{}: {}", path, err)))?;
+
+    events.sort_by_key(|event| event.offset);
+    for event in events {
+        let entry = event.entry.map(|index| format!(" (entry {})", index)).unwrap_or_default();
+        println!("{:08X}  {} bytes  {}{}", event.offset, event.length, event.kind, entry);
+
+        let start = event.offset as usize;
+        let end = (event.offset + event.length).min(data.len() as u64) as usize;
+        let shown = &data[start..end.min(start + DUMP_MAX_BYTES)];
+        for (line, chunk) in shown.chunks(16).enumerate() {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            let text: String = chunk.iter().map(|&b| if (0x20..0x7f).contains(&b) { b as char } else { '.' }).collect();
+            println!("    {:08X}  {:<47}  {}", start + line * 16, hex.join(" "), text);
+        }
+        if end - start > shown.len() {
+            println!("    ... {} more bytes", end - start - shown.len());
+        }
+    }
+
+    Ok(())
+}
+
+/// The `list --format json` representation of an entry, documented in docs/json-output.md
+fn entry_json(entry: &ZipEntry) -> Json {
+    let modified = entry.modified;
+    let timestamp = format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}", modified.year, modified.month, modified.day, modified.hour, modified.minute, modified.second);
+    let extra_fields: Vec<Json> = entry.extra_fields.iter().map(extra_field_json).collect();
+
+    Json::object()
+        .field("index", entry.index)
+        .field("name", entry.name.as_str())
+        .field("is_dir", entry.is_dir())
+        .field("compression_method", entry.compression_method.id())
+        .field("method_name", entry.compression_method.name())
+        .field("compressed_size", entry.compressed_size)
+        .field("uncompressed_size", entry.uncompressed_size)
+        .field("crc32", entry.crc32)
+        .field("modified", timestamp)
+        .field("dos_date", entry.dos_date)
+        .field("dos_time", entry.dos_time)
+        .field("version_made_by", entry.version_made_by)
+        .field("version_needed", entry.version_needed)
+        .field("flags", entry.flags)
+        .field("disk_number_start", entry.disk_number_start)
+        .field("internal_attributes", entry.internal_attributes)
+        .field("external_attributes", entry.external_attributes)
+        .field("central_header_offset", entry.central_header_offset)
+        .field("local_header_offset", entry.local_header_offset)
+        .field("data_offset", entry.data_offset)
+        .field("comment", entry.comment.as_str())
+        .field("extra_fields", extra_fields)
+}
+
+fn extra_field_json(field: &ExtraField) -> Json {
+    let json = Json::object().field("id", field.id());
+    match field {
+        ExtraField::Zip64 { uncompressed_size, compressed_size, local_header_offset, disk_start } => json
+            .field("type", "zip64")
+            .field("uncompressed_size", *uncompressed_size)
+            .field("compressed_size", *compressed_size)
+            .field("local_header_offset", *local_header_offset)
+            .field("disk_start", *disk_start),
+        ExtraField::Ntfs { modified, accessed, created } => json
+            .field("type", "ntfs")
+            .field("modified", *modified)
+            .field("accessed", *accessed)
+            .field("created", *created),
+        ExtraField::ExtendedTimestamp { flags, modified, accessed, created } => json
+            .field("type", "extended_timestamp")
+            .field("flags", *flags)
+            .field("modified", *modified)
+            .field("accessed", *accessed)
+            .field("created", *created),
+        ExtraField::UnicodePath { name_crc32, name } => json
+            .field("type", "unicode_path")
+            .field("name_crc32", *name_crc32)
+            .field("name", name.as_str()),
+        ExtraField::UnixIds { uid, gid } => json
+            .field("type", "unix_ids")
+            .field("uid", *uid)
+            .field("gid", *gid),
+        ExtraField::Unknown { data, .. } => {
+            let hex: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+            json.field("type", "unknown").field("data", hex)
+        }
+    }
+}
+
+fn huffman_file(args: &[String]) -> Result<(), CliError> {
+    let (decode, path) = match args {
+        [flag, path] if flag == "-d" => (true, path.as_str()),
+        [path] if path != "-d" => (false, path.as_str()),
+        [] | [_] => return Err(CliError::Usage("missing file path".to_string())),
+        _ => return Err(CliError::Usage(format!("unexpected argument '{}'", args[args.len() - 1])))
+    };
+
+    let input = fs::read(path).map_err(|err| CliError::Failed(format!("{}: {}", path, err)))?;
+    let (output_path, output) = if decode {
+        let output_path = path.strip_suffix(".huf")
+            .ok_or_else(|| CliError::Failed(format!("{}: expected a .huf file", path)))?;
+        let output = huffman::decompress(&input).map_err(|err| CliError::Failed(format!("{}: {}", path, err)))?;
+        (output_path.to_string(), output)
+    } else {
+        (format!("{}.huf", path), huffman::compress(&input))
+    };
+
+    fs::write(&output_path, &output)?;
+    println!("{} -> {}: {} bytes -> {} bytes", path, output_path, input.len(), output.len());
+    Ok(())
+}
+
+fn gzip_file(args: &[String]) -> Result<(), CliError> {
+    let mut level = deflate::DEFAULT_LEVEL;
+    let mut path = None;
+    for arg in args {
+        match level_option(arg) {
+            Some(value) => level = value,
+            None if arg.starts_with('-') => return Err(CliError::Usage(format!("unknown option '{}'", arg))),
+            None if path.is_none() => path = Some(arg.as_str()),
+            None => return Err(CliError::Usage(format!("unexpected argument '{}'", arg)))
+        }
+    }
+    let path = path.ok_or_else(|| CliError::Usage("missing file path".to_string()))?;
+
+    let input = fs::read(path).map_err(|err| CliError::Failed(format!("{}: {}", path, err)))?;
+    let metadata = fs::metadata(path)?;
+    let modified = metadata.modified()?.duration_since(std::time::UNIX_EPOCH).map(|age| age.as_secs()).unwrap_or(0);
+    let header = GzipHeader {
+        // MTIME can't go past 2106; 0 says there's no time rather than a wrong one
+        modified: u32::try_from(modified).unwrap_or(0),
+        name: Path::new(path).file_name().map(|name| name.to_string_lossy().into_owned().into_bytes()),
+        ..GzipHeader::default()
+    };
+
+    let output_path = format!("{}.gz", path);
+    let output = gzip::compress(&input, &header, level);
+    fs::write(&output_path, &output)?;
+    println!("{} -> {}: {} bytes -> {} bytes", path, output_path, input.len(), output.len());
+    Ok(())
+}
+
+fn gunzip_file(path: &str, limits: ReadLimits) -> Result<(), CliError> {
+    let output_path = path.strip_suffix(".gz")
+        .ok_or_else(|| CliError::Failed(format!("{}: expected a .gz file", path)))?;
+    let input = fs::read(path).map_err(|err| CliError::Failed(format!("{}: {}", path, err)))?;
+    let (max_output, _) = limits.entry_budget(input.len() as u64, 0);
+    let members = gzip::read_members(&input, max_output)
+        .map_err(|err| CliError::Failed(format!("{}: {}", path, err)))?;
+
+    let modified = members[0].header.modified;
+    let output: Vec<u8> = members.into_iter().flat_map(|member| member.data).collect();
+    let file = File::create(output_path)?;
+    (&file).write_all(&output)?;
+    if modified != 0 {
+        file.set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(modified as u64))?;
+    }
+    println!("{} -> {}: {} bytes -> {} bytes", path, output_path, input.len(), output.len());
+    Ok(())
+}
+
+/// How many samples `rip dictionary` compresses to show what the dictionary saves
+const DICTIONARY_TRIAL_SAMPLES: usize = 1000;
+
+fn build_dictionary(args: &[String]) -> Result<(), CliError> {
+    let mut size = dictionary::MAX_DICTIONARY_SIZE;
+    let mut paths: Vec<&str> = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--size" => {
+                let value = iter.next().ok_or_else(|| CliError::Usage("--size needs a value".to_string()))?;
+                size = value.parse().map_err(|_| CliError::Usage(format!("invalid size '{}'", value)))?;
+            },
+            _ if arg.starts_with('-') => return Err(CliError::Usage(format!("unknown option '{}'", arg))),
+            _ => paths.push(arg)
+        }
+    }
+    let (corpus_path, output_path) = match paths.as_slice() {
+        [corpus, output] => (*corpus, *output),
+        [] => return Err(CliError::Usage("missing corpus path".to_string())),
+        [_] => return Err(CliError::Usage("missing output path".to_string())),
+        _ => return Err(CliError::Usage(format!("unexpected argument '{}'", paths[2])))
+    };
+
+    let corpus = fs::read(corpus_path).map_err(|err| CliError::Failed(format!("{}: {}", corpus_path, err)))?;
+    let samples: Vec<&[u8]> = corpus.split_inclusive(|&byte| byte == b'\n').collect();
+    let dict = dictionary::build(&samples, size);
+    fs::write(output_path, &dict)?;
+    println!("{} -> {}: {} samples -> {} byte dictionary (id {:#010x})",
+        corpus_path, output_path, samples.len(), dict.len(), adler32::checksum(&dict));
+
+    // Samples spread across the corpus, zlib compressed each on its own as messages would be
+    let step = (samples.len() / DICTIONARY_TRIAL_SAMPLES).max(1);
+    let trial: Vec<&[u8]> = samples.iter().step_by(step).take(DICTIONARY_TRIAL_SAMPLES).copied().collect();
+    let plain: usize = trial.iter().map(|sample| zlib::compress(sample, deflate::DEFAULT_LEVEL).len()).sum();
+    let primed: usize = trial.iter().map(|sample| zlib::compress_with_dictionary(sample, &dict, deflate::DEFAULT_LEVEL).len()).sum();
+    let original: usize = trial.iter().map(|sample| sample.len()).sum();
+    println!("{} samples, {} bytes: {} bytes compressed alone, {} bytes with the dictionary",
+        trial.len(), original, plain, primed);
+    Ok(())
+}
+
+fn bzip2_file(args: &[String]) -> Result<(), CliError> {
+    let mut level = bzip2::MAX_LEVEL;
+    let mut path = None;
+    for arg in args {
+        match arg.strip_prefix('-').and_then(|digit| digit.parse::<u8>().ok()) {
+            Some(value) if (bzip2::MIN_LEVEL..=bzip2::MAX_LEVEL).contains(&value) => level = value,
+            _ if arg.starts_with('-') => return Err(CliError::Usage(format!("unknown option '{}'", arg))),
+            _ if path.is_none() => path = Some(arg.as_str()),
+            _ => return Err(CliError::Usage(format!("unexpected argument '{}'", arg)))
+        }
+    }
+    let path = path.ok_or_else(|| CliError::Usage("missing file path".to_string()))?;
+
+    let input = fs::read(path).map_err(|err| CliError::Failed(format!("{}: {}", path, err)))?;
+    let output_path = format!("{}.bz2", path);
+    let output = bzip2::compress(&input, level);
+    fs::write(&output_path, &output)?;
+    println!("{} -> {}: {} bytes -> {} bytes", path, output_path, input.len(), output.len());
+    Ok(())
+}
+
+fn bunzip2_file(path: &str, limits: ReadLimits) -> Result<(), CliError> {
+    let output_path = path.strip_suffix(".bz2")
+        .ok_or_else(|| CliError::Failed(format!("{}: expected a .bz2 file", path)))?;
+    let input = fs::read(path).map_err(|err| CliError::Failed(format!("{}: {}", path, err)))?;
+    let (max_output, _) = limits.entry_budget(input.len() as u64, 0);
+    let output = bzip2::decompress(&input, max_output)
+        .map_err(|err| CliError::Failed(format!("{}: {}", path, err)))?;
+    fs::write(output_path, &output)?;
+    println!("{} -> {}: {} bytes -> {} bytes", path, output_path, input.len(), output.len());
+    Ok(())
+}
@@ -0,0 +1,190 @@
+// Optimal parsing for the deflate encoder's ultra level, after zopfli's squeeze.c.
+// Every match at every position is found once; then the cheapest path through them is picked over and over,
+// each time pricing symbols by how often the previous path used them, keeping the smallest result.
+// https://github.com/google/zopfli/blob/master/src/zopfli/squeeze.c
+use crate::deflate::{distance_symbol, length_symbol};
+use crate::huffman::limited_code_lengths;
+use crate::inflate::{DIST_EXTRA, LENGTH_EXTRA, MAX_DIST_CODES, MAX_LIT_CODES};
+use crate::lz77;
+use crate::lz77::{LevelConfig, MatchFinder, Token, MAX_MATCH, MIN_MATCH};
+
+/// Rounds of reparsing against the previous round's statistics, zopfli's default
+const ITERATIONS: usize = 15;
+
+/// Chain links followed per position when collecting matches
+const MAX_CHAIN: usize = 4096;
+
+const END_OF_BLOCK: usize = 256;
+
+/// The matches at every position from `first` on, as `MatchFinder::matches` lists them, flattened
+struct MatchTable {
+    first: usize,
+    starts: Vec<usize>,
+    matches: Vec<(u16, u16)>
+}
+
+impl MatchTable {
+    fn build(data: &[u8], first: usize) -> MatchTable {
+        let config = LevelConfig { good_length: MAX_MATCH, max_lazy: MAX_MATCH, nice_length: MAX_MATCH, max_chain: MAX_CHAIN, lazy: true };
+        let mut finder = MatchFinder::new(data, config);
+        finder.insert_history(first);
+        let mut starts = Vec::with_capacity(data.len() - first + 1);
+        let mut matches = Vec::new();
+        for pos in first..data.len() {
+            starts.push(matches.len());
+            let head = finder.insert(pos);
+            finder.matches(pos, head, &mut matches);
+        }
+        starts.push(matches.len());
+        MatchTable { first, starts, matches }
+    }
+
+    fn at(&self, pos: usize) -> &[(u16, u16)] {
+        let index = pos - self.first;
+        &self.matches[self.starts[index]..self.starts[index + 1]]
+    }
+}
+
+/// Estimated bits per symbol, extra bits not included
+struct CostModel {
+    literal: Vec<f64>,
+    distance: Vec<f64>
+}
+
+impl CostModel {
+    /// Prices each symbol at its entropy under `tokens`' statistics; unused symbols cost as much as one
+    /// seen once, so a path may still pick them up
+    fn from_tokens(tokens: &[Token]) -> CostModel {
+        let (lit_freqs, dist_freqs) = symbol_frequencies(tokens);
+        CostModel { literal: entropy_costs(&lit_freqs), distance: entropy_costs(&dist_freqs) }
+    }
+}
+
+fn entropy_costs(frequencies: &[u64]) -> Vec<f64> {
+    let total: u64 = frequencies.iter().sum();
+    let log_total = (total.max(1) as f64).log2();
+    frequencies.iter().map(|&freq| log_total - (freq.max(1) as f64).log2()).collect()
+}
+
+/// How often each literal/length and distance symbol occurs, counting one end of block
+fn symbol_frequencies(tokens: &[Token]) -> (Vec<u64>, Vec<u64>) {
+    let mut lit_freqs = vec![0u64; MAX_LIT_CODES];
+    let mut dist_freqs = vec![0u64; MAX_DIST_CODES];
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => lit_freqs[byte as usize] += 1,
+            Token::Match { length, distance } => {
+                lit_freqs[257 + length_symbol(length)] += 1;
+                dist_freqs[distance_symbol(distance)] += 1;
+            }
+        }
+    }
+    lit_freqs[END_OF_BLOCK] = 1;
+    (lit_freqs, dist_freqs)
+}
+
+/// Bits the tokens take coded with huffman codes built from their own statistics, leaving out the code
+/// descriptions; what iterations are compared by
+fn coded_bits(tokens: &[Token]) -> u64 {
+    let (lit_freqs, dist_freqs) = symbol_frequencies(tokens);
+    let lit_lengths = limited_code_lengths(&lit_freqs, 15);
+    let dist_lengths = limited_code_lengths(&dist_freqs, 15);
+
+    let mut bits: u64 = lit_freqs.iter().zip(&lit_lengths).map(|(&freq, &len)| freq * len as u64).sum();
+    bits += dist_freqs.iter().zip(&dist_lengths).map(|(&freq, &len)| freq * len as u64).sum::<u64>();
+    for token in tokens {
+        if let Token::Match { length, distance } = *token {
+            bits += LENGTH_EXTRA[length_symbol(length)] as u64 + DIST_EXTRA[distance_symbol(distance)] as u64;
+        }
+    }
+    bits
+}
+
+/// The cheapest path from `table.first` to the end of `data` under `model`
+fn cheapest_parse(data: &[u8], table: &MatchTable, model: &CostModel) -> Vec<Token> {
+    let length_costs: Vec<f64> = (0..=MAX_MATCH).map(|length| {
+        if length < MIN_MATCH {
+            return 0.0;
+        }
+        let symbol = length_symbol(length as u16);
+        model.literal[257 + symbol] + LENGTH_EXTRA[symbol] as f64
+    }).collect();
+
+    // cost[i] is the cheapest way to code the first i bytes past `first`, and step[i] the token that ends it
+    let first = table.first;
+    let bytes = &data[first..];
+    let mut cost = vec![f64::INFINITY; bytes.len() + 1];
+    let mut step = vec![Token::Literal(0); bytes.len() + 1];
+    cost[0] = 0.0;
+
+    for pos in 0..bytes.len() {
+        let base = cost[pos];
+
+        let literal = base + model.literal[bytes[pos] as usize];
+        if literal < cost[pos + 1] {
+            cost[pos + 1] = literal;
+            step[pos + 1] = Token::Literal(bytes[pos]);
+        }
+
+        // Each listed match covers every length from just past the previous one up to its own
+        let mut shortest = MIN_MATCH;
+        for &(max_length, distance) in table.at(first + pos) {
+            let symbol = distance_symbol(distance);
+            let distance_cost = base + model.distance[symbol] + DIST_EXTRA[symbol] as f64;
+            for length in shortest..=max_length as usize {
+                let total = distance_cost + length_costs[length];
+                if total < cost[pos + length] {
+                    cost[pos + length] = total;
+                    step[pos + length] = Token::Match { length: length as u16, distance };
+                }
+            }
+            shortest = max_length as usize + 1;
+        }
+    }
+
+    let mut tokens = Vec::new();
+    let mut pos = bytes.len();
+    while pos > 0 {
+        let token = step[pos];
+        tokens.push(token);
+        pos -= match token {
+            Token::Literal(_) => 1,
+            Token::Match { length, .. } => length as usize
+        };
+    }
+    tokens.reverse();
+    tokens
+}
+
+/// Splits `data` into the literals and matches that code smallest, as near as the iterations get
+pub fn tokenize(data: &[u8]) -> Vec<Token> {
+    tokenize_from(data, 0)
+}
+
+/// Optimally tokenizes `data[start..]`, with matches free to reach back into `data[..start]`
+pub fn tokenize_from(data: &[u8], start: usize) -> Vec<Token> {
+    let table = MatchTable::build(data, start);
+
+    // Start from what the slowest zlib level picks
+    let mut best = lz77::tokenize_from(data, start, 9);
+    let mut best_bits = coded_bits(&best);
+    let mut model = CostModel::from_tokens(&best);
+
+    let mut last: Vec<Token> = Vec::new();
+    for _ in 0..ITERATIONS {
+        let tokens = cheapest_parse(data, &table, &model);
+        // The same parse gives the same statistics, and so the same parse again
+        if tokens == last {
+            break;
+        }
+        let bits = coded_bits(&tokens);
+        model = CostModel::from_tokens(&tokens);
+        if bits < best_bits {
+            best = tokens.clone();
+            best_bits = bits;
+        }
+        last = tokens;
+    }
+
+    best
+}
@@ -0,0 +1,168 @@
+// Streaming use of the DEFLATE coders outside of zip: the flush modes and status shared by
+// inflate::Inflater and deflate::Deflater, and std::io adaptors around them.
+// Flush semantics follow zlib's deflate(): https://www.zlib.net/manual.html
+use std::io;
+use std::io::{Read, Write};
+use crate::deflate::Deflater;
+use crate::inflate::{InflateError, Inflater};
+
+/// Size of the adaptors' internal buffers
+const BUFFER_SIZE: usize = 32 * 1024;
+
+/// How much a `Deflater::process` call should push out
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Flush {
+    /// Hold input back until there's enough to make good blocks
+    None,
+    /// Compress everything so far and byte-align it with an empty stored block, so the other end can
+    /// decode all of it without waiting for more
+    Sync,
+    /// As `Sync`, and forget the window, so decoding could start afresh from here
+    Full,
+    /// Compress everything and end the stream
+    Finish
+}
+
+/// Where a stream stands after a `process` call
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Status {
+    /// More to come: more input, more room for output, or both
+    Ok,
+    /// The stream has ended and every byte of it has been handed over
+    StreamEnd
+}
+
+impl From<InflateError> for io::Error {
+    fn from(err: InflateError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Deflates everything written to it into `inner`. `flush` does a sync flush; call `finish` to end the
+/// stream, since dropping the writer leaves it unfinished.
+pub struct DeflateWriter<W: Write> {
+    inner: W,
+    deflater: Deflater,
+    buffer: Vec<u8>
+}
+
+impl<W: Write> DeflateWriter<W> {
+    pub fn new(inner: W, level: u8) -> DeflateWriter<W> {
+        DeflateWriter { inner, deflater: Deflater::new(level), buffer: vec![0; BUFFER_SIZE] }
+    }
+
+    /// A writer compressing against a preset dictionary
+    pub fn with_dictionary(inner: W, level: u8, dictionary: &[u8]) -> DeflateWriter<W> {
+        DeflateWriter { inner, deflater: Deflater::with_dictionary(level, dictionary), buffer: vec![0; BUFFER_SIZE] }
+    }
+
+    /// Runs `flush` to completion, writing out everything it produces
+    fn drain(&mut self, flush: Flush) -> io::Result<()> {
+        loop {
+            let (_, produced, status) = self.deflater.process(&[], &mut self.buffer, flush);
+            self.inner.write_all(&self.buffer[..produced])?;
+            if status == Status::StreamEnd || produced < self.buffer.len() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Ends the stream and hands back the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.drain(Flush::Finish)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for DeflateWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            let (consumed, produced, _) = self.deflater.process(&buf[written..], &mut self.buffer, Flush::None);
+            self.inner.write_all(&self.buffer[..produced])?;
+            written += consumed;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.drain(Flush::Sync)?;
+        self.inner.flush()
+    }
+}
+
+/// Reads the inflated contents of a raw DEFLATE stream coming from `inner`.
+/// It reads ahead in chunks, so `inner` may be left past the end of the stream; `unused_input` has
+/// whatever was read beyond it.
+pub struct InflateReader<R: Read> {
+    inner: R,
+    inflater: Inflater,
+    buffer: Vec<u8>,
+    start: usize,
+    end: usize
+}
+
+impl<R: Read> InflateReader<R> {
+    pub fn new(inner: R) -> InflateReader<R> {
+        InflateReader::with_inflater(inner, Inflater::new())
+    }
+
+    /// A reader for a stream compressed against a preset dictionary
+    pub fn with_dictionary(inner: R, dictionary: &[u8]) -> InflateReader<R> {
+        InflateReader::with_inflater(inner, Inflater::with_dictionary(dictionary))
+    }
+
+    fn with_inflater(inner: R, inflater: Inflater) -> InflateReader<R> {
+        InflateReader { inner, inflater, buffer: vec![0; BUFFER_SIZE], start: 0, end: 0 }
+    }
+
+    /// Swaps in a fresh decoder primed with `dictionary`; only valid before the first `read`, for
+    /// containers that only learn they need a dictionary from their header
+    pub fn set_dictionary(&mut self, dictionary: &[u8]) {
+        self.inflater = Inflater::with_dictionary(dictionary);
+    }
+
+    /// Bytes read from `inner` past the end of the stream, once it has ended
+    pub fn unused_input(&self) -> Vec<u8> {
+        let mut unused = self.inflater.unused_input().to_vec();
+        unused.extend_from_slice(&self.buffer[self.start..self.end]);
+        unused
+    }
+
+    /// The underlying reader; reading from it directly is only safe before the first `read`, since
+    /// after that some of its data may already be buffered here
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for InflateReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let at_eof = if self.start == self.end && !self.inflater.is_done() {
+                self.start = 0;
+                self.end = self.inner.read(&mut self.buffer)?;
+                self.end == 0
+            } else {
+                false
+            };
+
+            let (consumed, produced, status) = self.inflater.process(&self.buffer[self.start..self.end], buf)?;
+            self.start += consumed;
+            if produced > 0 || status == Status::StreamEnd {
+                return Ok(produced);
+            }
+            if at_eof {
+                return Err(InflateError::UnexpectedEof.into());
+            }
+        }
+    }
+}
@@ -0,0 +1,78 @@
+// Parse events reported while an archive is read, for callers that want to see the structure
+// (hex dumps, debugging malformed archives) without the library printing anything itself
+use std::fmt;
+
+/// The kind of structure a `ParseEvent` covers
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RecordKind {
+    LocalFileHeader,
+    LocalFileName,
+    LocalExtraField,
+    FileData,
+    CentralDirectoryHeader,
+    CentralFileName,
+    CentralExtraField,
+    FileComment,
+    Zip64EndOfCentralDirectory,
+    Zip64EndOfCentralDirectoryLocator,
+    EndOfCentralDirectory,
+    ArchiveComment
+}
+
+impl fmt::Display for RecordKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            RecordKind::LocalFileHeader => "local file header",
+            RecordKind::LocalFileName => "local file name",
+            RecordKind::LocalExtraField => "local extra field",
+            RecordKind::FileData => "file data",
+            RecordKind::CentralDirectoryHeader => "central directory header",
+            RecordKind::CentralFileName => "central file name",
+            RecordKind::CentralExtraField => "central extra field",
+            RecordKind::FileComment => "file comment",
+            RecordKind::Zip64EndOfCentralDirectory => "zip64 end of central directory",
+            RecordKind::Zip64EndOfCentralDirectoryLocator => "zip64 end of central directory locator",
+            RecordKind::EndOfCentralDirectory => "end of central directory",
+            RecordKind::ArchiveComment => "archive comment",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// One structure read from the archive: what it is and which bytes it occupies
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ParseEvent {
+    pub kind: RecordKind,
+    pub offset: u64,
+    pub length: u64,
+    /// Central directory index of the entry the structure belongs to, for per-entry structures
+    pub entry: Option<usize>
+}
+
+/// Receives a `ParseEvent` for every structure as it is read.
+/// Events arrive in the order the reader visits them, which is not file order:
+/// the end records come first, then the central directory, then each entry's local data.
+pub trait ParseObserver {
+    fn record(&mut self, event: &ParseEvent);
+}
+
+/// Closures work as observers
+impl<F: FnMut(&ParseEvent)> ParseObserver for F {
+    fn record(&mut self, event: &ParseEvent) {
+        self(event)
+    }
+}
+
+/// Ignores every event; what archives are opened with unless a caller asks for tracing
+pub struct NoopObserver;
+
+impl ParseObserver for NoopObserver {
+    fn record(&mut self, _event: &ParseEvent) {}
+}
+
+/// Reports an event, skipping empty structures like absent names and comments
+pub(crate) fn emit(observer: &mut dyn ParseObserver, kind: RecordKind, offset: u64, length: u64, entry: Option<usize>) {
+    if length > 0 {
+        observer.record(&ParseEvent { kind, offset, length, entry });
+    }
+}
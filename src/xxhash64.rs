@@ -0,0 +1,125 @@
+// XXH64, the checksum zstd frames end with (its low 32 bits)
+// https://github.com/Cyan4973/xxHash/blob/dev/doc/xxhash_spec.md
+// Four accumulators each take every fourth 8 byte lane of 32 byte stripes, then get merged, the tail is
+// mixed in and the result avalanched.
+
+const PRIME_1: u64 = 0x9E37_79B1_85EB_CA87;
+const PRIME_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const PRIME_3: u64 = 0x1656_67B1_9E37_79F9;
+const PRIME_4: u64 = 0x85EB_CA77_C2B2_AE63;
+const PRIME_5: u64 = 0x27D4_EB2F_1656_67C5;
+
+const STRIPE: usize = 32;
+
+fn round(acc: u64, lane: u64) -> u64 {
+    acc.wrapping_add(lane.wrapping_mul(PRIME_2)).rotate_left(31).wrapping_mul(PRIME_1)
+}
+
+fn merge(acc: u64, value: u64) -> u64 {
+    (acc ^ round(0, value)).wrapping_mul(PRIME_1).wrapping_add(PRIME_4)
+}
+
+fn lane(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7]])
+}
+
+/// Running XXH64, for data that arrives in pieces
+#[derive(Debug, Copy, Clone)]
+pub struct XxHash64 {
+    seed: u64,
+    accumulators: [u64; 4],
+    /// The start of a stripe not yet complete
+    buffer: [u8; STRIPE],
+    buffered: usize,
+    total_len: u64
+}
+
+impl XxHash64 {
+    pub fn new(seed: u64) -> XxHash64 {
+        XxHash64 {
+            seed,
+            accumulators: [
+                seed.wrapping_add(PRIME_1).wrapping_add(PRIME_2),
+                seed.wrapping_add(PRIME_2),
+                seed,
+                seed.wrapping_sub(PRIME_1)
+            ],
+            buffer: [0; STRIPE],
+            buffered: 0,
+            total_len: 0
+        }
+    }
+
+    fn stripe(&mut self, stripe: &[u8]) {
+        for (i, acc) in self.accumulators.iter_mut().enumerate() {
+            *acc = round(*acc, lane(&stripe[i * 8..]));
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+
+        if self.buffered > 0 {
+            let take = (STRIPE - self.buffered).min(data.len());
+            self.buffer[self.buffered..self.buffered + take].copy_from_slice(&data[..take]);
+            self.buffered += take;
+            data = &data[take..];
+            if self.buffered < STRIPE {
+                return;
+            }
+            let buffer = self.buffer;
+            self.stripe(&buffer);
+            self.buffered = 0;
+        }
+
+        let mut stripes = data.chunks_exact(STRIPE);
+        for stripe in &mut stripes {
+            self.stripe(stripe);
+        }
+        let rest = stripes.remainder();
+        self.buffer[..rest.len()].copy_from_slice(rest);
+        self.buffered = rest.len();
+    }
+
+    /// The hash of everything passed to `update` so far
+    pub fn finish(&self) -> u64 {
+        let mut hash = if self.total_len >= STRIPE as u64 {
+            let [v1, v2, v3, v4] = self.accumulators;
+            let hash = v1.rotate_left(1).wrapping_add(v2.rotate_left(7)).wrapping_add(v3.rotate_left(12)).wrapping_add(v4.rotate_left(18));
+            self.accumulators.iter().fold(hash, |hash, &acc| merge(hash, acc))
+        } else {
+            self.seed.wrapping_add(PRIME_5)
+        };
+        hash = hash.wrapping_add(self.total_len);
+
+        let mut tail = &self.buffer[..self.buffered];
+        while tail.len() >= 8 {
+            hash ^= round(0, lane(tail));
+            hash = hash.rotate_left(27).wrapping_mul(PRIME_1).wrapping_add(PRIME_4);
+            tail = &tail[8..];
+        }
+        if tail.len() >= 4 {
+            let word = u32::from_le_bytes([tail[0], tail[1], tail[2], tail[3]]) as u64;
+            hash ^= word.wrapping_mul(PRIME_1);
+            hash = hash.rotate_left(23).wrapping_mul(PRIME_2).wrapping_add(PRIME_3);
+            tail = &tail[4..];
+        }
+        for &byte in tail {
+            hash ^= (byte as u64).wrapping_mul(PRIME_5);
+            hash = hash.rotate_left(11).wrapping_mul(PRIME_1);
+        }
+
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(PRIME_2);
+        hash ^= hash >> 29;
+        hash = hash.wrapping_mul(PRIME_3);
+        hash ^ (hash >> 32)
+    }
+}
+
+/// XXH64 of a complete buffer
+pub fn checksum(data: &[u8], seed: u64) -> u64 {
+    let mut hasher = XxHash64::new(seed);
+    hasher.update(data);
+    hasher.finish()
+}
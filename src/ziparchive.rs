@@ -7,12 +7,155 @@ use std::io::Seek;
 use std::slice;
 use std::mem;
 use std::io::SeekFrom;
+use std::io;
+use std::fmt;
+use std::cell::Cell;
+use std::rc::Rc;
+use crate::codec::{CodecRegistry, CompressionMethod};
+use crate::crc32;
+use crate::dostime::DosDateTime;
+use crate::extrafield;
+use crate::extrafield::{ExtraField, Zip64Fields};
+use crate::inflate::InflateError;
+use crate::bzip2::Bzip2Error;
+use crate::lzma::LzmaError;
+use crate::zstd::ZstdError;
+use crate::legacy::LegacyError;
+use crate::limits::{Limit, ReadLimits};
+use crate::trace;
+use crate::trace::{NoopObserver, ParseObserver, RecordKind};
+
+const LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x04034b50;
+const CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+
+/// The EOCD record is 22 bytes followed by a comment of at most 0xFFFF bytes, so it has to start within this many bytes of the end
+const MAX_EOCD_SEARCH: u64 = 22 + 0xFFFF;
+
+/// Everything that can go wrong reading an archive
+#[derive(Debug)]
+pub enum ZipError {
+    Io(io::Error),
+    /// No end of central directory signature anywhere near the end of the file
+    MissingEndOfCentralDirectory,
+    /// A record didn't start with the signature its type requires
+    InvalidSignature { offset: u64, expected: u32, found: u32 },
+    /// A record claims more bytes than the archive holds
+    Truncated { offset: u64 },
+    /// Two central records point at overlapping local file data, the classic overlapping-entry zip bomb
+    OverlappingEntries { first: usize, second: usize },
+    /// No codec registered for the entry's method
+    UnsupportedMethod(CompressionMethod),
+    NoSuchEntry(usize),
+    /// The decompressed data doesn't match the CRC-32 recorded for it
+    CrcMismatch { index: usize, expected: u32, found: u32 },
+    Inflate(InflateError),
+    Bzip2(Bzip2Error),
+    Lzma(LzmaError),
+    Zstd(ZstdError),
+    /// Unshrinking, unreducing or exploding failed
+    Legacy(LegacyError),
+    LimitExceeded { limit: Limit, max: u64 },
+    /// A decoder was asked for more than its output budget; `read_entry` reports it as the limit that set the budget
+    OutputLimit,
+    /// Whatever went wrong in a codec an application registered
+    Codec(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl fmt::Display for ZipError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ZipError::Io(err) => write!(f, "i/o error: {}", err),
+            ZipError::MissingEndOfCentralDirectory => write!(f, "end of central directory record not found"),
+            ZipError::InvalidSignature { offset, expected, found } => write!(f, "bad signature at offset {:#X}: expected {:#010X}, found {:#010X}", offset, expected, found),
+            ZipError::Truncated { offset } => write!(f, "archive truncated reading record at offset {:#X}", offset),
+            ZipError::OverlappingEntries { first, second } => write!(f, "entries {} and {} overlap", first, second),
+            ZipError::UnsupportedMethod(method) => write!(f, "unsupported compression method {} ({})", method.id(), method.name()),
+            ZipError::NoSuchEntry(index) => write!(f, "no entry at index {}", index),
+            ZipError::CrcMismatch { index, expected, found } => write!(f, "entry {} failed its CRC check: expected {:#010X}, found {:#010X}", index, expected, found),
+            ZipError::Inflate(err) => write!(f, "inflate failed: {}", err),
+            ZipError::Bzip2(err) => write!(f, "bzip2 failed: {}", err),
+            ZipError::Lzma(err) => write!(f, "lzma failed: {}", err),
+            ZipError::Zstd(err) => write!(f, "zstd failed: {}", err),
+            ZipError::Legacy(err) => write!(f, "legacy method failed: {}", err),
+            ZipError::LimitExceeded { limit, max } => write!(f, "{} limit of {} exceeded", limit, max),
+            ZipError::OutputLimit => write!(f, "decompressed data exceeds the size limit"),
+            ZipError::Codec(err) => write!(f, "codec failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ZipError {}
+
+impl From<io::Error> for ZipError {
+    fn from(err: io::Error) -> ZipError {
+        ZipError::Io(err)
+    }
+}
+
+impl From<InflateError> for ZipError {
+    fn from(err: InflateError) -> ZipError {
+        match err {
+            InflateError::OutputLimit => ZipError::OutputLimit,
+            err => ZipError::Inflate(err)
+        }
+    }
+}
+
+impl From<Bzip2Error> for ZipError {
+    fn from(err: Bzip2Error) -> ZipError {
+        match err {
+            Bzip2Error::OutputLimit => ZipError::OutputLimit,
+            err => ZipError::Bzip2(err)
+        }
+    }
+}
+
+impl From<LzmaError> for ZipError {
+    fn from(err: LzmaError) -> ZipError {
+        match err {
+            LzmaError::OutputLimit => ZipError::OutputLimit,
+            err => ZipError::Lzma(err)
+        }
+    }
+}
+
+impl From<ZstdError> for ZipError {
+    fn from(err: ZstdError) -> ZipError {
+        match err {
+            ZstdError::OutputLimit => ZipError::OutputLimit,
+            err => ZipError::Zstd(err)
+        }
+    }
+}
+
+impl From<LegacyError> for ZipError {
+    fn from(err: LegacyError) -> ZipError {
+        match err {
+            LegacyError::OutputLimit => ZipError::OutputLimit,
+            err => ZipError::Legacy(err)
+        }
+    }
+}
+
+/// Reads exactly `length` bytes at `offset`, without trusting `length` for the allocation
+fn read_bytes_at<R: Read + Seek>(file: &mut R, offset: u64, length: u64) -> Result<Vec<u8>, ZipError> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::new();
+    file.take(length).read_to_end(&mut data)?;
+    if (data.len() as u64) < length {
+        return Err(ZipError::Truncated { offset });
+    }
+    Ok(data)
+}
 
 /// Marks the start of a file, and provides the uncompressed data
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone)]
-struct LocalFileHeader {            
-    
+struct LocalFileHeader {
+
                                     // OFFSETS:
     magic_number: u32,              // 0            0x04034b50 (read as a little-endian number)
     version_needed: u16,            // 4
@@ -49,23 +192,21 @@ impl LocalFileHeader{
         }
     }
 
-    pub fn load_data(&mut self, mut file: &std::fs::File, start_offset: u64) -> u64 {
-        println!("Loading LocalFileHeader from offset: {:#X}", start_offset);
+    pub fn load_data<R: Read + Seek>(&mut self, file: &mut R, start_offset: u64) -> Result<u64, ZipError> {
         let data_size = mem::size_of::<LocalFileHeader>();
-        let mut struct_data = vec![0u8; data_size];
-
-        file.seek(SeekFrom::Start(start_offset)).expect("Could not seek to location.");
-        file.read(&mut struct_data).expect("Couldn't read.");
+        let struct_data = read_bytes_at(file, start_offset, data_size as u64)?;
 
         let mut data: LocalFileHeader = LocalFileHeader::new();
         let mut c = Cursor::new(struct_data);
 
         unsafe {
             let data_slice = slice::from_raw_parts_mut(&mut data as *mut _ as *mut u8, data_size);
-            c.read_exact(data_slice).expect("Couldn't read from struct data");
+            c.read_exact(data_slice)?;
         }
 
-        // TODO: Add check for correct magic num/sig here
+        if data.magic_number != LOCAL_FILE_HEADER_SIGNATURE {
+            return Err(ZipError::InvalidSignature { offset: start_offset, expected: LOCAL_FILE_HEADER_SIGNATURE, found: data.magic_number });
+        }
 
         self.magic_number = data.magic_number;
         self.version_needed = data.version_needed;
@@ -80,66 +221,65 @@ impl LocalFileHeader{
         self.extra_field_length = data.extra_field_length;
 
 
-        return start_offset + data_size as u64;
+        Ok(start_offset + data_size as u64)
     }
 }
 
 #[derive(Debug, Clone)]
 struct LocalFile {
     static_data: LocalFileHeader,
+    start_offset: u64,
     data_start_offset: u64,
     file_name_data: Vec<u8>,
-    extra_field: Vec<u8>,
-    compressed_data: Vec<u8>
+    extra_field: Vec<u8>
 }
 
 impl LocalFile {
     pub fn new() -> LocalFile {
         LocalFile {
             static_data: LocalFileHeader::new(),
+            start_offset: 0,
             data_start_offset: 0,
             file_name_data: Vec::new(),
-            extra_field: Vec::new(),
-            compressed_data: Vec::new()
+            extra_field: Vec::new()
         }
     }
 
     /// Load metadata
+    /// `compressed_size` comes from the central record, since the local copy is zero when a data descriptor follows the data.
     /// Returns the offset of the end (start_offset + static_data size + compressed_data_size)
-    pub fn load_metadata(&mut self, mut file: &std::fs::File, start_offset: u64) -> u64 {
+    pub fn load_metadata<R: Read + Seek>(&mut self, file: &mut R, start_offset: u64, compressed_size: u64, index: usize, observer: &mut dyn ParseObserver) -> Result<u64, ZipError> {
         let mut static_data = LocalFileHeader::new();
-        let end_o_static_data = static_data.load_data(&mut file, start_offset);
-
-        let mut file_name = vec![0; static_data.file_name_length as usize];
-        file.seek(SeekFrom::Start(end_o_static_data)).expect("Couldn't seek!");
-        file.read(&mut file_name).expect("Couldn't read");
+        let end_o_static_data = static_data.load_data(file, start_offset)?;
+        trace::emit(observer, RecordKind::LocalFileHeader, start_offset, end_o_static_data - start_offset, Some(index));
 
-        let mut extra_field = vec![0; static_data.extra_field_length as usize];
-        file.read(&mut extra_field).expect("Couldn't read");
+        let file_name = read_bytes_at(file, end_o_static_data, static_data.file_name_length as u64)?;
+        let extra_field = read_bytes_at(file, end_o_static_data + static_data.file_name_length as u64, static_data.extra_field_length as u64)?;
 
         self.static_data = static_data;
-        self.data_start_offset = static_data.file_name_length as u64 + static_data.extra_field_length as u64 + end_o_static_data as u64;
+        self.start_offset = start_offset;
+        self.data_start_offset = static_data.file_name_length as u64 + static_data.extra_field_length as u64 + end_o_static_data;
         self.file_name_data = file_name;
         self.extra_field = extra_field;
 
-        return self.data_start_offset + self.static_data.compressed_size as u64;
-    }
-
-    /// Loads the compressed data for the current LocalFileHeader into memory
-    pub fn load_compressed_data(&mut self, mut file: &std::fs::File){
-        file.seek(SeekFrom::Start(self.data_start_offset)).expect("Couldn't seek");
-        let mut data = vec![0; self.static_data.compressed_size as usize];
-        file.read(&mut data).expect("Couldn't read");
-        self.compressed_data = data;
+        trace::emit(observer, RecordKind::LocalFileName, end_o_static_data, static_data.file_name_length as u64, Some(index));
+        trace::emit(observer, RecordKind::LocalExtraField, end_o_static_data + static_data.file_name_length as u64, static_data.extra_field_length as u64, Some(index));
 
+        Ok(self.data_start_offset + compressed_size)
     }
+
 }
 
+/// Anything an archive can be read out of
+trait Source: Read + Seek {}
+
+impl<R: Read + Seek> Source for R {}
+
 /// The central directory record (CDR) is an expanded form of the local header
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone)]
 struct CentralDirectoryFileHeader {
-    /// The Central Directory Contains multiple CDRs     
+    /// The Central Directory Contains multiple CDRs
                                         // OFFSETS
     magic_number: u32,                  // 0        0x02014b50 (Central directory file header signature)
     version_made_by: u16,               // 4
@@ -188,13 +328,9 @@ impl CentralDirectoryFileHeader{
 
     /// Loads data into a CentralDirecotyFileHeader
     /// Returns where reading stopped. (offset + size of struct)
-    pub fn load_data(&mut self, mut file: &std::fs::File, start_offset: u64) -> u64{
-        println!("Loading CDFR from offset: {:#X}", start_offset);
+    pub fn load_data<R: Read + Seek>(&mut self, file: &mut R, start_offset: u64) -> Result<u64, ZipError> {
         let data_size = mem::size_of::<CentralDirectoryFileHeader>();
-        let mut struct_data = vec![0u8; data_size];
-
-        file.seek(SeekFrom::Start(start_offset)).expect("Couldn't seek to start of CDFR");
-        file.read(&mut struct_data).expect("Couldn't read from file.");
+        let struct_data = read_bytes_at(file, start_offset, data_size as u64)?;
 
         let mut data: CentralDirectoryFileHeader = unsafe { mem::zeroed() };
 
@@ -202,15 +338,13 @@ impl CentralDirectoryFileHeader{
 
         unsafe {
             let data_slice = slice::from_raw_parts_mut(&mut data as *mut _ as *mut u8, data_size);
-            c.read_exact(data_slice).expect("Couldn't read slice data into struct.");
+            c.read_exact(data_slice)?;
         }
 
-        if data.magic_number != 33639248{
-            println!("\tError!! Magic number incorrect!");
+        if data.magic_number != CENTRAL_DIRECTORY_SIGNATURE {
+            return Err(ZipError::InvalidSignature { offset: start_offset, expected: CENTRAL_DIRECTORY_SIGNATURE, found: data.magic_number });
         }
 
-        // println!("Got magic number: {:#X}", data.magic_number);
-
         self.magic_number = data.magic_number;
         self.version_made_by = data.version_made_by;
         self.version_needed = data.version_needed;
@@ -229,12 +363,13 @@ impl CentralDirectoryFileHeader{
         self.external_file_attributes = data.external_file_attributes;
         self.relative_offset_localheader = data.relative_offset_localheader;
 
-        return start_offset + data_size as u64;
+        Ok(start_offset + data_size as u64)
     }
 }
 
 /// A wrapper around CentralDirectoryFileHeader so that we can pac the static stuff, and then manually fill the rest.
 /// Central Directory File Header Record (CDFHR)
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone)]
 struct CDFHR {
     static_data: CentralDirectoryFileHeader,
@@ -242,7 +377,12 @@ struct CDFHR {
     end_offset: u64,
     file_name_data: Vec<u8>,
     extra_field_data: Vec<u8>,
-    file_comment_data: Vec<u8>
+    file_comment_data: Vec<u8>,
+    extra_fields: Vec<ExtraField>,
+    // The header's sizes and offset with any zip64 replacements applied
+    compressed_size: u64,
+    uncompressed_size: u64,
+    local_header_offset: u64
 }
 
 impl CDFHR {
@@ -253,36 +393,60 @@ impl CDFHR {
             end_offset: 0,
             file_name_data: Vec::new(),
             extra_field_data: Vec::new(),
-            file_comment_data: Vec::new()
+            file_comment_data: Vec::new(),
+            extra_fields: Vec::new(),
+            compressed_size: 0,
+            uncompressed_size: 0,
+            local_header_offset: 0
         }
     }
 
     /// Loads the object calling it.
     /// Returns a u64 containg the end position after reading.
-    pub fn load_data(&mut self, mut file: &std::fs::File, start_offset: u64) -> u64{
+    pub fn load_data<R: Read + Seek>(&mut self, file: &mut R, start_offset: u64, index: usize, observer: &mut dyn ParseObserver) -> Result<u64, ZipError> {
         let mut static_data = CentralDirectoryFileHeader::new();
-        let end_static_offset = static_data.load_data(&mut file, start_offset);
-
-        
+        let end_static_offset = static_data.load_data(file, start_offset)?;
 
-        let mut file_name_buf = vec![0; static_data.file_name_length as usize];
-        let mut extra_field_buf = vec![0; static_data.extra_field_length as usize];
-        let mut file_comment_buf = vec![0; static_data.file_comment_length as usize];
+        let file_name_length = static_data.file_name_length as u64;
+        let extra_field_length = static_data.extra_field_length as u64;
+        let file_comment_length = static_data.file_comment_length as u64;
 
-        file.seek(SeekFrom::Start(end_static_offset)).expect("Couldn't seek to end of static offset");
-
-        file.read(&mut file_name_buf).expect("Couldn't read filename");
-        file.read(&mut extra_field_buf).expect("Couldn't read extra field");
-        file.read(&mut file_comment_buf).expect("Couldn't read file comment");
+        let file_name_buf = read_bytes_at(file, end_static_offset, file_name_length)?;
+        let extra_field_buf = read_bytes_at(file, end_static_offset + file_name_length, extra_field_length)?;
+        let file_comment_buf = read_bytes_at(file, end_static_offset + file_name_length + extra_field_length, file_comment_length)?;
 
         self.static_data = static_data;
         self.start_offset = start_offset;
-        self.end_offset = end_static_offset + static_data.file_name_length as u64 + static_data.file_comment_length as u64 + static_data.extra_field_length as u64;
+        self.end_offset = end_static_offset + file_name_length + file_comment_length + extra_field_length;
         self.file_name_data = file_name_buf;
         self.extra_field_data = extra_field_buf;
         self.file_comment_data = file_comment_buf;
 
-        return self.end_offset;
+        trace::emit(observer, RecordKind::CentralDirectoryHeader, start_offset, end_static_offset - start_offset, Some(index));
+        trace::emit(observer, RecordKind::CentralFileName, end_static_offset, file_name_length, Some(index));
+        trace::emit(observer, RecordKind::CentralExtraField, end_static_offset + file_name_length, extra_field_length, Some(index));
+        trace::emit(observer, RecordKind::FileComment, end_static_offset + file_name_length + extra_field_length, file_comment_length, Some(index));
+
+        let zip64 = Zip64Fields {
+            uncompressed_size: static_data.uncompressed_size == u32::MAX,
+            compressed_size: static_data.compressed_size == u32::MAX,
+            local_header_offset: static_data.relative_offset_localheader == u32::MAX,
+            disk_start: static_data.disk_number_source == u16::MAX
+        };
+        self.extra_fields = extrafield::parse(&self.extra_field_data, zip64);
+
+        self.compressed_size = static_data.compressed_size as u64;
+        self.uncompressed_size = static_data.uncompressed_size as u64;
+        self.local_header_offset = static_data.relative_offset_localheader as u64;
+        for field in &self.extra_fields {
+            if let ExtraField::Zip64 { uncompressed_size, compressed_size, local_header_offset, .. } = *field {
+                self.uncompressed_size = uncompressed_size.unwrap_or(self.uncompressed_size);
+                self.compressed_size = compressed_size.unwrap_or(self.compressed_size);
+                self.local_header_offset = local_header_offset.unwrap_or(self.local_header_offset);
+            }
+        }
+
+        Ok(self.end_offset)
     }
 }
 
@@ -314,20 +478,20 @@ struct EofRecord {
 }
 
 impl EofRecord {
-    pub fn new(mut file: &std::fs::File, offset_starting: u64) -> EofRecord {
+    pub fn new<R: Read + Seek>(file: &mut R, offset_starting: u64, observer: &mut dyn ParseObserver) -> Result<EofRecord, ZipError> {
         let mut static_data = EndOfCentralDirectoryRecord::new();
-        let end_offset = static_data.load_data(&mut file, offset_starting);
-        let mut comment_buf = vec![0; static_data.comment_length as usize];
-        file.seek(SeekFrom::Start(end_offset)).expect("Couldn't seek to EOF comment");
-        file.read(&mut comment_buf).expect("Error reading EOF comment");
+        let end_offset = static_data.load_data(file, offset_starting)?;
+        let comment_buf = read_bytes_at(file, end_offset, static_data.comment_length as u64)?;
 
-        return EofRecord{
-            static_data: static_data,
+        trace::emit(observer, RecordKind::EndOfCentralDirectory, offset_starting, end_offset - offset_starting, None);
+        trace::emit(observer, RecordKind::ArchiveComment, end_offset, comment_buf.len() as u64, None);
+
+        Ok(EofRecord{
+            static_data,
             start_offset: offset_starting,
-            end_offset: end_offset,
+            end_offset,
             comment: comment_buf
-        }
-        
+        })
     }
 }
 
@@ -335,22 +499,18 @@ impl EndOfCentralDirectoryRecord {
     /// Reads a binary array into a struct, using the C representaion
     /// Returns a offset of where the reading ended
     /// https://stackoverflow.com/questions/25410028/how-to-read-a-struct-from-a-file-in-rust
-    pub fn load_data(&mut self, mut file: &std::fs::File, offset_starting: u64) -> u64{
-        println!("Loading EOF Record from offset: {:#X}", offset_starting);
+    pub fn load_data<R: Read + Seek>(&mut self, file: &mut R, offset_starting: u64) -> Result<u64, ZipError> {
         let data_size = mem::size_of::<EndOfCentralDirectoryRecord>();
-        let mut struct_data = vec![0u8; data_size];
-
-        file.seek(SeekFrom::Start(offset_starting)).expect("Couldn't seek to start of EOF Record");
-        file.read(&mut struct_data).expect("Couldn't read from file.");
+        let struct_data = read_bytes_at(file, offset_starting, data_size as u64)?;
 
         let mut data: EndOfCentralDirectoryRecord = unsafe {mem::zeroed()};
-        
+
 
         let mut c = Cursor::new(struct_data);
 
         unsafe {
             let data_slice = slice::from_raw_parts_mut(&mut data as *mut _ as *mut u8, data_size);
-            c.read_exact(data_slice).expect("Couldn't read data into struct.");
+            c.read_exact(data_slice)?;
         }
 
         self.magic_number = data.magic_number;
@@ -362,12 +522,12 @@ impl EndOfCentralDirectoryRecord {
         self.offset_cdr_start = data.offset_cdr_start;
         self.comment_length = data.comment_length;
 
-        return offset_starting + data_size as u64;
+        Ok(offset_starting + data_size as u64)
     }
 
     pub fn new() -> EndOfCentralDirectoryRecord{
         EndOfCentralDirectoryRecord{
-            magic_number: 0x06054b50,
+            magic_number: END_OF_CENTRAL_DIRECTORY_SIGNATURE,
             number_of_current_disk: 0,
             disk_where_cdr_starts: 0,
             num_cdr_on_disk: 0,
@@ -379,109 +539,550 @@ impl EndOfCentralDirectoryRecord {
     }
 }
 
-#[derive(Debug)]
-pub struct ZipArchive<'a> {
-    filename: &'a str,
+/// Sits right before the EOCD record when the archive needs zip64, pointing at the zip64 EOCD record
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+struct Zip64EndOfCentralDirectoryLocator {
+                                        // OFFSETS
+    magic_number: u32,                  // 0        0x07064b50
+    disk_with_zip64_record: u32,        // 4
+    offset_zip64_record: u64,           // 8
+    total_disks: u32,                   // 16
+}
+
+/// The zip64 EOCD record: the same information as the EOCD record, widened to 64 bits
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+struct Zip64EndOfCentralDirectoryRecord {
+                                        // OFFSETS
+    magic_number: u32,                  // 0        0x06064b50
+    size_of_record: u64,                // 4        Size of the rest of the record, not counting these first 12 bytes
+    version_made_by: u16,               // 12
+    version_needed: u16,                // 14
+    number_of_current_disk: u32,        // 16
+    disk_where_cdr_starts: u32,         // 20
+    num_cdr_on_disk: u64,               // 24
+    total_cdr: u64,                     // 32
+    size_of_cdr: u64,                   // 40
+    offset_cdr_start: u64,              // 48
+    // extensible_data: Vec<u8>         // 56
+}
+
+impl Zip64EndOfCentralDirectoryLocator {
+    pub fn load_data<R: Read + Seek>(file: &mut R, start_offset: u64) -> Result<Zip64EndOfCentralDirectoryLocator, ZipError> {
+        let data_size = mem::size_of::<Zip64EndOfCentralDirectoryLocator>();
+        let struct_data = read_bytes_at(file, start_offset, data_size as u64)?;
+
+        let mut data: Zip64EndOfCentralDirectoryLocator = unsafe { mem::zeroed() };
+        let mut c = Cursor::new(struct_data);
+
+        unsafe {
+            let data_slice = slice::from_raw_parts_mut(&mut data as *mut _ as *mut u8, data_size);
+            c.read_exact(data_slice)?;
+        }
+
+        Ok(data)
+    }
+}
+
+impl Zip64EndOfCentralDirectoryRecord {
+    pub fn load_data<R: Read + Seek>(file: &mut R, start_offset: u64) -> Result<Zip64EndOfCentralDirectoryRecord, ZipError> {
+        let data_size = mem::size_of::<Zip64EndOfCentralDirectoryRecord>();
+        let struct_data = read_bytes_at(file, start_offset, data_size as u64)?;
+
+        let mut data: Zip64EndOfCentralDirectoryRecord = unsafe { mem::zeroed() };
+        let mut c = Cursor::new(struct_data);
+
+        unsafe {
+            let data_slice = slice::from_raw_parts_mut(&mut data as *mut _ as *mut u8, data_size);
+            c.read_exact(data_slice)?;
+        }
+
+        if data.magic_number != ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE {
+            return Err(ZipError::InvalidSignature { offset: start_offset, expected: ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE, found: data.magic_number });
+        }
+
+        Ok(data)
+    }
+}
+
+/// Both zip64 end records along with where they were found
+#[derive(Debug, Clone)]
+struct Zip64Records {
+    locator_offset: u64,
+    locator: Zip64EndOfCentralDirectoryLocator,
+    record: Zip64EndOfCentralDirectoryRecord
+}
+
+impl Zip64Records {
+    /// Looks for a locator right before the EOCD record, and loads the record it points to if there is one
+    pub fn find<R: Read + Seek>(file: &mut R, eocd_offset: u64, observer: &mut dyn ParseObserver) -> Result<Option<Zip64Records>, ZipError> {
+        let locator_size = mem::size_of::<Zip64EndOfCentralDirectoryLocator>() as u64;
+        if eocd_offset < locator_size {
+            return Ok(None);
+        }
+
+        let locator_offset = eocd_offset - locator_size;
+        let locator = Zip64EndOfCentralDirectoryLocator::load_data(file, locator_offset)?;
+        if locator.magic_number != ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE {
+            return Ok(None);
+        }
+        trace::emit(observer, RecordKind::Zip64EndOfCentralDirectoryLocator, locator_offset, locator_size, None);
+
+        let record = Zip64EndOfCentralDirectoryRecord::load_data(file, locator.offset_zip64_record)?;
+        // size_of_record doesn't count the signature and the size field itself
+        trace::emit(observer, RecordKind::Zip64EndOfCentralDirectory, locator.offset_zip64_record, record.size_of_record.saturating_add(12), None);
+        Ok(Some(Zip64Records { locator_offset, locator, record }))
+    }
+}
+
+/// An entry as described by its central directory record
+#[derive(Debug, Clone)]
+pub struct ZipEntry {
+    pub index: usize,
+    pub name: String,
+    pub compression_method: CompressionMethod,
+    pub crc32: u32,
+    /// Sizes and offset have zip64 replacements applied already
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+    pub modified: DosDateTime,
+    pub dos_date: u16,
+    pub dos_time: u16,
+    pub version_made_by: u16,
+    pub version_needed: u16,
+    pub flags: u16,
+    pub disk_number_start: u16,
+    pub internal_attributes: u16,
+    pub external_attributes: u32,
+    /// Where this entry's central directory record starts
+    pub central_header_offset: u64,
+    pub local_header_offset: u64,
+    /// Where the compressed data starts, just past the local header's name and extra field
+    pub data_offset: u64,
+    pub extra_fields: Vec<ExtraField>,
+    pub comment: String
+}
+
+impl ZipEntry {
+    pub fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+}
+
+/// The end of central directory record's fields
+#[derive(Debug, Clone)]
+pub struct EndRecordInfo {
+    pub offset: u64,
+    pub disk_number: u16,
+    pub central_directory_disk: u16,
+    pub entries_on_disk: u16,
+    pub total_entries: u16,
+    pub central_directory_size: u32,
+    pub central_directory_offset: u32,
+    pub comment: Vec<u8>
+}
+
+/// The zip64 end of central directory locator and record
+#[derive(Debug, Clone)]
+pub struct Zip64EndRecordInfo {
+    pub locator_offset: u64,
+    pub disk_with_record: u32,
+    pub total_disks: u32,
+    pub offset: u64,
+    pub record_size: u64,
+    pub version_made_by: u16,
+    pub version_needed: u16,
+    pub disk_number: u32,
+    pub central_directory_disk: u32,
+    pub entries_on_disk: u64,
+    pub total_entries: u64,
+    pub central_directory_size: u64,
+    pub central_directory_offset: u64
+}
+
+pub struct ZipArchive {
+    filename: String,
+    /// Where the archive came from; compressed data stays here until `read_entry` asks for it
+    source: Box<dyn Source>,
     local_file_data: Vec<LocalFile>,
     central_records: Vec<CDFHR>,
-    eof_record: EofRecord
+    eof_record: EofRecord,
+    zip64_records: Option<Zip64Records>,
+    limits: ReadLimits,
+    codecs: CodecRegistry,
+    /// How many archives this one is nested inside
+    depth: u32,
+    /// Bytes decompressed so far, checked against `limits.max_total_size`. Shared with every archive opened
+    /// with `open_nested`, so nesting can't multiply the budget.
+    total_uncompressed: Rc<Cell<u64>>
+}
+
+impl fmt::Debug for ZipArchive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ZipArchive")
+            .field("filename", &self.filename)
+            .field("local_file_data", &self.local_file_data)
+            .field("central_records", &self.central_records)
+            .field("eof_record", &self.eof_record)
+            .field("zip64_records", &self.zip64_records)
+            .field("limits", &self.limits)
+            .field("codecs", &self.codecs)
+            .field("depth", &self.depth)
+            .field("total_uncompressed", &self.total_uncompressed)
+            .finish()
+    }
 }
 
 
-impl ZipArchive<'_> {
+impl ZipArchive {
 
     /// Find the start offset of the EOFRecord
     /// Returns u64 offset from start of file
-    fn find_eof_start_offset(mut file: &std::fs::File) -> u64{
-        let last_pos = match file.seek(SeekFrom::End(0)) {
-            Err(why) => panic!("Couldn't seek! {}", why.to_string()),
-            Ok(pos) => pos
-        };
+    fn find_eof_start_offset<R: Read + Seek>(file: &mut R) -> Result<u64, ZipError> {
+        let last_pos = file.seek(SeekFrom::End(0))?;
 
-        let eof_record_num:[u8; 4] = [0x50, 0x4b, 0x05, 0x06]; // 0x06054b50 Reversed for lil-endian
-
-        let mut current_index: i64 = 1;
-        while current_index < last_pos as i64 { // basically, this loop moves the read position back 1 byte at a time from the end, until our
-            // four-byte buffer looks like the eof_record_num, which means we have found the start of the EOF record.
-            let mut buffer: [u8; 4] = [0x0; 4];
-            file.seek(SeekFrom::End(-current_index)).unwrap();
-            file.read(&mut buffer[..]).unwrap();
-            if &eof_record_num[..] == &buffer[..] {
-                println!("Found magic number for EOF structure at offset {:#X}", last_pos-current_index as u64);
-                break;
-            }
-            current_index = current_index + 1;
+        let eof_record_num:[u8; 4] = END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes();
+
+        // Only the tail of the file can hold the record, so read that once and scan it backwards for the signature
+        let search_start = last_pos.saturating_sub(MAX_EOCD_SEARCH);
+        let tail = read_bytes_at(file, search_start, last_pos - search_start)?;
+        let found = tail.windows(4).rposition(|window| window == eof_record_num);
+
+        match found {
+            Some(index) => Ok(search_start + index as u64),
+            None => Err(ZipError::MissingEndOfCentralDirectory)
         }
+    }
 
-        let eofdirectory_offset: u64 = last_pos - current_index as u64;
-        return eofdirectory_offset;
+    /// Creates a new ZipArchive given a filename, using the default `ReadLimits`
+    pub fn new(filename: &str) -> Result<ZipArchive, ZipError> {
+        ZipArchive::with_limits(filename, ReadLimits::default())
     }
 
-    /// Creates a new ZipArchive given a filename
-    pub fn new(filename: &str) -> ZipArchive{
-        println!("New ZipArchive! {}", filename);
+    /// Creates a new ZipArchive given a filename, enforcing `limits` on everything read from it
+    pub fn with_limits(filename: &str, limits: ReadLimits) -> Result<ZipArchive, ZipError> {
         let path = Path::new(filename);
-        let mut file = match File::open(path) {
-            Err(why) => panic!("Couldn't open {}: {}", path.display(), why.to_string()),
-            Ok(file) => file
+        let file = File::open(path)?;
+
+        ZipArchive::load(Box::new(file), filename, limits, 0, &mut NoopObserver)
+    }
+
+    /// Reads an archive out of anything seekable, such as an in-memory buffer.
+    /// The archive keeps `reader` and reads each entry's data from it in `read_entry`.
+    pub fn from_reader<R: Read + Seek + 'static>(reader: R, name: &str, limits: ReadLimits) -> Result<ZipArchive, ZipError> {
+        ZipArchive::load(Box::new(reader), name, limits, 0, &mut NoopObserver)
+    }
+
+    /// Like `from_reader`, reporting every structure read to `observer`
+    pub fn from_reader_traced<R: Read + Seek + 'static>(reader: R, name: &str, limits: ReadLimits, observer: &mut dyn ParseObserver) -> Result<ZipArchive, ZipError> {
+        ZipArchive::load(Box::new(reader), name, limits, 0, observer)
+    }
+
+    fn load(mut source: Box<dyn Source>, name: &str, limits: ReadLimits, depth: u32, observer: &mut dyn ParseObserver) -> Result<ZipArchive, ZipError> {
+        let file = &mut source;
+        let eofdirectory_offset = ZipArchive::find_eof_start_offset(file)?;
+        let eof_record = EofRecord::new(file, eofdirectory_offset, observer)?;
+        let zip64_records = Zip64Records::find(file, eofdirectory_offset, observer)?;
+
+        // The zip64 record, when there is one, holds the real values for anything saturated in the EOCD record
+        let (entries_on_disk, total_entries, cd_start) = match &zip64_records {
+            Some(zip64) => (zip64.record.num_cdr_on_disk, zip64.record.total_cdr, zip64.record.offset_cdr_start),
+            None => (eof_record.static_data.num_cdr_on_disk as u64, eof_record.static_data.total_cdr as u64, eof_record.static_data.offset_cdr_start as u64)
         };
 
-        let eofdirectory_offset = ZipArchive::find_eof_start_offset(&mut file);
-        let eof_record = EofRecord::new(&mut file, eofdirectory_offset);
+        if total_entries.max(entries_on_disk) > limits.max_entries {
+            return Err(ZipError::LimitExceeded { limit: Limit::Entries, max: limits.max_entries });
+        }
 
         let mut cdrs: Vec<CDFHR> = Vec::new();
-        let mut last_cdfr_offset: u64 = eof_record.static_data.offset_cdr_start as u64;
+        let mut last_cdfr_offset: u64 = cd_start;
         // Load the CDR structures
-        for _ in 0..eof_record.static_data.num_cdr_on_disk{
+        for index in 0..entries_on_disk as usize {
             let mut cdfhr = CDFHR::new();
-            let new_offset = cdfhr.load_data(&mut file, last_cdfr_offset);
+            let new_offset = cdfhr.load_data(file, last_cdfr_offset, index, observer)?;
             last_cdfr_offset = new_offset;
             cdrs.push(cdfhr);
         }
 
+        // Only the headers are read here; every entry's extent is checked before any of its data is touched
+        let file_end = file.seek(SeekFrom::End(0))?;
         let mut lfh: Vec<LocalFile> = Vec::new();
-        for cdr in &cdrs {
+        let mut ranges: Vec<(u64, u64, usize)> = Vec::new();
+        for (index, cdr) in cdrs.iter().enumerate() {
+            let compressed_size = cdr.compressed_size;
             let mut localfile = LocalFile::new();
-            localfile.load_metadata(&mut file, cdr.static_data.relative_offset_localheader as u64);
-            localfile.load_compressed_data(&mut file);
+            let end_offset = localfile.load_metadata(file, cdr.local_header_offset, compressed_size, index, observer)?;
+            if end_offset > file_end {
+                return Err(ZipError::Truncated { offset: localfile.data_start_offset });
+            }
+            trace::emit(observer, RecordKind::FileData, localfile.data_start_offset, compressed_size, Some(index));
+            ranges.push((localfile.start_offset, end_offset, index));
             lfh.push(localfile);
         }
 
-        println!("Zip metadata loaded.");
-        return ZipArchive{
-            filename: filename,
+        ZipArchive::check_overlaps(&mut ranges)?;
+
+        Ok(ZipArchive{
+            filename: name.to_string(),
+            source,
             local_file_data: lfh,
             central_records: cdrs,
-            eof_record: eof_record
-        };
+            eof_record,
+            zip64_records,
+            limits,
+            codecs: CodecRegistry::default(),
+            depth,
+            total_uncompressed: Rc::new(Cell::new(0))
+        })
     }
 
-    pub fn print_eof(self){
-        println!("EofRecord: {:#?}", self.eof_record);
+    /// Rejects archives where two central records share local file bytes.
+    /// Each range is (local header offset, end of compressed data, entry index).
+    fn check_overlaps(ranges: &mut [(u64, u64, usize)]) -> Result<(), ZipError> {
+        ranges.sort();
+        for pair in ranges.windows(2) {
+            let (_, first_end, first) = pair[0];
+            let (second_start, _, second) = pair[1];
+            if second_start < first_end {
+                return Err(ZipError::OverlappingEntries { first: first.min(second), second: first.max(second) });
+            }
+        }
+        Ok(())
     }
 
-    pub fn test_cdr_read(self){
-        let start_offset = self.eof_record.static_data.offset_cdr_start;
+    /// Number of entries in the central directory
+    pub fn len(&self) -> usize {
+        self.central_records.len()
+    }
 
-        let path = Path::new(self.filename);
-        let mut file = match File::open(path) {
-            Err(why) => panic!("Couldn't open {}: {}", path.display(), why.to_string()),
-            Ok(file) => file
+    pub fn is_empty(&self) -> bool {
+        self.central_records.is_empty()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.filename
+    }
+
+    /// The codecs `read_entry` decompresses with
+    pub fn codecs(&self) -> &CodecRegistry {
+        &self.codecs
+    }
+
+    /// Decompresses with `codecs` from now on, for methods rip doesn't implement itself
+    pub fn set_codecs(&mut self, codecs: CodecRegistry) {
+        self.codecs = codecs;
+    }
+
+    /// Describes the entry at `index`, if there is one
+    pub fn entry(&self, index: usize) -> Option<ZipEntry> {
+        let cdr = self.central_records.get(index)?;
+        let data = cdr.static_data;
+        Some(ZipEntry {
+            index,
+            name: String::from_utf8_lossy(&cdr.file_name_data).into_owned(),
+            compression_method: CompressionMethod::from_id(data.compression_method),
+            crc32: data.crc32_uncompressed,
+            compressed_size: cdr.compressed_size,
+            uncompressed_size: cdr.uncompressed_size,
+            modified: DosDateTime::from_dos(data.last_modify_date, data.last_modify_time),
+            dos_date: data.last_modify_date,
+            dos_time: data.last_modify_time,
+            version_made_by: data.version_made_by,
+            version_needed: data.version_needed,
+            flags: data.spacer_unused,
+            disk_number_start: data.disk_number_source,
+            internal_attributes: data.internal_file_attributes,
+            external_attributes: data.external_file_attributes,
+            central_header_offset: cdr.start_offset,
+            local_header_offset: cdr.local_header_offset,
+            data_offset: self.local_file_data[index].data_start_offset,
+            extra_fields: cdr.extra_fields.clone(),
+            comment: String::from_utf8_lossy(&cdr.file_comment_data).into_owned()
+        })
+    }
+
+    pub fn entries(&self) -> Vec<ZipEntry> {
+        (0..self.len()).filter_map(|index| self.entry(index)).collect()
+    }
+
+    pub fn end_record(&self) -> EndRecordInfo {
+        let data = self.eof_record.static_data;
+        EndRecordInfo {
+            offset: self.eof_record.start_offset,
+            disk_number: data.number_of_current_disk,
+            central_directory_disk: data.disk_where_cdr_starts,
+            entries_on_disk: data.num_cdr_on_disk,
+            total_entries: data.total_cdr,
+            central_directory_size: data.size_of_cdr,
+            central_directory_offset: data.offset_cdr_start,
+            comment: self.eof_record.comment.clone()
+        }
+    }
+
+    /// The zip64 end records, for archives that have them
+    pub fn zip64_end_record(&self) -> Option<Zip64EndRecordInfo> {
+        let zip64 = self.zip64_records.as_ref()?;
+        let locator = zip64.locator;
+        let record = zip64.record;
+        Some(Zip64EndRecordInfo {
+            locator_offset: zip64.locator_offset,
+            disk_with_record: locator.disk_with_zip64_record,
+            total_disks: locator.total_disks,
+            offset: locator.offset_zip64_record,
+            record_size: record.size_of_record,
+            version_made_by: record.version_made_by,
+            version_needed: record.version_needed,
+            disk_number: record.number_of_current_disk,
+            central_directory_disk: record.disk_where_cdr_starts,
+            entries_on_disk: record.num_cdr_on_disk,
+            total_entries: record.total_cdr,
+            central_directory_size: record.size_of_cdr,
+            central_directory_offset: record.offset_cdr_start
+        })
+    }
+
+    /// Decompresses the entry at `index`, checking the result against its recorded CRC-32.
+    /// The size and ratio limits are enforced while decompressing, and whatever is produced counts against the archive's total.
+    pub fn read_entry(&mut self, index: usize) -> Result<Vec<u8>, ZipError> {
+        let entry = self.entry(index).ok_or(ZipError::NoSuchEntry(index))?;
+        let codec = self.codecs.get(entry.compression_method).ok_or(ZipError::UnsupportedMethod(entry.compression_method))?;
+        let compressed = read_bytes_at(&mut self.source, entry.data_offset, entry.compressed_size)?;
+
+        let (budget, limit) = self.limits.entry_budget(compressed.len() as u64, self.total_uncompressed.get());
+        let data = match codec.decoder(&entry).decode(&compressed, budget) {
+            Ok(data) => data,
+            Err(ZipError::OutputLimit) => return Err(ZipError::LimitExceeded { limit, max: self.limits.value_of(limit) }),
+            Err(err) => return Err(err)
         };
 
-        let mut x = CDFHR::new();
-        let mut y = CDFHR::new();
-        let _done = x.load_data(&mut file, start_offset as u64);
-        let _done2 = y.load_data(&mut file, _done as u64);
-        println!("Data1: {:#?}", x);
-        let filename1 = std::str::from_utf8(&x.file_name_data).expect("Couldn't convert bytes to utf8");
-        println!("Data1 file: {}", filename1);
-        println!("Data2: {:#?}", y);
-        let filename2 = std::str::from_utf8(&y.file_name_data).expect("Couldn't convert bytes to utf8");
-        println!("Data2 file: {}", filename2);
+        self.total_uncompressed.set(self.total_uncompressed.get() + data.len() as u64);
+
+        let expected = entry.crc32;
+        let found = crc32::checksum(&data);
+        if found != expected {
+            return Err(ZipError::CrcMismatch { index, expected, found });
+        }
+
+        Ok(data)
+    }
+
+    /// Opens the entry at `index` as an archive of its own.
+    /// The nested archive draws on the same total size budget as this one, and has one less level of nesting.
+    pub fn open_nested(&mut self, index: usize) -> Result<ZipArchive, ZipError> {
+        if self.depth >= self.limits.max_nesting {
+            return Err(ZipError::LimitExceeded { limit: Limit::Nesting, max: self.limits.max_nesting as u64 });
+        }
+
+        let data = self.read_entry(index)?;
+        let name = format!("{}/{}", self.filename, String::from_utf8_lossy(&self.central_records[index].file_name_data));
+
+        let mut nested = ZipArchive::load(Box::new(Cursor::new(data)), &name, self.limits, self.depth + 1, &mut NoopObserver)?;
+        nested.codecs = self.codecs.clone();
+        nested.total_uncompressed = Rc::clone(&self.total_uncompressed);
+        Ok(nested)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zipwriter::ZipWriter;
+
+    fn zip_of(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for &(name, data) in files {
+            writer.add_file(name, data, DosDateTime::from_unix(0)).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn open(data: Vec<u8>, limits: ReadLimits) -> Result<ZipArchive, ZipError> {
+        ZipArchive::from_reader(Cursor::new(data), "test.zip", limits)
     }
 
-    pub fn print_all_data(self){
-        println!("Data: {:#?}", self);
+    fn limit_of(err: ZipError) -> Limit {
+        match err {
+            ZipError::LimitExceeded { limit, .. } => limit,
+            other => panic!("expected a limit error, got {}", other)
+        }
+    }
+
+    #[test]
+    fn rejects_two_records_sharing_a_local_header() {
+        let single = zip_of(&[("a.txt", b"hello")]);
+        let cd_start = single.windows(4).position(|window| window == CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes()).unwrap();
+        let eocd_start = single.len() - 22;
+        let record = &single[cd_start..eocd_start];
+
+        let mut data = single[..cd_start].to_vec();
+        data.extend_from_slice(record);
+        data.extend_from_slice(record);
+        let mut eocd = single[eocd_start..].to_vec();
+        eocd[8..10].copy_from_slice(&2u16.to_le_bytes());
+        eocd[10..12].copy_from_slice(&2u16.to_le_bytes());
+        eocd[12..16].copy_from_slice(&(2 * record.len() as u32).to_le_bytes());
+        data.extend_from_slice(&eocd);
+
+        match open(data, ReadLimits::default()) {
+            Err(ZipError::OverlappingEntries { first: 0, second: 1 }) => {},
+            other => panic!("expected overlapping entries, got {:?}", other.map(|archive| archive.len()))
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn limits_the_number_of_entries() {
+        let data = zip_of(&[("a", b"1"), ("b", b"2"), ("c", b"3")]);
+        let limits = ReadLimits { max_entries: 2, ..ReadLimits::default() };
+        assert_eq!(limit_of(open(data.clone(), limits).unwrap_err()), Limit::Entries);
+
+        let limits = ReadLimits { max_entries: 3, ..ReadLimits::default() };
+        assert_eq!(open(data, limits).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn limits_the_compression_ratio() {
+        let limits = ReadLimits { max_ratio: 100, ..ReadLimits::default() };
+        let mut archive = open(zip_of(&[("zeros", &[0; 200_000])]), limits).unwrap();
+        assert_eq!(limit_of(archive.read_entry(0).unwrap_err()), Limit::Ratio);
+
+        // Small entries get to compress as well as they like
+        let limits = ReadLimits { max_ratio: 1, ..ReadLimits::default() };
+        let mut archive = open(zip_of(&[("zeros", &[0; 60_000])]), limits).unwrap();
+        assert_eq!(archive.read_entry(0).unwrap().len(), 60_000);
+    }
+
+    #[test]
+    fn limits_the_entry_size() {
+        let limits = ReadLimits { max_entry_size: 999, ..ReadLimits::unlimited() };
+        let mut archive = open(zip_of(&[("small", &[7; 999]), ("large", &[7; 1000])]), limits).unwrap();
+        assert_eq!(archive.read_entry(0).unwrap().len(), 999);
+        assert_eq!(limit_of(archive.read_entry(1).unwrap_err()), Limit::EntrySize);
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let innermost = zip_of(&[("file", b"data")]);
+        let middle = zip_of(&[("innermost.zip", &innermost)]);
+        let outer = zip_of(&[("middle.zip", &middle)]);
+
+        let limits = ReadLimits { max_nesting: 1, ..ReadLimits::default() };
+        let mut archive = open(outer, limits).unwrap();
+        let mut nested = archive.open_nested(0).unwrap();
+        assert_eq!(nested.name(), "test.zip/middle.zip");
+        assert_eq!(limit_of(nested.open_nested(0).unwrap_err()), Limit::Nesting);
+    }
+
+    #[test]
+    fn nested_archives_share_the_total_size_budget() {
+        let inner = zip_of(&[("text", &[b'x'; 1000])]);
+        let outer = zip_of(&[("first.zip", &inner), ("second.zip", &inner)]);
+
+        let limits = ReadLimits { max_total_size: 2000, ..ReadLimits::unlimited() };
+        let mut archive = open(outer, limits).unwrap();
+        let mut first = archive.open_nested(0).unwrap();
+        assert_eq!(first.read_entry(0).unwrap().len(), 1000);
+        let mut second = archive.open_nested(1).unwrap();
+        assert_eq!(limit_of(second.read_entry(0).unwrap_err()), Limit::TotalSize);
+    }
+}
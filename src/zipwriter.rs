@@ -0,0 +1,216 @@
+// Writes zip archives: local headers and data as entries are added, then the central directory on finish
+// https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT
+use std::io;
+use std::io::Write;
+use std::convert::TryFrom;
+use crate::codec::{CodecRegistry, CompressionMethod};
+use crate::crc32;
+use crate::deflate;
+use crate::dostime::DosDateTime;
+
+const LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x04034b50;
+const CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+
+/// Version 2.0, the first to have directories and deflate
+const VERSION_MADE_BY: u16 = 20;
+
+/// MS-DOS directory attribute bit
+const DIRECTORY_ATTRIBUTE: u32 = 0x10;
+
+/// What the central directory needs to remember about an entry once its data has been written
+#[derive(Debug, Clone)]
+struct WrittenEntry {
+    name: Vec<u8>,
+    method: CompressionMethod,
+    version_needed: u16,
+    flags: u16,
+    dos_date: u16,
+    dos_time: u16,
+    crc32: u32,
+    compressed_size: u32,
+    uncompressed_size: u32,
+    external_attributes: u32,
+    local_header_offset: u32
+}
+
+pub struct ZipWriter<W: Write> {
+    inner: W,
+    offset: u64,
+    entries: Vec<WrittenEntry>,
+    comment: Vec<u8>,
+    codecs: CodecRegistry,
+    method: CompressionMethod,
+    level: u8
+}
+
+fn too_large(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{} too large for a zip without zip64", what))
+}
+
+fn cant_compress(method: CompressionMethod) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("can't compress with method {} ({})", method.id(), method.name()))
+}
+
+impl<W: Write> ZipWriter<W> {
+    pub fn new(inner: W) -> ZipWriter<W> {
+        ZipWriter {
+            inner,
+            offset: 0,
+            entries: Vec::new(),
+            comment: Vec::new(),
+            codecs: CodecRegistry::default(),
+            method: CompressionMethod::Deflated,
+            level: deflate::DEFAULT_LEVEL
+        }
+    }
+
+    /// Sets the deflate level for files added from now on: 0 stores them, 1-9 trade speed for size,
+    /// and `deflate::ULTRA_LEVEL` squeezes out what it can however slowly
+    pub fn set_level(&mut self, level: u8) {
+        self.level = level.min(deflate::ULTRA_LEVEL);
+    }
+
+    /// Sets how files added from now on are compressed, any method with a codec that can encode: deflate,
+    /// bzip2, LZMA and zstd unless `set_codecs` adds more. With bzip2 the level picks the block size, 1-9
+    /// times 100k; with LZMA the dictionary size and search effort; with zstd the same match search as deflate.
+    pub fn set_method(&mut self, method: CompressionMethod) -> io::Result<()> {
+        if self.codecs.get(method).and_then(|codec| codec.encoder(self.level)).is_none() {
+            return Err(cant_compress(method));
+        }
+        self.method = method;
+        Ok(())
+    }
+
+    /// Compresses with `codecs` from now on, for methods rip doesn't implement itself
+    pub fn set_codecs(&mut self, codecs: CodecRegistry) {
+        self.codecs = codecs;
+    }
+
+    /// Sets the archive comment written into the end of central directory record
+    pub fn set_comment(&mut self, comment: &[u8]) {
+        self.comment = comment.to_vec();
+    }
+
+    /// Adds `data` under `name`, compressed with the writer's method and level, or stored when that doesn't
+    /// make it smaller
+    pub fn add_file(&mut self, name: &str, data: &[u8], modified: DosDateTime) -> io::Result<()> {
+        let crc = crc32::checksum(data);
+        let size = u32::try_from(data.len()).map_err(|_| too_large("entry"))?;
+
+        if self.level > 0 && self.method != CompressionMethod::Stored {
+            // set_level or set_codecs may have changed what the method can do since set_method
+            let codec = self.codecs.get(self.method).ok_or_else(|| cant_compress(self.method))?;
+            let mut encoder = codec.encoder(self.level).ok_or_else(|| cant_compress(self.method))?;
+            let (version_needed, flags) = (codec.version_needed(), codec.flags());
+            let compressed = encoder.encode(data)?;
+            if compressed.len() < data.len() {
+                let entry = self.entry_for(name, modified, self.method, version_needed, flags, 0)?;
+                return self.write_entry(entry, crc, size, &compressed);
+            }
+        }
+
+        let entry = self.entry_for(name, modified, CompressionMethod::Stored, 10, 0, 0)?;
+        self.write_entry(entry, crc, size, data)
+    }
+
+    /// Adds a directory entry; `name` gets a trailing slash if it doesn't have one
+    pub fn add_directory(&mut self, name: &str, modified: DosDateTime) -> io::Result<()> {
+        let name = if name.ends_with('/') { name.to_string() } else { format!("{}/", name) };
+        let entry = self.entry_for(&name, modified, CompressionMethod::Stored, 20, 0, DIRECTORY_ATTRIBUTE)?;
+        self.write_entry(entry, 0, 0, &[])
+    }
+
+    fn entry_for(&self, name: &str, modified: DosDateTime, method: CompressionMethod, version_needed: u16, flags: u16, external_attributes: u32) -> io::Result<WrittenEntry> {
+        if name.len() > u16::MAX as usize {
+            return Err(too_large("file name"));
+        }
+        let local_header_offset = u32::try_from(self.offset).map_err(|_| too_large("archive"))?;
+        let (dos_date, dos_time) = modified.to_dos();
+        Ok(WrittenEntry {
+            name: name.as_bytes().to_vec(),
+            method,
+            version_needed,
+            flags,
+            dos_date,
+            dos_time,
+            crc32: 0,
+            compressed_size: 0,
+            uncompressed_size: 0,
+            external_attributes,
+            local_header_offset
+        })
+    }
+
+    fn write_entry(&mut self, mut entry: WrittenEntry, crc: u32, uncompressed_size: u32, compressed: &[u8]) -> io::Result<()> {
+        entry.crc32 = crc;
+        entry.uncompressed_size = uncompressed_size;
+        entry.compressed_size = u32::try_from(compressed.len()).map_err(|_| too_large("entry"))?;
+
+        let mut header: Vec<u8> = Vec::with_capacity(30 + entry.name.len());
+        header.extend_from_slice(&LOCAL_FILE_HEADER_SIGNATURE.to_le_bytes());
+        header.extend_from_slice(&entry.version_needed.to_le_bytes());
+        header.extend_from_slice(&entry.flags.to_le_bytes());
+        header.extend_from_slice(&entry.method.id().to_le_bytes());
+        header.extend_from_slice(&entry.dos_time.to_le_bytes());
+        header.extend_from_slice(&entry.dos_date.to_le_bytes());
+        header.extend_from_slice(&entry.crc32.to_le_bytes());
+        header.extend_from_slice(&entry.compressed_size.to_le_bytes());
+        header.extend_from_slice(&entry.uncompressed_size.to_le_bytes());
+        header.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes()); // extra field length
+        header.extend_from_slice(&entry.name);
+
+        self.inner.write_all(&header)?;
+        self.inner.write_all(compressed)?;
+        self.offset += header.len() as u64 + compressed.len() as u64;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Writes the central directory and end record, handing back the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        let cd_start = u32::try_from(self.offset).map_err(|_| too_large("archive"))?;
+        if self.entries.len() > u16::MAX as usize {
+            return Err(too_large("entry count"));
+        }
+
+        let mut directory: Vec<u8> = Vec::new();
+        for entry in &self.entries {
+            directory.extend_from_slice(&CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+            directory.extend_from_slice(&VERSION_MADE_BY.to_le_bytes());
+            directory.extend_from_slice(&entry.version_needed.to_le_bytes());
+            directory.extend_from_slice(&entry.flags.to_le_bytes());
+            directory.extend_from_slice(&entry.method.id().to_le_bytes());
+            directory.extend_from_slice(&entry.dos_time.to_le_bytes());
+            directory.extend_from_slice(&entry.dos_date.to_le_bytes());
+            directory.extend_from_slice(&entry.crc32.to_le_bytes());
+            directory.extend_from_slice(&entry.compressed_size.to_le_bytes());
+            directory.extend_from_slice(&entry.uncompressed_size.to_le_bytes());
+            directory.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            directory.extend_from_slice(&0u16.to_le_bytes()); // extra field length
+            directory.extend_from_slice(&0u16.to_le_bytes()); // comment length
+            directory.extend_from_slice(&0u16.to_le_bytes()); // disk number start
+            directory.extend_from_slice(&0u16.to_le_bytes()); // internal attributes
+            directory.extend_from_slice(&entry.external_attributes.to_le_bytes());
+            directory.extend_from_slice(&entry.local_header_offset.to_le_bytes());
+            directory.extend_from_slice(&entry.name);
+        }
+        let cd_size = u32::try_from(directory.len()).map_err(|_| too_large("central directory"))?;
+        let entry_count = self.entries.len() as u16;
+
+        directory.extend_from_slice(&END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+        directory.extend_from_slice(&0u16.to_le_bytes()); // this disk
+        directory.extend_from_slice(&0u16.to_le_bytes()); // disk where the central directory starts
+        directory.extend_from_slice(&entry_count.to_le_bytes());
+        directory.extend_from_slice(&entry_count.to_le_bytes());
+        directory.extend_from_slice(&cd_size.to_le_bytes());
+        directory.extend_from_slice(&cd_start.to_le_bytes());
+        directory.extend_from_slice(&(self.comment.len().min(u16::MAX as usize) as u16).to_le_bytes());
+        directory.extend_from_slice(&self.comment[..self.comment.len().min(u16::MAX as usize)]);
+
+        self.inner.write_all(&directory)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
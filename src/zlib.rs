@@ -0,0 +1,487 @@
+// zlib stream format, RFC 1950 https://tools.ietf.org/html/rfc1950
+// Two header bytes (CMF, FLG), a DICTID when FDICT is set, the raw DEFLATE stream, then the Adler-32 of
+// the uncompressed data, big-endian. PNG chunks and git objects are framed this way.
+// A preset dictionary primes the window before the stream starts, so even the first bytes of a short
+// message can be coded as matches; both ends must agree on it, which DICTID checks.
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use crate::adler32;
+use crate::adler32::Adler32;
+use crate::deflate;
+use crate::inflate;
+use crate::inflate::InflateError;
+use crate::stream::{DeflateWriter, InflateReader};
+
+/// CM = 8, deflate, the only method RFC 1950 defines
+const METHOD_DEFLATE: u8 = 8;
+/// CINFO: log2 of the window size, less 8. 7 is the 32 KiB window deflate uses.
+const MAX_WINDOW_BITS: u8 = 7;
+const FDICT: u8 = 0x20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ZlibError {
+    /// CMF and FLG aren't a multiple of 31 when read as a big-endian u16
+    InvalidHeader,
+    /// A compression method other than deflate
+    UnsupportedMethod(u8),
+    /// CINFO asks for a window bigger than 32 KiB
+    InvalidWindowSize(u8),
+    /// The stream was compressed against a preset dictionary with this Adler-32, which wasn't supplied
+    DictionaryRequired(u32),
+    /// The dictionary supplied isn't the one the stream was compressed against
+    WrongDictionary { expected: u32, found: u32 },
+    /// The data ended before the header did
+    TruncatedHeader,
+    /// The stream ended before its Adler-32 trailer
+    TruncatedTrailer,
+    ChecksumMismatch { expected: u32, found: u32 },
+    Inflate(InflateError)
+}
+
+impl fmt::Display for ZlibError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ZlibError::InvalidHeader => write!(f, "incorrect zlib header check"),
+            ZlibError::UnsupportedMethod(method) => write!(f, "unsupported compression method {}", method),
+            ZlibError::InvalidWindowSize(bits) => write!(f, "invalid window size (CINFO {})", bits),
+            ZlibError::DictionaryRequired(id) => write!(f, "stream needs a preset dictionary (id {:#010X})", id),
+            ZlibError::WrongDictionary { expected, found } =>
+                write!(f, "wrong preset dictionary (expected id {:#010X}, found {:#010X})", expected, found),
+            ZlibError::TruncatedHeader => write!(f, "truncated zlib header"),
+            ZlibError::TruncatedTrailer => write!(f, "truncated zlib trailer"),
+            ZlibError::ChecksumMismatch { expected, found } =>
+                write!(f, "Adler-32 mismatch (expected {:#010X}, found {:#010X})", expected, found),
+            ZlibError::Inflate(err) => write!(f, "{}", err)
+        }
+    }
+}
+
+impl std::error::Error for ZlibError {}
+
+impl From<InflateError> for ZlibError {
+    fn from(err: InflateError) -> ZlibError {
+        ZlibError::Inflate(err)
+    }
+}
+
+impl From<ZlibError> for io::Error {
+    fn from(err: ZlibError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// What a zlib header says about the stream after it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ZlibHeader {
+    /// Window size as a power of two, 8 to 15
+    pub window_bits: u8,
+    /// FLEVEL, 0 (fastest) to 3 (slowest), only a hint of how the data was compressed
+    pub level_hint: u8,
+    /// DICTID: the Adler-32 of the preset dictionary the stream was compressed against
+    pub dictionary_id: Option<u32>
+}
+
+impl ZlibHeader {
+    /// The header for a stream deflated at `level`
+    pub fn for_level(level: u8, dictionary_id: Option<u32>) -> ZlibHeader {
+        let level_hint = match level {
+            0 | 1 => 0,
+            2..=5 => 1,
+            6 => 2,
+            _ => 3
+        };
+        ZlibHeader { window_bits: MAX_WINDOW_BITS + 8, level_hint, dictionary_id }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let cmf = (self.window_bits - 8) << 4 | METHOD_DEFLATE;
+        let mut flg = self.level_hint << 6;
+        if self.dictionary_id.is_some() {
+            flg |= FDICT;
+        }
+        // FCHECK makes CMF * 256 + FLG a multiple of 31
+        flg += (31 - (((cmf as u16) << 8 | flg as u16) % 31) as u8) % 31;
+
+        let mut bytes = vec![cmf, flg];
+        if let Some(id) = self.dictionary_id {
+            bytes.extend_from_slice(&id.to_be_bytes());
+        }
+        bytes
+    }
+
+    /// Parses a header from the start of `data`, returning it along with its length
+    pub fn parse(data: &[u8]) -> Result<(ZlibHeader, usize), ZlibError> {
+        let fixed = data.get(..2).ok_or(ZlibError::TruncatedHeader)?;
+        let (cmf, flg) = (fixed[0], fixed[1]);
+        if u16::from_be_bytes([cmf, flg]) % 31 != 0 {
+            return Err(ZlibError::InvalidHeader);
+        }
+        if cmf & 0x0F != METHOD_DEFLATE {
+            return Err(ZlibError::UnsupportedMethod(cmf & 0x0F));
+        }
+        if cmf >> 4 > MAX_WINDOW_BITS {
+            return Err(ZlibError::InvalidWindowSize(cmf >> 4));
+        }
+
+        let mut header = ZlibHeader { window_bits: (cmf >> 4) + 8, level_hint: flg >> 6, dictionary_id: None };
+        if flg & FDICT == 0 {
+            return Ok((header, 2));
+        }
+        let id = data.get(2..6).ok_or(ZlibError::TruncatedHeader)?;
+        header.dictionary_id = Some(u32::from_be_bytes([id[0], id[1], id[2], id[3]]));
+        Ok((header, 6))
+    }
+}
+
+/// Compresses `data` into a zlib stream, deflating at `level`
+pub fn compress(data: &[u8], level: u8) -> Vec<u8> {
+    let mut output = ZlibHeader::for_level(level, None).to_bytes();
+    output.extend_from_slice(&deflate::deflate(data, level));
+    output.extend_from_slice(&adler32::checksum(data).to_be_bytes());
+    output
+}
+
+/// Compresses `data` against a preset dictionary, which the header names by its Adler-32 (FDICT).
+/// Only the dictionary's last 32 KiB is used, as with zlib's deflateSetDictionary.
+pub fn compress_with_dictionary(data: &[u8], dictionary: &[u8], level: u8) -> Vec<u8> {
+    let mut output = ZlibHeader::for_level(level, Some(adler32::checksum(dictionary))).to_bytes();
+    output.extend_from_slice(&deflate::deflate_with_dictionary(data, dictionary, level));
+    output.extend_from_slice(&adler32::checksum(data).to_be_bytes());
+    output
+}
+
+/// Decompresses a zlib stream, refusing to produce more than `max_output` bytes
+pub fn decompress(data: &[u8], max_output: u64) -> Result<Vec<u8>, ZlibError> {
+    decompress_stream(data, None, max_output)
+}
+
+/// Decompresses a zlib stream that may have been compressed against `dictionary`. Streams without FDICT
+/// decompress as usual, the dictionary unused.
+pub fn decompress_with_dictionary(data: &[u8], dictionary: &[u8], max_output: u64) -> Result<Vec<u8>, ZlibError> {
+    decompress_stream(data, Some(dictionary), max_output)
+}
+
+fn decompress_stream(data: &[u8], dictionary: Option<&[u8]>, max_output: u64) -> Result<Vec<u8>, ZlibError> {
+    let (header, header_len) = ZlibHeader::parse(data)?;
+    let body = &data[header_len..];
+    let (output, used) = match header.dictionary_id {
+        None => inflate::inflate_prefix(body, max_output)?,
+        Some(id) => inflate::inflate_prefix_with_dictionary(body, check_dictionary(id, dictionary)?, max_output)?
+    };
+
+    let trailer = body.get(used..used + 4).ok_or(ZlibError::TruncatedTrailer)?;
+    let expected = u32::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let found = adler32::checksum(&output);
+    if expected != found {
+        return Err(ZlibError::ChecksumMismatch { expected, found });
+    }
+    Ok(output)
+}
+
+/// The dictionary to decompress with, if it's the one a header's DICTID names
+fn check_dictionary(id: u32, dictionary: Option<&[u8]>) -> Result<&[u8], ZlibError> {
+    let dictionary = dictionary.ok_or(ZlibError::DictionaryRequired(id))?;
+    let found = adler32::checksum(dictionary);
+    if found != id {
+        return Err(ZlibError::WrongDictionary { expected: id, found });
+    }
+    Ok(dictionary)
+}
+
+/// Writes a zlib stream of everything written to it into `inner`. `flush` does a sync flush; call
+/// `finish` to write the trailer, since dropping the encoder leaves the stream unfinished.
+pub struct ZlibEncoder<W: Write> {
+    writer: DeflateWriter<W>,
+    adler: Adler32
+}
+
+impl<W: Write> ZlibEncoder<W> {
+    pub fn new(mut inner: W, level: u8) -> io::Result<ZlibEncoder<W>> {
+        inner.write_all(&ZlibHeader::for_level(level, None).to_bytes())?;
+        Ok(ZlibEncoder { writer: DeflateWriter::new(inner, level), adler: Adler32::new() })
+    }
+
+    /// An encoder compressing against a preset dictionary, as for `compress_with_dictionary`
+    pub fn with_dictionary(mut inner: W, level: u8, dictionary: &[u8]) -> io::Result<ZlibEncoder<W>> {
+        inner.write_all(&ZlibHeader::for_level(level, Some(adler32::checksum(dictionary))).to_bytes())?;
+        Ok(ZlibEncoder { writer: DeflateWriter::with_dictionary(inner, level, dictionary), adler: Adler32::new() })
+    }
+
+    /// Ends the stream and hands back the underlying writer
+    pub fn finish(self) -> io::Result<W> {
+        let mut inner = self.writer.finish()?;
+        inner.write_all(&self.adler.finish().to_be_bytes())?;
+        inner.flush()?;
+        Ok(inner)
+    }
+}
+
+impl<W: Write> Write for ZlibEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.adler.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads the decompressed contents of a zlib stream coming from `inner`, checking the Adler-32 at the end
+pub struct ZlibDecoder<R: Read> {
+    reader: InflateReader<R>,
+    dictionary: Option<Vec<u8>>,
+    adler: Adler32,
+    header_read: bool,
+    finished: bool
+}
+
+impl<R: Read> ZlibDecoder<R> {
+    pub fn new(inner: R) -> ZlibDecoder<R> {
+        ZlibDecoder { reader: InflateReader::new(inner), dictionary: None, adler: Adler32::new(), header_read: false, finished: false }
+    }
+
+    /// A decoder for streams that may have been compressed against `dictionary`
+    pub fn with_dictionary(inner: R, dictionary: &[u8]) -> ZlibDecoder<R> {
+        let mut decoder = ZlibDecoder::new(inner);
+        decoder.dictionary = Some(dictionary.to_vec());
+        decoder
+    }
+
+    fn read_header(&mut self) -> io::Result<()> {
+        let inner = self.reader.get_mut();
+        let mut header = [0u8; 6];
+        inner.read_exact(&mut header[..2]).map_err(|_| ZlibError::TruncatedHeader)?;
+        let (parsed, _) = match ZlibHeader::parse(&header[..2]) {
+            Err(ZlibError::TruncatedHeader) => {
+                inner.read_exact(&mut header[2..]).map_err(|_| ZlibError::TruncatedHeader)?;
+                ZlibHeader::parse(&header)?
+            },
+            result => result?
+        };
+        if let Some(id) = parsed.dictionary_id {
+            let dictionary = check_dictionary(id, self.dictionary.as_deref())?.to_vec();
+            self.reader.set_dictionary(&dictionary);
+        }
+        self.header_read = true;
+        Ok(())
+    }
+
+    /// Checks the Adler-32 trailer, which may be partly read ahead already
+    fn check_trailer(&mut self) -> io::Result<()> {
+        let mut trailer = self.reader.unused_input();
+        if trailer.len() < 4 {
+            let mut rest = vec![0u8; 4 - trailer.len()];
+            self.reader.get_mut().read_exact(&mut rest).map_err(|_| ZlibError::TruncatedTrailer)?;
+            trailer.extend_from_slice(&rest);
+        }
+        let expected = u32::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        let found = self.adler.finish();
+        if expected != found {
+            return Err(ZlibError::ChecksumMismatch { expected, found }.into());
+        }
+        self.finished = true;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader.into_inner()
+    }
+}
+
+impl<R: Read> Read for ZlibDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.finished || buf.is_empty() {
+            return Ok(0);
+        }
+        if !self.header_read {
+            self.read_header()?;
+        }
+
+        let count = self.reader.read(buf)?;
+        self.adler.update(&buf[..count]);
+        if count == 0 {
+            self.check_trailer()?;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // zlib.compress(b"hello hello hello hello\n", 9) from Python with zlib 1.2.13
+    const PYTHON_LEVEL_9: [u8; 17] = [
+        0x78, 0xDA, 0xCB, 0x48, 0xCD, 0xC9, 0xC9, 0x57, 0xC8, 0x40, 0x27, 0xB9, 0x00, 0x70, 0xBE, 0x08, 0xBB
+    ];
+
+    // The same from compressobj(9, zdict=DICTIONARY), so with FDICT set and DICTID 0xEAEA0BD8
+    const DICTIONARY: &[u8] = br#"{"name": "", "email": "", "active": true}"#;
+    const MESSAGE: &[u8] = br#"{"name": "ann", "email": "ann@example.com", "active": true}"#;
+    const PYTHON_WITH_DICTIONARY: [u8; 36] = [
+        0x78, 0xF9, 0xEA, 0xEA, 0x0B, 0xD8, 0xAB, 0x86, 0x2B, 0x4C, 0xCC, 0xCB, 0x43, 0x51, 0x0B, 0xE4,
+        0x3B, 0xA4, 0x56, 0x24, 0xE6, 0x16, 0xE4, 0xA4, 0xEA, 0x25, 0xE7, 0xE7, 0x62, 0x6A, 0x05, 0x00,
+        0x22, 0x09, 0x12, 0xEB
+    ];
+
+    /// A reader that hands over one byte per read, to catch assumptions about how much arrives at once
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match (self.0.split_first(), buf.first_mut()) {
+                (Some((&byte, rest)), Some(slot)) => {
+                    *slot = byte;
+                    self.0 = rest;
+                    Ok(1)
+                },
+                _ => Ok(0)
+            }
+        }
+    }
+
+    fn text() -> Vec<u8> {
+        std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/red.txt")).unwrap()
+    }
+
+    #[test]
+    fn headers_match_zlibs() {
+        for &(level, bytes) in &[(0, [0x78, 0x01]), (1, [0x78, 0x01]), (5, [0x78, 0x5E]), (6, [0x78, 0x9C]), (9, [0x78, 0xDA])] {
+            assert_eq!(ZlibHeader::for_level(level, None).to_bytes(), bytes, "level {}", level);
+        }
+        let with_dictionary = ZlibHeader::for_level(6, Some(0x0102_0304)).to_bytes();
+        assert_eq!(with_dictionary, [0x78, 0xBB, 0x01, 0x02, 0x03, 0x04]);
+    }
+
+    #[test]
+    fn header_check_bits() {
+        for window_bits in 8..=15 {
+            for level_hint in 0..4 {
+                for &dictionary_id in &[None, Some(0xFFFF_FFFF)] {
+                    let header = ZlibHeader { window_bits, level_hint, dictionary_id };
+                    let bytes = header.to_bytes();
+                    assert_eq!(u16::from_be_bytes([bytes[0], bytes[1]]) % 31, 0);
+                    assert_eq!(ZlibHeader::parse(&bytes), Ok((header, bytes.len())));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        assert_eq!(ZlibHeader::parse(&[0x78, 0x9D]), Err(ZlibError::InvalidHeader));
+        assert_eq!(ZlibHeader::parse(&[0x77, 0x85]), Err(ZlibError::UnsupportedMethod(7)));
+        assert_eq!(ZlibHeader::parse(&[0x88, 0x98]), Err(ZlibError::InvalidWindowSize(8)));
+        assert_eq!(ZlibHeader::parse(&[0x78]), Err(ZlibError::TruncatedHeader));
+        assert_eq!(ZlibHeader::parse(&[0x78, 0xBB, 0x01, 0x02]), Err(ZlibError::TruncatedHeader));
+    }
+
+    #[test]
+    fn reads_zlibs_output() {
+        assert_eq!(decompress(&PYTHON_LEVEL_9, 100).unwrap(), b"hello hello hello hello\n");
+        let mut data = Vec::new();
+        ZlibDecoder::new(Trickle(&PYTHON_LEVEL_9)).read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"hello hello hello hello\n");
+    }
+
+    #[test]
+    fn checks_the_trailer() {
+        let mut data = PYTHON_LEVEL_9;
+        data[16] ^= 1;
+        assert!(matches!(decompress(&data, 100), Err(ZlibError::ChecksumMismatch { .. })));
+        let err = ZlibDecoder::new(&data[..]).read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        assert_eq!(decompress(&PYTHON_LEVEL_9[..15], 100), Err(ZlibError::TruncatedTrailer));
+        assert!(ZlibDecoder::new(&PYTHON_LEVEL_9[..15]).read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn round_trips() {
+        let text = text();
+        for &level in &[0, 1, 6, 9] {
+            let compressed = compress(&text, level);
+            assert_eq!(compressed[..2], ZlibHeader::for_level(level, None).to_bytes()[..]);
+            assert_eq!(decompress(&compressed, text.len() as u64).unwrap(), text, "level {}", level);
+        }
+    }
+
+    #[test]
+    fn encoder_and_decoder() {
+        let text = text();
+        let mut encoder = ZlibEncoder::new(Vec::new(), 6).unwrap();
+        for chunk in text.chunks(1000) {
+            encoder.write_all(chunk).unwrap();
+            // Sync flushes along the way don't change what comes out the other end
+            if chunk.len() == 1000 && chunk[0] % 4 == 0 {
+                encoder.flush().unwrap();
+            }
+        }
+        let compressed = encoder.finish().unwrap();
+        assert_eq!(decompress(&compressed, u64::MAX).unwrap(), text);
+
+        let mut data = Vec::new();
+        ZlibDecoder::new(&compressed[..]).read_to_end(&mut data).unwrap();
+        assert!(data == text);
+        let mut data = Vec::new();
+        ZlibDecoder::new(Trickle(&compressed)).read_to_end(&mut data).unwrap();
+        assert!(data == text);
+    }
+
+    #[test]
+    fn decoder_leaves_what_follows() {
+        let mut data = PYTHON_LEVEL_9.to_vec();
+        data.extend_from_slice(b"next");
+        let mut decoder = ZlibDecoder::new(Trickle(&data));
+        let mut output = Vec::new();
+        decoder.read_to_end(&mut output).unwrap();
+        assert_eq!(output, b"hello hello hello hello\n");
+        assert_eq!(decoder.into_inner().0, b"next");
+    }
+
+    #[test]
+    fn fdict_streams_need_their_dictionary() {
+        let (header, _) = ZlibHeader::parse(&PYTHON_WITH_DICTIONARY).unwrap();
+        assert_eq!(header.dictionary_id, Some(0xEAEA_0BD8));
+        assert_eq!(adler32::checksum(DICTIONARY), 0xEAEA_0BD8);
+
+        assert_eq!(decompress(&PYTHON_WITH_DICTIONARY, 100), Err(ZlibError::DictionaryRequired(0xEAEA_0BD8)));
+        assert_eq!(decompress_with_dictionary(&PYTHON_WITH_DICTIONARY, DICTIONARY, 100).unwrap(), MESSAGE);
+        assert_eq!(decompress_with_dictionary(&PYTHON_WITH_DICTIONARY, b"something else", 100),
+            Err(ZlibError::WrongDictionary { expected: 0xEAEA_0BD8, found: adler32::checksum(b"something else") }));
+        // A dictionary on hand does no harm to a stream that doesn't use one
+        assert_eq!(decompress_with_dictionary(&PYTHON_LEVEL_9, DICTIONARY, 100).unwrap(), b"hello hello hello hello\n");
+    }
+
+    #[test]
+    fn fdict_round_trips() {
+        let compressed = compress_with_dictionary(MESSAGE, DICTIONARY, 9);
+        assert_eq!(compressed[..6], [0x78, 0xF9, 0xEA, 0xEA, 0x0B, 0xD8]);
+        assert!(compressed.len() < compress(MESSAGE, 9).len());
+        assert_eq!(decompress_with_dictionary(&compressed, DICTIONARY, 100).unwrap(), MESSAGE);
+        assert_eq!(decompress(&compressed, 100), Err(ZlibError::DictionaryRequired(0xEAEA_0BD8)));
+
+        // Only the last 32K of a longer dictionary is used, at both ends
+        let long: Vec<u8> = [&[b'x'; 40_000][..], DICTIONARY].concat();
+        let compressed = compress_with_dictionary(MESSAGE, &long, 9);
+        assert_eq!(decompress_with_dictionary(&compressed, &long, 100).unwrap(), MESSAGE);
+    }
+
+    #[test]
+    fn fdict_through_the_adaptors() {
+        let mut encoder = ZlibEncoder::with_dictionary(Vec::new(), 9, DICTIONARY).unwrap();
+        encoder.write_all(MESSAGE).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert_eq!(decompress_with_dictionary(&compressed, DICTIONARY, 100).unwrap(), MESSAGE);
+
+        let mut data = Vec::new();
+        ZlibDecoder::with_dictionary(Trickle(&PYTHON_WITH_DICTIONARY), DICTIONARY).read_to_end(&mut data).unwrap();
+        assert_eq!(data, MESSAGE);
+        let err = ZlibDecoder::new(&PYTHON_WITH_DICTIONARY[..]).read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("0xEAEA0BD8"), "{}", err);
+    }
+}
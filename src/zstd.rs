@@ -0,0 +1,1286 @@
+// Zstandard frames, zip method 93, RFC 8878 https://tools.ietf.org/html/rfc8878
+// A frame is a header, blocks of up to 128K each raw, RLE or compressed, and optionally the low 32 bits of the
+// content's XXH64. A compressed block holds literals, huffman coded or not, and sequences (literal length,
+// offset, match length) coded with three interleaved FSE (tANS) streams read backwards from the block's end.
+use std::fmt;
+use crate::bits::{BitReader, BitWriter};
+use crate::huffman::limited_code_lengths;
+use crate::lz77;
+use crate::lz77::Token;
+use crate::xxhash64;
+
+const FRAME_MAGIC: u32 = 0xFD2F_B528;
+/// Skippable frames use any of 16 magic numbers, 0x184D2A50 to 0x184D2A5F
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+const SKIPPABLE_MASK: u32 = 0xFFFF_FFF0;
+
+const MAX_BLOCK_SIZE: usize = 128 * 1024;
+
+const BLOCK_RAW: u32 = 0;
+const BLOCK_RLE: u32 = 1;
+const BLOCK_COMPRESSED: u32 = 2;
+
+const LITERALS_RAW: u8 = 0;
+const LITERALS_RLE: u8 = 1;
+const LITERALS_COMPRESSED: u8 = 2;
+/// Compressed literals that reuse the previous block's huffman table
+const LITERALS_TREELESS: u8 = 3;
+
+/// Longest huffman code for literals
+const MAX_HUFFMAN_BITS: u32 = 11;
+/// Accuracy log limit for the FSE table that codes huffman weights
+const MAX_WEIGHT_LOG: u32 = 6;
+/// The most weights a direct (4 bits each) description can hold
+const MAX_DIRECT_WEIGHTS: usize = 128;
+
+const MODE_PREDEFINED: u8 = 0;
+const MODE_RLE: u8 = 1;
+const MODE_FSE: u8 = 2;
+/// Reuse the previous block's table
+const MODE_REPEAT: u8 = 3;
+
+const MAX_LITERAL_LENGTH_CODE: usize = 35;
+const MAX_MATCH_LENGTH_CODE: usize = 52;
+const MAX_OFFSET_CODE: usize = 31;
+const MAX_LITERAL_LENGTH_LOG: u32 = 9;
+const MAX_MATCH_LENGTH_LOG: u32 = 9;
+const MAX_OFFSET_LOG: u32 = 8;
+
+const LITERAL_LENGTH_BASE: [u32; 36] = [
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+    16, 18, 20, 22, 24, 28, 32, 40, 48, 64, 128, 256, 512, 1024, 2048, 4096, 8192, 16384, 32768, 65536
+];
+const LITERAL_LENGTH_BITS: [u32; 36] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    1, 1, 1, 1, 2, 2, 3, 3, 4, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16
+];
+const MATCH_LENGTH_BASE: [u32; 53] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34,
+    35, 37, 39, 41, 43, 47, 51, 59, 67, 83, 99, 131, 259, 515, 1027, 2051, 4099, 8195, 16387, 32771, 65539
+];
+const MATCH_LENGTH_BITS: [u32; 53] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    1, 1, 1, 1, 2, 2, 3, 3, 4, 4, 5, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16
+];
+
+/// The predefined distributions, for blocks that don't describe their own (-1 is "less than 1")
+const LITERAL_LENGTH_DEFAULT: [i16; 36] = [
+    4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1, 1, 1, 1, -1, -1, -1, -1
+];
+const LITERAL_LENGTH_DEFAULT_LOG: u32 = 6;
+const MATCH_LENGTH_DEFAULT: [i16; 53] = [
+    1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1, -1, -1
+];
+const MATCH_LENGTH_DEFAULT_LOG: u32 = 6;
+const OFFSET_DEFAULT: [i16; 29] = [
+    1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1
+];
+const OFFSET_DEFAULT_LOG: u32 = 5;
+
+/// The repeat offsets every frame starts with
+const INITIAL_REPEATS: [usize; 3] = [1, 4, 8];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ZstdError {
+    /// Neither a zstd frame nor a skippable frame
+    InvalidMagic,
+    /// The frame header's reserved bit is set
+    InvalidFrameHeader,
+    /// The frame was compressed against a dictionary, which isn't supported
+    DictionaryRequired(u32),
+    ReservedBlockType,
+    BlockTooLarge,
+    /// The literals section is malformed or its huffman streams don't decode to the right sizes
+    InvalidLiterals,
+    InvalidHuffmanTable,
+    /// An FSE table description is malformed
+    InvalidFseTable,
+    /// The sequences section is malformed or its bitstream doesn't end where it should
+    InvalidSequences,
+    /// A match reaches back before the start of the frame
+    InvalidOffset,
+    ChecksumMismatch { expected: u32, found: u32 },
+    /// The frame doesn't hold as much data as its header says
+    ContentSizeMismatch { expected: u64, found: u64 },
+    UnexpectedEof,
+    /// Decompressing would produce more than the allowed number of bytes
+    OutputLimit,
+    /// Bytes after the last frame that aren't another frame
+    TrailingData
+}
+
+impl fmt::Display for ZstdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ZstdError::InvalidMagic => write!(f, "not in zstd format"),
+            ZstdError::InvalidFrameHeader => write!(f, "reserved frame header bit set"),
+            ZstdError::DictionaryRequired(id) => write!(f, "frame needs dictionary {:#010X}", id),
+            ZstdError::ReservedBlockType => write!(f, "reserved block type"),
+            ZstdError::BlockTooLarge => write!(f, "block is larger than 128K"),
+            ZstdError::InvalidLiterals => write!(f, "invalid literals section"),
+            ZstdError::InvalidHuffmanTable => write!(f, "invalid huffman table"),
+            ZstdError::InvalidFseTable => write!(f, "invalid FSE table"),
+            ZstdError::InvalidSequences => write!(f, "invalid sequences section"),
+            ZstdError::InvalidOffset => write!(f, "match offset out of range"),
+            ZstdError::ChecksumMismatch { expected, found } =>
+                write!(f, "content checksum mismatch (expected {:#010X}, found {:#010X})", expected, found),
+            ZstdError::ContentSizeMismatch { expected, found } =>
+                write!(f, "length mismatch (expected {}, found {})", expected, found),
+            ZstdError::UnexpectedEof => write!(f, "unexpected end of compressed data"),
+            ZstdError::OutputLimit => write!(f, "output exceeds the size limit"),
+            ZstdError::TrailingData => write!(f, "trailing garbage after zstd data")
+        }
+    }
+}
+
+impl std::error::Error for ZstdError {}
+
+fn mask(count: u32) -> u64 {
+    if count >= 64 { u64::MAX } else { (1 << count) - 1 }
+}
+
+/// Index of the highest set bit
+fn high_bit(value: u32) -> u32 {
+    31 - value.leading_zeros()
+}
+
+fn slice(data: &[u8], start: usize, len: usize) -> Result<&[u8], ZstdError> {
+    data.get(start..start.checked_add(len).ok_or(ZstdError::UnexpectedEof)?).ok_or(ZstdError::UnexpectedEof)
+}
+
+fn le_value(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |value, &byte| value << 8 | byte as u64)
+}
+
+/// Huffman and FSE streams are written forwards and read from the end back: the last byte's highest set
+/// bit marks where the data stops, and each read takes the bits just below the last
+struct BackwardBits<'a> {
+    data: &'a [u8],
+    /// Bits left to read; negative once reads have gone past the start, which read as zeros
+    position: isize
+}
+
+impl<'a> BackwardBits<'a> {
+    fn new(data: &'a [u8]) -> Result<BackwardBits<'a>, ZstdError> {
+        match data.last() {
+            Some(&last) if last != 0 => Ok(BackwardBits { data, position: ((data.len() - 1) * 8 + high_bit(last as u32) as usize) as isize }),
+            _ => Err(ZstdError::UnexpectedEof)
+        }
+    }
+
+    /// Up to 57 bits starting at bit `start`
+    fn window(&self, start: usize) -> u64 {
+        let byte = start / 8;
+        let mut buf = [0u8; 8];
+        let len = (self.data.len() - byte).min(8);
+        buf[..len].copy_from_slice(&self.data[byte..byte + len]);
+        u64::from_le_bytes(buf) >> (start % 8)
+    }
+
+    fn peek(&self, count: u32) -> u64 {
+        if count == 0 || self.position <= 0 {
+            return 0;
+        }
+        let start = self.position - count as isize;
+        if start >= 0 {
+            self.window(start as usize) & mask(count)
+        } else {
+            (self.window(0) & mask(self.position as u32)) << -start
+        }
+    }
+
+    fn consume(&mut self, count: u32) {
+        self.position -= count as isize;
+    }
+
+    fn read(&mut self, count: u32) -> u64 {
+        let value = self.peek(count);
+        self.consume(count);
+        value
+    }
+
+    fn overflowed(&self) -> bool {
+        self.position < 0
+    }
+
+    fn finished(&self) -> bool {
+        self.position == 0
+    }
+}
+
+/// Reads an FSE table description: the normalized counts, indexed by symbol, and the accuracy log,
+/// along with the bytes it took up
+fn read_fse_counts(data: &[u8], max_symbol: usize, max_log: u32) -> Result<(Vec<i16>, u32, usize), ZstdError> {
+    let mut input = BitReader::new(data);
+    let log = input.bits(4).map_err(|_| ZstdError::UnexpectedEof)? + 5;
+    if log > max_log {
+        return Err(ZstdError::InvalidFseTable);
+    }
+
+    let mut counts: Vec<i16> = Vec::new();
+    let mut remaining = (1i32 << log) + 1;
+    let mut threshold = 1i32 << log;
+    let mut bits = log + 1;
+    while remaining > 1 && counts.len() <= max_symbol {
+        let max = 2 * threshold - 1 - remaining;
+        let value = input.peek(bits) as i32;
+        let mut count;
+        if value & (threshold - 1) < max {
+            count = value & (threshold - 1);
+            bits -= 1;
+            input.consume(bits).map_err(|_| ZstdError::UnexpectedEof)?;
+            bits += 1;
+        } else {
+            count = value & (2 * threshold - 1);
+            if count >= threshold {
+                count -= max;
+            }
+            input.consume(bits).map_err(|_| ZstdError::UnexpectedEof)?;
+        }
+        // Counts are stored plus one so "less than 1" can be -1
+        count -= 1;
+        remaining -= count.abs();
+        counts.push(count as i16);
+
+        if count == 0 {
+            // Then a run of more zero counts, two bits at a time until a 2 bit value other than 3
+            loop {
+                let repeat = input.bits(2).map_err(|_| ZstdError::UnexpectedEof)?;
+                counts.extend(std::iter::repeat(0).take(repeat as usize));
+                if repeat != 3 {
+                    break;
+                }
+            }
+        }
+        if remaining < 1 {
+            break;
+        }
+        while remaining < threshold {
+            bits -= 1;
+            threshold >>= 1;
+        }
+    }
+    if remaining != 1 || counts.len() > max_symbol + 1 {
+        return Err(ZstdError::InvalidFseTable);
+    }
+
+    Ok((counts, log, input.bit_position().div_ceil(8)))
+}
+
+/// The symbol each state of a table decodes to, spread the way every FSE coder spreads them:
+/// "less than 1" symbols at the top, the rest stepping through the table
+fn spread_symbols(counts: &[i16], log: u32) -> Result<Vec<u8>, ZstdError> {
+    let size = 1usize << log;
+    let mut symbols = vec![0u8; size];
+    let mut high = size;
+    for (symbol, &count) in counts.iter().enumerate() {
+        if count == -1 {
+            high = high.checked_sub(1).ok_or(ZstdError::InvalidFseTable)?;
+            symbols[high] = symbol as u8;
+        }
+    }
+
+    let step = (size >> 1) + (size >> 3) + 3;
+    let mut pos = 0;
+    for (symbol, &count) in counts.iter().enumerate() {
+        for _ in 0..count.max(0) {
+            symbols[pos] = symbol as u8;
+            loop {
+                pos = (pos + step) & (size - 1);
+                if pos < high {
+                    break;
+                }
+            }
+        }
+    }
+    if pos != 0 {
+        return Err(ZstdError::InvalidFseTable);
+    }
+    Ok(symbols)
+}
+
+#[derive(Debug, Copy, Clone)]
+struct FseEntry {
+    symbol: u8,
+    bits: u8,
+    baseline: u16
+}
+
+/// An FSE decoding table: each state gives a symbol, and how many bits to read to add to `baseline`
+/// for the next state
+#[derive(Debug, Clone)]
+struct FseTable {
+    log: u32,
+    entries: Vec<FseEntry>
+}
+
+impl FseTable {
+    fn new(counts: &[i16], log: u32) -> Result<FseTable, ZstdError> {
+        let size = 1u32 << log;
+        let symbols = spread_symbols(counts, log)?;
+        let mut next: Vec<u32> = counts.iter().map(|&count| if count == -1 { 1 } else { count.max(0) as u32 }).collect();
+        let entries = symbols.iter().map(|&symbol| {
+            let state = next[symbol as usize];
+            next[symbol as usize] += 1;
+            let bits = log - high_bit(state);
+            FseEntry { symbol, bits: bits as u8, baseline: ((state << bits) - size) as u16 }
+        }).collect();
+        Ok(FseTable { log, entries })
+    }
+
+    /// A table with a single state, for RLE mode
+    fn rle(symbol: u8) -> FseTable {
+        FseTable { log: 0, entries: vec![FseEntry { symbol, bits: 0, baseline: 0 }] }
+    }
+
+    fn initial_state(&self, input: &mut BackwardBits) -> usize {
+        input.read(self.log) as usize
+    }
+
+    fn symbol(&self, state: usize) -> usize {
+        self.entries[state].symbol as usize
+    }
+
+    fn next_state(&self, state: usize, input: &mut BackwardBits) -> usize {
+        let entry = self.entries[state];
+        entry.baseline as usize + input.read(entry.bits as u32) as usize
+    }
+}
+
+/// A literals huffman decoding table, indexed by the next `max_bits` bits
+#[derive(Debug, Clone)]
+struct HuffmanTable {
+    max_bits: u32,
+    /// (symbol, code length)
+    entries: Vec<(u8, u8)>
+}
+
+impl HuffmanTable {
+    /// Builds the table from the weights of every symbol but the last, whose weight is whatever
+    /// makes the code complete
+    fn from_weights(mut weights: Vec<u8>) -> Result<HuffmanTable, ZstdError> {
+        let total: u32 = weights.iter().filter(|&&weight| weight > 0).map(|&weight| 1u32 << (weight - 1)).sum();
+        if total == 0 {
+            return Err(ZstdError::InvalidHuffmanTable);
+        }
+        let max_bits = high_bit(total) + 1;
+        if max_bits > MAX_HUFFMAN_BITS {
+            return Err(ZstdError::InvalidHuffmanTable);
+        }
+        let left = (1 << max_bits) - total;
+        if !left.is_power_of_two() {
+            return Err(ZstdError::InvalidHuffmanTable);
+        }
+        weights.push(high_bit(left) as u8 + 1);
+        if weights.len() > 256 {
+            return Err(ZstdError::InvalidHuffmanTable);
+        }
+
+        // Lightest weights (longest codes) first, each weight in symbol order
+        let mut entries = Vec::with_capacity(1 << max_bits);
+        for weight in 1..=max_bits as u8 {
+            for (symbol, _) in weights.iter().enumerate().filter(|&(_, &w)| w == weight) {
+                let code_length = (max_bits + 1) as u8 - weight;
+                entries.extend(std::iter::repeat((symbol as u8, code_length)).take(1 << (weight - 1)));
+            }
+        }
+        Ok(HuffmanTable { max_bits, entries })
+    }
+
+    /// Reads a huffman tree description, returning the table and the bytes the description took up
+    fn read(data: &[u8]) -> Result<(HuffmanTable, usize), ZstdError> {
+        let header = *data.first().ok_or(ZstdError::UnexpectedEof)? as usize;
+        if header >= 128 {
+            // Weights stored directly, 4 bits each
+            let count = header - 127;
+            let bytes = slice(data, 1, count.div_ceil(2))?;
+            let weights = (0..count).map(|i| if i % 2 == 0 { bytes[i / 2] >> 4 } else { bytes[i / 2] & 0xF }).collect();
+            return Ok((HuffmanTable::from_weights(weights)?, 1 + bytes.len()));
+        }
+
+        // Weights FSE coded, with two states taking turns until the bits run out
+        let compressed = slice(data, 1, header)?;
+        let (counts, log, used) = read_fse_counts(compressed, MAX_HUFFMAN_BITS as usize + 1, MAX_WEIGHT_LOG)?;
+        let table = FseTable::new(&counts, log)?;
+        let mut input = BackwardBits::new(&compressed[used..]).map_err(|_| ZstdError::InvalidHuffmanTable)?;
+        let mut states = [table.initial_state(&mut input), table.initial_state(&mut input)];
+        let mut weights = Vec::new();
+        for turn in 0.. {
+            let current = turn % 2;
+            weights.push(table.symbol(states[current]) as u8);
+            states[current] = table.next_state(states[current], &mut input);
+            if input.overflowed() {
+                weights.push(table.symbol(states[1 - current]) as u8);
+                break;
+            }
+            if weights.len() >= 255 {
+                return Err(ZstdError::InvalidHuffmanTable);
+            }
+        }
+        Ok((HuffmanTable::from_weights(weights)?, 1 + header))
+    }
+
+    /// Decodes a stream of exactly `count` literals, which has to use up every bit
+    fn decode_stream(&self, data: &[u8], count: usize, output: &mut Vec<u8>) -> Result<(), ZstdError> {
+        let mut input = BackwardBits::new(data).map_err(|_| ZstdError::InvalidLiterals)?;
+        for _ in 0..count {
+            let (symbol, length) = self.entries[input.peek(self.max_bits) as usize];
+            input.consume(length as u32);
+            output.push(symbol);
+        }
+        if !input.finished() {
+            return Err(ZstdError::InvalidLiterals);
+        }
+        Ok(())
+    }
+}
+
+/// What carries over from one block of a frame to the next
+struct FrameState {
+    repeats: [usize; 3],
+    huffman: Option<HuffmanTable>,
+    literal_lengths: Option<FseTable>,
+    offsets: Option<FseTable>,
+    match_lengths: Option<FseTable>
+}
+
+/// Decompresses one or more concatenated frames, skipping skippable frames, refusing to produce more than
+/// `max_output` bytes
+pub fn decompress(data: &[u8], max_output: u64) -> Result<Vec<u8>, ZstdError> {
+    let mut output = Vec::new();
+    let mut pos = 0;
+    loop {
+        let magic = match data.get(pos..pos + 4) {
+            Some(magic) => u32::from_le_bytes([magic[0], magic[1], magic[2], magic[3]]),
+            None if pos == 0 => return Err(ZstdError::InvalidMagic),
+            None if pos == data.len() => return Ok(output),
+            None => return Err(ZstdError::TrailingData)
+        };
+        if magic == FRAME_MAGIC {
+            pos += read_frame(&data[pos + 4..], &mut output, max_output)? + 4;
+        } else if magic & SKIPPABLE_MASK == SKIPPABLE_MAGIC {
+            let size = le_value(slice(data, pos + 4, 4)?) as usize;
+            slice(data, pos + 8, size)?;
+            pos += 8 + size;
+        } else if pos == 0 {
+            return Err(ZstdError::InvalidMagic);
+        } else {
+            return Err(ZstdError::TrailingData);
+        }
+    }
+}
+
+/// Decodes the frame after its magic number onto `output`, returning the bytes it took up
+fn read_frame(data: &[u8], output: &mut Vec<u8>, max_output: u64) -> Result<usize, ZstdError> {
+    let descriptor = *data.first().ok_or(ZstdError::UnexpectedEof)?;
+    let content_size_flag = descriptor >> 6;
+    let single_segment = descriptor & 0x20 != 0;
+    let has_checksum = descriptor & 0x04 != 0;
+    if descriptor & 0x08 != 0 {
+        return Err(ZstdError::InvalidFrameHeader);
+    }
+    // The window size only matters to decoders that don't keep everything they've produced
+    let mut pos = if single_segment { 1 } else { 2 };
+
+    let dictionary_len = [0, 1, 2, 4][(descriptor & 3) as usize];
+    let dictionary_id = le_value(slice(data, pos, dictionary_len)?) as u32;
+    if dictionary_id != 0 {
+        return Err(ZstdError::DictionaryRequired(dictionary_id));
+    }
+    pos += dictionary_len;
+
+    let content_size_len = match content_size_flag {
+        0 if single_segment => 1,
+        0 => 0,
+        1 => 2,
+        2 => 4,
+        _ => 8
+    };
+    let content_size = match content_size_len {
+        0 => None,
+        // Two byte sizes start from 256, since one byte covers the rest
+        2 => Some(le_value(slice(data, pos, 2)?) + 256),
+        len => Some(le_value(slice(data, pos, len)?))
+    };
+    pos += content_size_len;
+
+    let start = output.len();
+    if content_size.is_some_and(|size| size > max_output.saturating_sub(start as u64)) {
+        return Err(ZstdError::OutputLimit);
+    }
+
+    let mut state = FrameState { repeats: INITIAL_REPEATS, huffman: None, literal_lengths: None, offsets: None, match_lengths: None };
+    loop {
+        let header = le_value(slice(data, pos, 3)?) as u32;
+        pos += 3;
+        let last = header & 1 != 0;
+        let size = (header >> 3) as usize;
+        if size > MAX_BLOCK_SIZE {
+            return Err(ZstdError::BlockTooLarge);
+        }
+        let regenerated = if (header >> 1) & 3 == BLOCK_COMPRESSED { 0 } else { size };
+        if (output.len() + regenerated) as u64 > max_output {
+            return Err(ZstdError::OutputLimit);
+        }
+
+        match (header >> 1) & 3 {
+            BLOCK_RAW => {
+                output.extend_from_slice(slice(data, pos, size)?);
+                pos += size;
+            },
+            BLOCK_RLE => {
+                let byte = *data.get(pos).ok_or(ZstdError::UnexpectedEof)?;
+                output.resize(output.len() + size, byte);
+                pos += 1;
+            },
+            BLOCK_COMPRESSED => {
+                read_block(slice(data, pos, size)?, &mut state, output, start, max_output)?;
+                pos += size;
+            },
+            _ => return Err(ZstdError::ReservedBlockType)
+        }
+        if last {
+            break;
+        }
+    }
+
+    let produced = (output.len() - start) as u64;
+    if let Some(expected) = content_size {
+        if produced != expected {
+            return Err(ZstdError::ContentSizeMismatch { expected, found: produced });
+        }
+    }
+    if has_checksum {
+        let expected = le_value(slice(data, pos, 4)?) as u32;
+        let found = xxhash64::checksum(&output[start..], 0) as u32;
+        if expected != found {
+            return Err(ZstdError::ChecksumMismatch { expected, found });
+        }
+        pos += 4;
+    }
+    Ok(pos)
+}
+
+fn read_block(block: &[u8], state: &mut FrameState, output: &mut Vec<u8>, frame_start: usize, max_output: u64) -> Result<(), ZstdError> {
+    let (literals, used) = read_literals(block, &mut state.huffman)?;
+    let sequences = read_sequences(&block[used..], state)?;
+
+    let mut literal_pos = 0;
+    for (literal_length, offset_value, match_length) in sequences {
+        let copied = literals.get(literal_pos..literal_pos + literal_length).ok_or(ZstdError::InvalidSequences)?;
+        literal_pos += literal_length;
+        if (output.len() + literal_length + match_length) as u64 > max_output {
+            return Err(ZstdError::OutputLimit);
+        }
+        output.extend_from_slice(copied);
+
+        // Offset values 1-3 pick a repeat offset, shifted by one when there are no literals before the match
+        let offset = if offset_value > 3 {
+            let offset = offset_value - 3;
+            state.repeats = [offset, state.repeats[0], state.repeats[1]];
+            offset
+        } else {
+            let index = offset_value - 1 + (literal_length == 0) as usize;
+            let offset = if index == 3 { state.repeats[0] - 1 } else { state.repeats[index] };
+            match index {
+                0 => {},
+                1 => state.repeats.swap(0, 1),
+                _ => state.repeats = [offset, state.repeats[0], state.repeats[1]]
+            }
+            offset
+        };
+        if offset == 0 || offset > output.len() - frame_start {
+            return Err(ZstdError::InvalidOffset);
+        }
+
+        let from = output.len() - offset;
+        for i in 0..match_length {
+            output.push(output[from + i]);
+        }
+    }
+
+    let rest = &literals[literal_pos..];
+    if (output.len() + rest.len()) as u64 > max_output {
+        return Err(ZstdError::OutputLimit);
+    }
+    output.extend_from_slice(rest);
+    Ok(())
+}
+
+/// Reads the literals section, returning the literals and the section's length
+fn read_literals(block: &[u8], huffman: &mut Option<HuffmanTable>) -> Result<(Vec<u8>, usize), ZstdError> {
+    let first = *block.first().ok_or(ZstdError::UnexpectedEof)?;
+    let kind = first & 3;
+    let size_format = (first >> 2) & 3;
+
+    if kind == LITERALS_RAW || kind == LITERALS_RLE {
+        // 5, 12 or 20 bits of size after the type bits and as many of the size format bits as are used
+        let header_len = match size_format {
+            0 | 2 => 1,
+            1 => 2,
+            _ => 3
+        };
+        let size = match header_len {
+            1 => (first >> 3) as usize,
+            _ => (le_value(slice(block, 0, header_len)?) >> 4) as usize
+        };
+        if size > MAX_BLOCK_SIZE {
+            return Err(ZstdError::InvalidLiterals);
+        }
+        return if kind == LITERALS_RAW {
+            Ok((slice(block, header_len, size)?.to_vec(), header_len + size))
+        } else {
+            let byte = *block.get(header_len).ok_or(ZstdError::UnexpectedEof)?;
+            Ok((vec![byte; size], header_len + 1))
+        };
+    }
+
+    let (header_len, size_bits, streams) = match size_format {
+        0 => (3, 10, 1),
+        1 => (3, 10, 4),
+        2 => (4, 14, 4),
+        _ => (5, 18, 4)
+    };
+    let header = le_value(slice(block, 0, header_len)?) >> 4;
+    let regenerated = (header & mask(size_bits)) as usize;
+    let compressed = ((header >> size_bits) & mask(size_bits)) as usize;
+    if regenerated > MAX_BLOCK_SIZE {
+        return Err(ZstdError::InvalidLiterals);
+    }
+    let mut data = slice(block, header_len, compressed)?;
+
+    if kind == LITERALS_COMPRESSED {
+        let (table, used) = HuffmanTable::read(data)?;
+        *huffman = Some(table);
+        data = data.get(used..).ok_or(ZstdError::InvalidHuffmanTable)?;
+    }
+    debug_assert!(kind == LITERALS_COMPRESSED || kind == LITERALS_TREELESS);
+    let table = huffman.as_ref().ok_or(ZstdError::InvalidLiterals)?;
+
+    let mut literals = Vec::with_capacity(regenerated);
+    if streams == 1 {
+        table.decode_stream(data, regenerated, &mut literals)?;
+    } else {
+        // A jump table gives the sizes of the first three streams; each of those holds a quarter of the
+        // literals, rounded up
+        let jumps = slice(data, 0, 6)?;
+        let sizes = [le_value(&jumps[0..2]) as usize, le_value(&jumps[2..4]) as usize, le_value(&jumps[4..6]) as usize];
+        let segment = regenerated.div_ceil(4);
+        if regenerated < 3 * segment || sizes.iter().sum::<usize>() + 6 > data.len() {
+            return Err(ZstdError::InvalidLiterals);
+        }
+        let mut pos = 6;
+        for &size in &sizes {
+            table.decode_stream(&data[pos..pos + size], segment, &mut literals)?;
+            pos += size;
+        }
+        table.decode_stream(&data[pos..], regenerated - 3 * segment, &mut literals)?;
+    }
+    Ok((literals, header_len + compressed))
+}
+
+/// Reads the sequences section as (literal length, offset value, match length) triples
+fn read_sequences(data: &[u8], state: &mut FrameState) -> Result<Vec<(usize, usize, usize)>, ZstdError> {
+    let first = *data.first().ok_or(ZstdError::UnexpectedEof)? as usize;
+    let (count, mut pos) = match first {
+        0 => return if data.len() == 1 { Ok(Vec::new()) } else { Err(ZstdError::InvalidSequences) },
+        1..=127 => (first, 1),
+        128..=254 => (((first - 128) << 8) + *data.get(1).ok_or(ZstdError::UnexpectedEof)? as usize, 2),
+        _ => (le_value(slice(data, 1, 2)?) as usize + 0x7F00, 3)
+    };
+
+    let modes = *data.get(pos).ok_or(ZstdError::UnexpectedEof)?;
+    pos += 1;
+    if modes & 3 != 0 {
+        return Err(ZstdError::InvalidSequences);
+    }
+    pos += read_table(&data[pos..], modes >> 6, &mut state.literal_lengths, &LITERAL_LENGTH_DEFAULT, LITERAL_LENGTH_DEFAULT_LOG,
+        MAX_LITERAL_LENGTH_CODE, MAX_LITERAL_LENGTH_LOG)?;
+    pos += read_table(&data[pos..], (modes >> 4) & 3, &mut state.offsets, &OFFSET_DEFAULT, OFFSET_DEFAULT_LOG,
+        MAX_OFFSET_CODE, MAX_OFFSET_LOG)?;
+    pos += read_table(&data[pos..], (modes >> 2) & 3, &mut state.match_lengths, &MATCH_LENGTH_DEFAULT, MATCH_LENGTH_DEFAULT_LOG,
+        MAX_MATCH_LENGTH_CODE, MAX_MATCH_LENGTH_LOG)?;
+
+    let literal_lengths = state.literal_lengths.as_ref().ok_or(ZstdError::InvalidSequences)?;
+    let offsets = state.offsets.as_ref().ok_or(ZstdError::InvalidSequences)?;
+    let match_lengths = state.match_lengths.as_ref().ok_or(ZstdError::InvalidSequences)?;
+
+    let mut input = BackwardBits::new(data.get(pos..).unwrap_or(&[])).map_err(|_| ZstdError::InvalidSequences)?;
+    let mut literal_state = literal_lengths.initial_state(&mut input);
+    let mut offset_state = offsets.initial_state(&mut input);
+    let mut match_state = match_lengths.initial_state(&mut input);
+
+    let mut sequences = Vec::with_capacity(count);
+    for i in 0..count {
+        let offset_code = offsets.symbol(offset_state) as u32;
+        let match_code = match_lengths.symbol(match_state);
+        let literal_code = literal_lengths.symbol(literal_state);
+
+        let offset_value = (1u64 << offset_code) + input.read(offset_code);
+        let match_length = MATCH_LENGTH_BASE[match_code] as u64 + input.read(MATCH_LENGTH_BITS[match_code]);
+        let literal_length = LITERAL_LENGTH_BASE[literal_code] as u64 + input.read(LITERAL_LENGTH_BITS[literal_code]);
+        sequences.push((literal_length as usize, offset_value as usize, match_length as usize));
+
+        if i + 1 < count {
+            literal_state = literal_lengths.next_state(literal_state, &mut input);
+            match_state = match_lengths.next_state(match_state, &mut input);
+            offset_state = offsets.next_state(offset_state, &mut input);
+        }
+    }
+    if !input.finished() {
+        return Err(ZstdError::InvalidSequences);
+    }
+    Ok(sequences)
+}
+
+/// Sets up one of the three sequence tables for `mode`, returning the bytes its description took up
+fn read_table(data: &[u8], mode: u8, table: &mut Option<FseTable>, default: &[i16], default_log: u32, max_symbol: usize, max_log: u32)
+    -> Result<usize, ZstdError> {
+    match mode {
+        MODE_PREDEFINED => {
+            *table = Some(FseTable::new(default, default_log)?);
+            Ok(0)
+        },
+        MODE_RLE => {
+            let symbol = *data.first().ok_or(ZstdError::UnexpectedEof)?;
+            if symbol as usize > max_symbol {
+                return Err(ZstdError::InvalidSequences);
+            }
+            *table = Some(FseTable::rle(symbol));
+            Ok(1)
+        },
+        MODE_FSE => {
+            let (counts, log, used) = read_fse_counts(data, max_symbol, max_log)?;
+            *table = Some(FseTable::new(&counts, log)?);
+            Ok(used)
+        },
+        _ => {
+            debug_assert_eq!(mode, MODE_REPEAT);
+            if table.is_none() {
+                return Err(ZstdError::InvalidSequences);
+            }
+            Ok(0)
+        }
+    }
+}
+
+/// Compresses `data` into a single frame with its content size and checksum. Matches come from the deflate
+/// match finder at `level`, so they reach back at most 32K; literals are huffman coded where that helps and
+/// sequences use the predefined FSE tables or ones fitted to the block, whichever comes out smaller.
+pub fn compress(data: &[u8], level: u8) -> Vec<u8> {
+    let mut output = FRAME_MAGIC.to_le_bytes().to_vec();
+
+    // Single segment: the window is the whole content, so no window descriptor
+    let (size_flag, size_bytes) = match data.len() {
+        0..=255 => (0, vec![data.len() as u8]),
+        256..=65791 => (1, ((data.len() - 256) as u16).to_le_bytes().to_vec()),
+        len if len <= u32::MAX as usize => (2, (len as u32).to_le_bytes().to_vec()),
+        len => (3, (len as u64).to_le_bytes().to_vec())
+    };
+    output.push(size_flag << 6 | 0x20 | 0x04);
+    output.extend_from_slice(&size_bytes);
+
+    let mut start = 0;
+    loop {
+        let end = (start + MAX_BLOCK_SIZE).min(data.len());
+        let last = end == data.len();
+        let block = &data[start..end];
+        let compressed = compress_block(&lz77::tokenize_from(&data[..end], start, level));
+
+        let (kind, body) = if compressed.len() < block.len() { (BLOCK_COMPRESSED, &compressed[..]) } else { (BLOCK_RAW, block) };
+        let header = (body.len() as u32) << 3 | kind << 1 | last as u32;
+        output.extend_from_slice(&header.to_le_bytes()[..3]);
+        output.extend_from_slice(body);
+
+        if last {
+            break;
+        }
+        start = end;
+    }
+
+    output.extend_from_slice(&(xxhash64::checksum(data, 0) as u32).to_le_bytes());
+    output
+}
+
+/// The code whose base is the largest not above `value`, and the extra bits that make up the difference
+fn length_code(value: u32, bases: &[u32], bits: &[u32]) -> (usize, u32, u32) {
+    let code = bases.iter().rposition(|&base| base <= value).expect("lengths are at least the smallest base");
+    (code, value - bases[code], bits[code])
+}
+
+fn compress_block(tokens: &[Token]) -> Vec<u8> {
+    let mut literals = Vec::new();
+    // (literal length, offset, match length)
+    let mut sequences: Vec<(u32, u32, u32)> = Vec::new();
+    let mut pending_literals = 0;
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => {
+                literals.push(byte);
+                pending_literals += 1;
+            },
+            Token::Match { length, distance } => {
+                sequences.push((pending_literals, distance as u32, length as u32));
+                pending_literals = 0;
+            }
+        }
+    }
+
+    let mut output = encode_literals(&literals);
+
+    match sequences.len() {
+        count @ 0..=127 => output.push(count as u8),
+        count @ 128..=0x7EFF => output.extend_from_slice(&[(count >> 8) as u8 + 128, count as u8]),
+        count => {
+            output.push(255);
+            output.extend_from_slice(&((count - 0x7F00) as u16).to_le_bytes());
+        }
+    }
+    if sequences.is_empty() {
+        return output;
+    }
+
+    // Offset values past 3 are real offsets; the repeat offsets go unused
+    let codes: Vec<[(usize, u32, u32); 3]> = sequences.iter().map(|&(literal_length, offset, match_length)| {
+        let offset_value = offset + 3;
+        let offset_code = high_bit(offset_value);
+        [
+            length_code(literal_length, &LITERAL_LENGTH_BASE, &LITERAL_LENGTH_BITS),
+            (offset_code as usize, offset_value - (1 << offset_code), offset_code),
+            length_code(match_length, &MATCH_LENGTH_BASE, &MATCH_LENGTH_BITS)
+        ]
+    }).collect();
+
+    let predefined = encode_sequences(&codes, false).expect("predefined tables cover every code");
+    match encode_sequences(&codes, true) {
+        Some(fitted) if fitted.len() < predefined.len() => output.extend_from_slice(&fitted),
+        _ => output.extend_from_slice(&predefined)
+    }
+    output
+}
+
+/// Codes the sequences with the predefined tables, or with tables fitted to them and described up front
+/// when `fitted`; None if a fitted table can't be made
+fn encode_sequences(codes: &[[(usize, u32, u32); 3]], fitted: bool) -> Option<Vec<u8>> {
+    let defaults: [(&[i16], u32, u32); 3] = [
+        (&LITERAL_LENGTH_DEFAULT, LITERAL_LENGTH_DEFAULT_LOG, MAX_LITERAL_LENGTH_LOG),
+        (&OFFSET_DEFAULT, OFFSET_DEFAULT_LOG, MAX_OFFSET_LOG),
+        (&MATCH_LENGTH_DEFAULT, MATCH_LENGTH_DEFAULT_LOG, MAX_MATCH_LENGTH_LOG)
+    ];
+    let mut modes = 0;
+    let mut descriptions = Vec::new();
+    let mut tables = Vec::with_capacity(3);
+    for (i, &(default, default_log, max_log)) in defaults.iter().enumerate() {
+        if fitted {
+            let (counts, log) = normalize_counts(codes.iter().map(|code| code[i].0), default.len(), max_log)?;
+            descriptions.extend_from_slice(&write_fse_counts(&counts, log));
+            tables.push(FseEncoder::new(&counts, log));
+            modes |= MODE_FSE << (6 - 2 * i);
+        } else {
+            tables.push(FseEncoder::new(default, default_log));
+        }
+    }
+    let (literal_table, offset_table, match_table) = (&tables[0], &tables[1], &tables[2]);
+    let mut output = vec![modes];
+    output.extend_from_slice(&descriptions);
+
+    // The decoder reads backwards, so the last sequence goes first and the states are written last
+    let mut bits = BitWriter::new();
+    let [last_literal, last_offset, last_match] = codes[codes.len() - 1];
+    let mut literal_state = literal_table.initial_state(last_literal.0);
+    let mut offset_state = offset_table.initial_state(last_offset.0);
+    let mut match_state = match_table.initial_state(last_match.0);
+    for &(_, extra, count) in &[last_literal, last_match, last_offset] {
+        bits.write_bits(extra as u64, count);
+    }
+    for &[literal, offset, matched] in codes.iter().rev().skip(1) {
+        offset_table.encode(&mut bits, &mut offset_state, offset.0);
+        match_table.encode(&mut bits, &mut match_state, matched.0);
+        literal_table.encode(&mut bits, &mut literal_state, literal.0);
+        for &(_, extra, count) in &[literal, matched, offset] {
+            bits.write_bits(extra as u64, count);
+        }
+    }
+    match_table.flush(&mut bits, match_state);
+    offset_table.flush(&mut bits, offset_state);
+    literal_table.flush(&mut bits, literal_state);
+    output.extend_from_slice(&close_stream(bits));
+    Some(output)
+}
+
+/// Scales how often each code turns up to counts summing to a power of two no more than `2^max_log`,
+/// every code used getting at least 1. None if the rounding can't be made to fit.
+fn normalize_counts(codes: impl Iterator<Item = usize>, symbols: usize, max_log: u32) -> Option<(Vec<i16>, u32)> {
+    let mut frequencies = vec![0u32; symbols];
+    for code in codes {
+        frequencies[code] += 1;
+    }
+    let total: u32 = frequencies.iter().sum();
+    let used = frequencies.iter().filter(|&&freq| freq > 0).count() as u32;
+    let log = (high_bit(total) + 1).max(high_bit(used) + 2).clamp(5, max_log);
+    let size = 1i32 << log;
+    if used as i32 > size {
+        return None;
+    }
+
+    let mut counts: Vec<i16> = frequencies.iter().map(|&freq| {
+        if freq == 0 { 0 } else { ((freq as u64 * size as u64 + total as u64 / 2) / total as u64).max(1) as i16 }
+    }).collect();
+    let last = frequencies.iter().rposition(|&freq| freq > 0)?;
+    counts.truncate(last + 1);
+
+    // Whatever rounding left over comes out of, or goes to, the most common code
+    let largest = (0..counts.len()).max_by_key(|&i| counts[i])?;
+    let excess = counts.iter().map(|&count| count as i32).sum::<i32>() - size;
+    let adjusted = counts[largest] as i32 - excess;
+    if adjusted < 1 {
+        return None;
+    }
+    counts[largest] = adjusted as i16;
+    Some((counts, log))
+}
+
+/// The inverse of `read_fse_counts`, zstd's FSE_writeNCount
+fn write_fse_counts(counts: &[i16], log: u32) -> Vec<u8> {
+    let mut bits = BitWriter::new();
+    bits.write_bits((log - 5) as u64, 4);
+
+    let mut remaining = (1i32 << log) + 1;
+    let mut threshold = 1i32 << log;
+    let mut width = log + 1;
+    let mut symbol = 0;
+    let mut previous_zero = false;
+    while remaining > 1 {
+        if previous_zero {
+            let start = symbol;
+            while counts[symbol] == 0 {
+                symbol += 1;
+            }
+            let mut run = symbol - start;
+            while run >= 3 {
+                bits.write_bits(3, 2);
+                run -= 3;
+            }
+            bits.write_bits(run as u64, 2);
+        }
+
+        let count = counts[symbol] as i32;
+        symbol += 1;
+        let max = 2 * threshold - 1 - remaining;
+        remaining -= count.abs();
+        // Stored plus one; values below `max` take a bit less
+        let mut value = count + 1;
+        if value >= threshold {
+            value += max;
+        }
+        bits.write_bits(value as u64, if value < max { width - 1 } else { width });
+        previous_zero = value == 1;
+        while remaining < threshold {
+            width -= 1;
+            threshold >>= 1;
+        }
+    }
+    bits.finish()
+}
+
+/// Ends a backwards-read stream with the marker bit the decoder starts from
+fn close_stream(mut bits: BitWriter) -> Vec<u8> {
+    bits.write_bits(1, 1);
+    bits.finish()
+}
+
+/// An FSE encoding table, zstd's FSE_buildCTable. States run from `size` to `2 * size - 1`.
+struct FseEncoder {
+    log: u32,
+    /// The next state for each (symbol's first slot + state >> bits written)
+    states: Vec<u32>,
+    /// Per symbol: added to a state, the top 16 bits are how many bits to write
+    delta_bits: Vec<u32>,
+    /// Per symbol: where its slots start in `states`, less its count
+    delta_state: Vec<i32>
+}
+
+impl FseEncoder {
+    fn new(counts: &[i16], log: u32) -> FseEncoder {
+        let size = 1u32 << log;
+        let symbols = spread_symbols(counts, log).expect("predefined distributions are valid");
+
+        // "Less than 1" takes a single slot, like 1
+        let slots: Vec<u32> = counts.iter().map(|&count| if count == -1 { 1 } else { count.max(0) as u32 }).collect();
+        let mut next = Vec::with_capacity(counts.len());
+        let mut total = 0;
+        for &count in &slots {
+            next.push(total);
+            total += count;
+        }
+        let mut states = vec![0; size as usize];
+        for (u, &symbol) in symbols.iter().enumerate() {
+            states[next[symbol as usize] as usize] = size + u as u32;
+            next[symbol as usize] += 1;
+        }
+
+        let mut delta_bits = Vec::with_capacity(counts.len());
+        let mut delta_state = Vec::with_capacity(counts.len());
+        let mut total = 0i32;
+        for &count in &slots {
+            match count {
+                // Never encoded
+                0 => {
+                    delta_bits.push(0);
+                    delta_state.push(0);
+                },
+                1 => {
+                    delta_bits.push((log << 16).wrapping_sub(size));
+                    delta_state.push(total - 1);
+                },
+                _ => {
+                    let max_bits_out = log - high_bit(count - 1);
+                    delta_bits.push((max_bits_out << 16).wrapping_sub(count << max_bits_out));
+                    delta_state.push(total - count as i32);
+                }
+            }
+            total += count as i32;
+        }
+        FseEncoder { log, states, delta_bits, delta_state }
+    }
+
+    /// The state to start from so that the last symbol written is `symbol`, without writing any bits
+    fn initial_state(&self, symbol: usize) -> u32 {
+        let bits = self.delta_bits[symbol].wrapping_add(1 << 15) >> 16;
+        let value = (bits << 16).wrapping_sub(self.delta_bits[symbol]);
+        self.states[((value >> bits) as i32 + self.delta_state[symbol]) as usize]
+    }
+
+    fn encode(&self, output: &mut BitWriter, state: &mut u32, symbol: usize) {
+        let bits = state.wrapping_add(self.delta_bits[symbol]) >> 16;
+        output.write_bits(*state as u64, bits);
+        *state = self.states[((*state >> bits) as i32 + self.delta_state[symbol]) as usize];
+    }
+
+    /// Writes the final state, which the decoder reads first
+    fn flush(&self, output: &mut BitWriter, state: u32) {
+        output.write_bits(state as u64, self.log);
+    }
+}
+
+/// Writes a raw or RLE literals section header, whose sizes fit in 5, 12 or 20 bits
+fn literals_header(kind: u8, size: usize) -> Vec<u8> {
+    match size {
+        0..=31 => vec![kind | (size as u8) << 3],
+        32..=4095 => vec![kind | 1 << 2 | (size as u8 & 0xF) << 4, (size >> 4) as u8],
+        _ => vec![kind | 3 << 2 | (size as u8 & 0xF) << 4, (size >> 4) as u8, (size >> 12) as u8]
+    }
+}
+
+fn encode_literals(literals: &[u8]) -> Vec<u8> {
+    let raw = || {
+        let mut output = literals_header(LITERALS_RAW, literals.len());
+        output.extend_from_slice(literals);
+        output
+    };
+    if literals.len() > 1 && literals.iter().all(|&byte| byte == literals[0]) {
+        let mut output = literals_header(LITERALS_RLE, literals.len());
+        output.push(literals[0]);
+        return output;
+    }
+
+    let mut frequencies = [0u64; 256];
+    for &byte in literals {
+        frequencies[byte as usize] += 1;
+    }
+    // Weights are written directly, which covers every symbol but the last up to 128; one symbol needs no code
+    let last_symbol = match frequencies.iter().rposition(|&freq| freq > 0) {
+        Some(last) if last <= MAX_DIRECT_WEIGHTS && frequencies.iter().filter(|&&freq| freq > 0).count() > 1 => last,
+        _ => return raw()
+    };
+    let lengths = limited_code_lengths(&frequencies[..=last_symbol], MAX_HUFFMAN_BITS as u8);
+    let max_bits = *lengths.iter().max().expect("at least two symbols") as u32;
+    // The last weight is implied by the others, so the code has to be complete
+    let kraft: u32 = lengths.iter().filter(|&&len| len > 0).map(|&len| 1 << (max_bits - len as u32)).sum();
+    if kraft != 1 << max_bits {
+        return raw();
+    }
+
+    let weights: Vec<u8> = lengths.iter().map(|&len| if len == 0 { 0 } else { (max_bits + 1) as u8 - len }).collect();
+    let mut description = vec![(127 + last_symbol) as u8];
+    description.extend(weights[..last_symbol].chunks(2).map(|pair| pair[0] << 4 | pair.get(1).copied().unwrap_or(0)));
+
+    // Codes in the order the decoder's table lays them out: lightest weight first, then by symbol
+    let mut codes = [(0u32, 0u32); 256];
+    let mut next = 0u32;
+    for weight in 1..=max_bits as u8 {
+        for symbol in (0..=last_symbol).filter(|&symbol| weights[symbol] == weight) {
+            codes[symbol] = (next >> (weight - 1), max_bits + 1 - weight as u32);
+            next += 1 << (weight - 1);
+        }
+    }
+    let encode_stream = |segment: &[u8]| {
+        let mut bits = BitWriter::new();
+        for &byte in segment.iter().rev() {
+            let (code, length) = codes[byte as usize];
+            bits.write_bits(code as u64, length);
+        }
+        close_stream(bits)
+    };
+
+    // One stream when both sizes fit in 10 bits, otherwise four behind a jump table
+    let mut body = description;
+    let single = encode_stream(literals);
+    let streams = if literals.len() <= 1023 && body.len() + single.len() <= 1023 {
+        body.extend_from_slice(&single);
+        1
+    } else {
+        let segment = literals.len().div_ceil(4);
+        let encoded: Vec<Vec<u8>> = literals.chunks(segment).map(encode_stream).collect();
+        if encoded.len() != 4 || encoded[..3].iter().any(|stream| stream.len() > u16::MAX as usize) {
+            return raw();
+        }
+        for stream in &encoded[..3] {
+            body.extend_from_slice(&(stream.len() as u16).to_le_bytes());
+        }
+        for stream in &encoded {
+            body.extend_from_slice(stream);
+        }
+        4
+    };
+    if body.len() >= literals.len() {
+        return raw();
+    }
+
+    let (size_format, size_bits, header_len) = match (streams, literals.len().max(body.len())) {
+        (1, _) => (0, 10, 3),
+        (_, 0..=1023) => (1, 10, 3),
+        (_, 1024..=16383) => (2, 14, 4),
+        _ => (3, 18, 5)
+    };
+    let header = LITERALS_COMPRESSED as u64 | size_format << 2 | (literals.len() as u64) << 4 | (body.len() as u64) << (4 + size_bits);
+    let mut output = header.to_le_bytes()[..header_len].to_vec();
+    output.extend_from_slice(&body);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::CompressionMethod;
+    use crate::crc32;
+    use crate::ziparchive::ZipArchive;
+
+    // resources/zstd.zip holds the same 161024 bytes (text, every byte value, 140000 zeros, more text) as
+    // compressed by the zstd 1.5.7 command line at levels 1 and 19, without a checksum, without a content
+    // size, and as two frames with a skippable frame between them.
+    const FIXTURE_CRC: u32 = 0x6D87_28F6;
+    const FIXTURE_SIZE: usize = 161_024;
+
+    fn check(name: &str) {
+        let mut archive = ZipArchive::new(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/zstd.zip")).unwrap();
+        let entry = archive.entries().into_iter().find(|entry| entry.name == name).unwrap();
+        assert_eq!(entry.compression_method, CompressionMethod::Zstd);
+
+        let data = archive.read_entry(entry.index).unwrap();
+        assert_eq!(data.len(), FIXTURE_SIZE, "{}", name);
+        assert_eq!(crc32::checksum(&data), FIXTURE_CRC, "{}", name);
+    }
+
+    fn round_trip(data: &[u8], level: u8) {
+        let compressed = compress(data, level);
+        assert_eq!(decompress(&compressed, data.len() as u64).unwrap(), data, "{} bytes at level {}", data.len(), level);
+    }
+
+    fn text() -> Vec<u8> {
+        std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/resources/english3.txt")).unwrap()
+    }
+
+    #[test]
+    fn level_1() {
+        check("level1.txt");
+    }
+
+    #[test]
+    fn level_19() {
+        check("level19.txt");
+    }
+
+    #[test]
+    fn without_checksum() {
+        check("no-checksum.txt");
+    }
+
+    #[test]
+    fn without_content_size() {
+        check("no-content-size.txt");
+    }
+
+    #[test]
+    fn concatenated_and_skippable_frames() {
+        check("frames.txt");
+    }
+
+    #[test]
+    fn round_trips_every_content_size_width() {
+        let text = text();
+        for &len in &[0, 1, 255, 256, 65791, 65792] {
+            round_trip(&text[..len], 6);
+        }
+    }
+
+    #[test]
+    fn round_trips_at_every_level() {
+        let text = &text()[..50_000];
+        for level in 0..=9 {
+            round_trip(text, level);
+        }
+    }
+
+    #[test]
+    fn round_trips_several_blocks() {
+        let text = text();
+        round_trip(&text[..3 * MAX_BLOCK_SIZE + 1000], 6);
+        round_trip(&vec![0; 2 * MAX_BLOCK_SIZE + 1], 6);
+    }
+
+    #[test]
+    fn round_trips_incompressible_data() {
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        let data: Vec<u8> = (0..200_000).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect();
+        let compressed = compress(&data, 6);
+        // Stored as raw blocks: the header, two block headers and the checksum
+        assert_eq!(compressed.len(), data.len() + 9 + 2 * 3 + 4);
+        round_trip(&data, 6);
+    }
+
+    #[test]
+    fn rejects_a_bad_checksum() {
+        let mut compressed = compress(b"hello, hello, hello, world", 6);
+        let last = compressed.len() - 1;
+        compressed[last] ^= 1;
+        assert!(matches!(decompress(&compressed, 100), Err(ZstdError::ChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn limits_the_output() {
+        let compressed = compress(&[7; 10_000], 6);
+        assert!(matches!(decompress(&compressed, 9_999), Err(ZstdError::OutputLimit)));
+        assert_eq!(decompress(&compressed, 10_000).unwrap().len(), 10_000);
+    }
+
+    #[test]
+    fn rejects_trailing_data() {
+        let mut compressed = compress(b"abc", 6);
+        compressed.extend_from_slice(b"xy");
+        assert!(matches!(decompress(&compressed, 100), Err(ZstdError::TrailingData)));
+    }
+
+    #[test]
+    fn asks_for_a_missing_dictionary() {
+        // Single segment, one byte of dictionary ID, one byte of content size
+        let frame = [0x28, 0xB5, 0x2F, 0xFD, 0x21, 0x2A, 0x00];
+        assert!(matches!(decompress(&frame, 100), Err(ZstdError::DictionaryRequired(0x2A))));
+    }
+}